@@ -0,0 +1,187 @@
+//! Cancel in-flight requests.
+
+use std::any::Any;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+use futures::future::{select, Either};
+
+/// The future was cancelled before it completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    /// Dropped along with the token, such as to remove an event listener.
+    guards: Mutex<Vec<Box<dyn Any>>>,
+}
+
+impl std::fmt::Debug for Inner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Inner")
+            .field("cancelled", &self.cancelled)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A token shared between the caller and the futures it can cancel.
+///
+/// Cloning the token shares the cancellation state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+impl CancelToken {
+    /// Build a token that isn't cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel all futures using this token.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let wakers = std::mem::take(&mut *self.inner.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Keep the `guard` until every clone of the token is dropped, such as to
+    /// remove the event listener cancelling the token once it's unused.
+    pub fn keep(&self, guard: impl Any) {
+        self.inner.guards.lock().unwrap().push(Box::new(guard));
+    }
+
+    /// A reference to the token that doesn't keep it, or its guards, alive.
+    pub fn downgrade(&self) -> WeakCancelToken {
+        WeakCancelToken {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Has the token been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// A future that resolves once the token is cancelled.
+    pub fn cancelled(&self) -> WaitCancelled<'_> {
+        WaitCancelled { token: self }
+    }
+
+    /// Run `future` to completion unless the token is cancelled first.
+    ///
+    /// When cancelled, `future` is dropped, which aborts any request it is
+    /// waiting on.
+    pub async fn run<F: Future>(&self, future: F) -> Result<F::Output, Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled);
+        }
+        match select(pin!(future), self.cancelled()).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Cancelled),
+        }
+    }
+}
+
+/// A reference to a [`CancelToken`] that can cancel it while it's in use.
+#[derive(Debug, Clone)]
+pub struct WeakCancelToken {
+    inner: Weak<Inner>,
+}
+
+impl WeakCancelToken {
+    /// Cancel the token, unless every clone of it was dropped.
+    pub fn cancel(&self) {
+        if let Some(inner) = self.inner.upgrade() {
+            CancelToken { inner }.cancel();
+        }
+    }
+}
+
+/// Future returned by [`CancelToken::cancelled`].
+pub struct WaitCancelled<'a> {
+    token: &'a CancelToken,
+}
+
+impl Future for WaitCancelled<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.token.inner.wakers.lock().unwrap();
+        if !wakers.iter().any(|x| x.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        drop(wakers);
+        // check again in case `cancel` ran before the waker was registered
+        if self.token.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use futures::future::pending;
+
+    use super::*;
+
+    #[test]
+    fn runs_to_completion() {
+        let token = CancelToken::new();
+        assert_eq!(block_on(token.run(async { 1 })), Ok(1));
+    }
+
+    #[test]
+    fn cancels_pending_future() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        let result = block_on(async {
+            let run = token.run(pending::<()>());
+            let cancel = async { clone.cancel() };
+            futures::join!(run, cancel).0
+        });
+        assert_eq!(result, Err(Cancelled));
+    }
+
+    #[test]
+    fn drops_guards_with_the_token() {
+        struct Guard(Arc<AtomicBool>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+        let dropped = Arc::new(AtomicBool::new(false));
+        let token = CancelToken::new();
+        let weak = token.downgrade();
+        token.keep(Guard(dropped.clone()));
+        let clone = token.clone();
+        drop(token);
+        assert!(!dropped.load(Ordering::SeqCst));
+        weak.cancel();
+        assert!(clone.is_cancelled());
+        drop(clone);
+        assert!(dropped.load(Ordering::SeqCst));
+        weak.cancel();
+    }
+
+    #[test]
+    fn cancels_before_start() {
+        let token = CancelToken::new();
+        token.cancel();
+        assert_eq!(block_on(token.run(async { 1 })), Err(Cancelled));
+    }
+}
@@ -0,0 +1,158 @@
+//! Settings shared by all the requests made by the library.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// How many times a failed request is retried by default.
+pub const DEFAULT_MAX_RETRIES: usize = 3;
+
+/// How long a streamed response can go without new data by default.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Library-wide settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Request reproducible completions: every request uses `seed` and a
+    /// temperature of 0.
+    pub deterministic: bool,
+    /// The seed sent with every request when `deterministic`.
+    pub seed: i64,
+    /// Extra headers sent with every request, e.g. `OpenAI-Organization` or
+    /// the authentication of an API gateway.
+    pub headers: Vec<(String, String)>,
+    /// The locale of the user, such as `es` or `es-MX`, which selects the
+    /// prompt templates for it and the language of the replies, for the tasks
+    /// without a locale of their own. English if `None`.
+    pub locale: Option<String>,
+    /// Give up on a streamed response that goes this long without new data,
+    /// or wait forever if `None`.
+    pub stall_timeout: Option<Duration>,
+    /// How many times to retry a failed request that isn't made for a task,
+    /// which has its own `max_retries`.
+    pub max_retries: usize,
+    /// Leave out the validation keywords, such as `minimum` and `format`,
+    /// from the function schemas, for compatible backends that reject them.
+    pub portable_schemas: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            deterministic: false,
+            seed: 0,
+            headers: Vec::new(),
+            locale: None,
+            stall_timeout: Some(DEFAULT_STALL_TIMEOUT),
+            max_retries: DEFAULT_MAX_RETRIES,
+            portable_schemas: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    config: Config,
+    /// The distinct `system_fingerprint` values returned by the API, in the
+    /// order they were first seen.
+    fingerprints: Vec<String>,
+    /// The keys to fail over between, used instead of the key passed to each
+    /// request when not empty.
+    keys: Vec<String>,
+    /// The index of the key requests are made with.
+    active_key: usize,
+}
+
+fn state() -> &'static Mutex<State> {
+    static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+    STATE.get_or_init(Default::default)
+}
+
+/// The current settings.
+pub fn config() -> Config {
+    state().lock().unwrap().config.clone()
+}
+
+/// Change the settings used by requests made from now on.
+pub fn update_config(update: impl FnOnce(&mut Config)) {
+    update(&mut state().lock().unwrap().config);
+}
+
+/// Remember the backend configuration that served a completion.
+///
+/// A new fingerprint means the model changed and seeded completions may no
+/// longer reproduce earlier ones.
+pub fn record_fingerprint(fingerprint: &str) {
+    let mut state = state().lock().unwrap();
+    if !state.fingerprints.iter().any(|x| x == fingerprint) {
+        state.fingerprints.push(fingerprint.to_string());
+    }
+}
+
+/// The fingerprints recorded so far.
+pub fn fingerprints() -> Vec<String> {
+    state().lock().unwrap().fingerprints.clone()
+}
+
+/// Authenticate requests with `keys` instead of the key passed to each
+/// request, failing over to the next key when one is rejected.
+///
+/// Starts again from the first key. An empty list uses the key passed to each
+/// request.
+pub fn set_keys(keys: Vec<String>) {
+    let mut state = state().lock().unwrap();
+    state.keys = keys;
+    state.active_key = 0;
+}
+
+/// The index and value of the key requests are made with, if keys are set.
+pub fn active_key() -> Option<(usize, String)> {
+    let state = state().lock().unwrap();
+    let key = state.keys.get(state.active_key)?;
+    Some((state.active_key, key.clone()))
+}
+
+/// Switch to the next key after the key at index `failed` was rejected.
+///
+/// Returns whether there is another key to try. Requests that fail at the
+/// same time with the same key only switch once.
+pub fn fail_over(failed: usize) -> bool {
+    let mut state = state().lock().unwrap();
+    if state.active_key != failed {
+        return state.active_key < state.keys.len();
+    }
+    if failed + 1 < state.keys.len() {
+        state.active_key += 1;
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Clears the keys when dropped, even if the test fails.
+    struct ClearKeys;
+
+    impl Drop for ClearKeys {
+        fn drop(&mut self) {
+            set_keys(Vec::new());
+        }
+    }
+
+    #[test]
+    fn fails_over_between_keys() {
+        let _clear = ClearKeys;
+        set_keys(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(active_key(), Some((0, "a".to_string())));
+        assert!(fail_over(0));
+        // a concurrent request rejected with the first key
+        assert!(fail_over(0));
+        assert_eq!(active_key(), Some((1, "b".to_string())));
+        assert!(!fail_over(1));
+        assert_eq!(active_key(), Some((1, "b".to_string())));
+        set_keys(Vec::new());
+        assert_eq!(active_key(), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! A single file packing the resources a database is built from.
+//!
+//! The archive starts with the magic bytes and a version, followed by the
+//! number of sections and the sections themselves. Each section is a name
+//! prefixed by its length in one byte, and data prefixed by its length in
+//! eight little-endian bytes. Readers skip sections they don't know, so
+//! sections can be added without a new version.
+
+use std::collections::HashMap;
+
+use super::{Error, Result};
+
+/// The bytes an archive starts with.
+const MAGIC: &[u8; 8] = b"CLINTDB\0";
+/// The version of the archive layout written.
+const VERSION: u16 = 1;
+
+/// The resources of a database, borrowed from an archive or to be written
+/// to one.
+///
+/// The resources are those of [`super::DocDb::new`], with an optional
+/// serialized index from [`super::DocDb::get_index_bytes`], and optional
+/// metadata for [`super::DocDb::set_content_hashes`],
+/// [`super::DocDb::set_languages`], [`super::DocDb::set_reviewed`],
+/// [`super::DocDb::set_aliases`], [`super::DocDb::set_codes`] and
+/// [`super::DocDb::set_spans`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Archive<'a> {
+    /// The URL the document contents are fetched from.
+    pub origin: &'a str,
+    /// The `.npy` array of document embeddings, one per row.
+    pub embeddings: &'a [u8],
+    /// The `.npy` array of scale factors, if the embeddings are int8.
+    pub embeddings_scales: Option<&'a [u8]>,
+    /// The `.npy` array mapping query embeddings to the stored dimensions.
+    pub embeddings_pca_mapping: Option<&'a [u8]>,
+    /// The hex document ID of each row, one per line.
+    pub embeddings_id: &'a [u8],
+    /// The document ID and parent ID on each line, separated by a tab.
+    pub parents: &'a [u8],
+    /// The document ID and title on each line, separated by a tab.
+    pub titles: &'a [u8],
+    /// The document ID and URL on each line, separated by a tab.
+    pub urls: &'a [u8],
+    /// The document ID and one of its tags on each line, separated by a tab.
+    pub tags: &'a [u8],
+    /// The serialized index, if one was built ahead of time.
+    pub index: Option<&'a [u8]>,
+    /// The document ID and hash of its contents on each line, if known.
+    pub content_hashes: Option<&'a [u8]>,
+    /// The document ID and its language on each line, if known.
+    pub languages: Option<&'a [u8]>,
+    /// The document ID and the date it was last reviewed on each line, if
+    /// known.
+    pub reviewed: Option<&'a [u8]>,
+    /// The condition's document ID and one of its aliases on each line, if
+    /// known.
+    pub aliases: Option<&'a [u8]>,
+    /// The condition's document ID, a terminology and a code on each line,
+    /// if known.
+    pub codes: Option<&'a [u8]>,
+    /// The chunk's document ID and its start and end in its parent on each
+    /// line, if known.
+    pub spans: Option<&'a [u8]>,
+}
+
+/// Reads the fields of an archive in order, failing if it ends early.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(Error::Archive("archive is truncated"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+}
+
+impl<'a> Archive<'a> {
+    /// Read the resources packed in `bytes`.
+    ///
+    /// Fails if the bytes aren't an archive, the archive is a newer version,
+    /// or a required section is missing.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(Error::Archive("not a database archive"));
+        }
+        if u16::from_le_bytes(reader.take_array()?) > VERSION {
+            return Err(Error::Archive("archive version is not supported"));
+        }
+        let count = u16::from_le_bytes(reader.take_array()?);
+        let mut sections = HashMap::new();
+        for _ in 0..count {
+            let [name_len] = reader.take_array()?;
+            let name = reader.take(name_len as usize)?;
+            let len = u64::from_le_bytes(reader.take_array()?);
+            let len = usize::try_from(len).map_err(|_| Error::Archive("archive is truncated"))?;
+            sections.insert(name, reader.take(len)?);
+        }
+        let required = |name: &str| {
+            sections
+                .get(name.as_bytes())
+                .copied()
+                .ok_or(Error::Archive("archive is missing a section"))
+        };
+        Ok(Self {
+            origin: std::str::from_utf8(required("origin")?)
+                .map_err(|_| Error::Archive("origin is not UTF-8"))?,
+            embeddings: required("embeddings")?,
+            embeddings_scales: required("embeddings_scales").ok(),
+            embeddings_pca_mapping: required("embeddings_pca_mapping").ok(),
+            embeddings_id: required("embeddings_id")?,
+            parents: required("parents")?,
+            titles: required("titles")?,
+            urls: required("urls")?,
+            tags: required("tags")?,
+            index: required("index").ok(),
+            content_hashes: required("content_hashes").ok(),
+            languages: required("languages").ok(),
+            reviewed: required("reviewed").ok(),
+            aliases: required("aliases").ok(),
+            codes: required("codes").ok(),
+            spans: required("spans").ok(),
+        })
+    }
+
+    /// Pack the resources into an archive.
+    #[cfg(any(test, all(feature = "archive-writer", not(target_arch = "wasm32"))))]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sections = [
+            ("origin", Some(self.origin.as_bytes())),
+            ("embeddings", Some(self.embeddings)),
+            ("embeddings_scales", self.embeddings_scales),
+            ("embeddings_pca_mapping", self.embeddings_pca_mapping),
+            ("embeddings_id", Some(self.embeddings_id)),
+            ("parents", Some(self.parents)),
+            ("titles", Some(self.titles)),
+            ("urls", Some(self.urls)),
+            ("tags", Some(self.tags)),
+            ("index", self.index),
+            ("content_hashes", self.content_hashes),
+            ("languages", self.languages),
+            ("reviewed", self.reviewed),
+            ("aliases", self.aliases),
+            ("codes", self.codes),
+            ("spans", self.spans),
+        ];
+        let sections = sections
+            .into_iter()
+            .filter_map(|(name, data)| Some((name, data?)))
+            .collect::<Vec<_>>();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend((sections.len() as u16).to_le_bytes());
+        for (name, data) in sections {
+            bytes.push(name.len() as u8);
+            bytes.extend(name.as_bytes());
+            bytes.extend((data.len() as u64).to_le_bytes());
+            bytes.extend(data);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn archive_round_trips() {
+        let archive = Archive {
+            origin: "https://example.com",
+            embeddings: b"abc",
+            embeddings_pca_mapping: Some(b""),
+            titles: b"def",
+            ..Default::default()
+        };
+        let bytes = archive.to_bytes();
+        assert_eq!(Archive::parse(&bytes).unwrap(), archive);
+        assert!(matches!(
+            Archive::parse(&bytes[..bytes.len() - 1]),
+            Err(Error::Archive(_))
+        ));
+        assert!(matches!(Archive::parse(b"abc"), Err(Error::Archive(_))));
+    }
+}
@@ -0,0 +1,124 @@
+//! Cache the contents of documents so they aren't fetched more than once.
+
+use std::collections::HashMap;
+
+use super::DocId;
+
+/// How many bytes of document contents are kept in memory by default.
+pub const DEFAULT_CAPACITY: usize = 4 << 20;
+
+/// An in-memory cache of document contents that evicts the least recently
+/// used documents once their total size exceeds the capacity.
+#[derive(Debug)]
+pub struct DocumentCache {
+    /// The most bytes of contents kept.
+    capacity: usize,
+    /// The bytes of contents kept.
+    size: usize,
+    /// The contents and when they were last used, for each document.
+    entries: HashMap<DocId, (String, u64)>,
+    clock: u64,
+}
+
+impl Default for DocumentCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl DocumentCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn get(&mut self, id: &DocId) -> Option<String> {
+        self.clock += 1;
+        let (content, used) = self.entries.get_mut(id)?;
+        *used = self.clock;
+        Some(content.clone())
+    }
+
+    /// The bytes of contents kept.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Is the content of the document with `id` kept?
+    pub fn contains(&self, id: &DocId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Keep the `content` of the document with `id`, unless it alone is
+    /// larger than the capacity.
+    pub fn put(&mut self, id: DocId, content: String) {
+        if content.len() > self.capacity {
+            return;
+        }
+        self.clock += 1;
+        self.remove(&id);
+        self.size += content.len();
+        self.entries.insert(id, (content, self.clock));
+        self.evict();
+    }
+
+    /// Drop the contents of the document with `id`, if kept.
+    pub fn remove(&mut self, id: &DocId) {
+        if let Some((content, _)) = self.entries.remove(id) {
+            self.size -= content.len();
+        }
+    }
+
+    /// Drop the contents of every document.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    /// Change the capacity, evicting the least recently used documents that
+    /// no longer fit.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Drop the least recently used documents until the rest fit.
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(x, _)| *x);
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = DocumentCache::new(6);
+        cache.put([0x01; 16], "ab".to_string());
+        cache.put([0x02; 16], "cd".to_string());
+        assert_eq!(cache.get(&[0x01; 16]).as_deref(), Some("ab"));
+        cache.put([0x03; 16], "efg".to_string());
+        assert_eq!(cache.get(&[0x02; 16]), None);
+        assert_eq!(cache.get(&[0x01; 16]).as_deref(), Some("ab"));
+        cache.put([0x04; 16], "too long".to_string());
+        assert_eq!(cache.get(&[0x04; 16]), None);
+        cache.set_capacity(3);
+        assert_eq!(cache.get(&[0x03; 16]), None);
+        assert_eq!(cache.get(&[0x01; 16]).as_deref(), Some("ab"));
+    }
+}
@@ -0,0 +1,54 @@
+//! Resources compressed with gzip or zstd, recognized by their magic bytes.
+
+use std::borrow::Cow;
+use std::io::{self, Read};
+
+/// The bytes a gzip stream starts with.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// The bytes a zstd frame starts with.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decompress the `bytes` if they're compressed with gzip or zstd, or
+/// borrow them as they are.
+///
+/// Neither `.npy` arrays nor the hex IDs starting each line of metadata can
+/// start like a compressed stream, so uncompressed resources are never
+/// mistaken for compressed ones.
+pub fn decompress(bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let mut decompressed = Vec::new();
+    if bytes.starts_with(GZIP_MAGIC) {
+        flate2::read::MultiGzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+    } else if bytes.starts_with(ZSTD_MAGIC) {
+        ruzstd::decoding::StreamingDecoder::new(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+            .read_to_end(&mut decompressed)?;
+    } else {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn decompresses_gzip_and_zstd() {
+        let data = b"0123456789abcdef\tsome title\n".repeat(10);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&data).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = ruzstd::encoding::compress_to_vec(
+            data.as_slice(),
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+        for compressed in [&gzip, &zstd] {
+            assert!(compressed.len() < data.len());
+            assert_eq!(decompress(compressed).unwrap(), data.as_slice());
+        }
+        assert!(matches!(decompress(&data).unwrap(), Cow::Borrowed(_)));
+        assert!(decompress(&gzip[..gzip.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Document embeddings, stored as floats or quantized to save memory.
+
+use ndarray::{concatenate, Array1, Array2, ArrayView1, Axis};
+use noisy_float::prelude::{n32, N32};
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+/// The embeddings of the documents, one per row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Embeddings {
+    /// Plain floats, checked for NaN when they're read, so similarities use
+    /// ndarray's unrolled `f32` kernels, which compile to SIMD instructions.
+    Float(Array2<f32>),
+    /// Each value is `values[[i, j]] * scales[i]`, a quarter of the size of
+    /// float embeddings.
+    Int8 {
+        values: Array2<i8>,
+        scales: Array1<N32>,
+    },
+}
+
+impl Default for Embeddings {
+    fn default() -> Self {
+        Self::Float(Array2::default((0, 0)))
+    }
+}
+
+impl From<Array2<N32>> for Embeddings {
+    fn from(x: Array2<N32>) -> Self {
+        Self::Float(x.mapv(N32::raw))
+    }
+}
+
+impl Embeddings {
+    /// The number of embeddings.
+    pub fn nrows(&self) -> usize {
+        match self {
+            Self::Float(x) => x.nrows(),
+            Self::Int8 { values, .. } => values.nrows(),
+        }
+    }
+
+    /// The number of dimensions of each embedding.
+    pub fn ncols(&self) -> usize {
+        match self {
+            Self::Float(x) => x.ncols(),
+            Self::Int8 { values, .. } => values.ncols(),
+        }
+    }
+
+    /// How many bytes the values take.
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            Self::Float(x) => x.len() * size_of::<f32>(),
+            Self::Int8 { values, scales } => values.len() + scales.len() * size_of::<N32>(),
+        }
+    }
+
+    /// Do quantized embeddings have a scale factor per row?
+    pub fn has_scales(&self) -> bool {
+        match self {
+            Self::Float(_) => true,
+            Self::Int8 { values, scales } => scales.len() == values.nrows(),
+        }
+    }
+
+    /// Are any of the float values NaN, as can only be the case for
+    /// embeddings that weren't read from an array?
+    pub fn has_nan(&self) -> bool {
+        match self {
+            Self::Float(x) => x.iter().any(|x| x.is_nan()),
+            Self::Int8 { .. } => false,
+        }
+    }
+
+    /// Can the `other` embeddings be added after these?
+    pub fn can_append(&self, other: &Embeddings) -> bool {
+        let same_storage = matches!(
+            (self, other),
+            (Self::Float(_), Self::Float(_)) | (Self::Int8 { .. }, Self::Int8 { .. })
+        );
+        self.nrows() == 0 || (same_storage && self.ncols() == other.ncols())
+    }
+
+    /// Add the `other` embeddings after these.
+    ///
+    /// Fails if they aren't stored the same way or don't have as many
+    /// dimensions, unless there are no embeddings yet.
+    pub fn append(&mut self, other: Embeddings) -> Result<(), Embeddings> {
+        if !self.can_append(&other) {
+            return Err(other);
+        }
+        if self.nrows() == 0 {
+            *self = other;
+            return Ok(());
+        }
+        match (&mut *self, other) {
+            (Self::Float(x), Self::Float(y)) => {
+                *x = concatenate![Axis(0), *x, y];
+            }
+            (
+                Self::Int8 { values, scales },
+                Self::Int8 {
+                    values: other_values,
+                    scales: other_scales,
+                },
+            ) => {
+                *values = concatenate![Axis(0), *values, other_values];
+                *scales = concatenate![Axis(0), *scales, other_scales];
+            }
+            (_, other) => return Err(other),
+        }
+        Ok(())
+    }
+
+    /// Keep only the embeddings in `rows`, in that order.
+    pub fn select(&self, rows: &[usize]) -> Embeddings {
+        match self {
+            Self::Float(x) => Self::Float(x.select(Axis(0), rows)),
+            Self::Int8 { values, scales } => Self::Int8 {
+                values: values.select(Axis(0), rows),
+                scales: scales.select(Axis(0), rows),
+            },
+        }
+    }
+
+    /// The similarities of every embedding with the `query`.
+    pub fn dot(&self, query: ArrayView1<N32>) -> Array1<N32> {
+        match self {
+            Self::Float(x) => x.dot(&query.mapv(N32::raw)).mapv(n32),
+            Self::Int8 { .. } => (0..self.nrows()).map(|i| self.row_dot(i, query)).collect(),
+        }
+    }
+
+    /// The similarity of embedding `i` with the `query`.
+    pub fn row_dot(&self, i: usize, query: ArrayView1<N32>) -> N32 {
+        match self {
+            Self::Float(x) => x
+                .row(i)
+                .iter()
+                .zip(query)
+                .map(|(&x, y)| x * y.raw())
+                .sum::<f32>()
+                .pipe(n32),
+            Self::Int8 { values, scales } => {
+                let dot = values
+                    .row(i)
+                    .iter()
+                    .zip(query)
+                    .map(|(&x, y)| x as f32 * y.raw())
+                    .sum::<f32>();
+                n32(dot) * scales[i]
+            }
+        }
+    }
+
+    /// Scale every embedding to unit length, so dot products are cosine
+    /// similarities.
+    pub fn normalize(&mut self) {
+        match self {
+            Self::Float(x) => {
+                for mut row in x.rows_mut() {
+                    let norm = row.dot(&row).sqrt();
+                    if norm > 0.0 {
+                        row.mapv_inplace(|x| x / norm);
+                    }
+                }
+            }
+            Self::Int8 { values, scales } => {
+                for (row, scale) in values.rows().into_iter().zip(scales) {
+                    let norm = row.iter().map(|&x| x as i32 * x as i32).sum::<i32>();
+                    if norm > 0 {
+                        *scale = n32(1.0 / (norm as f32).sqrt());
+                    }
+                }
+            }
+        }
+    }
+
+    /// The similarity of embeddings `i` and `j`.
+    pub fn pair_dot(&self, i: usize, j: usize) -> N32 {
+        match self {
+            Self::Float(x) => n32(x.row(i).dot(&x.row(j))),
+            Self::Int8 { values, scales } => {
+                // integer products don't lose precision or overflow
+                let dot = values
+                    .row(i)
+                    .iter()
+                    .zip(values.row(j))
+                    .map(|(&x, &y)| x as i32 * y as i32)
+                    .sum::<i32>();
+                n32(dot as f32) * scales[i] * scales[j]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::array;
+
+    use super::*;
+
+    #[test]
+    fn int8_matches_float() {
+        let float: Embeddings = array![[0.5, -1.0], [1.0, 0.25]].mapv(n32).into();
+        let int8 = Embeddings::Int8 {
+            values: array![[64, -128], [127, 32]],
+            scales: array![1.0 / 128.0, 1.0 / 127.0].mapv(n32),
+        };
+        let query = array![1.0, 2.0].mapv(n32);
+        for (x, y) in float.dot(query.view()).iter().zip(&int8.dot(query.view())) {
+            assert!((x.raw() - y.raw()).abs() < 0.01);
+        }
+        assert!((float.pair_dot(0, 1).raw() - int8.pair_dot(0, 1).raw()).abs() < 0.01);
+    }
+
+    #[test]
+    fn appends_and_selects() {
+        let mut embeddings: Embeddings = array![[1.0, 0.0]].mapv(n32).into();
+        let other: Embeddings = array![[0.0, 1.0], [1.0, 1.0]].mapv(n32).into();
+        embeddings.append(other).unwrap();
+        let other: Embeddings = array![[0.0, 1.0, 0.0]].mapv(n32).into();
+        assert!(!embeddings.can_append(&other));
+        assert!(embeddings.append(other).is_err());
+        let selected = embeddings.select(&[2, 0]);
+        assert_eq!(selected.nrows(), 2);
+        assert_eq!(selected.pair_dot(0, 1), 1.0);
+    }
+
+    #[test]
+    fn normalizes_to_unit_length() {
+        let mut float: Embeddings = array![[3.0, 4.0], [0.0, 0.0]].mapv(n32).into();
+        let mut int8 = Embeddings::Int8 {
+            values: array![[30, 40], [0, 0]],
+            scales: array![0.5, 0.5].mapv(n32),
+        };
+        float.normalize();
+        int8.normalize();
+        for x in [float, int8] {
+            assert!((x.pair_dot(0, 0).raw() - 1.0).abs() < 1e-6);
+            assert_eq!(x.pair_dot(1, 1), 0.0);
+        }
+    }
+}
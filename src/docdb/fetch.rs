@@ -0,0 +1,66 @@
+//! Where the contents of documents are fetched from.
+
+use std::fmt;
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+
+use super::DocId;
+
+/// The URL of a document by default, where `{path}` is the first three hex
+/// digits of `{id}` as directories.
+pub const DEFAULT_DOCUMENT_URL: &str = "{origin}/db/documents/{path}/{id}.md";
+
+/// Fetches documents in place of HTTP requests, such as from a bundle or
+/// with signed URLs.
+pub trait DocumentFetcher {
+    /// Get the contents of the document at `url`, or why it can't be
+    /// fetched.
+    fn fetch(&self, url: &str) -> LocalBoxFuture<'_, Result<String, String>>;
+}
+
+/// The fetcher of a database, if any.
+#[derive(Clone, Default)]
+pub struct Fetcher(pub Option<Rc<dyn DocumentFetcher>>);
+
+impl fmt::Debug for Fetcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Fetcher")
+            .field(&self.0.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// The URL of the document with `id` from `origin`, by the `pattern` with
+/// `{origin}`, `{path}` and `{id}` placeholders.
+pub fn document_url(pattern: &str, origin: &str, id: &DocId) -> String {
+    let name = hex::encode(id);
+    let path = name
+        .chars()
+        .take(3)
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join("/");
+    pattern
+        .replace("{origin}", origin)
+        .replace("{path}", &path)
+        .replace("{id}", &name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_document_urls() {
+        let id = [0xab; 16];
+        assert_eq!(
+            document_url(DEFAULT_DOCUMENT_URL, "https://a.com", &id),
+            format!("https://a.com/db/documents/a/b/a/{}.md", hex::encode(id))
+        );
+        assert_eq!(
+            document_url("bundle:{id}", "https://a.com", &id),
+            format!("bundle:{}", hex::encode(id))
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Filters on the documents considered by a search.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::DocId;
+
+/// Which documents a search considers, by their tags and IDs.
+///
+/// An empty filter considers every document.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Only documents with at least one of these tags, unless empty.
+    pub any_of: Vec<String>,
+    /// Only documents with all of these tags.
+    pub all_of: Vec<String>,
+    /// No documents with any of these tags.
+    pub none_of: Vec<String>,
+    /// Only documents in one of these languages, unless empty. Documents in
+    /// the earlier languages are preferred, and those in the later languages
+    /// only fill in for the missing ones. Documents of unknown language pass
+    /// as if in the first language.
+    pub languages: Vec<String>,
+    /// No documents with these IDs, such as documents already used.
+    #[serde(skip)]
+    pub exclude: HashSet<DocId>,
+}
+
+impl Filter {
+    /// Only documents with at least one of the `tags`.
+    pub fn any_of<T: ToString>(tags: &[T]) -> Self {
+        Self {
+            any_of: tags.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The position of the `language` in the preference order of the filter,
+    /// 0 if it's unknown or the filter has no languages.
+    pub fn language_rank(&self, language: Option<&String>) -> usize {
+        language
+            .and_then(|x| self.languages.iter().position(|y| y == x))
+            .unwrap_or(0)
+    }
+
+    /// No documents with the IDs in `exclude`.
+    pub fn excluding(exclude: impl IntoIterator<Item = DocId>) -> Self {
+        Self {
+            exclude: exclude.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Does the document with `id` pass the filter, given the documents with
+    /// each tag and the language of each document, if known?
+    pub fn matches(
+        &self,
+        tags: &HashMap<String, HashSet<DocId>>,
+        languages: &HashMap<DocId, String>,
+        id: &DocId,
+    ) -> bool {
+        let has = |tag: &String| tags.get(tag).is_some_and(|x| x.contains(id));
+        (self.any_of.is_empty() || self.any_of.iter().any(has))
+            && (self.languages.is_empty()
+                || languages.get(id).is_none_or(|x| self.languages.contains(x)))
+            && self.all_of.iter().all(has)
+            && !self.none_of.iter().any(has)
+            && !self.exclude.contains(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_matches_tags() {
+        let tags: HashMap<String, HashSet<DocId>> = [
+            ("drug", vec![[0x01; 16], [0x02; 16]]),
+            ("pediatric", vec![[0x02; 16], [0x03; 16]]),
+        ]
+        .into_iter()
+        .map(|(tag, ids)| (tag.to_string(), ids.into_iter().collect()))
+        .collect();
+        let languages: HashMap<DocId, String> = [([0x01; 16], "es"), ([0x02; 16], "en")]
+            .into_iter()
+            .map(|(id, language)| (id, language.to_string()))
+            .collect();
+        let matching = |filter: &Filter| {
+            (1..=4)
+                .map(|x| [x; 16])
+                .filter(|x| filter.matches(&tags, &languages, x))
+                .map(|x| x[0])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching(&Filter::default()), [1, 2, 3, 4]);
+        assert_eq!(matching(&Filter::any_of(&["drug", "pediatric"])), [1, 2, 3]);
+        let filter = Filter {
+            all_of: vec!["drug".to_string(), "pediatric".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(matching(&filter), [2]);
+        let filter = Filter {
+            any_of: vec!["drug".to_string()],
+            none_of: vec!["pediatric".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(matching(&filter), [1]);
+        let filter = Filter::excluding([[0x02; 16], [0x04; 16]]);
+        assert_eq!(matching(&filter), [1, 3]);
+        let filter = Filter {
+            languages: vec!["es".to_string(), "fr".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(matching(&filter), [1, 3, 4]);
+        assert_eq!(filter.language_rank(None), 0);
+        assert_eq!(filter.language_rank(Some(&"fr".to_string())), 1);
+    }
+}
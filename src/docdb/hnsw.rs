@@ -0,0 +1,330 @@
+//! A hierarchical navigable small world (HNSW) graph for approximate
+//! nearest-neighbor search.
+//!
+//! The graph only stores the links between the rows of the embeddings, so it
+//! can be built ahead of time and shipped with a corpus. Similarities are
+//! computed by callbacks, so the graph doesn't depend on how the embeddings
+//! are stored.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+
+use noisy_float::prelude::N32;
+use serde::{Deserialize, Serialize};
+
+/// How the graph is built and searched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswParams {
+    /// The number of links per node, twice as many in the bottom layer.
+    pub m: usize,
+    /// The number of candidates considered when linking a node.
+    pub ef_construction: usize,
+    /// The number of candidates considered when searching. Higher is slower
+    /// but misses fewer neighbors.
+    pub ef_search: usize,
+    /// Seeds the choice of layers, so builds are reproducible.
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+            seed: 0x5eed,
+        }
+    }
+}
+
+/// A similarity and the node it's for.
+type Scored = (N32, u32);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hnsw {
+    m: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    /// The state of the generator of layers, to keep adding nodes.
+    rng: u64,
+    /// The node in the top layer where searches start.
+    entry: Option<u32>,
+    /// The links of each node, for each layer the node is in.
+    links: Vec<Vec<Vec<u32>>>,
+}
+
+/// A xorshift generator, enough to draw the layers.
+struct Rng(u64);
+
+impl Rng {
+    /// A number in `(0, 1]`.
+    fn next_unit(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        ((self.0 >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Hnsw {
+    /// Build the graph of `n` nodes, where `similarity(i, j)` is the
+    /// similarity of nodes `i` and `j`.
+    pub fn build(n: usize, params: HnswParams, similarity: impl Fn(usize, usize) -> N32) -> Self {
+        let mut graph = Self {
+            m: params.m.max(2),
+            ef_construction: params.ef_construction,
+            ef_search: params.ef_search,
+            // zero would be a fixed point of the generator
+            rng: params.seed.max(1),
+            entry: None,
+            links: Vec::with_capacity(n),
+        };
+        graph.extend(n, similarity);
+        graph
+    }
+
+    /// Add nodes to the graph until it has `n` nodes, where
+    /// `similarity(i, j)` is the similarity of nodes `i` and `j`.
+    pub fn extend(&mut self, n: usize, similarity: impl Fn(usize, usize) -> N32) {
+        let level_scale = 1.0 / (self.m as f64).ln();
+        let mut rng = Rng(self.rng);
+        for node in self.links.len()..n {
+            let level = (-rng.next_unit().ln() * level_scale) as usize;
+            self.insert(node as u32, level, &similarity);
+        }
+        self.rng = rng.0;
+    }
+
+    /// Set the number of candidates considered when searching.
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.ef_search = ef_search;
+    }
+
+    /// Roughly how many bytes the links hold.
+    pub fn memory_bytes(&self) -> usize {
+        self.links
+            .iter()
+            .map(|layers| {
+                size_of::<Vec<Vec<u32>>>()
+                    + layers
+                        .iter()
+                        .map(|x| size_of::<Vec<u32>>() + x.capacity() * size_of::<u32>())
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Are the links consistent with a graph of `n` nodes?
+    ///
+    /// Every node is in at least the bottom layer.
+    pub fn is_valid(&self, n: usize) -> bool {
+        self.links.len() == n
+            && self.entry.map_or(n == 0, |x| (x as usize) < n)
+            && self.links.iter().all(|x| !x.is_empty())
+            && self
+                .links
+                .iter()
+                .flatten()
+                .flatten()
+                .all(|&x| (x as usize) < n)
+    }
+
+    fn neighbors(&self, node: u32, layer: usize) -> &[u32] {
+        self.links[node as usize]
+            .get(layer)
+            .map_or(&[], |x| x.as_slice())
+    }
+
+    /// Find up to `ef` nodes most similar to the query, starting from
+    /// `entries`, keeping only the `allowed` nodes.
+    ///
+    /// Nodes that aren't allowed are still traversed, so a selective filter
+    /// explores more of the graph. Returns the most similar first.
+    fn search_layer(
+        &self,
+        similarity: &impl Fn(usize) -> N32,
+        entries: &[Scored],
+        ef: usize,
+        layer: usize,
+        allowed: &impl Fn(usize) -> bool,
+    ) -> Vec<Scored> {
+        let mut visited = entries.iter().map(|x| x.1).collect::<HashSet<_>>();
+        let mut candidates = entries.iter().copied().collect::<BinaryHeap<_>>();
+        // the least similar on top, to be replaced first
+        let mut found = entries
+            .iter()
+            .filter(|x| allowed(x.1 as usize))
+            .copied()
+            .map(Reverse)
+            .collect::<BinaryHeap<_>>();
+        let worst = |found: &BinaryHeap<Reverse<Scored>>| match found.peek() {
+            Some(Reverse((x, _))) if found.len() >= ef => Some(*x),
+            _ => None,
+        };
+        while let Some((closest, node)) = candidates.pop() {
+            if worst(&found).is_some_and(|x| closest < x) {
+                break;
+            }
+            for &next in self.neighbors(node, layer) {
+                if !visited.insert(next) {
+                    continue;
+                }
+                let score = similarity(next as usize);
+                if worst(&found).is_some_and(|x| score <= x) {
+                    continue;
+                }
+                candidates.push((score, next));
+                if allowed(next as usize) {
+                    found.push(Reverse((score, next)));
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        let mut found = found.into_iter().map(|Reverse(x)| x).collect::<Vec<_>>();
+        // `y.cmp(x)` for descending order
+        found.sort_by(|x, y| y.cmp(x));
+        found
+    }
+
+    /// Add `node`, the next node, to the graph in layers up to `level`.
+    fn insert(&mut self, node: u32, level: usize, similarity: &impl Fn(usize, usize) -> N32) {
+        let m = self.m;
+        self.links.push(vec![Vec::new(); level + 1]);
+        // an entry in no layer, as in a corrupt index, is replaced
+        let Some((entry, top)) = self
+            .entry
+            .and_then(|x| Some((x, self.links[x as usize].len().checked_sub(1)?)))
+        else {
+            self.entry = Some(node);
+            return;
+        };
+        let to_node = |x: usize| similarity(node as usize, x);
+        let all = |_: usize| true;
+        let mut nearest = vec![(to_node(entry as usize), entry)];
+        for layer in (level + 1..=top).rev() {
+            nearest = self.search_layer(&to_node, &nearest, 1, layer, &all);
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found =
+                self.search_layer(&to_node, &nearest, self.ef_construction.max(m), layer, &all);
+            let max_links = if layer == 0 { 2 * m } else { m };
+            let neighbors = found.iter().take(m).map(|x| x.1).collect::<Vec<_>>();
+            for &neighbor in &neighbors {
+                let links = &mut self.links[neighbor as usize][layer];
+                links.push(node);
+                if links.len() > max_links {
+                    // keep the links to the most similar nodes
+                    let mut scored = links
+                        .iter()
+                        .map(|&x| (similarity(neighbor as usize, x as usize), x))
+                        .collect::<Vec<_>>();
+                    scored.sort_by(|x, y| y.cmp(x));
+                    *links = scored.into_iter().take(max_links).map(|x| x.1).collect();
+                }
+            }
+            self.links[node as usize][layer] = neighbors;
+            nearest = found;
+        }
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+
+    /// Find up to `k` of the `allowed` nodes most similar to the query, where
+    /// `similarity(i)` is the similarity of node `i` to the query.
+    ///
+    /// Returns the similarities and nodes, the most similar first.
+    pub fn search(
+        &self,
+        similarity: impl Fn(usize) -> N32,
+        k: usize,
+        allowed: impl Fn(usize) -> bool,
+    ) -> Vec<(N32, usize)> {
+        let entry = match self.entry {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let mut nearest = vec![(similarity(entry as usize), entry)];
+        for layer in (1..self.links[entry as usize].len()).rev() {
+            nearest = self.search_layer(&similarity, &nearest, 1, layer, &|_| true);
+        }
+        self.search_layer(&similarity, &nearest, self.ef_search.max(k), 0, &allowed)
+            .into_iter()
+            .take(k)
+            .map(|(x, node)| (x, node as usize))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::Array2;
+    use noisy_float::prelude::n32;
+
+    use super::*;
+
+    fn random_points(n: usize, dimensions: usize) -> Array2<N32> {
+        let mut rng = Rng(42);
+        Array2::from_shape_fn((n, dimensions), |_| n32(rng.next_unit() as f32 - 0.5))
+    }
+
+    fn exact(points: &Array2<N32>, query: usize, k: usize) -> Vec<usize> {
+        let mut scored = (0..points.nrows())
+            .map(|x| (points.row(query).dot(&points.row(x)), x))
+            .collect::<Vec<_>>();
+        scored.sort_by(|x, y| y.cmp(x));
+        scored.into_iter().take(k).map(|x| x.1).collect()
+    }
+
+    #[test]
+    fn finds_most_neighbors() {
+        let points = random_points(500, 8);
+        let similarity = |i: usize, j: usize| points.row(i).dot(&points.row(j));
+        let graph = Hnsw::build(points.nrows(), HnswParams::default(), similarity);
+        assert!(graph.is_valid(points.nrows()));
+        let k = 10;
+        let mut hits = 0;
+        for query in 0..50 {
+            let expected = exact(&points, query, k);
+            let found = graph.search(|x| similarity(query, x), k, |_| true);
+            hits += found.iter().filter(|x| expected.contains(&x.1)).count();
+        }
+        assert!(hits as f64 / (50 * k) as f64 > 0.9);
+    }
+
+    #[test]
+    fn keeps_allowed_nodes() {
+        let points = random_points(200, 4);
+        let similarity = |i: usize, j: usize| points.row(i).dot(&points.row(j));
+        let graph = Hnsw::build(points.nrows(), HnswParams::default(), similarity);
+        let found = graph.search(|x| similarity(0, x), 5, |x| x % 2 == 1);
+        assert_eq!(found.len(), 5);
+        assert!(found.iter().all(|x| x.1 % 2 == 1));
+    }
+
+    #[test]
+    fn extends_past_corrupt_entry() {
+        let points = random_points(20, 4);
+        let similarity = |i: usize, j: usize| points.row(i).dot(&points.row(j));
+        let mut graph = Hnsw::build(10, HnswParams::default(), similarity);
+        let entry = graph.entry.unwrap() as usize;
+        graph.links[entry].clear();
+        assert!(!graph.is_valid(10));
+        graph.extend(points.nrows(), similarity);
+        assert_eq!(graph.entry, Some(10));
+    }
+
+    #[test]
+    fn extends_like_build() {
+        let points = random_points(100, 4);
+        let similarity = |i: usize, j: usize| points.row(i).dot(&points.row(j));
+        let mut graph = Hnsw::build(60, HnswParams::default(), similarity);
+        graph.extend(points.nrows(), similarity);
+        assert_eq!(
+            graph,
+            Hnsw::build(points.nrows(), HnswParams::default(), similarity)
+        );
+    }
+}
@@ -0,0 +1,2495 @@
+//! An in-memory document database with vector embeddings lookup.
+
+mod archive;
+mod cache;
+mod compress;
+mod embeddings;
+mod fetch;
+mod filter;
+mod hnsw;
+mod stats;
+mod validate;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use futures::{stream, FutureExt, StreamExt};
+use half::f16;
+use ndarray::{Array2, ArrayView1, CowArray, Ix1};
+use noisy_float::prelude::{n32, N32};
+use npyz::{DType, NpyFile, TypeChar};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tap::Pipe;
+
+use crate::http::client;
+use crate::openai::embed::EmbeddingModel;
+use crate::timer::{unix_ms, yield_now};
+pub use archive::Archive;
+use cache::DocumentCache;
+use compress::decompress;
+use embeddings::Embeddings;
+pub use fetch::DocumentFetcher;
+use fetch::{document_url, Fetcher, DEFAULT_DOCUMENT_URL};
+pub use filter::Filter;
+use hnsw::Hnsw;
+pub use hnsw::HnswParams;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("array data shape is invalid")]
+    ArrayShape,
+    #[error("array format is invalid: {0}")]
+    ArrayRaeding(io::Error),
+    #[error("ID format is invalid: {0}")]
+    Id(hex::FromHexError),
+    #[error("array values must not be NaN")]
+    NotNan,
+    #[error("record format is invalid: {0}")]
+    Record(&'static str),
+    #[error("document not available: {0}")]
+    DocumentNotAvailable(#[from] reqwest::Error),
+    #[error("query embedding has {found} dimensions, but the database expects {expected}")]
+    Dimensions { expected: usize, found: usize },
+    #[error("quantized embeddings need one scale factor per row")]
+    Scales,
+    #[error("databases can't be merged: {0}")]
+    Merge(&'static str),
+    #[error("index is invalid: {0}")]
+    Index(&'static str),
+    #[error("origin weight must be a positive number")]
+    Weight,
+    #[error("archive is invalid: {0}")]
+    Archive(&'static str),
+    #[error("resource can't be decompressed: {0}")]
+    Decompress(io::Error),
+    #[error("cached database is invalid: {0}")]
+    Cache(&'static str),
+    #[error("document content doesn't match its hash: {0}")]
+    Integrity(String),
+    #[error("content hash format is invalid: {0}")]
+    ContentHash(hex::FromHexError),
+    #[error("document content isn't valid UTF-8: {0}")]
+    Encoding(String),
+    #[error("chunk span is outside its parent document: {0}")]
+    Span(String),
+    #[error("document URL pattern must contain {{id}}")]
+    DocumentUrl,
+    #[error("document fetcher failed: {0}")]
+    Fetch(String),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+pub type DocId = [u8; 16];
+
+/// The tag of the documents that describe a condition.
+pub const TAG_CONDITION: &str = "condition";
+/// The tag of the introduction sections.
+pub const TAG_INTRODUCTION: &str = "introduction";
+/// The tag of the sections about the symptoms of a condition.
+pub const TAG_SYMPTOMS: &str = "symptoms";
+
+/// The version of the layout written by [`DocDb::to_bytes`], bumped when the
+/// database changes so older caches are rebuilt rather than misread.
+const CACHE_VERSION: u32 = 8;
+
+/// A stage of loading a database, reported once it's done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadStage {
+    /// The compressed resources were decompressed.
+    Decompressed,
+    /// The embeddings were parsed.
+    Embeddings,
+    /// The IDs of the embeddings were parsed.
+    Ids,
+    /// The parents, titles and URLs were parsed.
+    Metadata,
+    /// The tags were parsed.
+    Tags,
+    /// The index packed with the resources was loaded.
+    Index,
+    /// The index was built once the documents were loaded.
+    IndexBuilt,
+}
+
+/// Report the `stage` of loading to `progress`, if any, and let the browser
+/// repaint before the next stage.
+async fn report(progress: Option<&dyn Fn(LoadStage)>, stage: LoadStage) {
+    if let Some(progress) = progress {
+        progress(stage);
+        yield_now().await;
+    }
+}
+
+/// How the similarities of the chunks of a document add up to the
+/// similarity of the document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    /// The similarity of the most similar chunk.
+    Max,
+    /// The sum of the similarities of the chunks, favoring documents with
+    /// many similar chunks.
+    Sum,
+}
+
+/// How the similarity of embeddings is measured.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Similarity {
+    /// The dot product, for embeddings exported with unit length.
+    #[default]
+    Dot,
+    /// The cosine similarity, for embeddings of any length.
+    Cosine,
+}
+
+/// The codes of a condition in clinical terminologies, for records that
+/// need coded diagnoses rather than names.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConditionCodes {
+    /// The ICD-10 codes, such as `I21.9`.
+    pub icd10: Vec<String>,
+    /// The SNOMED CT concept IDs, such as `22298006`.
+    pub snomed: Vec<String>,
+}
+
+/// Where a chunk is in its parent document, in Unicode code points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    /// The offset of the first character of the chunk.
+    pub start: u32,
+    /// The offset after the last character of the chunk.
+    pub end: u32,
+}
+
+/// How many documents are fetched at once by default.
+const DEFAULT_FETCH_CONCURRENCY: usize = 4;
+
+/// Below this many documents, exact search is fast enough that an index isn't
+/// built.
+const MIN_INDEXED_DOCUMENTS: usize = 10_000;
+
+/// How many more candidates than requested are re-ranked for diversity.
+const MMR_CANDIDATES_FACTOR: usize = 4;
+
+/// How many more chunks than requested documents are grouped by parent.
+const PARENT_CANDIDATES_FACTOR: usize = 4;
+
+/// How many of the most similar introductions and symptoms vote for the
+/// condition they belong to.
+const CONDITION_CANDIDATES: usize = 8;
+
+fn check_dimensions(expected: usize, found: usize) -> Result<()> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(Error::Dimensions { expected, found })
+    }
+}
+
+/// The number of days from the Unix epoch to the `YYYY-MM-DD` date, if
+/// it's valid.
+fn days_from_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-').map(|x| x.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if date.len() != 10 || !(1..=12).contains(&month) || !(1..=month_days).contains(&day) {
+        return None;
+    }
+    // the days-from-civil algorithm, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+/// The `alias` in lowercase with runs of whitespace as single spaces, so
+/// aliases match regardless of case and spacing.
+fn normalize_alias(alias: &str) -> String {
+    alias
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Decode a hex document ID.
+pub fn decode_doc_id(data: &[u8]) -> Result<DocId> {
+    let mut id = [0u8; 16];
+    hex::decode_to_slice(data, &mut id[..]).map_err(Error::Id)?;
+    Ok(id)
+}
+
+/// Parse the lines of a `table` that can be compressed with gzip or zstd,
+/// each a document ID and `N` more columns, separated by tabs.
+///
+/// Fails with `lacks` if a line has too few columns, or `invalid` if a column
+/// isn't a valid string.
+fn parse_id_tsv<const N: usize>(
+    table: &[u8],
+    lacks: &'static str,
+    invalid: &'static str,
+) -> Result<Vec<(DocId, [String; N])>> {
+    let table = decompress(table).map_err(Error::Decompress)?;
+    table
+        .split(|&x| x == 0x0a)
+        .filter(|x| !x.is_empty())
+        .map(|line| {
+            let mut columns = line.splitn(N + 1, |&x| x == 0x09);
+            let id = decode_doc_id(columns.next().unwrap_or_default())?;
+            let columns = columns
+                .map(|x| String::from_utf8(x.to_vec()).map_err(|_| Error::Record(invalid)))
+                .collect::<Result<Vec<_>>>()?
+                .pipe(<[String; N]>::try_from)
+                .map_err(|_| Error::Record(lacks))?;
+            Ok((id, columns))
+        })
+        .collect()
+}
+
+/// The document database data.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DocDb {
+    /// The origins of the documents, the first for the documents that aren't
+    /// in `document_origins`.
+    origins: Vec<String>,
+    /// The index in `origins` of the documents from merged databases.
+    document_origins: HashMap<DocId, usize>,
+    /// The factor each origin's similarities are scaled by, one if missing.
+    origin_weights: Vec<N32>,
+    embeddings: Embeddings,
+    embeddings_pca_mapping: Option<Array2<N32>>,
+    embeddings_id: Vec<DocId>,
+    parents: HashMap<DocId, DocId>,
+    titles: HashMap<DocId, String>,
+    urls: HashMap<DocId, String>,
+    tags: HashMap<String, HashSet<DocId>>,
+    /// The SHA-256 hash of the contents of each document, if known.
+    content_hashes: HashMap<DocId, [u8; 32]>,
+    /// The language of each document, if known, such as `en`.
+    languages: HashMap<DocId, String>,
+    /// The date each document was last reviewed, if known, as `YYYY-MM-DD`.
+    reviewed: HashMap<DocId, String>,
+    /// The condition each alias names, keyed by the normalized alias, such
+    /// as `heart attack` for the myocardial infarction article.
+    aliases: HashMap<String, DocId>,
+    /// The ICD-10 and SNOMED CT codes of each condition, if known.
+    codes: HashMap<DocId, ConditionCodes>,
+    /// Where each chunk is in its parent document, if known.
+    spans: HashMap<DocId, Span>,
+    /// The URL pattern of the documents, if not [`DEFAULT_DOCUMENT_URL`].
+    document_url: Option<String>,
+    /// Fetches the documents in place of HTTP requests, if set.
+    #[serde(skip)]
+    fetcher: Fetcher,
+    /// The contents of the documents fetched recently.
+    #[serde(skip)]
+    document_cache: Mutex<DocumentCache>,
+    fetch_concurrency: usize,
+    embedding_model: EmbeddingModel,
+    embedding_dimensions: Option<usize>,
+    index: Option<Hnsw>,
+    similarity: Similarity,
+}
+
+fn array2_from_npy<T: npyz::Deserialize>(npy_data: NpyFile<&[u8]>) -> Result<Array2<T>> {
+    array2_from_npy_with(npy_data, Ok)
+}
+
+/// Read an array, converting each value with `convert` as it's read.
+///
+/// The values are converted in place of a copy of the whole array, so
+/// reading a large array only allocates the converted array.
+fn array2_from_npy_with<T: npyz::Deserialize, U>(
+    npy_data: NpyFile<&[u8]>,
+    convert: impl Fn(T) -> Result<U>,
+) -> Result<Array2<U>> {
+    use ndarray::ShapeBuilder;
+    let shape = match npy_data.shape()[..] {
+        [i1, i2] => [i1 as usize, i2 as usize],
+        _ => Err(Error::ArrayShape)?,
+    };
+    let true_shape = shape.set_f(npy_data.order() == npyz::Order::Fortran);
+    let mut values = Vec::with_capacity(npy_data.len() as usize);
+    let reader = npy_data
+        .data::<T>()
+        .map_err(|x| Error::ArrayRaeding(io::Error::new(io::ErrorKind::InvalidData, x)))?;
+    for value in reader {
+        values.push(convert(value.map_err(Error::ArrayRaeding)?)?);
+    }
+    ndarray::Array2::from_shape_vec(true_shape, values).map_err(|_| Error::ArrayShape)
+}
+
+fn is_dtype(npy_data: &NpyFile<&[u8]>, type_char: TypeChar, size: u64) -> bool {
+    match npy_data.dtype() {
+        DType::Plain(x) => x.type_char() == type_char && x.size_field() == size,
+        _ => false,
+    }
+}
+
+/// Read a float array, converting half-precision values to single precision.
+///
+/// Fails if any value is NaN.
+fn float_array2_from_npy(npy_data: NpyFile<&[u8]>) -> Result<Array2<f32>> {
+    let checked = |x: f32| {
+        if x.is_nan() {
+            Err(Error::NotNan)
+        } else {
+            Ok(x)
+        }
+    };
+    if is_dtype(&npy_data, TypeChar::Float, 2) {
+        array2_from_npy_with(npy_data, |x: f16| checked(f32::from(x)))
+    } else {
+        array2_from_npy_with(npy_data, checked)
+    }
+}
+
+/// Read float embeddings, or int8 embeddings scaled by `scales`, one per row.
+fn embeddings_from_npy(embeddings: &[u8], scales: Option<&[u8]>) -> Result<Embeddings> {
+    let npy_data = NpyFile::new(embeddings).map_err(Error::ArrayRaeding)?;
+    if !is_dtype(&npy_data, TypeChar::Int, 1) {
+        return float_array2_from_npy(npy_data).map(Embeddings::Float);
+    }
+    let values: Array2<i8> = array2_from_npy(npy_data)?;
+    let scales: Vec<f32> = NpyFile::new(scales.ok_or(Error::Scales)?)
+        .map_err(Error::ArrayRaeding)?
+        .into_vec()
+        .map_err(Error::ArrayRaeding)?;
+    if scales.len() != values.nrows() {
+        return Err(Error::Scales);
+    }
+    if scales.iter().any(|x| x.is_nan()) {
+        return Err(Error::NotNan);
+    }
+    Ok(Embeddings::Int8 {
+        values,
+        scales: scales.into_iter().map(n32).collect(),
+    })
+}
+
+/// Documents read from the resources, before they're added to a database.
+struct Documents {
+    embeddings: Embeddings,
+    ids: Vec<DocId>,
+    parents: HashMap<DocId, DocId>,
+    titles: HashMap<DocId, String>,
+    urls: HashMap<DocId, String>,
+    tags: HashMap<String, HashSet<DocId>>,
+}
+
+impl Documents {
+    /// Read the documents from the resources described in [`DocDb::new`].
+    ///
+    /// Only yields between stages if there's a `progress` to report to.
+    #[allow(clippy::too_many_arguments)]
+    async fn parse(
+        embeddings: &[u8],
+        embeddings_scales: Option<&[u8]>,
+        embeddings_id: &[u8],
+        parents: &[u8],
+        titles: &[u8],
+        urls: &[u8],
+        tags: &[u8],
+        progress: Option<&dyn Fn(LoadStage)>,
+    ) -> Result<Documents> {
+        let embeddings = decompress(embeddings).map_err(Error::Decompress)?;
+        let embeddings_scales = embeddings_scales
+            .map(decompress)
+            .transpose()
+            .map_err(Error::Decompress)?;
+        let embeddings_id = decompress(embeddings_id).map_err(Error::Decompress)?;
+        let parents = decompress(parents).map_err(Error::Decompress)?;
+        let titles = decompress(titles).map_err(Error::Decompress)?;
+        let urls = decompress(urls).map_err(Error::Decompress)?;
+        let tags = decompress(tags).map_err(Error::Decompress)?;
+        report(progress, LoadStage::Decompressed).await;
+
+        let embeddings = embeddings_from_npy(&embeddings, embeddings_scales.as_deref())?;
+        report(progress, LoadStage::Embeddings).await;
+
+        let embeddings_id: Vec<DocId> = embeddings_id
+            .split(|&x| x == 0x0a)
+            .filter(|x| !x.is_empty())
+            .map(decode_doc_id)
+            .collect::<Result<Vec<_>>>()?;
+
+        if embeddings_id.len() != embeddings.nrows() {
+            return Err(Error::ArrayShape);
+        }
+        report(progress, LoadStage::Ids).await;
+
+        let parents: HashMap<DocId, DocId> = parents
+            .split(|&x| x == 0x0a)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                x.splitn(2, |&x| x == 0x09)
+                    .collect::<Vec<&[u8]>>()
+                    .pipe(<[&[u8]; 2]>::try_from)
+                    .map_err(|_| Error::Record("parent line lacks two columns"))
+            })
+            .map(|x| match x {
+                Ok([id, parent]) => Ok((decode_doc_id(id)?, decode_doc_id(parent)?)),
+                Err(x) => Err(x),
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let titles: HashMap<DocId, String> = titles
+            .split(|&x| x == 0x0a)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                x.splitn(2, |&x| x == 0x09)
+                    .collect::<Vec<&[u8]>>()
+                    .pipe(<[&[u8]; 2]>::try_from)
+                    .map_err(|_| Error::Record("title line lacks two columns"))
+            })
+            .map(|x| match x {
+                Ok([id, title]) => Ok((
+                    decode_doc_id(id)?,
+                    String::from_utf8(title.to_vec())
+                        .map_err(|_| Error::Record("title line isn't a valid string"))?,
+                )),
+                Err(x) => Err(x),
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let urls: HashMap<DocId, String> = urls
+            .split(|&x| x == 0x0a)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                x.splitn(2, |&x| x == 0x09)
+                    .collect::<Vec<&[u8]>>()
+                    .pipe(<[&[u8]; 2]>::try_from)
+                    .map_err(|_| Error::Record("url line lacks two columns"))
+            })
+            .map(|x| match x {
+                Ok([id, title]) => Ok((
+                    decode_doc_id(id)?,
+                    String::from_utf8(title.to_vec())
+                        .map_err(|_| Error::Record("url line isn't a valid string"))?,
+                )),
+                Err(x) => Err(x),
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        report(progress, LoadStage::Metadata).await;
+
+        let mut tags_ids: HashMap<String, HashSet<DocId>> = HashMap::new();
+        for line in tags.split(|&x| x == 0x0a).filter(|x| !x.is_empty()) {
+            let [id, tag] = line
+                .splitn(2, |&x| x == 0x09)
+                .collect::<Vec<&[u8]>>()
+                .pipe(<[&[u8]; 2]>::try_from)
+                .map_err(|_| Error::Record("tag line lacks two columns"))?;
+            let tag = String::from_utf8(tag.to_vec())
+                .map_err(|_| Error::Record("tag line isn't a valid string"))?;
+            tags_ids.entry(tag).or_default().insert(decode_doc_id(id)?);
+        }
+        report(progress, LoadStage::Tags).await;
+
+        Ok(Documents {
+            embeddings,
+            ids: embeddings_id,
+            parents,
+            titles,
+            urls,
+            tags: tags_ids,
+        })
+    }
+}
+
+impl DocDb {
+    /// Build a new database with the provided resources.
+    ///
+    /// The resources are bytes for the embeddings and metadata. Each document
+    /// is represented by a [`DocId`]. The document contents aren't stored in
+    /// the database, but are fetched from the URL.
+    ///
+    /// The embeddings are either floats, in single or half precision, or int8
+    /// values with `embeddings_scales` holding a float scale factor per row.
+    /// Each line of `tags` is a document ID and one of its tags, such as
+    /// [`TAG_CONDITION`], separated by a tab. Any of the resources can be
+    /// compressed with gzip or zstd. Each stage of loading is reported to
+    /// `progress` once it's done, yielding to the browser in between so it
+    /// can show the progress.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        origin: String,
+        embeddings: &[u8],
+        embeddings_scales: Option<&[u8]>,
+        embeddings_pca_mapping: Option<&[u8]>,
+        embeddings_id: &[u8],
+        parents: &[u8],
+        titles: &[u8],
+        urls: &[u8],
+        tags: &[u8],
+        progress: &dyn Fn(LoadStage),
+    ) -> Result<DocDb> {
+        let documents = Documents::parse(
+            embeddings,
+            embeddings_scales,
+            embeddings_id,
+            parents,
+            titles,
+            urls,
+            tags,
+            Some(progress),
+        )
+        .await?;
+
+        let embeddings_pca_mapping: Option<Array2<N32>> = embeddings_pca_mapping
+            .map(|x| {
+                let x = decompress(x).map_err(Error::Decompress)?;
+                float_array2_from_npy(NpyFile::new(&*x).map_err(Error::ArrayRaeding)?)
+                    .map(|x| x.mapv(n32))
+            })
+            .transpose()?;
+        if let Some(mapping) = &embeddings_pca_mapping {
+            if mapping.shape()[1] != documents.embeddings.ncols() {
+                return Err(Error::ArrayShape);
+            }
+        }
+
+        Ok(DocDb {
+            origins: vec![origin],
+            document_origins: HashMap::new(),
+            origin_weights: vec![n32(1.0)],
+            embeddings: documents.embeddings,
+            embeddings_pca_mapping,
+            embeddings_id: documents.ids,
+            parents: documents.parents,
+            titles: documents.titles,
+            urls: documents.urls,
+            tags: documents.tags,
+            content_hashes: HashMap::new(),
+            languages: HashMap::new(),
+            reviewed: HashMap::new(),
+            aliases: HashMap::new(),
+            codes: HashMap::new(),
+            spans: HashMap::new(),
+            document_url: None,
+            fetcher: Fetcher::default(),
+            document_cache: Mutex::default(),
+            fetch_concurrency: DEFAULT_FETCH_CONCURRENCY,
+            embedding_model: EmbeddingModel::default(),
+            embedding_dimensions: None,
+            index: None,
+            similarity: Similarity::Dot,
+        })
+    }
+
+    /// Build a database from the resources packed in an archive, using the
+    /// index in the archive if there is one.
+    ///
+    /// The archive, or the resources in it, can be compressed with gzip or
+    /// zstd. Each stage of loading is reported to `progress` once it's done,
+    /// like [`DocDb::new`].
+    pub async fn from_archive(bytes: &[u8], progress: &dyn Fn(LoadStage)) -> Result<DocDb> {
+        let bytes = decompress(bytes).map_err(Error::Decompress)?;
+        let archive = Archive::parse(&bytes)?;
+        let mut db = DocDb::new(
+            archive.origin.to_string(),
+            archive.embeddings,
+            archive.embeddings_scales,
+            archive.embeddings_pca_mapping,
+            archive.embeddings_id,
+            archive.parents,
+            archive.titles,
+            archive.urls,
+            archive.tags,
+            progress,
+        )
+        .await?;
+        if let Some(index) = archive.index {
+            db.set_index_bytes(index)?;
+            report(Some(progress), LoadStage::Index).await;
+        }
+        if let Some(content_hashes) = archive.content_hashes {
+            db.set_content_hashes(content_hashes)?;
+        }
+        if let Some(languages) = archive.languages {
+            db.set_languages(languages)?;
+        }
+        if let Some(reviewed) = archive.reviewed {
+            db.set_reviewed(reviewed)?;
+        }
+        if let Some(aliases) = archive.aliases {
+            db.set_aliases(aliases)?;
+        }
+        if let Some(codes) = archive.codes {
+            db.set_codes(codes)?;
+        }
+        if let Some(spans) = archive.spans {
+            db.set_spans(spans)?;
+        }
+        Ok(db)
+    }
+
+    /// Serialize the parsed database, so it can be cached and rebuilt with
+    /// [`DocDb::from_bytes`] much faster than from its resources.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        rmp_serde::to_vec(&(CACHE_VERSION, self)).map_err(|_| Error::Cache("can't serialize"))
+    }
+
+    /// Rebuild a database serialized with [`DocDb::to_bytes`].
+    ///
+    /// Fails if the bytes were written by another version of the database,
+    /// in which case it must be built from its resources again.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DocDb> {
+        let (version, db): (u32, DocDb) =
+            rmp_serde::from_slice(bytes).map_err(|_| Error::Cache("format is invalid"))?;
+        if version != CACHE_VERSION {
+            return Err(Error::Cache("version is not supported"));
+        }
+        if db.embeddings.has_nan() {
+            return Err(Error::Cache("embeddings aren't numbers"));
+        }
+        if !db.embeddings.has_scales() {
+            return Err(Error::Cache(
+                "quantized embeddings need one scale factor per row",
+            ));
+        }
+        if db
+            .embeddings_pca_mapping
+            .as_ref()
+            .is_some_and(|x| x.ncols() != db.embeddings.ncols())
+        {
+            return Err(Error::Cache("PCA mapping doesn't match the embeddings"));
+        }
+        if db.embeddings_id.len() != db.embeddings.nrows()
+            || db
+                .index
+                .as_ref()
+                .is_some_and(|x| !x.is_valid(db.embeddings.nrows()))
+        {
+            return Err(Error::Cache("embeddings don't match the documents"));
+        }
+        Ok(db)
+    }
+
+    /// Add documents from resources like those of [`DocDb::new`], replacing
+    /// the documents with the same IDs.
+    ///
+    /// The embeddings must be stored like those of the database, or the
+    /// database is left unchanged. If there's an index, the documents are
+    /// added to it, unless they replace documents, which drops the index so
+    /// it must be built again.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_documents(
+        &mut self,
+        embeddings: &[u8],
+        embeddings_scales: Option<&[u8]>,
+        embeddings_id: &[u8],
+        parents: &[u8],
+        titles: &[u8],
+        urls: &[u8],
+        tags: &[u8],
+    ) -> Result<()> {
+        let documents = Documents::parse(
+            embeddings,
+            embeddings_scales,
+            embeddings_id,
+            parents,
+            titles,
+            urls,
+            tags,
+            None,
+        )
+        .now_or_never()
+        .expect("parsing without progress doesn't yield")?;
+        // checked before replacing documents, so a failed add loses nothing
+        if !self.embeddings.can_append(&documents.embeddings) {
+            return Err(Error::ArrayShape);
+        }
+        self.remove_documents(&documents.ids.iter().copied().collect());
+        let mut embeddings = documents.embeddings;
+        if self.similarity == Similarity::Cosine {
+            embeddings.normalize();
+        }
+        self.embeddings
+            .append(embeddings)
+            .map_err(|_| Error::ArrayShape)?;
+        self.embeddings_id.extend(documents.ids);
+        self.parents.extend(documents.parents);
+        self.titles.extend(documents.titles);
+        self.urls.extend(documents.urls);
+        for (tag, ids) in documents.tags {
+            self.tags.entry(tag).or_default().extend(ids);
+        }
+        if let Some(index) = &mut self.index {
+            let embeddings = &self.embeddings;
+            index.extend(embeddings.nrows(), |i, j| embeddings.pair_dot(i, j));
+        }
+        Ok(())
+    }
+
+    /// Add the documents of the `other` database, which are fetched from its
+    /// origins, returning how many were left out.
+    ///
+    /// The documents of `other` with the IDs of documents already in the
+    /// database are left out. Both databases must be embedded the same way,
+    /// with the same model, dimensions, PCA mapping and similarity.
+    pub fn merge(&mut self, other: DocDb) -> Result<usize> {
+        if self.embedding_model != other.embedding_model
+            || self.embedding_dimensions != other.embedding_dimensions
+        {
+            return Err(Error::Merge("embedding models differ"));
+        }
+        if self.embeddings_pca_mapping != other.embeddings_pca_mapping {
+            return Err(Error::Merge("PCA mappings differ"));
+        }
+        if self.similarity != other.similarity {
+            return Err(Error::Merge("similarities differ"));
+        }
+        let ours = self
+            .embeddings_id
+            .iter()
+            .chain(self.titles.keys())
+            .copied()
+            .collect::<HashSet<_>>();
+        let theirs = other
+            .embeddings_id
+            .iter()
+            .chain(other.titles.keys())
+            .chain(other.urls.keys())
+            .chain(other.parents.keys())
+            .copied()
+            .collect::<HashSet<_>>();
+        let rows = (0..other.embeddings_id.len())
+            .filter(|&i| !ours.contains(&other.embeddings_id[i]))
+            .collect::<Vec<_>>();
+        self.embeddings
+            .append(other.embeddings.select(&rows))
+            .map_err(|_| Error::Merge("embeddings are stored differently"))?;
+        self.embeddings_id
+            .extend(rows.iter().map(|&i| other.embeddings_id[i]));
+
+        let is_new = |x: &DocId| !ours.contains(x);
+        self.parents
+            .extend(other.parents.into_iter().filter(|(x, _)| is_new(x)));
+        self.titles
+            .extend(other.titles.into_iter().filter(|(x, _)| is_new(x)));
+        self.urls
+            .extend(other.urls.into_iter().filter(|(x, _)| is_new(x)));
+        self.content_hashes
+            .extend(other.content_hashes.into_iter().filter(|(x, _)| is_new(x)));
+        self.languages
+            .extend(other.languages.into_iter().filter(|(x, _)| is_new(x)));
+        self.reviewed
+            .extend(other.reviewed.into_iter().filter(|(x, _)| is_new(x)));
+        self.codes
+            .extend(other.codes.into_iter().filter(|(x, _)| is_new(x)));
+        self.spans
+            .extend(other.spans.into_iter().filter(|(x, _)| is_new(x)));
+        for (alias, id) in other.aliases {
+            self.aliases.entry(alias).or_insert(id);
+        }
+        for (tag, ids) in other.tags {
+            self.tags
+                .entry(tag)
+                .or_default()
+                .extend(ids.into_iter().filter(is_new));
+        }
+        let offset = self.origins.len();
+        self.origin_weights.resize(offset, n32(1.0));
+        self.origin_weights.extend(
+            (0..other.origins.len())
+                .map(|x| other.origin_weights.get(x).copied().unwrap_or(n32(1.0))),
+        );
+        for id in theirs.iter().filter(|x| is_new(x)) {
+            let index = other.document_origins.get(id).copied().unwrap_or(0);
+            self.document_origins.insert(*id, offset + index);
+        }
+        self.origins.extend(other.origins);
+        if let Some(index) = &mut self.index {
+            let embeddings = &self.embeddings;
+            index.extend(embeddings.nrows(), |i, j| embeddings.pair_dot(i, j));
+        }
+        Ok(theirs.len() - theirs.iter().filter(|x| is_new(x)).count())
+    }
+
+    /// Remove the documents with the IDs in `ids`, returning how many were
+    /// removed.
+    ///
+    /// Removing documents drops the index, which must be built again.
+    pub fn remove_documents(&mut self, ids: &HashSet<DocId>) -> usize {
+        let kept = (0..self.embeddings_id.len())
+            .filter(|&i| !ids.contains(&self.embeddings_id[i]))
+            .collect::<Vec<_>>();
+        let removed = self.embeddings_id.len() - kept.len();
+        if removed == 0 {
+            return 0;
+        }
+        self.embeddings = self.embeddings.select(&kept);
+        self.embeddings_id.retain(|x| !ids.contains(x));
+        self.document_origins.retain(|x, _| !ids.contains(x));
+        self.parents.retain(|x, _| !ids.contains(x));
+        self.titles.retain(|x, _| !ids.contains(x));
+        self.urls.retain(|x, _| !ids.contains(x));
+        self.content_hashes.retain(|x, _| !ids.contains(x));
+        self.languages.retain(|x, _| !ids.contains(x));
+        self.reviewed.retain(|x, _| !ids.contains(x));
+        self.aliases.retain(|_, x| !ids.contains(x));
+        self.codes.retain(|x, _| !ids.contains(x));
+        self.spans.retain(|x, _| !ids.contains(x));
+        self.invalidate_documents(Some(ids));
+        for tagged in self.tags.values_mut() {
+            tagged.retain(|x| !ids.contains(x));
+        }
+        self.index = None;
+        removed
+    }
+
+    /// Get up to `n` IDs for the documents with embeddings most similar to
+    /// `query`.
+    ///
+    /// If `filter` is provided, only documents with tags that pass the
+    /// `filter` are considered. Fails if the `query` doesn't have as many
+    /// dimensions as the stored embeddings.
+    pub fn get_similar(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<DocId>> {
+        self.get_similar_scored(query, n, filter)?
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect::<Vec<_>>()
+            .pipe(Ok)
+    }
+
+    /// Like [`DocDb::get_similar`], but also get the similarity of each
+    /// document with the `query`, the most similar first.
+    pub fn get_similar_scored(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<(DocId, f32)>> {
+        check_dimensions(self.embeddings.ncols(), query.len())?;
+        let query = self.normalized(query);
+        self.search(query.view(), n, filter, None)
+            .into_iter()
+            .map(|(score, i)| (self.embeddings_id[i], score.raw()))
+            .collect::<Vec<_>>()
+            .pipe(Ok)
+    }
+
+    /// Like [`DocDb::get_similar_scored`], but re-rank the most similar
+    /// documents so they aren't near duplicates of each other.
+    ///
+    /// The documents are picked by maximal marginal relevance, where `lambda`
+    /// balances the similarity with the `query` against the dissimilarity
+    /// with the documents already picked: 1 ranks by similarity only, 0 by
+    /// diversity only. The scores are the similarities with the `query`.
+    ///
+    /// Documents less similar than `min_similarity` aren't candidates. The
+    /// documents in the earlier languages of the `filter` are picked first.
+    pub fn get_similar_diverse(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+        lambda: f32,
+        min_similarity: Option<f32>,
+    ) -> Result<Vec<(DocId, f32)>> {
+        check_dimensions(self.embeddings.ncols(), query.len())?;
+        let query = self.normalized(query);
+        let mut candidates = self.search(
+            query.view(),
+            n * MMR_CANDIDATES_FACTOR,
+            filter,
+            min_similarity,
+        );
+        let mut picked: Vec<(N32, usize)> = Vec::with_capacity(n);
+        while picked.len() < n && !candidates.is_empty() {
+            let marginal = |&(score, i): &(N32, usize)| {
+                let redundancy = picked
+                    .iter()
+                    .map(|&(_, j)| self.embeddings.pair_dot(i, j))
+                    .max()
+                    .unwrap_or(n32(0.0));
+                n32(lambda) * score - n32(1.0 - lambda) * redundancy
+            };
+            let rank = candidates
+                .iter()
+                .map(|&(_, i)| self.language_rank(filter, i))
+                .min()
+                .expect("candidates aren't empty");
+            let (best, _) = candidates
+                .iter()
+                .enumerate()
+                .filter(|&(_, &(_, i))| self.language_rank(filter, i) == rank)
+                // the first of the ties, the most similar
+                .rev()
+                .max_by_key(|(_, x)| marginal(x))
+                .expect("candidates aren't empty");
+            picked.push(candidates.remove(best));
+        }
+        picked
+            .into_iter()
+            .map(|(score, i)| (self.embeddings_id[i], score.raw()))
+            .collect::<Vec<_>>()
+            .pipe(Ok)
+    }
+
+    /// Like [`DocDb::get_similar_scored`], but get the most similar chunk of
+    /// each parent document, so the documents are distinct.
+    ///
+    /// The chunks of a parent are ranked together by their `aggregation`,
+    /// which is the score returned with the chunk. Documents without a parent
+    /// are their own parent. Chunks less similar than `min_similarity` aren't
+    /// counted, so the threshold is on the similarity of each chunk rather
+    /// than on the aggregate. The documents in the earlier languages of the
+    /// `filter` come first.
+    pub fn get_similar_by_parent(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+        aggregation: Aggregation,
+        min_similarity: Option<f32>,
+    ) -> Result<Vec<(DocId, f32)>> {
+        check_dimensions(self.embeddings.ncols(), query.len())?;
+        let query = self.normalized(query);
+        // the best chunk of each parent first, since candidates are sorted
+        let mut parents: Vec<(DocId, DocId, N32, usize)> = Vec::new();
+        let candidates = self.search(
+            query.view(),
+            n * PARENT_CANDIDATES_FACTOR,
+            filter,
+            min_similarity,
+        );
+        for (score, i) in candidates {
+            let id = self.embeddings_id[i];
+            let parent = self.parents.get(&id).copied().unwrap_or(id);
+            match parents.iter_mut().find(|(x, _, _, _)| *x == parent) {
+                Some((_, _, total, _)) => match aggregation {
+                    Aggregation::Max => (),
+                    Aggregation::Sum => *total += score,
+                },
+                None => parents.push((parent, id, score, self.language_rank(filter, i))),
+            }
+        }
+        // a stable sort keeps ties in order of similarity
+        parents.sort_by(|(_, _, x, a), (_, _, y, b)| a.cmp(b).then(y.cmp(x)));
+        parents
+            .into_iter()
+            .take(n)
+            .map(|(_, id, score, _)| (id, score.raw()))
+            .collect::<Vec<_>>()
+            .pipe(Ok)
+    }
+
+    /// Get up to `n` rows most similar to the already normalized `query`,
+    /// with their similarities, the most similar first.
+    ///
+    /// If the `filter` has several languages, the rows in each language are
+    /// only used once there aren't enough in the earlier languages, so the
+    /// rows are the most similar first within each language.
+    ///
+    /// Rows less similar than `min_similarity` are left out. The threshold is
+    /// on the similarity itself, before the weight of the row's origin.
+    fn search(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+        min_similarity: Option<f32>,
+    ) -> Vec<(N32, usize)> {
+        let filter = match filter {
+            Some(filter) if filter.languages.len() > 1 => filter,
+            _ => return self.search_above(query, n, filter, min_similarity),
+        };
+        let mut found = Vec::new();
+        for language in &filter.languages {
+            if found.len() >= n {
+                break;
+            }
+            // documents of unknown language pass every language, so they
+            // mustn't be found again
+            let filter = Filter {
+                languages: vec![language.clone()],
+                exclude: filter
+                    .exclude
+                    .iter()
+                    .copied()
+                    .chain(found.iter().map(|&(_, i)| self.embeddings_id[i]))
+                    .collect(),
+                ..filter.clone()
+            };
+            found.extend(self.search_above(query, n - found.len(), Some(&filter), min_similarity));
+        }
+        found
+    }
+
+    /// The position of the language of row `i` in the preference order of
+    /// the `filter`, 0 without a filter.
+    fn language_rank(&self, filter: Option<&Filter>, i: usize) -> usize {
+        filter.map_or(0, |x| {
+            x.language_rank(self.languages.get(&self.embeddings_id[i]))
+        })
+    }
+
+    /// Like [`DocDb::search`], but without a preference between languages.
+    fn search_above(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+        min_similarity: Option<f32>,
+    ) -> Vec<(N32, usize)> {
+        let mut found = self.search_nearest(query, n, filter);
+        if let Some(min_similarity) = min_similarity {
+            found.retain(|&(_, i)| self.embeddings.row_dot(i, query) >= min_similarity);
+        }
+        found
+    }
+
+    /// Like [`DocDb::search`], but rank the rows by similarity alone.
+    fn search_nearest(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+    ) -> Vec<(N32, usize)> {
+        if let Some(index) = &self.index {
+            let found = index.search(
+                |i| self.embeddings.row_dot(i, query) * self.row_weight(i),
+                n,
+                |i| self.passes(filter, i),
+            );
+            // a selective filter can leave the graph without enough matches,
+            // which exact search finds if there are any
+            if found.len() >= n {
+                return found;
+            }
+        }
+        self.search_exact(query, n, filter)
+    }
+
+    /// Get up to `max_n` IDs for the documents with embeddings at least
+    /// `min_score` similar to `query`, the most similar first.
+    ///
+    /// If `filter` is provided, only documents that pass the `filter` are
+    /// considered. Fails if the `query` doesn't have as many dimensions as the
+    /// stored embeddings.
+    pub fn get_similar_above(
+        &self,
+        query: ArrayView1<N32>,
+        min_score: f32,
+        max_n: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<DocId>> {
+        check_dimensions(self.embeddings.ncols(), query.len())?;
+        let query = self.normalized(query);
+        self.search(query.view(), max_n, filter, Some(min_score))
+            .into_iter()
+            .map(|(_, i)| self.embeddings_id[i])
+            .collect::<Vec<_>>()
+            .pipe(Ok)
+    }
+
+    /// Scale the `query` to unit length if embeddings are compared by cosine
+    /// similarity.
+    fn normalized<'a>(&self, query: ArrayView1<'a, N32>) -> CowArray<'a, N32, Ix1> {
+        let norm = n32(query.dot(&query).raw().sqrt());
+        if self.similarity == Similarity::Cosine && norm > 0.0 {
+            CowArray::from(query.mapv(|x| x / norm))
+        } else {
+            CowArray::from(query)
+        }
+    }
+
+    /// Set how the similarity of embeddings is measured.
+    ///
+    /// Switching to cosine similarity scales the stored embeddings to unit
+    /// length, which can't be undone, and drops the index, which must be
+    /// built again.
+    pub fn set_similarity(&mut self, similarity: Similarity) {
+        if similarity == Similarity::Cosine && self.similarity != similarity {
+            self.embeddings.normalize();
+            self.index = None;
+        }
+        self.similarity = similarity;
+    }
+
+    /// The factor the similarities of the document in row `i` are scaled by,
+    /// the weight of its origin.
+    fn row_weight(&self, i: usize) -> N32 {
+        if self.origin_weights.iter().all(|&x| x == 1.0) {
+            return n32(1.0);
+        }
+        let index = self
+            .document_origins
+            .get(&self.embeddings_id[i])
+            .copied()
+            .unwrap_or(0);
+        self.origin_weights.get(index).copied().unwrap_or(n32(1.0))
+    }
+
+    /// Set the `weight` the similarities of the documents from `origin` are
+    /// scaled by, so documents from authoritative sources outrank others.
+    ///
+    /// The weight is one by default. Returns whether any documents are from
+    /// `origin`.
+    pub fn set_origin_weight(&mut self, origin: &str, weight: f32) -> Result<bool> {
+        if !(weight.is_finite() && weight > 0.0) {
+            return Err(Error::Weight);
+        }
+        self.origin_weights.resize(self.origins.len(), n32(1.0));
+        let mut found = false;
+        for (x, w) in self.origins.iter().zip(&mut self.origin_weights) {
+            if x == origin {
+                *w = n32(weight);
+                found = true;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Does the document in row `i` pass the `filter`?
+    fn passes(&self, filter: Option<&Filter>, i: usize) -> bool {
+        filter.is_none_or(|x| x.matches(&self.tags, &self.languages, &self.embeddings_id[i]))
+    }
+
+    /// Like [`DocDb::search_nearest`], but compare the `query` with every
+    /// document.
+    fn search_exact(
+        &self,
+        query: ArrayView1<N32>,
+        n: usize,
+        filter: Option<&Filter>,
+    ) -> Vec<(N32, usize)> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let mut similarities = self
+            .embeddings
+            .dot(query)
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| self.passes(filter, *i))
+            .map(|(i, x)| (x * self.row_weight(i), i))
+            .collect::<Vec<_>>();
+        // `y.cmp(x)` for descending order, with ties in row order
+        let descending = |(x, i): &(N32, usize), (y, j): &(N32, usize)| y.cmp(x).then(i.cmp(j));
+        if similarities.len() > n {
+            // only the `n` most similar need sorting, which scales with `n`
+            // rather than the number of documents
+            similarities.select_nth_unstable_by(n - 1, descending);
+            similarities.truncate(n);
+        }
+        similarities.sort_unstable_by(descending);
+        similarities
+    }
+
+    /// Build an approximate nearest-neighbor index over the embeddings, so
+    /// [`DocDb::get_similar`] doesn't compare the query with every document.
+    ///
+    /// Small databases are searched exactly and aren't indexed.
+    pub fn build_index(&mut self, params: HnswParams) {
+        let n = self.embeddings.nrows();
+        if n < MIN_INDEXED_DOCUMENTS {
+            self.index = None;
+            return;
+        }
+        let embeddings = &self.embeddings;
+        let index = Hnsw::build(n, params, |i, j| embeddings.pair_dot(i, j));
+        self.index = Some(index);
+    }
+
+    /// Use an index built ahead of time with [`DocDb::get_index_bytes`].
+    pub fn set_index_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let index: Hnsw =
+            rmp_serde::from_slice(bytes).map_err(|_| Error::Index("index format is invalid"))?;
+        if !index.is_valid(self.embeddings.nrows()) {
+            return Err(Error::Index("index doesn't match the embeddings"));
+        }
+        self.index = Some(index);
+        Ok(())
+    }
+
+    /// Is there an index, built or set?
+    pub fn has_index(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// Get the serialized index, if one was built or set.
+    pub fn get_index_bytes(&self) -> Option<Vec<u8>> {
+        self.index
+            .as_ref()
+            .map(|x| rmp_serde::to_vec(x).expect("index serializes"))
+    }
+
+    /// Set the number of candidates the index considers for each search.
+    pub fn set_index_ef_search(&mut self, ef_search: usize) {
+        if let Some(index) = &mut self.index {
+            index.set_ef_search(ef_search);
+        }
+    }
+
+    /// Get the PCA-mapped version of the embedding `query`.
+    ///
+    /// Fails if the `query` doesn't have as many dimensions as the mapping.
+    pub fn get_pca_mapped<'a>(&self, query: ArrayView1<'a, N32>) -> Result<CowArray<'a, N32, Ix1>> {
+        if let Some(mapping) = &self.embeddings_pca_mapping {
+            check_dimensions(mapping.shape()[0], query.len())?;
+            CowArray::from(query.dot(mapping)).pipe(Ok)
+        } else {
+            CowArray::from(query).pipe(Ok)
+        }
+    }
+
+    /// Get the model used to embed the documents.
+    pub fn get_embedding_model(&self) -> EmbeddingModel {
+        self.embedding_model
+    }
+
+    /// Get the dimensions the document embeddings were shortened to, if any.
+    pub fn get_embedding_dimensions(&self) -> Option<usize> {
+        self.embedding_dimensions
+    }
+
+    /// Set the model used to embed the documents, so queries are embedded
+    /// with the same model and `dimensions`.
+    pub fn set_embedding_model(&mut self, model: EmbeddingModel, dimensions: Option<usize>) {
+        self.embedding_model = model;
+        self.embedding_dimensions = dimensions;
+    }
+
+    /// Get the origin of the document with `id`.
+    fn get_origin(&self, id: &DocId) -> &str {
+        let index = self.document_origins.get(id).copied().unwrap_or(0);
+        self.origins.get(index).map_or("", |x| x.as_str())
+    }
+
+    /// Get the contents of the document with `id` by making a request to
+    /// the document's URL.
+    ///
+    /// The contents of recently fetched documents are kept, so they're only
+    /// fetched again once they're evicted or invalidated.
+    pub async fn get_document(&self, id: &DocId) -> Result<String> {
+        if let Some(content) = self.document_cache.lock().unwrap().get(id) {
+            return Ok(content);
+        }
+        let pattern = self.document_url.as_deref().unwrap_or(DEFAULT_DOCUMENT_URL);
+        let url = document_url(pattern, self.get_origin(id), id);
+        let content = if let Some(fetcher) = &self.fetcher.0 {
+            let content = fetcher.fetch(&url).await.map_err(Error::Fetch)?;
+            self.verify_content(id, content.as_bytes())?;
+            content
+        } else {
+            // an error page isn't the document, so it isn't kept
+            let response = client()
+                .get(&url)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .map_err(Error::DocumentNotAvailable)?;
+            // the hash is of the file, so it's checked before decoding
+            let bytes = response
+                .bytes()
+                .await
+                .map_err(Error::DocumentNotAvailable)?;
+            self.verify_content(id, &bytes)?;
+            let content =
+                String::from_utf8(bytes.to_vec()).map_err(|_| Error::Encoding(hex::encode(id)))?;
+            match content.strip_prefix('\u{feff}') {
+                Some(x) => x.to_string(),
+                None => content,
+            }
+        };
+        self.document_cache
+            .lock()
+            .unwrap()
+            .put(*id, content.clone());
+        Ok(content)
+    }
+
+    /// Get the contents of the chunk with `id`: its span of the parent
+    /// document if known, or else the whole document with `id`.
+    ///
+    /// Fails if the span is outside the parent document.
+    pub async fn get_chunk(&self, id: &DocId) -> Result<String> {
+        let (Some(span), Some(parent)) = (self.spans.get(id), self.get_parent(id)) else {
+            return self.get_document(id).await;
+        };
+        let document = self.get_document(parent).await?;
+        let (start, end) = (span.start as usize, span.end as usize);
+        if start > end || end > document.chars().count() {
+            return Err(Error::Span(hex::encode(id)));
+        }
+        document
+            .chars()
+            .skip(start)
+            .take(end - start)
+            .collect::<String>()
+            .pipe(Ok)
+    }
+
+    /// Set the URL pattern of the documents, with `{origin}`, `{path}` and
+    /// `{id}` placeholders, or use [`DEFAULT_DOCUMENT_URL`] if `None`.
+    ///
+    /// Fails if the pattern doesn't contain `{id}`. The contents fetched from
+    /// the previous URLs are dropped.
+    pub fn set_document_url(&mut self, pattern: Option<String>) -> Result<()> {
+        if pattern.as_ref().is_some_and(|x| !x.contains("{id}")) {
+            return Err(Error::DocumentUrl);
+        }
+        self.document_url = pattern;
+        self.invalidate_documents(None);
+        Ok(())
+    }
+
+    /// Fetch the documents with the `fetcher` in place of HTTP requests, or
+    /// with HTTP requests again if `None`.
+    ///
+    /// The fetcher gets the URL of each document by the URL pattern. The
+    /// contents fetched before are dropped.
+    pub fn set_fetcher(&mut self, fetcher: Option<Rc<dyn DocumentFetcher>>) {
+        self.fetcher = Fetcher(fetcher);
+        self.invalidate_documents(None);
+    }
+
+    /// Fetch the documents with `ids` that aren't kept yet, at most
+    /// `concurrency` at once, so later calls to [`DocDb::get_document`] don't
+    /// wait for them.
+    ///
+    /// Returns how many documents were fetched. Documents that can't be
+    /// fetched are skipped, and fail again once they're needed.
+    pub async fn prefetch(&self, ids: &[DocId], concurrency: usize) -> usize {
+        let missing = {
+            let cache = self.document_cache.lock().unwrap();
+            ids.iter()
+                .filter(|x| !cache.contains(x))
+                .collect::<HashSet<_>>()
+        };
+        stream::iter(missing)
+            .map(|x| self.get_document(x))
+            .buffer_unordered(concurrency.max(1))
+            .filter(|x| futures::future::ready(x.is_ok()))
+            .count()
+            .await
+    }
+
+    /// Set the most bytes of document contents kept in memory, evicting the
+    /// least recently used documents that no longer fit.
+    pub fn set_document_cache_capacity(&mut self, capacity: usize) {
+        self.document_cache.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// Drop the kept contents of the documents with `ids`, or of every
+    /// document if `None`, so they're fetched again.
+    pub fn invalidate_documents(&self, ids: Option<&HashSet<DocId>>) {
+        let mut cache = self.document_cache.lock().unwrap();
+        match ids {
+            Some(ids) => ids.iter().for_each(|x| cache.remove(x)),
+            None => cache.clear(),
+        }
+    }
+
+    /// Set the SHA-256 hash of the contents of the documents, so contents
+    /// that don't match are rejected by [`DocDb::get_document`] rather than
+    /// used.
+    ///
+    /// Each line of `content_hashes` is a document ID and the hex hash of
+    /// its contents, separated by a tab, and can be compressed with gzip or
+    /// zstd. Documents without a hash aren't verified.
+    pub fn set_content_hashes(&mut self, content_hashes: &[u8]) -> Result<()> {
+        let mut hashes = HashMap::new();
+        for (id, [hash]) in parse_id_tsv(
+            content_hashes,
+            "hash line lacks two columns",
+            "hash line isn't a valid string",
+        )? {
+            let mut content_hash = [0u8; 32];
+            hex::decode_to_slice(hash, &mut content_hash).map_err(Error::ContentHash)?;
+            hashes.insert(id, content_hash);
+        }
+        self.content_hashes = hashes;
+        // the kept contents were verified against the old hashes
+        self.invalidate_documents(None);
+        Ok(())
+    }
+
+    /// Set the language of the documents, such as `en`, so searches can be
+    /// filtered by language.
+    ///
+    /// Each line of `languages` is a document ID and its language, separated
+    /// by a tab, and can be compressed with gzip or zstd.
+    pub fn set_languages(&mut self, languages: &[u8]) -> Result<()> {
+        self.languages = parse_id_tsv(
+            languages,
+            "language line lacks two columns",
+            "language line isn't a valid string",
+        )?
+        .into_iter()
+        .map(|(id, [language])| (id, language))
+        .collect();
+        Ok(())
+    }
+
+    /// Set the aliases of the conditions, so a diagnosis named by a synonym
+    /// such as `heart attack` resolves to its article without relying on
+    /// embeddings.
+    ///
+    /// Each line of `aliases` is a condition's document ID and one of its
+    /// aliases, separated by a tab, and can be compressed with gzip or zstd.
+    pub fn set_aliases(&mut self, aliases: &[u8]) -> Result<()> {
+        self.aliases = parse_id_tsv(
+            aliases,
+            "alias line lacks two columns",
+            "alias line isn't a valid string",
+        )?
+        .into_iter()
+        .map(|(id, [alias])| (normalize_alias(&alias), id))
+        .collect();
+        Ok(())
+    }
+
+    /// Get the ID of the condition named `name`, if it's a known alias.
+    ///
+    /// Aliases match regardless of case and spacing.
+    pub fn resolve_alias(&self, name: &str) -> Option<&DocId> {
+        self.aliases.get(&normalize_alias(name))
+    }
+
+    /// Set the ICD-10 and SNOMED CT codes of the conditions.
+    ///
+    /// Each line of `codes` is a condition's document ID, the terminology,
+    /// either `icd10` or `snomed`, and a code, separated by tabs. It can be
+    /// compressed with gzip or zstd.
+    pub fn set_codes(&mut self, codes: &[u8]) -> Result<()> {
+        let mut condition_codes: HashMap<DocId, ConditionCodes> = HashMap::new();
+        for (id, [system, code]) in parse_id_tsv(
+            codes,
+            "code line lacks three columns",
+            "code line isn't a valid string",
+        )? {
+            let entry = condition_codes.entry(id).or_default();
+            match system.as_str() {
+                "icd10" => entry.icd10.push(code),
+                "snomed" => entry.snomed.push(code),
+                _ => return Err(Error::Record("code line has an unknown terminology")),
+            }
+        }
+        self.codes = condition_codes;
+        Ok(())
+    }
+
+    /// Set where the chunks are in their parent documents, so excerpts are
+    /// only the chunk and the UI can highlight it in the parent.
+    ///
+    /// Each line of `spans` is a chunk's document ID and the offsets of its
+    /// start and end in its parent, in Unicode code points, separated by
+    /// tabs. It can be compressed with gzip or zstd.
+    pub fn set_spans(&mut self, spans: &[u8]) -> Result<()> {
+        let mut chunk_spans = HashMap::new();
+        for (id, [start, end]) in parse_id_tsv(
+            spans,
+            "span line lacks three columns",
+            "span line isn't a valid offset",
+        )? {
+            let offset = |x: &str| {
+                x.trim()
+                    .parse::<u32>()
+                    .map_err(|_| Error::Record("span line isn't a valid offset"))
+            };
+            let span = Span {
+                start: offset(&start)?,
+                end: offset(&end)?,
+            };
+            if span.start > span.end {
+                return Err(Error::Record("span line ends before it starts"));
+            }
+            chunk_spans.insert(id, span);
+        }
+        self.spans = chunk_spans;
+        Ok(())
+    }
+
+    /// Get where the chunk with `id` is in its parent document, if known.
+    pub fn get_span(&self, id: &DocId) -> Option<Span> {
+        self.spans.get(id).copied()
+    }
+
+    /// Get the ICD-10 and SNOMED CT codes of the condition with `id`, if
+    /// known.
+    pub fn get_codes(&self, id: &DocId) -> Option<&ConditionCodes> {
+        self.codes.get(id)
+    }
+
+    /// Set the date each document was last reviewed, so excerpts from
+    /// documents that haven't been reviewed in a while can be flagged.
+    ///
+    /// Each line of `reviewed` is a document ID and a `YYYY-MM-DD` date,
+    /// separated by a tab, and can be compressed with gzip or zstd.
+    pub fn set_reviewed(&mut self, reviewed: &[u8]) -> Result<()> {
+        let mut dates = HashMap::new();
+        for (id, [date]) in parse_id_tsv(
+            reviewed,
+            "reviewed line lacks two columns",
+            "reviewed line isn't a valid date",
+        )? {
+            if days_from_date(&date).is_none() {
+                return Err(Error::Record("reviewed line isn't a valid date"));
+            }
+            dates.insert(id, date);
+        }
+        self.reviewed = dates;
+        Ok(())
+    }
+
+    /// Check the `content` of the document with `id`, as bytes of the file,
+    /// against its hash, if it has one.
+    fn verify_content(&self, id: &DocId, content: &[u8]) -> Result<()> {
+        match self.content_hashes.get(id) {
+            Some(expected) if Sha256::digest(content)[..] != expected[..] => {
+                Err(Error::Integrity(hex::encode(id)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the most documents to fetch at once.
+    pub fn get_fetch_concurrency(&self) -> usize {
+        self.fetch_concurrency.max(1)
+    }
+
+    /// Set the most documents to fetch at once.
+    pub fn set_fetch_concurrency(&mut self, n: usize) {
+        self.fetch_concurrency = n;
+    }
+
+    /// Get the title of the document with `id`.
+    pub fn get_title(&self, id: &DocId) -> Option<&str> {
+        self.titles.get(id).map(|x| x.as_str())
+    }
+
+    /// Get the url of the document with `id`.
+    pub fn get_url(&self, id: &DocId) -> Option<&str> {
+        self.urls.get(id).map(|x| x.as_str())
+    }
+
+    /// Get the parent `id` of the document with `id`.
+    pub fn get_parent(&self, id: &DocId) -> Option<&DocId> {
+        self.parents.get(id)
+    }
+
+    /// Get the IDs of the documents whose parent is the document with `id`,
+    /// ordered by title.
+    pub fn get_children(&self, id: &DocId) -> Vec<DocId> {
+        let mut children = self
+            .parents
+            .iter()
+            .filter(|(_, parent)| *parent == id)
+            .map(|(x, _)| *x)
+            .collect::<Vec<_>>();
+        children.sort_by_key(|x| (self.get_title(x), *x));
+        children
+    }
+
+    /// Get the IDs of the other documents with the same parent as the
+    /// document with `id`, ordered by title.
+    ///
+    /// Documents without a parent have no siblings.
+    pub fn get_siblings(&self, id: &DocId) -> Vec<DocId> {
+        match self.get_parent(id) {
+            Some(parent) => self
+                .get_children(parent)
+                .into_iter()
+                .filter(|x| x != id)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether the text of the document with `outer` contains that of the
+    /// chunk with `inner`: `outer` is its parent, an ancestor through chunks,
+    /// or a chunk of the same parent whose span covers it.
+    pub fn contains_chunk(&self, outer: &DocId, inner: &DocId) -> bool {
+        let mut id = *inner;
+        let mut seen = vec![id];
+        while let (Some(span), Some(parent)) = (self.get_span(&id), self.get_parent(&id)) {
+            let covers = self.get_parent(outer) == Some(parent)
+                && self
+                    .get_span(outer)
+                    .is_some_and(|x| x.start <= span.start && span.end <= x.end);
+            if parent == outer || (covers && *outer != id) {
+                return true;
+            }
+            // a malformed corpus can have cycles
+            if seen.contains(parent) {
+                break;
+            }
+            seen.push(*parent);
+            id = *parent;
+        }
+        false
+    }
+
+    /// Replace each of the documents `ids` by the outermost of the others
+    /// containing its text, if any, so no text is repeated, keeping the first
+    /// occurrence of each.
+    ///
+    /// Of two chunks with the same span, the first is kept.
+    pub fn merge_contained(&self, ids: &[DocId]) -> Vec<DocId> {
+        let position = |x: &DocId| ids.iter().position(|y| y == x);
+        let container = |inner: &DocId| {
+            ids.iter().copied().find(|x| {
+                self.contains_chunk(x, inner)
+                    && (!self.contains_chunk(inner, x) || position(x) < position(inner))
+            })
+        };
+        let mut merged = Vec::new();
+        for id in ids {
+            let mut outer = *id;
+            for _ in 0..ids.len() {
+                match container(&outer) {
+                    Some(x) => outer = x,
+                    None => break,
+                }
+            }
+            if !merged.contains(&outer) {
+                merged.push(outer);
+            }
+        }
+        merged
+    }
+
+    /// Get the IDs of the ancestors of the document with `id`, the root
+    /// first, followed by `id`.
+    pub fn get_path(&self, id: &DocId) -> Vec<DocId> {
+        let mut path = vec![*id];
+        while let Some(parent) = self.get_parent(path.last().expect("path isn't empty")) {
+            // a malformed corpus can have cycles
+            if path.contains(parent) {
+                break;
+            }
+            path.push(*parent);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Get the ID of the condition the document with `id` belongs to: the
+    /// document itself or its nearest ancestor tagged [`TAG_CONDITION`].
+    pub fn get_condition(&self, id: &DocId) -> Option<DocId> {
+        self.get_path(id)
+            .into_iter()
+            .rev()
+            .find(|x| self.has_tag(x, TAG_CONDITION))
+    }
+
+    /// Get the ID of the condition best matching the `query` embedding, such
+    /// as that of a diagnosis name, if any.
+    ///
+    /// The introductions and symptoms most similar to the `query` each vote
+    /// for the condition they belong to, and the condition with the most
+    /// votes wins, the one with the most similar document on a tie. Fails if
+    /// the `query` doesn't have as many dimensions as the stored embeddings.
+    pub fn resolve_condition(&self, query: ArrayView1<N32>) -> Result<Option<DocId>> {
+        let filter = Filter::any_of(&[TAG_INTRODUCTION, TAG_SYMPTOMS]);
+        let mut votes: Vec<(DocId, usize)> = Vec::new();
+        for id in self.get_similar(query, CONDITION_CANDIDATES, Some(&filter))? {
+            let Some(condition) = self.get_condition(&id) else {
+                continue;
+            };
+            match votes.iter_mut().find(|(x, _)| *x == condition) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((condition, 1)),
+            }
+        }
+        // `max_by_key` keeps the last of the ties, so reverse for the first
+        Ok(votes
+            .into_iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .map(|(x, _)| x))
+    }
+
+    /// Get the date the document with `id` was last reviewed, as
+    /// `YYYY-MM-DD`.
+    pub fn get_reviewed(&self, id: &DocId) -> Option<&str> {
+        self.reviewed.get(id).map(|x| x.as_str())
+    }
+
+    /// Was the document with `id` last reviewed more than `max_age_days`
+    /// ago? Documents without a date aren't stale.
+    pub fn is_stale(&self, id: &DocId, max_age_days: u32) -> bool {
+        let today = (unix_ms() / 86_400_000.0).floor() as i64;
+        self.is_stale_on(id, max_age_days, today)
+    }
+
+    /// Like [`DocDb::is_stale`], but as of the day `today`, in days from the
+    /// Unix epoch.
+    fn is_stale_on(&self, id: &DocId, max_age_days: u32, today: i64) -> bool {
+        self.get_reviewed(id)
+            .and_then(days_from_date)
+            .is_some_and(|x| today - x > max_age_days as i64)
+    }
+
+    /// Does the document with `id` have the `tag`?
+    pub fn has_tag(&self, id: &DocId, tag: &str) -> bool {
+        self.tags.get(tag).is_some_and(|x| x.contains(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::{array, Array1};
+    use noisy_float::prelude::n32;
+
+    use super::*;
+
+    fn tags(tags: &[(&str, &[DocId])]) -> HashMap<String, HashSet<DocId>> {
+        tags.iter()
+            .map(|(tag, ids)| (tag.to_string(), ids.iter().copied().collect()))
+            .collect()
+    }
+
+    fn npy_bytes<T: npyz::AutoSerialize>(shape: &[u64], values: Vec<T>) -> Vec<u8> {
+        use npyz::WriterBuilder;
+        let mut bytes = Vec::new();
+        let mut writer = npyz::WriteOptions::new()
+            .default_dtype()
+            .shape(shape)
+            .writer(&mut bytes)
+            .begin_nd()
+            .unwrap();
+        writer.extend(values).unwrap();
+        writer.finish().unwrap();
+        bytes
+    }
+
+    #[test]
+    fn reads_half_precision() {
+        let values = [0.5, -1.0, 2.0, 0.25];
+        let bytes = npy_bytes(&[2, 2], values.iter().map(|&x| f16::from_f32(x)).collect());
+        let actual = float_array2_from_npy(NpyFile::new(&bytes[..]).unwrap()).unwrap();
+        assert_eq!(array![[0.5, -1.0], [2.0, 0.25]], actual);
+        let bytes = npy_bytes(&[1, 2], vec![1.0f32, f32::NAN]);
+        assert!(matches!(
+            float_array2_from_npy(NpyFile::new(&bytes[..]).unwrap()),
+            Err(Error::NotNan)
+        ));
+    }
+
+    #[test]
+    fn reads_int8_with_scales() {
+        let values = npy_bytes(&[2, 2], vec![1i8, -2, 3, 4]);
+        let scales = npy_bytes(&[2], vec![0.5f32, 0.25]);
+        let embeddings = embeddings_from_npy(&values, Some(&scales)).unwrap();
+        assert_eq!(embeddings.pair_dot(0, 1), n32(-5.0 * 0.125));
+        assert!(matches!(
+            embeddings_from_npy(&values, None),
+            Err(Error::Scales)
+        ));
+    }
+
+    #[test]
+    fn parses_id_tsv() {
+        let id = hex::encode([0x01; 16]);
+        let table = format!("{id}\ta\tb\tc\n\n{id}\td\te\n");
+        let rows = parse_id_tsv::<2>(table.as_bytes(), "lacks", "invalid").unwrap();
+        let rows = rows
+            .iter()
+            .map(|(id, [x, y])| (id[0], x.as_str(), y.as_str()))
+            .collect::<Vec<_>>();
+        // the last column keeps any further tabs
+        assert_eq!(rows, [(0x01, "a", "b\tc"), (0x01, "d", "e")]);
+        let table = format!("{id}\ta\n");
+        assert!(matches!(
+            parse_id_tsv::<2>(table.as_bytes(), "lacks", "invalid"),
+            Err(Error::Record("lacks"))
+        ));
+        let table = [id.as_bytes(), b"\t\xff\n"].concat();
+        assert!(matches!(
+            parse_id_tsv::<1>(&table, "lacks", "invalid"),
+            Err(Error::Record("invalid"))
+        ));
+        assert!(matches!(
+            parse_id_tsv::<1>(b"xyz\ta\n", "lacks", "invalid"),
+            Err(Error::Id(_))
+        ));
+    }
+
+    #[test]
+    fn document_db_adds_and_removes_documents() {
+        let mut db = DocDb {
+            embeddings: array![[0.0, 1.0], [1.0, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16]],
+            titles: [([0x01; 16], "a".to_string())].into_iter().collect(),
+            ..Default::default()
+        };
+        let id = |x: u8| hex::encode([x; 16]);
+        db.add_documents(
+            &npy_bytes(&[2, 2], vec![0.5f32, 0.0, 2.0, 0.0]),
+            None,
+            format!("{}\n{}\n", id(1), id(3)).as_bytes(),
+            b"",
+            format!("{}\tb\n", id(1)).as_bytes(),
+            b"",
+            format!("{}\tdrug\n", id(3)).as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(db.get_title(&[0x01; 16]), Some("b"));
+        assert!(db.has_tag(&[0x03; 16], "drug"));
+        // embeddings of the wrong dimensions replace nothing
+        let added = db.add_documents(
+            &npy_bytes(&[1, 3], vec![1.0f32, 0.0, 0.0]),
+            None,
+            format!(
+                "{}
+",
+                id(1)
+            )
+            .as_bytes(),
+            b"",
+            b"",
+            b"",
+            b"",
+        );
+        assert!(matches!(added, Err(Error::ArrayShape)));
+        assert_eq!(db.get_title(&[0x01; 16]), Some("b"));
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let actual = db.get_similar(query.view(), 3, None).unwrap();
+        assert_eq!(actual, [[0x03; 16], [0x02; 16], [0x01; 16]]);
+
+        let removed = [[0x03; 16], [0x04; 16]].into_iter().collect();
+        assert_eq!(db.remove_documents(&removed), 1);
+        assert!(!db.has_tag(&[0x03; 16], "drug"));
+        let actual = db.get_similar(query.view(), 3, None).unwrap();
+        assert_eq!(actual, [[0x02; 16], [0x01; 16]]);
+    }
+
+    #[test]
+    fn document_db_reads_archive() {
+        let ids = format!("{}\n", hex::encode([0x01; 16]));
+        let titles = format!("{}\ta\n", hex::encode([0x01; 16]));
+        let embeddings = npy_bytes(&[1, 2], vec![1.0f32, 0.0]);
+        let archive = Archive {
+            origin: "https://example.com",
+            embeddings: &embeddings,
+            embeddings_id: ids.as_bytes(),
+            titles: titles.as_bytes(),
+            ..Default::default()
+        };
+        let stages = std::cell::RefCell::new(Vec::new());
+        let load = |bytes: &[u8]| {
+            futures::executor::block_on(DocDb::from_archive(bytes, &|x| {
+                stages.borrow_mut().push(x)
+            }))
+        };
+        let db = load(&archive.to_bytes()).unwrap();
+        assert_eq!(db.get_title(&[0x01; 16]), Some("a"));
+        assert_eq!(stages.borrow().first(), Some(&LoadStage::Decompressed));
+        assert_eq!(stages.borrow().last(), Some(&LoadStage::Tags));
+        let compressed = ruzstd::encoding::compress_to_vec(
+            archive.to_bytes().as_slice(),
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+        let db = load(&compressed).unwrap();
+        assert_eq!(db.get_title(&[0x01; 16]), Some("a"));
+        assert_eq!(db.get_origin(&[0x01; 16]), "https://example.com");
+        let archive = Archive {
+            index: Some(b"abc"),
+            ..archive
+        };
+        assert!(matches!(load(&archive.to_bytes()), Err(Error::Index(_))));
+    }
+
+    #[test]
+    fn document_db_round_trips_bytes() {
+        let mut db = DocDb {
+            origins: vec!["a".to_string()],
+            embeddings: array![[0.0, 1.0], [1.0, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16]],
+            titles: [([0x02; 16], "b".to_string())].into_iter().collect(),
+            tags: tags(&[("drug", &[[0x01; 16]])]),
+            ..Default::default()
+        };
+        db.set_similarity(Similarity::Cosine);
+        let cached = DocDb::from_bytes(&db.to_bytes().unwrap()).unwrap();
+        assert_eq!(cached.get_title(&[0x02; 16]), Some("b"));
+        assert!(cached.has_tag(&[0x01; 16], "drug"));
+        assert_eq!(cached.similarity, Similarity::Cosine);
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        assert_eq!(
+            cached.get_similar(query.view(), 2, None).unwrap(),
+            db.get_similar(query.view(), 2, None).unwrap()
+        );
+
+        let bytes = rmp_serde::to_vec(&(CACHE_VERSION + 1, &db)).unwrap();
+        assert!(matches!(DocDb::from_bytes(&bytes), Err(Error::Cache(_))));
+        assert!(matches!(DocDb::from_bytes(b"abc"), Err(Error::Cache(_))));
+        let db = DocDb {
+            embeddings: Embeddings::Float(array![[f32::NAN]]),
+            embeddings_id: vec![[0x01; 16]],
+            ..Default::default()
+        };
+        assert!(matches!(
+            DocDb::from_bytes(&db.to_bytes().unwrap()),
+            Err(Error::Cache(_))
+        ));
+        for db in [
+            DocDb {
+                embeddings: Embeddings::Int8 {
+                    values: array![[1, 2], [3, 4]],
+                    scales: array![n32(0.5)],
+                },
+                embeddings_id: vec![[0x01; 16], [0x02; 16]],
+                ..Default::default()
+            },
+            DocDb {
+                embeddings: array![[1.0, 0.0]].mapv(n32).into(),
+                embeddings_id: vec![[0x01; 16]],
+                embeddings_pca_mapping: Some(array![[1.0, 0.0, 0.0]].mapv(n32)),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                DocDb::from_bytes(&db.to_bytes().unwrap()),
+                Err(Error::Cache(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn document_db_verifies_content() {
+        let mut db = DocDb::default();
+        let id = hex::encode([0x01; 16]);
+        let hash = hex::encode(Sha256::digest(b"abc"));
+        db.set_content_hashes(format!("{}\t{}\n", id, hash).as_bytes())
+            .unwrap();
+        assert!(db.verify_content(&[0x01; 16], b"abc").is_ok());
+        assert!(matches!(
+            db.verify_content(&[0x01; 16], b"abd"),
+            Err(Error::Integrity(_))
+        ));
+        assert!(db.verify_content(&[0x02; 16], b"abd").is_ok());
+        assert!(matches!(
+            db.set_content_hashes(format!("{}\tab\n", id).as_bytes()),
+            Err(Error::ContentHash(_))
+        ));
+    }
+
+    #[test]
+    fn document_db_traverses_tree() {
+        let id = |x: u8| [x; 16];
+        let db = DocDb {
+            parents: [
+                (id(2), id(1)),
+                (id(3), id(1)),
+                (id(4), id(3)),
+                (id(5), id(5)),
+            ]
+            .into_iter()
+            .collect(),
+            titles: [(id(2), "b".to_string()), (id(3), "a".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(db.get_children(&id(1)), [id(3), id(2)]);
+        assert_eq!(db.get_siblings(&id(2)), [id(3)]);
+        assert!(db.get_siblings(&id(1)).is_empty());
+        assert_eq!(db.get_path(&id(4)), [id(1), id(3), id(4)]);
+        assert_eq!(db.get_path(&id(5)), [id(5)]);
+    }
+
+    #[test]
+    fn document_db_uses_fetcher() {
+        struct Bundle;
+        impl DocumentFetcher for Bundle {
+            fn fetch(
+                &self,
+                url: &str,
+            ) -> futures::future::LocalBoxFuture<'_, core::result::Result<String, String>>
+            {
+                let result = match url.strip_prefix("bundle:") {
+                    Some(id) => Ok(format!("contents of {}", &id[..2])),
+                    None => Err(format!("not bundled: {}", url)),
+                };
+                Box::pin(futures::future::ready(result))
+            }
+        }
+        let mut db = DocDb::default();
+        db.set_fetcher(Some(Rc::new(Bundle)));
+        let get = |db: &DocDb| futures::executor::block_on(db.get_document(&[0x01; 16]));
+        assert!(matches!(get(&db), Err(Error::Fetch(_))));
+        assert!(matches!(
+            db.set_document_url(Some("bundle:".to_string())),
+            Err(Error::DocumentUrl)
+        ));
+        db.set_document_url(Some("bundle:{id}".to_string()))
+            .unwrap();
+        assert_eq!(get(&db).unwrap(), "contents of 01");
+    }
+
+    #[test]
+    fn document_db_reads_spans() {
+        let mut db = DocDb::default();
+        let spans = format!(
+            "{}\t2\t5\n{}\t0\t3\n",
+            hex::encode([0x01; 16]),
+            hex::encode([0x02; 16])
+        );
+        db.set_spans(spans.as_bytes()).unwrap();
+        assert_eq!(db.get_span(&[0x01; 16]), Some(Span { start: 2, end: 5 }));
+        assert_eq!(db.get_span(&[0x03; 16]), None);
+        let spans = format!("{}\t5\t2\n", hex::encode([0x01; 16]));
+        assert!(db.set_spans(spans.as_bytes()).is_err());
+
+        db.parents.insert([0x01; 16], [0x03; 16]);
+        db.document_cache
+            .lock()
+            .unwrap()
+            .put([0x03; 16], "a\u{e9}bcdef".to_string());
+        let chunk = futures::executor::block_on(db.get_chunk(&[0x01; 16])).unwrap();
+        assert_eq!(chunk, "bcd");
+        db.spans.insert([0x01; 16], Span { start: 2, end: 50 });
+        let chunk = futures::executor::block_on(db.get_chunk(&[0x01; 16]));
+        assert!(matches!(chunk, Err(Error::Span(_))));
+    }
+
+    #[test]
+    fn document_db_merges_contained_chunks() {
+        let id = |x: u8| [x; 16];
+        let span = |start, end| Span { start, end };
+        let db = DocDb {
+            parents: [
+                (id(2), id(1)),
+                (id(3), id(2)),
+                (id(4), id(2)),
+                (id(5), id(2)),
+            ]
+            .into_iter()
+            .collect(),
+            spans: [
+                (id(2), span(0, 100)),
+                (id(3), span(0, 10)),
+                (id(4), span(0, 50)),
+                (id(5), span(0, 50)),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        assert!(db.contains_chunk(&id(1), &id(3)));
+        assert!(db.contains_chunk(&id(4), &id(3)));
+        assert!(!db.contains_chunk(&id(3), &id(4)));
+        assert!(!db.contains_chunk(&id(3), &id(3)));
+        assert_eq!(db.merge_contained(&[id(3), id(6), id(4)]), [id(4), id(6)]);
+        assert_eq!(db.merge_contained(&[id(5), id(4), id(3)]), [id(5)]);
+        assert_eq!(db.merge_contained(&[id(3), id(1), id(2)]), [id(1)]);
+    }
+
+    #[test]
+    fn document_db_resolves_conditions() {
+        let id = |x: u8| [x; 16];
+        let db = DocDb {
+            embeddings: array![[1.0, 0.0], [0.9, 0.1], [0.8, 0.2], [0.0, 1.0]]
+                .mapv(n32)
+                .into(),
+            embeddings_id: vec![id(11), id(21), id(22), id(31)],
+            parents: [
+                (id(11), id(1)),
+                (id(21), id(20)),
+                (id(20), id(2)),
+                (id(22), id(2)),
+                (id(31), id(3)),
+            ]
+            .into_iter()
+            .collect(),
+            tags: tags(&[
+                ("condition", &[id(1), id(2)]),
+                ("symptoms", &[id(11), id(21), id(22), id(31)]),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(db.get_condition(&id(21)), Some(id(2)));
+        assert_eq!(db.get_condition(&id(2)), Some(id(2)));
+        assert_eq!(db.get_condition(&id(31)), None);
+        let query = array![1.0, 0.0].mapv(n32);
+        assert_eq!(db.resolve_condition(query.view()).unwrap(), Some(id(2)));
+        let query = array![0.0, 1.0].mapv(n32);
+        assert_eq!(db.resolve_condition(query.view()).unwrap(), Some(id(2)));
+    }
+
+    #[test]
+    fn document_db_prefers_languages() {
+        let id = |x: u8| hex::encode([x; 16]);
+        let mut db = DocDb {
+            embeddings: array![[1.0, 0.0], [0.5, 0.0], [0.8, 0.0], [0.9, 0.0]]
+                .mapv(n32)
+                .into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16], [0x04; 16]],
+            ..Default::default()
+        };
+        let languages = format!("{}\ten\n{}\tes\n{}\tes\n", id(1), id(2), id(3));
+        db.set_languages(languages.as_bytes()).unwrap();
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let filter = Filter {
+            languages: vec!["es".to_string(), "en".to_string()],
+            ..Default::default()
+        };
+        // the document of unknown language passes as the first language
+        let actual = db.get_similar(query.view(), 3, Some(&filter)).unwrap();
+        assert_eq!(actual, [[0x04; 16], [0x03; 16], [0x02; 16]]);
+        let actual = db
+            .get_similar_above(query.view(), 0.7, 3, Some(&filter))
+            .unwrap();
+        assert_eq!(actual, [[0x04; 16], [0x03; 16], [0x01; 16]]);
+        let ids = |x: Vec<(DocId, f32)>| x.into_iter().map(|(id, _)| id[0]).collect::<Vec<_>>();
+        let actual = db
+            .get_similar_diverse(query.view(), 4, Some(&filter), 0.5, None)
+            .unwrap();
+        assert_eq!(ids(actual)[3], 0x01);
+        let actual = db
+            .get_similar_by_parent(query.view(), 4, Some(&filter), Aggregation::Max, None)
+            .unwrap();
+        assert_eq!(ids(actual), [0x04, 0x03, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn search_exact_selects_most_similar() {
+        let scores = [0.3, 0.9, 0.1, 0.9, 0.5, 0.7, 0.2];
+        let db = DocDb {
+            embeddings: Array2::from_shape_fn((scores.len(), 1), |(i, _)| n32(scores[i])).into(),
+            embeddings_id: (0..scores.len() as u8).map(|x| [x; 16]).collect(),
+            ..Default::default()
+        };
+        let query = array![n32(1.0)];
+        let rows = |n| {
+            db.search_exact(query.view(), n, None)
+                .into_iter()
+                .map(|(_, i)| i)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rows(3), vec![1, 3, 5]);
+        assert_eq!(rows(0), Vec::<usize>::new());
+        assert_eq!(rows(10), vec![1, 3, 5, 4, 0, 6, 2]);
+    }
+
+    #[test]
+    fn document_db_resolves_aliases() {
+        let mut db = DocDb::default();
+        let aliases = format!(
+            "{}\tHeart attack\n{}\tMI\n",
+            hex::encode([0x01; 16]),
+            hex::encode([0x01; 16])
+        );
+        db.set_aliases(aliases.as_bytes()).unwrap();
+        assert_eq!(db.resolve_alias(" heart   ATTACK"), Some(&[0x01; 16]));
+        assert_eq!(db.resolve_alias("mi"), Some(&[0x01; 16]));
+        assert_eq!(db.resolve_alias("stroke"), None);
+        db.aliases.retain(|_, x| *x != [0x01; 16]);
+        assert_eq!(db.resolve_alias("mi"), None);
+    }
+
+    #[test]
+    fn document_db_reads_codes() {
+        let mut db = DocDb::default();
+        let id = hex::encode([0x01; 16]);
+        let codes = format!("{id}\ticd10\tI21.9\n{id}\tsnomed\t22298006\n{id}\ticd10\tI21.4\n");
+        db.set_codes(codes.as_bytes()).unwrap();
+        assert_eq!(
+            db.get_codes(&[0x01; 16]),
+            Some(&ConditionCodes {
+                icd10: vec!["I21.9".to_string(), "I21.4".to_string()],
+                snomed: vec!["22298006".to_string()],
+            })
+        );
+        assert_eq!(db.get_codes(&[0x02; 16]), None);
+        let codes = format!("{id}\tread\tX200E\n");
+        assert!(db.set_codes(codes.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn document_db_flags_stale_documents() {
+        assert_eq!(days_from_date("1970-01-01"), Some(0));
+        assert_eq!(days_from_date("2000-03-01"), Some(11_017));
+        assert_eq!(days_from_date("2000-13-01"), None);
+        assert_eq!(days_from_date("2023-02-30"), None);
+        assert_eq!(days_from_date("2023-04-31"), None);
+        assert_eq!(days_from_date("2024-02-29"), Some(19_782));
+        assert_eq!(days_from_date("2100-02-29"), None);
+        let mut db = DocDb::default();
+        let reviewed = format!("{}\t2000-03-01\n", hex::encode([0x01; 16]));
+        db.set_reviewed(reviewed.as_bytes()).unwrap();
+        assert_eq!(db.get_reviewed(&[0x01; 16]), Some("2000-03-01"));
+        assert!(!db.is_stale_on(&[0x01; 16], 30, 11_047));
+        assert!(db.is_stale_on(&[0x01; 16], 30, 11_048));
+        assert!(!db.is_stale_on(&[0x02; 16], 30, 11_048));
+        let reviewed = format!("{}\tMarch 2000\n", hex::encode([0x01; 16]));
+        assert!(db.set_reviewed(reviewed.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn document_db_merges() {
+        let mut db = DocDb {
+            origins: vec!["a".to_string()],
+            embeddings: array![[0.0, 1.0], [1.0, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16]],
+            ..Default::default()
+        };
+        let other = DocDb {
+            origins: vec!["b".to_string()],
+            embeddings: array![[0.5, 0.5], [2.0, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x02; 16], [0x03; 16]],
+            titles: [([0x02; 16], "b".to_string()), ([0x03; 16], "c".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(db.merge(other).unwrap(), 1);
+        assert_eq!(db.get_title(&[0x02; 16]), None);
+        assert_eq!(db.get_title(&[0x03; 16]), Some("c"));
+        assert_eq!(db.get_origin(&[0x02; 16]), "a");
+        assert_eq!(db.get_origin(&[0x03; 16]), "b");
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let actual = db.get_similar(query.view(), 3, None).unwrap();
+        assert_eq!(actual, [[0x03; 16], [0x02; 16], [0x01; 16]]);
+
+        let other = DocDb {
+            embeddings: array![[0.5, 0.5, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x04; 16]],
+            ..Default::default()
+        };
+        assert!(matches!(db.merge(other), Err(Error::Merge(_))));
+        for (ours, theirs) in [
+            (Similarity::Cosine, Similarity::Dot),
+            (Similarity::Dot, Similarity::Cosine),
+        ] {
+            let mut db = DocDb::default();
+            db.set_similarity(ours);
+            let mut other = DocDb::default();
+            other.set_similarity(theirs);
+            assert!(matches!(db.merge(other), Err(Error::Merge(_))));
+        }
+    }
+
+    #[test]
+    fn document_db_weights_origins() {
+        let mut db = DocDb {
+            origins: vec!["a".to_string()],
+            embeddings: array![[1.0, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16]],
+            ..Default::default()
+        };
+        let mut other = DocDb {
+            origins: vec!["b".to_string()],
+            embeddings: array![[0.8, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x02; 16]],
+            ..Default::default()
+        };
+        assert!(other.set_origin_weight("b", 1.5).unwrap());
+        db.merge(other).unwrap();
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let actual = db.get_similar_scored(query.view(), 2, None).unwrap();
+        assert_eq!(actual, [([0x02; 16], 1.2), ([0x01; 16], 1.0)]);
+
+        assert!(!db.set_origin_weight("c", 2.0).unwrap());
+        assert!(matches!(db.set_origin_weight("a", 0.0), Err(Error::Weight)));
+        assert!(db.set_origin_weight("b", 1.0).unwrap());
+        let actual = db.get_similar(query.view(), 2, None).unwrap();
+        assert_eq!(actual, [[0x01; 16], [0x02; 16]]);
+    }
+
+    #[test]
+    fn document_db_gets_similar() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let expected: Vec<DocId> = vec![[0x02; 16], [0x03; 16]];
+        let actual = DocDb {
+            embeddings: array![[0.0, 1.0], [1.0, 0.0], [1.0, 1.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16]],
+            ..Default::default()
+        }
+        .get_similar(query.view(), expected.len(), None)
+        .unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    /// DocumentDb gets most similar IDs that are filtered.
+    #[test]
+    fn document_db_gets_similar_filtered() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let filter = Filter::any_of(&["a"]);
+        let expected: Vec<DocId> = vec![[0x02; 16], [0x01; 16]];
+        let actual = DocDb {
+            embeddings: array![[0.0, 1.0], [1.0, 0.0], [1.0, 1.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16]],
+            tags: tags(&[("a", &[[0x01; 16], [0x02; 16]])]),
+            ..Default::default()
+        }
+        .get_similar(query.view(), expected.len(), Some(&filter))
+        .unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn document_db_gets_similar_indexed() {
+        let embeddings: Embeddings = array![[0.0, 1.0], [2.0, 0.0], [1.0, 1.0], [-1.0, 0.0]]
+            .mapv(n32)
+            .into();
+        let index = Hnsw::build(4, HnswParams::default(), |i, j| embeddings.pair_dot(i, j));
+        let mut db = DocDb {
+            embeddings,
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16], [0x04; 16]],
+            tags: tags(&[("a", &[[0x01; 16]])]),
+            ..Default::default()
+        };
+        db.set_index_bytes(&rmp_serde::to_vec(&index).unwrap())
+            .unwrap();
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let expected: Vec<DocId> = vec![[0x02; 16], [0x03; 16]];
+        assert_eq!(expected, db.get_similar(query.view(), 2, None).unwrap());
+        let actual = db
+            .get_similar(query.view(), 2, Some(&Filter::any_of(&["a"])))
+            .unwrap();
+        assert_eq!(vec![[0x01; 16]], actual);
+        assert!(db.set_index_bytes(b"\x90").is_err());
+    }
+
+    #[test]
+    fn document_db_gets_similar_scored() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let actual = DocDb {
+            embeddings: array![[0.0, 1.0], [0.5, 0.0], [0.25, 1.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16]],
+            ..Default::default()
+        }
+        .get_similar_scored(query.view(), 2, None)
+        .unwrap();
+        assert_eq!(actual, [([0x02; 16], 0.5), ([0x03; 16], 0.25)]);
+    }
+
+    #[test]
+    fn document_db_gets_similar_above() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let db = DocDb {
+            embeddings: array![[0.0, 1.0], [0.5, 0.0], [0.25, 1.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16]],
+            ..Default::default()
+        };
+        let actual = db.get_similar_above(query.view(), 0.3, 8, None).unwrap();
+        assert_eq!(actual, [[0x02; 16]]);
+        let actual = db.get_similar_above(query.view(), 0.0, 1, None).unwrap();
+        assert_eq!(actual, [[0x02; 16]]);
+    }
+
+    #[test]
+    fn document_db_gets_similar_diverse() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let db = DocDb {
+            embeddings: array![[0.9, 0.1], [0.9, 0.1], [0.7, -0.7]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16]],
+            ..Default::default()
+        };
+        let ids = |x: Vec<(DocId, f32)>| x.into_iter().map(|(x, _)| x).collect::<Vec<_>>();
+        let relevant = db
+            .get_similar_diverse(query.view(), 2, None, 1.0, None)
+            .unwrap();
+        assert_eq!(ids(relevant), [[0x01; 16], [0x02; 16]]);
+        let diverse = db
+            .get_similar_diverse(query.view(), 2, None, 0.5, None)
+            .unwrap();
+        assert_eq!(ids(diverse), [[0x01; 16], [0x03; 16]]);
+    }
+
+    #[test]
+    fn document_db_gets_similar_by_parent() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let db = DocDb {
+            embeddings: array![[0.9, 0.0], [0.5, 0.0], [0.45, 0.0], [0.8, 0.0]]
+                .mapv(n32)
+                .into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16], [0x04; 16]],
+            parents: [([0x02; 16], [0x0a; 16]), ([0x03; 16], [0x0a; 16])]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let max = db
+            .get_similar_by_parent(query.view(), 2, None, Aggregation::Max, None)
+            .unwrap();
+        assert_eq!(max, [([0x01; 16], 0.9), ([0x04; 16], 0.8)]);
+        let sum = db
+            .get_similar_by_parent(query.view(), 2, None, Aggregation::Sum, None)
+            .unwrap();
+        assert_eq!(sum[0].0, [0x02; 16]);
+        assert!((sum[0].1 - 0.95).abs() < 1e-6);
+        // the chunk below the threshold doesn't add to its parent
+        let above = db
+            .get_similar_by_parent(query.view(), 2, None, Aggregation::Sum, Some(0.46))
+            .unwrap();
+        assert_eq!(above, [([0x01; 16], 0.9), ([0x04; 16], 0.8)]);
+    }
+
+    #[test]
+    fn document_db_gets_similar_cosine() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let mut db = DocDb {
+            embeddings: array![[0.0, 1.0], [2.0, 2.0], [1.0, 0.1]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16], [0x03; 16]],
+            ..Default::default()
+        };
+        assert_eq!(db.get_similar(query.view(), 1, None).unwrap(), [[0x02; 16]]);
+        db.set_similarity(Similarity::Cosine);
+        assert_eq!(db.get_similar(query.view(), 1, None).unwrap(), [[0x03; 16]]);
+    }
+
+    #[test]
+    fn document_db_gets_pca_mapped() {
+        let query: Array1<N32> = array![1.0, 0.0, 2.0].mapv(n32);
+        let expected: Array1<N32> = array![0.0, 1.0].mapv(n32);
+        let actual = DocDb {
+            embeddings_pca_mapping: Some(array![[0.0, 1.0], [1.0, 0.0], [0.0, 0.0]].mapv(n32)),
+            ..Default::default()
+        }
+        .get_pca_mapped(query.view())
+        .unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn document_db_checks_dimensions() {
+        let query: Array1<N32> = array![1.0, 0.0, 0.0].mapv(n32);
+        let db = DocDb {
+            embeddings: array![[0.0, 1.0], [1.0, 0.0]].mapv(n32).into(),
+            embeddings_id: vec![[0x01; 16], [0x02; 16]],
+            ..Default::default()
+        };
+        assert!(matches!(
+            db.get_similar(query.view(), 1, None),
+            Err(Error::Dimensions {
+                expected: 2,
+                found: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn document_db_gets_pca_mapped_no_mapping() {
+        let query: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let expected: Array1<N32> = array![1.0, 0.0].mapv(n32);
+        let actual = DocDb::default().get_pca_mapped(query.view()).unwrap();
+        assert_eq!(expected, actual);
+    }
+}
@@ -0,0 +1,108 @@
+//! Statistics on the size of a database, to tell whether it fits in memory.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use super::{DocDb, Embeddings};
+
+/// The size of a database and roughly how much memory it uses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// The number of embedded documents.
+    pub documents: usize,
+    /// The number of dimensions of each embedding.
+    pub embedding_dimensions: usize,
+    /// How the embeddings are stored, `float` or `int8`.
+    pub embedding_storage: &'static str,
+    /// How the embeddings are searched, `exact` or `hnsw`.
+    pub index: &'static str,
+    /// Roughly how many bytes the database holds in memory, including the
+    /// cached document contents.
+    pub memory_bytes: usize,
+}
+
+/// Roughly how many bytes the `map` holds, where `value` is the bytes each
+/// value holds outside the map.
+fn map_bytes<K, V>(map: &HashMap<K, V>, value: impl Fn(&V) -> usize) -> usize {
+    // a control byte per bucket
+    map.capacity() * (size_of::<K>() + size_of::<V>() + 1) + map.values().map(value).sum::<usize>()
+}
+
+fn set_bytes<T>(set: &HashSet<T>) -> usize {
+    set.capacity() * (size_of::<T>() + 1)
+}
+
+impl DocDb {
+    /// Get the size of the database and an estimate of the memory it uses.
+    pub fn stats(&self) -> Stats {
+        let memory_bytes = size_of::<DocDb>()
+            + self.embeddings.memory_bytes()
+            + self
+                .embeddings_pca_mapping
+                .as_ref()
+                .map_or(0, |x| x.len() * size_of::<f32>())
+            + self.embeddings_id.capacity() * size_of::<super::DocId>()
+            + map_bytes(&self.parents, |_| 0)
+            + map_bytes(&self.titles, String::capacity)
+            + map_bytes(&self.urls, String::capacity)
+            + map_bytes(&self.tags, set_bytes)
+            + map_bytes(&self.content_hashes, |_| 0)
+            + map_bytes(&self.languages, String::capacity)
+            + map_bytes(&self.reviewed, String::capacity)
+            + map_bytes(&self.aliases, |_| 0)
+            + self.aliases.keys().map(String::capacity).sum::<usize>()
+            + map_bytes(&self.codes, |x| {
+                (x.icd10.iter().chain(&x.snomed))
+                    .map(|x| x.capacity() + size_of::<String>())
+                    .sum()
+            })
+            + map_bytes(&self.spans, |_| 0)
+            + map_bytes(&self.document_origins, |_| 0)
+            + self.origins.iter().map(String::capacity).sum::<usize>()
+            + self.index.as_ref().map_or(0, |x| x.memory_bytes())
+            + self.document_cache.lock().unwrap().size();
+        Stats {
+            documents: self.embeddings.nrows(),
+            embedding_dimensions: self.embeddings.ncols(),
+            embedding_storage: match self.embeddings {
+                Embeddings::Float(_) => "float",
+                Embeddings::Int8 { .. } => "int8",
+            },
+            index: if self.index.is_some() {
+                "hnsw"
+            } else {
+                "exact"
+            },
+            memory_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::Array2;
+    use noisy_float::prelude::n32;
+
+    use super::super::{Hnsw, HnswParams};
+    use super::*;
+
+    #[test]
+    fn estimates_memory() {
+        let mut db = DocDb {
+            embeddings: Embeddings::Float(Array2::ones((100, 8))),
+            embeddings_id: (0..100u8).map(|x| [x; 16]).collect(),
+            ..Default::default()
+        };
+        let stats = db.stats();
+        assert_eq!(stats.documents, 100);
+        assert_eq!(stats.embedding_dimensions, 8);
+        assert_eq!(stats.embedding_storage, "float");
+        assert_eq!(stats.index, "exact");
+        assert!(stats.memory_bytes >= 100 * (8 * 4 + 16));
+        db.index = Some(Hnsw::build(100, HnswParams::default(), |_, _| n32(1.0)));
+        let indexed = db.stats();
+        assert_eq!(indexed.index, "hnsw");
+        assert!(indexed.memory_bytes > stats.memory_bytes);
+    }
+}
@@ -0,0 +1,116 @@
+//! Checks that the resources of a database are consistent.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+
+use super::{DocDb, DocId};
+
+/// The inconsistencies found in a database, with documents as hex IDs.
+///
+/// A malformed corpus otherwise only shows as documents without titles or
+/// tags deep in the Clint process.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Validation {
+    /// Documents with embeddings but without a title.
+    pub missing_titles: Vec<String>,
+    /// Documents with embeddings but without a URL.
+    pub missing_urls: Vec<String>,
+    /// Documents with parents that aren't in the database.
+    pub dangling_parents: Vec<String>,
+    /// Documents with more than one embedding.
+    pub duplicate_ids: Vec<String>,
+    /// For each tag, the tagged documents that aren't in the database.
+    pub orphaned_tags: BTreeMap<String, Vec<String>>,
+}
+
+/// The hex IDs of the `ids`, sorted so reports are stable.
+fn sorted_hex<'a>(ids: impl IntoIterator<Item = &'a DocId>) -> Vec<String> {
+    let mut ids = ids.into_iter().map(hex::encode).collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+impl DocDb {
+    /// Check that the documents are consistent, such as every document with
+    /// an embedding having a title.
+    ///
+    /// A document is in the database if it has an embedding, a title or a
+    /// URL.
+    pub fn validate(&self) -> Validation {
+        let embedded = self.embeddings_id.iter().collect::<HashSet<_>>();
+        let known = |id: &DocId| {
+            embedded.contains(id) || self.titles.contains_key(id) || self.urls.contains_key(id)
+        };
+        let mut counts: HashMap<&DocId, usize> = HashMap::new();
+        for id in &self.embeddings_id {
+            *counts.entry(id).or_default() += 1;
+        }
+        Validation {
+            missing_titles: sorted_hex(
+                embedded
+                    .iter()
+                    .copied()
+                    .filter(|x| !self.titles.contains_key(*x)),
+            ),
+            missing_urls: sorted_hex(
+                embedded
+                    .iter()
+                    .copied()
+                    .filter(|x| !self.urls.contains_key(*x)),
+            ),
+            dangling_parents: sorted_hex(
+                self.parents
+                    .iter()
+                    .filter(|(_, parent)| !known(parent))
+                    .map(|(id, _)| id),
+            ),
+            duplicate_ids: sorted_hex(counts.into_iter().filter(|(_, x)| *x > 1).map(|(id, _)| id)),
+            orphaned_tags: self
+                .tags
+                .iter()
+                .map(|(tag, ids)| (tag.clone(), sorted_hex(ids.iter().filter(|x| !known(x)))))
+                .filter(|(_, ids)| !ids.is_empty())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::array;
+    use noisy_float::prelude::n32;
+
+    use super::*;
+
+    #[test]
+    fn finds_inconsistencies() {
+        let id = |x: u8| [x; 16];
+        let db = DocDb {
+            embeddings: array![[1.0], [1.0], [1.0]].mapv(n32).into(),
+            embeddings_id: vec![id(1), id(2), id(2)],
+            parents: [(id(1), id(3)), (id(2), id(4))].into_iter().collect(),
+            titles: [(id(1), "a".to_string()), (id(3), "c".to_string())]
+                .into_iter()
+                .collect(),
+            urls: [(id(1), "a".to_string()), (id(2), "b".to_string())]
+                .into_iter()
+                .collect(),
+            tags: [("drug".to_string(), [id(1), id(5)].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let hex = |x: u8| hex::encode(id(x));
+        assert_eq!(
+            db.validate(),
+            Validation {
+                missing_titles: vec![hex(2)],
+                missing_urls: Vec::new(),
+                dangling_parents: vec![hex(2)],
+                duplicate_ids: vec![hex(2)],
+                orphaned_tags: [("drug".to_string(), vec![hex(5)])].into_iter().collect(),
+            }
+        );
+    }
+}
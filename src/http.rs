@@ -0,0 +1,77 @@
+//! The HTTP client shared by all requests.
+
+use std::sync::Mutex;
+
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::{Client, RequestBuilder};
+
+use tap::Pipe;
+
+use crate::config::config;
+
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+/// Get the shared client, building it on first use.
+///
+/// Sharing a client reuses its connections instead of opening new ones for
+/// every request.
+pub fn client() -> Client {
+    CLIENT
+        .lock()
+        .unwrap()
+        .get_or_insert_with(Client::new)
+        .clone()
+}
+
+/// Replace the shared client used by all requests, e.g. with one that
+/// points at a test server.
+#[cfg(test)]
+pub fn set_client(client: Client) {
+    *CLIENT.lock().unwrap() = Some(client);
+}
+
+/// Add the extra headers from the [`Config`](crate::config::Config) to a
+/// request.
+pub fn with_headers(request: RequestBuilder) -> RequestBuilder {
+    config()
+        .headers
+        .into_iter()
+        .fold(request, |request, (name, value)| {
+            request.header(name, value)
+        })
+}
+
+/// Authenticate a request with `token`.
+///
+/// Requests sent with retries use the active key instead if keys are set
+/// (see [`set_keys`](crate::config::set_keys)).
+pub fn authorize(request: RequestBuilder, token: &str) -> RequestBuilder {
+    request.bearer_auth(token).pipe(with_headers)
+}
+
+/// Replace the authentication of a request with `key`.
+///
+/// Fails if the request can't be built.
+pub fn with_key(request: RequestBuilder, key: &str) -> reqwest::Result<RequestBuilder> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    if let Ok(mut value) = HeaderValue::from_str(&format!("Bearer {}", key)) {
+        value.set_sensitive(true);
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    RequestBuilder::from_parts(client, request).pipe(Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injects_the_shared_client() {
+        set_client(Client::builder().user_agent("clint-test").build().unwrap());
+        let injected = format!("{:?}", client());
+        *CLIENT.lock().unwrap() = None;
+        assert!(injected.contains("clint-test"));
+        assert!(!format!("{:?}", client()).contains("clint-test"));
+    }
+}
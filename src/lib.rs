@@ -7,28 +7,65 @@
 
 use core::fmt::Debug;
 
-use futures::future::join_all;
-use hex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
 
+use futures::future::{join_all, LocalBoxFuture};
+use futures::FutureExt;
+
+mod cancel;
+mod config;
 mod docdb;
+mod http;
 mod openai;
 mod prompt;
+mod timer;
 mod utils;
 
 use prompt::{
     cite::cite,
-    diagnosis::{initial_diagnosis, refine_diagnosis, ResolvedDiagnosis},
-    notes::{create_update_notes, Notes},
+    config::ClintConfig,
+    diagnosis::{
+        initial_diagnosis, rank_diagnoses, refine_diagnosis, resolve_initial_diagnosis,
+        select_diagnoses, stream_initial_diagnosis, verify_diagnosis, ResolvedDiagnosis,
+    },
+    gaps::information_gaps,
+    medications::{check_medications, MedicationWarning, WarningKind},
+    notes::{check_notes, create_update_notes, stream_notes, Notes, NotesDiff},
+    profile::Profile,
     respond::respond,
     rewrite::rewrite_message,
+    scope::classify_scope,
+    search::search,
+    templates::{default_templates, set_templates},
+    triage::{triage, Triage},
+    urgency::assess_urgency,
 };
 use serde::{Deserialize, Serialize};
-use serde_json;
 use tap::Pipe;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
 
-use docdb::{DocDb, DocId};
-use openai::chat::{ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionParts};
+use cancel::CancelToken;
+#[cfg(all(feature = "archive-writer", not(target_arch = "wasm32")))]
+pub use docdb::Archive;
+use docdb::{DocDb, DocId, DocumentFetcher, LoadStage};
+use openai::audio::transcribe;
+use openai::cache::{memory_cache, set_persistent_cache, PersistentCache};
+use openai::chat::{
+    ChatCompletionArgs, ChatCompletionMessage, ChatCompletionModel, ChatCompletionParts, ImageUrl,
+};
+use openai::key::validate_key;
+use openai::limit::{limiter, RateLimits};
+use openai::models::{register_model, Encoding, ModelInfo};
+use openai::moderate::{moderate, Moderation};
+#[cfg(feature = "replay")]
+use openai::replay;
+use openai::trace::{set_tracer, TraceEvent, Tracer};
+use openai::tts::{speak, Voice};
+use openai::usage::{Pricing, UsageTotal, UsageTracker};
 
 /// Library errors.
 #[allow(missing_docs)]
@@ -46,20 +83,111 @@ pub enum Error {
     PromptError(prompt::utils::Error),
     #[error("Serialization error: {0}")]
     SerdeError(serde_json::Error),
+    #[error("Serialization error: {0}")]
+    JsSerdeError(serde_wasm_bindgen::Error),
+    #[error("Cancelled.")]
+    Cancelled,
+    #[error("The response stream stalled.")]
+    StreamStalled,
+    #[error("The response was blocked by the content filter.")]
+    ContentFilter,
+    #[error("The message was flagged by moderation.")]
+    Flagged(Moderation),
+    #[error("Invalid option: {0}.")]
+    InvalidOption(&'static str),
+    #[error("There are no databases to merge.")]
+    NoDatabases,
+}
+
+impl From<openai::Error> for Error {
+    fn from(e: openai::Error) -> Self {
+        match e {
+            openai::Error::Cancelled => Error::Cancelled,
+            openai::Error::StreamStalled => Error::StreamStalled,
+            openai::Error::ContentFilter => Error::ContentFilter,
+            e => Error::OpenAIError(e),
+        }
+    }
+}
+
+impl From<prompt::utils::Error> for Error {
+    fn from(e: prompt::utils::Error) -> Self {
+        match e {
+            prompt::utils::Error::OpenAIError(e) => e.into(),
+            prompt::utils::Error::Flagged(moderation) => Error::Flagged(moderation),
+            e => Error::PromptError(e),
+        }
+    }
 }
 
 impl From<Error> for JsValue {
     fn from(e: Error) -> Self {
-        JsValue::from_str(&e.to_string())
+        match e {
+            // structured so the app can route to crisis resources
+            Error::Flagged(moderation) => serde_wasm_bindgen::to_value(&moderation)
+                .unwrap_or_else(|_| JsValue::from_str(&Error::Flagged(moderation).to_string())),
+            e => JsValue::from_str(&e.to_string()),
+        }
     }
 }
 
 type Result<T> = core::result::Result<T, Error>;
 
+/// The "abort" listener of a [`cancel_token`], removed when dropped.
+struct AbortListener {
+    signal: web_sys::AbortSignal,
+    callback: Closure<dyn FnMut()>,
+}
+
+impl Drop for AbortListener {
+    fn drop(&mut self) {
+        let _ = self
+            .signal
+            .remove_event_listener_with_callback("abort", self.callback.as_ref().unchecked_ref());
+    }
+}
+
+/// Build a token that is cancelled when the `signal` aborts.
+///
+/// Without a `signal`, the token is never cancelled. The listener on the
+/// `signal` is removed once the token is dropped, when the request is done.
+fn cancel_token(signal: Option<&web_sys::AbortSignal>) -> CancelToken {
+    let token = CancelToken::new();
+    match signal {
+        Some(signal) if signal.aborted() => token.cancel(),
+        Some(signal) => {
+            let cancel = token.downgrade();
+            let callback = Closure::<dyn FnMut()>::new(move || cancel.cancel());
+            // an `AbortSignal` is always a valid event target
+            let _ =
+                signal.add_event_listener_with_callback("abort", callback.as_ref().unchecked_ref());
+            token.keep(AbortListener {
+                signal: signal.clone(),
+                callback,
+            });
+        }
+        None => (),
+    }
+    token
+}
+
+/// Decode the hex document ID, if it's valid.
+fn decode_doc_id(id: &str) -> Option<DocId> {
+    docdb::decode_doc_id(id.as_bytes()).ok()
+}
+
+/// Decode the hex document IDs, failing if any is invalid.
+fn decode_doc_ids(ids: &[String]) -> Result<HashSet<DocId>> {
+    ids.iter()
+        .map(|x| docdb::decode_doc_id(x.as_bytes()).map_err(Error::DocumentDbError))
+        .collect()
+}
+
 /// State for a sequence of chat message updates.
 #[wasm_bindgen]
 pub struct ChatMessageUpdates {
     parts: ChatCompletionParts,
+    grounded: Option<bool>,
 }
 
 #[wasm_bindgen]
@@ -69,11 +197,59 @@ impl ChatMessageUpdates {
         self.parts
             .next()
             .await
-            .map_err(Error::OpenAIError)?
+            .map_err(Error::from)?
             .and_then(|x| x.choices.first())
             .and_then(|x| x.message.content.as_ref().map(|y| y.to_string()))
             .pipe(Ok)
     }
+
+    /// Get the message received so far.
+    ///
+    /// If `next` fails, this is the partial message that can be salvaged.
+    pub fn partial(&self) -> Option<String> {
+        self.parts
+            .response()
+            .choices
+            .first()
+            .and_then(|x| x.message.content.clone())
+    }
+
+    /// Get the tokens used by the request once all the updates are received,
+    /// or `undefined` before then.
+    pub fn usage(&self) -> Result<JsValue> {
+        serde_wasm_bindgen::to_value(&self.parts.usage()).map_err(Error::JsSerdeError)
+    }
+
+    /// Whether documents relevant to the message were found to ground the
+    /// reply, or `undefined` if the reply doesn't use documents.
+    ///
+    /// If not, the reply says the knowledge base doesn't cover the message.
+    pub fn grounded(&self) -> Option<bool> {
+        self.grounded
+    }
+}
+
+/// State for a sequence of structured output updates.
+#[wasm_bindgen]
+pub struct FunctionCallUpdates {
+    parts: ChatCompletionParts,
+}
+
+#[wasm_bindgen]
+impl FunctionCallUpdates {
+    /// Get the JSON output received so far.
+    ///
+    /// The JSON is incomplete until the last update.
+    pub async fn next(&mut self) -> Result<Option<String>> {
+        self.parts
+            .next()
+            .await
+            .map_err(Error::from)?
+            .and_then(|x| x.choices.first())
+            .and_then(|x| x.message.function_call.as_ref())
+            .map(|x| x.arguments.clone())
+            .pipe(Ok)
+    }
 }
 
 /// Wraps a `DocDb` object for passing between Rust and JS.
@@ -82,51 +258,777 @@ pub struct DocDbJs {
     db: DocDb,
 }
 
+/// Report the stages of loading a database to the JS `callback`, if any.
+fn load_progress(callback: Option<js_sys::Function>) -> impl Fn(LoadStage) {
+    move |stage| {
+        if let Some(callback) = &callback {
+            let stage = serde_wasm_bindgen::to_value(&stage).unwrap_or(JsValue::NULL);
+            let _ = callback.call1(&JsValue::NULL, &stage);
+        }
+    }
+}
+
+/// Options for building a database with [`DocDbJs::load`].
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct DocDbOptions {
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    embeddings_scales: JsValue,
+    weight: Option<f32>,
+    build_index: bool,
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    progress: JsValue,
+}
+
+/// Cast an optional JS `value`, failing with the `name` of the option if it
+/// has the wrong type.
+fn optional<T: JsCast>(value: JsValue, name: &'static str) -> Result<Option<T>> {
+    if value.is_undefined() || value.is_null() {
+        return Ok(None);
+    }
+    value
+        .dyn_into()
+        .map(Some)
+        .map_err(|_| Error::InvalidOption(name))
+}
+
 #[wasm_bindgen]
 impl DocDbJs {
     /// Build a new `DocDb` wrapped in a `DocDbJs`.
     ///
-    /// Build from the raw bytes.
-    #[wasm_bindgen(constructor)]
-    pub fn new(
+    /// Build from the raw bytes. Each line of `tags` is a document ID and
+    /// one of its tags separated by a tab, where conditions are tagged
+    /// `condition`, and their sections `introduction` and `symptoms`. Any of
+    /// the resources can be compressed with gzip or zstd.
+    ///
+    /// The `options` object has optional fields:
+    ///
+    /// - `embeddings_scales`: if the embeddings are int8-quantized, a float
+    ///   `.npy` array with a scale factor per row.
+    /// - `weight`: scales the similarities of the documents, one by default,
+    ///   to rank documents from this origin above or below those of databases
+    ///   merged with it.
+    /// - `build_index`: build an index with the default parameters once the
+    ///   documents are loaded, as with `build_index`.
+    /// - `progress`: called with the name of each stage of loading once it's
+    ///   done: `"decompressed"`, `"embeddings"`, `"ids"`, `"metadata"`,
+    ///   `"tags"`, and `"index_built"` if an index is built. Loading yields to
+    ///   the browser between stages, so the progress can be shown.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn load(
         origin: String,
+        embeddings: Vec<u8>,
+        embeddings_pca_mapping: Vec<u8>,
+        embeddings_hash: Vec<u8>,
+        parents: Vec<u8>,
+        titles: Vec<u8>,
+        urls: Vec<u8>,
+        tags: Vec<u8>,
+        options: JsValue,
+    ) -> Result<DocDbJs> {
+        let options: DocDbOptions = if options.is_undefined() || options.is_null() {
+            DocDbOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options).map_err(Error::JsSerdeError)?
+        };
+        let embeddings_scales = optional::<js_sys::Uint8Array>(
+            options.embeddings_scales,
+            "embeddings_scales must be a Uint8Array",
+        )?
+        .map(|x| x.to_vec());
+        let progress = load_progress(optional(options.progress, "progress must be a function")?);
+        let mut db = DocDb::new(
+            origin.clone(),
+            &embeddings,
+            embeddings_scales.as_deref(),
+            Some(&embeddings_pca_mapping),
+            &embeddings_hash,
+            &parents,
+            &titles,
+            &urls,
+            &tags,
+            &progress,
+        )
+        .await
+        .map_err(Error::DocumentDbError)?;
+        if let Some(weight) = options.weight {
+            db.set_origin_weight(&origin, weight)
+                .map_err(Error::DocumentDbError)?;
+        }
+        if options.build_index {
+            db.build_index(docdb::HnswParams::default());
+            if db.has_index() {
+                progress(LoadStage::IndexBuilt);
+            }
+        }
+        Ok(DocDbJs { db })
+    }
+
+    /// Build a new `DocDb` from the resources of `load` packed in a single
+    /// archive, along with an index built ahead of time if any.
+    ///
+    /// The archive can be compressed with gzip or zstd. The `progress` is
+    /// called like that of `load`, with `"index"` once the packed index is
+    /// loaded.
+    pub async fn from_archive(
+        bytes: Vec<u8>,
+        progress: Option<js_sys::Function>,
+    ) -> Result<DocDbJs> {
+        DocDbJs {
+            db: DocDb::from_archive(&bytes, &load_progress(progress))
+                .await
+                .map_err(Error::DocumentDbError)?,
+        }
+        .pipe(Ok)
+    }
+
+    /// Check that the documents are consistent, returning an object with the
+    /// hex IDs of the documents with problems: `missing_titles`,
+    /// `missing_urls`, `dangling_parents`, `duplicate_ids`, and
+    /// `orphaned_tags` with the documents by tag.
+    pub fn validate(&self) -> Result<JsValue> {
+        self.db
+            .validate()
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(Error::JsSerdeError)
+    }
+
+    /// Set where the chunks are in their parent documents, so excerpts are
+    /// only the chunk.
+    ///
+    /// Each line of `spans` is a chunk's document ID and the offsets of its
+    /// start and end in its parent, separated by tabs. The offsets count
+    /// Unicode code points, not the UTF-16 code units of JS strings.
+    pub fn set_spans(&mut self, spans: &[u8]) -> Result<()> {
+        self.db.set_spans(spans).map_err(Error::DocumentDbError)
+    }
+
+    /// Get where the chunk with hex ID `id` is in its parent document, as
+    /// `{start, end}` offsets, or `undefined` if unknown, to highlight the
+    /// chunk in the parent.
+    ///
+    /// The offsets count Unicode code points, so slice the parent with
+    /// `Array.from(parent).slice(start, end).join("")` rather than
+    /// `parent.slice(start, end)`, which counts UTF-16 code units.
+    pub fn get_span(&self, id: &str) -> Result<JsValue> {
+        decode_doc_id(id)
+            .and_then(|x| self.db.get_span(&x))
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(Error::JsSerdeError)
+    }
+
+    /// Set the URL pattern of the documents, with `{origin}`, `{path}` and
+    /// `{id}` placeholders, where `{path}` is the first three hex digits of
+    /// `{id}` as directories. The default is
+    /// `{origin}/db/documents/{path}/{id}.md`, used again if `pattern` is
+    /// `undefined`.
+    pub fn set_document_url(&mut self, pattern: Option<String>) -> Result<()> {
+        self.db
+            .set_document_url(pattern)
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Fetch the documents with the `fetch` callback in place of HTTP
+    /// requests, such as from a bundle in the app, or with HTTP requests
+    /// again if `undefined`.
+    ///
+    /// The callback gets the URL of a document by the URL pattern and
+    /// returns its contents, or a promise of them.
+    pub fn set_fetcher(&mut self, fetch: Option<js_sys::Function>) {
+        self.db
+            .set_fetcher(fetch.map(|x| Rc::new(JsDocumentFetcher(x)) as Rc<dyn DocumentFetcher>));
+    }
+
+    /// Get the size of the database as `{documents, embedding_dimensions,
+    /// embedding_storage, index, memory_bytes}`, where `memory_bytes` is a
+    /// rough estimate of the memory it uses, to warn on constrained devices.
+    pub fn stats(&self) -> Result<JsValue> {
+        self.db
+            .stats()
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(Error::JsSerdeError)
+    }
+
+    /// Serialize the parsed database, to store it in IndexedDB and rebuild
+    /// it quickly with `from_bytes` on later visits.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.db.to_bytes().map_err(Error::DocumentDbError)
+    }
+
+    /// Rebuild a database serialized with `to_bytes`.
+    ///
+    /// Fails if the bytes were written by another version of the library,
+    /// in which case build the database from its resources again.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DocDbJs> {
+        DocDbJs {
+            db: DocDb::from_bytes(bytes).map_err(Error::DocumentDbError)?,
+        }
+        .pipe(Ok)
+    }
+
+    /// Add documents from resources like those of `load`, replacing the
+    /// documents with the same IDs.
+    ///
+    /// The embeddings must be stored like those of the database, and already
+    /// mapped to its dimensions, or the database is left unchanged. Replacing
+    /// documents drops the index, so build or load it afterwards.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_documents(
+        &mut self,
         embeddings: &[u8],
-        embeddings_pca_mapping: &[u8],
         embeddings_hash: &[u8],
         parents: &[u8],
         titles: &[u8],
         urls: &[u8],
-        is_introduction: &[u8],
-        is_condition: &[u8],
-        is_symptoms: &[u8],
-    ) -> Result<DocDbJs> {
-        DocDbJs {
-            db: DocDb::new(
-                origin,
+        tags: &[u8],
+        embeddings_scales: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.db
+            .add_documents(
                 embeddings,
-                Some(embeddings_pca_mapping),
+                embeddings_scales.as_deref(),
                 embeddings_hash,
                 parents,
                 titles,
                 urls,
-                is_introduction,
-                is_condition,
-                is_symptoms,
+                tags,
             )
-            .map_err(Error::DocumentDbError)?,
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Build a database that combines several databases with distinct
+    /// origins, keeping the first of the documents with the same IDs.
+    ///
+    /// Fails if `dbs` is empty.
+    pub fn merged(dbs: Vec<DocDbJs>) -> Result<DocDbJs> {
+        let mut dbs = dbs.into_iter();
+        let mut merged = dbs.next().ok_or(Error::NoDatabases)?;
+        for db in dbs {
+            merged.merge(db)?;
+        }
+        Ok(merged)
+    }
+
+    /// Add the documents of the `other` database, returning how many were
+    /// left out because a document with the same ID was already there.
+    ///
+    /// Both databases must be embedded the same way, so set the embedding
+    /// model and similarity of both first.
+    pub fn merge(&mut self, other: DocDbJs) -> Result<usize> {
+        self.db.merge(other.db).map_err(Error::DocumentDbError)
+    }
+
+    /// Set the `weight` the similarities of the documents from `origin` are
+    /// scaled by, returning whether any documents are from `origin`.
+    pub fn set_origin_weight(&mut self, origin: &str, weight: f32) -> Result<bool> {
+        self.db
+            .set_origin_weight(origin, weight)
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Set the date each document was last reviewed, which is shown with
+    /// its excerpts and citations, and flagged by the `stale_after_days`
+    /// setting.
+    ///
+    /// Each line of `reviewed` is a document ID and a `YYYY-MM-DD` date,
+    /// separated by a tab.
+    pub fn set_reviewed(&mut self, reviewed: &[u8]) -> Result<()> {
+        self.db
+            .set_reviewed(reviewed)
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Set the aliases of the conditions, such as `heart attack`, so
+    /// diagnoses named by a synonym resolve to the right document.
+    ///
+    /// Each line of `aliases` is a condition's document ID and one of its
+    /// aliases, separated by a tab.
+    pub fn set_aliases(&mut self, aliases: &[u8]) -> Result<()> {
+        self.db.set_aliases(aliases).map_err(Error::DocumentDbError)
+    }
+
+    /// Get the hex ID of the condition named `name`, if it's a known alias.
+    pub fn resolve_alias(&self, name: &str) -> Option<String> {
+        self.db.resolve_alias(name).map(hex::encode)
+    }
+
+    /// Set the ICD-10 and SNOMED CT codes of the conditions, which are
+    /// included with the resolved diagnoses.
+    ///
+    /// Each line of `codes` is a condition's document ID, the terminology,
+    /// either `icd10` or `snomed`, and a code, separated by tabs.
+    pub fn set_codes(&mut self, codes: &[u8]) -> Result<()> {
+        self.db.set_codes(codes).map_err(Error::DocumentDbError)
+    }
+
+    /// Get the codes of the condition with hex ID `id` as `{icd10, snomed}`
+    /// lists, or `undefined` if unknown.
+    pub fn get_codes(&self, id: &str) -> Result<JsValue> {
+        decode_doc_id(id)
+            .and_then(|x| self.db.get_codes(&x))
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(Error::JsSerdeError)
+    }
+
+    /// Set the language of the documents, such as `en`, so the `languages`
+    /// setting of the tasks can filter the retrieved documents.
+    ///
+    /// Each line of `languages` is a document ID and its language, separated
+    /// by a tab.
+    pub fn set_languages(&mut self, languages: &[u8]) -> Result<()> {
+        self.db
+            .set_languages(languages)
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Set the SHA-256 hashes of the document contents, so fetched contents
+    /// that don't match are rejected rather than used.
+    ///
+    /// Each line of `content_hashes` is a document ID and the hex hash of
+    /// its contents, separated by a tab.
+    pub fn set_content_hashes(&mut self, content_hashes: &[u8]) -> Result<()> {
+        self.db
+            .set_content_hashes(content_hashes)
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Set the most bytes of fetched document contents kept in memory.
+    pub fn set_document_cache_capacity(&mut self, capacity: usize) {
+        self.db.set_document_cache_capacity(capacity);
+    }
+
+    /// Drop the kept contents of the documents with the hex IDs in `ids`, or
+    /// of every document if `undefined`, so they're fetched again.
+    ///
+    /// Fails if any of the IDs is invalid.
+    pub fn invalidate_documents(&self, ids: Option<Vec<String>>) -> Result<()> {
+        match ids {
+            Some(ids) => self.db.invalidate_documents(Some(&decode_doc_ids(&ids)?)),
+            None => self.db.invalidate_documents(None),
+        }
+        Ok(())
+    }
+
+    /// Remove the documents with the hex IDs in `ids`, returning how many
+    /// were removed.
+    ///
+    /// Removing documents drops the index, so build or load it afterwards.
+    /// Fails if any of the IDs is invalid, without removing any document.
+    pub fn remove_documents(&mut self, ids: Vec<String>) -> Result<usize> {
+        Ok(self.db.remove_documents(&decode_doc_ids(&ids)?))
+    }
+
+    /// Set the model used to embed the documents, so queries are embedded
+    /// with the same model.
+    ///
+    /// The `model` is an OpenAI embedding model name such as
+    /// `text-embedding-3-small`. If the document embeddings were shortened,
+    /// `dimensions` is their length before the PCA mapping.
+    pub fn set_embedding_model(&mut self, model: &str, dimensions: Option<usize>) -> Result<()> {
+        let model = serde_json::from_value(serde_json::Value::String(model.to_string()))
+            .map_err(Error::SerdeError)?;
+        self.db.set_embedding_model(model, dimensions);
+        Ok(())
+    }
+
+    /// Set how documents are compared with queries, either `dot` or
+    /// `cosine`.
+    ///
+    /// Use `cosine` for embeddings exported without normalization. Switching
+    /// to `cosine` drops the index, so build or load it afterwards.
+    pub fn set_similarity(&mut self, similarity: &str) -> Result<()> {
+        let similarity = serde_json::from_value(serde_json::Value::String(similarity.to_string()))
+            .map_err(Error::SerdeError)?;
+        self.db.set_similarity(similarity);
+        Ok(())
+    }
+
+    /// Find up to `n` documents most similar to the `query` text, the most
+    /// similar first.
+    ///
+    /// Returns an array of objects with the hex `id`, `title`, `url` and
+    /// similarity `score` of each document. The query is embedded with the
+    /// user's API `key`.
+    pub async fn search(
+        &self,
+        query: &str,
+        n: usize,
+        key: &str,
+        signal: Option<web_sys::AbortSignal>,
+    ) -> Result<JsValue> {
+        search_documents(&self.db, query, n, key, signal, &UsageTracker::default()).await
+    }
+
+    /// Find documents similar to the `query` text as [`DocDbJs::search`],
+    /// recording the tokens used for the query in the `state`.
+    pub async fn search_with_state(
+        &self,
+        state: &mut StateJs,
+        query: &str,
+        n: usize,
+        key: &str,
+        signal: Option<web_sys::AbortSignal>,
+    ) -> Result<JsValue> {
+        search_documents(&self.db, query, n, key, signal, &state.usage.search).await
+    }
+
+    /// Get the title of the document with hex ID `id`, if any.
+    pub fn get_title(&self, id: &str) -> Option<String> {
+        decode_doc_id(id)
+            .and_then(|x| self.db.get_title(&x))
+            .map(String::from)
+    }
+
+    /// Get the URL of the document with hex ID `id`, if any.
+    pub fn get_url(&self, id: &str) -> Option<String> {
+        decode_doc_id(id)
+            .and_then(|x| self.db.get_url(&x))
+            .map(String::from)
+    }
+
+    /// Get the date the document with hex ID `id` was last reviewed, as
+    /// `YYYY-MM-DD`, if known.
+    pub fn get_reviewed(&self, id: &str) -> Option<String> {
+        decode_doc_id(id)
+            .and_then(|x| self.db.get_reviewed(&x))
+            .map(String::from)
+    }
+
+    /// Get the hex ID of the parent of the document with hex ID `id`, if any.
+    pub fn get_parent(&self, id: &str) -> Option<String> {
+        decode_doc_id(id)
+            .and_then(|x| self.db.get_parent(&x).copied())
+            .map(hex::encode)
+    }
+
+    /// Fetch the Markdown contents of the document with hex ID `id`.
+    ///
+    /// Fails if the ID is invalid or the document can't be fetched.
+    pub async fn get_document(
+        &self,
+        id: &str,
+        signal: Option<web_sys::AbortSignal>,
+    ) -> Result<String> {
+        let doc_id = docdb::decode_doc_id(id.as_bytes()).map_err(Error::DocumentDbError)?;
+        cancel_token(signal.as_ref())
+            .run(self.db.get_document(&doc_id))
+            .await
+            .map_err(|_| Error::Cancelled)?
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Get the hex IDs of the documents whose parent is the document with
+    /// hex ID `id`, ordered by title.
+    pub fn get_children(&self, id: &str) -> Vec<String> {
+        decode_doc_id(id).map_or(Vec::new(), |x| {
+            self.db.get_children(&x).iter().map(hex::encode).collect()
+        })
+    }
+
+    /// Get the hex IDs of the other documents with the same parent as the
+    /// document with hex ID `id`, ordered by title.
+    pub fn get_siblings(&self, id: &str) -> Vec<String> {
+        decode_doc_id(id).map_or(Vec::new(), |x| {
+            self.db.get_siblings(&x).iter().map(hex::encode).collect()
+        })
+    }
+
+    /// Get the hex IDs of the ancestors of the document with hex ID `id`, the
+    /// root first, followed by `id`, to render a breadcrumb.
+    pub fn get_path(&self, id: &str) -> Vec<String> {
+        decode_doc_id(id).map_or(Vec::new(), |x| {
+            self.db.get_path(&x).iter().map(hex::encode).collect()
+        })
+    }
+
+    /// Set the most documents to fetch at once when building prompts.
+    pub fn set_fetch_concurrency(&mut self, n: usize) {
+        self.db.set_fetch_concurrency(n);
+    }
+
+    /// Fetch the documents with the hex IDs in `ids` ahead of time, such as
+    /// those of the diagnoses about to be refined, so building the prompts
+    /// doesn't wait for them.
+    ///
+    /// At most `concurrency` documents are fetched at once, or as many as
+    /// set with `set_fetch_concurrency` if `undefined`. Resolves to how many
+    /// documents were fetched; the rest were already kept or not available.
+    /// Fails if any of the IDs is invalid.
+    pub async fn prefetch(&self, ids: Vec<String>, concurrency: Option<usize>) -> Result<usize> {
+        let ids = decode_doc_ids(&ids)?.into_iter().collect::<Vec<_>>();
+        let concurrency = concurrency.unwrap_or(self.db.get_fetch_concurrency());
+        Ok(self.db.prefetch(&ids, concurrency).await)
+    }
+
+    /// Build an approximate nearest-neighbor index to speed up document
+    /// lookups in large databases.
+    ///
+    /// `m` is the number of links per document, `ef_construction` and
+    /// `ef_search` the number of candidates considered when building and
+    /// searching. Small databases are searched exactly and aren't indexed.
+    pub fn build_index(
+        &mut self,
+        m: Option<usize>,
+        ef_construction: Option<usize>,
+        ef_search: Option<usize>,
+    ) {
+        let default = docdb::HnswParams::default();
+        self.db.build_index(docdb::HnswParams {
+            m: m.unwrap_or(default.m),
+            ef_construction: ef_construction.unwrap_or(default.ef_construction),
+            ef_search: ef_search.unwrap_or(default.ef_search),
+            ..default
+        });
+    }
+
+    /// Use an index built ahead of time and exported with `export_index`.
+    pub fn load_index(&mut self, index: &[u8]) -> Result<()> {
+        self.db
+            .set_index_bytes(index)
+            .map_err(Error::DocumentDbError)
+    }
+
+    /// Export the index, if one was built or loaded, to ship with the
+    /// database.
+    pub fn export_index(&self) -> Option<Vec<u8>> {
+        self.db.get_index_bytes()
+    }
+
+    /// Set the number of candidates the index considers for each lookup.
+    pub fn set_index_ef_search(&mut self, ef_search: usize) {
+        self.db.set_index_ef_search(ef_search);
+    }
+}
+
+/// Search the `db` for documents similar to the `query` as
+/// [`DocDbJs::search`], recording the tokens used in `usage`.
+async fn search_documents(
+    db: &DocDb,
+    query: &str,
+    n: usize,
+    key: &str,
+    signal: Option<web_sys::AbortSignal>,
+    usage: &UsageTracker,
+) -> Result<JsValue> {
+    let results = cancel_token(signal.as_ref())
+        .run(search(query, n, db, key, usage))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    results
+        .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .map_err(Error::JsSerdeError)
+}
+
+/// Wraps a `ClintConfig` object for passing between Rust and JS.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct ClintConfigJs {
+    config: ClintConfig,
+}
+
+#[wasm_bindgen]
+impl ClintConfigJs {
+    /// Build the settings for each task of the Clint process.
+    ///
+    /// The `config` object has an optional entry for each task: `scope`,
+    /// `rewrite`, `notes`, `gaps`, `urgency`, `diagnosis`, `refine`, `verify`,
+    /// `medications`, `triage`, `summarize`, `respond` and `cite`. Each entry
+    /// has optional `model`, `temperature`, `retrieval_depth`,
+    /// `search_queries`, `hypothetical_document`, `conversation_turns`,
+    /// `conversation_weight`, `query_model`, `max_diagnoses`, `min_similarity`,
+    /// `mmr_lambda`, `parent_aggregation`, `languages`, `stale_after_days`,
+    /// `excerpt_tokens`, `min_grounding`, `system_identity`, `audience`
+    /// (`patient`, `clinician` or `eighth_grade`), `locale`, `max_retries`,
+    /// `max_continuations`, `moderate`, `samples` and `examples` fields. The
+    /// `examples` are `{user, assistant}` exchanges shown to the model before
+    /// the instructions. Omitted settings use the defaults, `gpt-4o` except
+    /// for `scope`, which uses `gpt-4o-mini`. Only `rewrite` and `respond`
+    /// reply with text that `max_continuations` can continue.
+    ///
+    /// A `locale` entry, such as `es`, sets the locale of the user for the
+    /// calls made with the config, for every task without its own, rather
+    /// than that set by `set_locale_js`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(config: JsValue) -> Result<ClintConfigJs> {
+        if config.is_undefined() || config.is_null() {
+            return ClintConfigJs::default().pipe(Ok);
+        }
+        ClintConfigJs {
+            config: serde_wasm_bindgen::from_value::<ClintConfig>(config)
+                .map_err(Error::JsSerdeError)?
+                .localized(),
         }
         .pipe(Ok)
     }
 }
 
+/// The tokens used by each stage of the Clint process.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StageUsage {
+    #[serde(default)]
+    scope: UsageTracker,
+    rewrite: UsageTracker,
+    notes: UsageTracker,
+    #[serde(default)]
+    gaps: UsageTracker,
+    #[serde(default)]
+    urgency: UsageTracker,
+    diagnosis: UsageTracker,
+    #[serde(default)]
+    verify: UsageTracker,
+    #[serde(default)]
+    medications: UsageTracker,
+    #[serde(default)]
+    triage: UsageTracker,
+    respond: UsageTracker,
+    cite: UsageTracker,
+    #[serde(default)]
+    search: UsageTracker,
+}
+
+/// The totals of [`StageUsage`] reported to JS.
+#[derive(Debug, Serialize)]
+struct StageUsageTotals {
+    scope: UsageTotal,
+    rewrite: UsageTotal,
+    notes: UsageTotal,
+    gaps: UsageTotal,
+    urgency: UsageTotal,
+    diagnosis: UsageTotal,
+    verify: UsageTotal,
+    medications: UsageTotal,
+    triage: UsageTotal,
+    respond: UsageTotal,
+    cite: UsageTotal,
+    search: UsageTotal,
+    total: UsageTotal,
+}
+
+impl StageUsage {
+    fn totals(&self) -> StageUsageTotals {
+        let stages = [
+            self.scope.total(),
+            self.rewrite.total(),
+            self.notes.total(),
+            self.gaps.total(),
+            self.urgency.total(),
+            self.diagnosis.total(),
+            self.verify.total(),
+            self.medications.total(),
+            self.triage.total(),
+            self.respond.total(),
+            self.cite.total(),
+            self.search.total(),
+        ];
+        let total = stages
+            .iter()
+            .fold(UsageTotal::default(), |x, y| UsageTotal {
+                prompt_tokens: x.prompt_tokens + y.prompt_tokens,
+                completion_tokens: x.completion_tokens + y.completion_tokens,
+                cost: x.cost + y.cost,
+            });
+        let [scope, rewrite, notes, gaps, urgency, diagnosis, verify, medications, triage, respond, cite, search] =
+            stages;
+        StageUsageTotals {
+            scope,
+            rewrite,
+            notes,
+            gaps,
+            urgency,
+            diagnosis,
+            verify,
+            medications,
+            triage,
+            respond,
+            cite,
+            search,
+            total,
+        }
+    }
+}
+
+/// A document supporting a diagnosis, reported to JS.
+#[derive(Serialize)]
+struct Source {
+    id: String,
+    title: String,
+    url: String,
+}
+
+/// A risk of the patient's medications, reported to JS.
+#[derive(Serialize)]
+struct Warning {
+    kind: WarningKind,
+    medications: Vec<String>,
+    condition: Option<String>,
+    explanation: String,
+    sources: Vec<Source>,
+}
+
+impl Warning {
+    /// The `warning` with its sources found in the `db`, skipping the others.
+    fn new(warning: MedicationWarning, db: &DocDb) -> Self {
+        Self {
+            kind: warning.kind,
+            medications: warning.medications,
+            condition: warning.condition,
+            explanation: warning.explanation,
+            sources: warning
+                .sources
+                .iter()
+                .filter_map(|hash| {
+                    Some(Source {
+                        id: hex::encode(hash),
+                        title: db.get_title(hash)?.to_string(),
+                        url: db.get_url(hash)?.to_string(),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A document cited for a message, reported to JS.
+#[derive(Serialize)]
+struct CitedSource {
+    id: String,
+    title: String,
+    url: String,
+    reviewed: Option<String>,
+    quote: String,
+    score: f32,
+}
+
 /// The state of the conversation.
 #[wasm_bindgen]
 #[derive(Serialize, Deserialize)]
 pub struct StateJs {
     statement: Option<String>,
     notes: Option<Notes>,
+    /// What the patient entered about themselves in the app.
+    #[serde(default)]
+    profile: Profile,
+    /// The changes the last update made to the notes.
+    #[serde(default)]
+    notes_changes: NotesDiff,
     diagnoses: Option<Vec<ResolvedDiagnosis>>,
+    /// The level of care recommended for the diagnoses, cleared when the
+    /// notes or the diagnoses change.
+    #[serde(default)]
+    triage: Option<Triage>,
     messages: Vec<ChatCompletionMessage>,
+    #[serde(default)]
+    usage: StageUsage,
+    /// The documents cited so far, so they aren't cited again.
+    #[serde(default)]
+    cited: HashSet<DocId>,
+}
+
+impl Default for StateJs {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
@@ -137,8 +1039,13 @@ impl StateJs {
         StateJs {
             statement: None,
             notes: None,
+            profile: Profile::default(),
+            notes_changes: NotesDiff::default(),
             diagnoses: None,
+            triage: None,
             messages: Vec::new(),
+            usage: StageUsage::default(),
+            cited: HashSet::new(),
         }
     }
 
@@ -165,6 +1072,34 @@ impl StateJs {
         )
     }
 
+    /// Set the patient's `profile`, entered in a form rather than extracted
+    /// from the conversation, as an object with the optional `age` in years,
+    /// `sex` (`female`, `male` or `other`), `pregnant` and `medications`
+    /// fields. The profile is given as context to every prompt about the
+    /// patient.
+    pub fn set_profile(&mut self, profile: JsValue) -> Result<()> {
+        self.profile = serde_wasm_bindgen::from_value(profile).map_err(Error::JsSerdeError)?;
+        Ok(())
+    }
+
+    /// Get the patient's profile.
+    pub fn profile(&self) -> Result<JsValue> {
+        serde_wasm_bindgen::to_value(&self.profile).map_err(Error::JsSerdeError)
+    }
+
+    /// Get the changes the last update made to the notes as a Markdown
+    /// string.
+    pub fn notes_changes_to_markdown(&self, depth: usize) -> String {
+        self.notes_changes.to_markdown(depth)
+    }
+
+    /// Get the changes the last update made to the notes, each with its
+    /// `section`, `kind` (`added`, `changed` or `removed`), and the text
+    /// `before` and `after`.
+    pub fn notes_changes(&self) -> Result<JsValue> {
+        serde_wasm_bindgen::to_value(&self.notes_changes.changes).map_err(Error::JsSerdeError)
+    }
+
     /// Get the candidate diagnoses as a Markdown string.
     pub fn diagnoses_to_markdown(&self, depth: usize) -> String {
         self.diagnoses
@@ -178,83 +1113,955 @@ impl StateJs {
             .unwrap_or_default()
     }
 
+    /// Get the documents supporting each diagnosis, as an array with an
+    /// array of `{id, title, url}` sources for each diagnosis, skipping the
+    /// documents not in the `db`.
+    pub fn diagnoses_sources(&self, db: &DocDbJs) -> Result<JsValue> {
+        let sources = self
+            .diagnoses
+            .iter()
+            .flatten()
+            .map(|x| {
+                x.sources
+                    .iter()
+                    .filter_map(|hash| {
+                        Some(Source {
+                            id: hex::encode(hash),
+                            title: db.db.get_title(hash)?.to_string(),
+                            url: db.db.get_url(hash)?.to_string(),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        serde_wasm_bindgen::to_value(&sources).map_err(Error::JsSerdeError)
+    }
+
+    /// Get the recommended level of care, its reasoning and caveats as a
+    /// Markdown string, empty if there's no recommendation yet. The level of
+    /// care is titled in the locale of the triage task of the `config`.
+    pub fn triage_to_markdown(&self, depth: usize, config: &ClintConfigJs) -> String {
+        self.triage
+            .as_ref()
+            .map(|x| x.to_markdown(depth, &config.config.triage))
+            .unwrap_or_default()
+    }
+
+    /// Get the tokens used and their estimated cost in USD for each stage of
+    /// the Clint process.
+    pub fn usage(&self) -> Result<JsValue> {
+        serde_wasm_bindgen::to_value(&self.usage.totals()).map_err(Error::JsSerdeError)
+    }
+
     /// Add a user message to the chat history.
     pub fn add_user_message(&mut self, message: String) {
+        self.messages.push(ChatCompletionMessage::user(message));
+    }
+
+    /// Add a user message with attached `images` (URLs or base64 `data:`
+    /// URLs) to the chat history.
+    ///
+    /// The images are dropped once the assistant replies, so they aren't sent
+    /// again with every later message.
+    pub fn add_user_message_with_images(&mut self, message: String, images: Vec<String>) {
         self.messages.push(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
-            content: Some(message),
-            name: None,
-            function_call: None,
+            images: images.into_iter().map(ImageUrl::new).collect(),
+            ..ChatCompletionMessage::user(message)
         });
     }
 
     /// Add as assistant reply to the chat history.
     pub fn add_assistant_message(&mut self, message: String) {
-        self.messages.push(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::Assistant,
-            content: Some(message),
-            name: None,
-            function_call: None,
-        });
+        for x in &mut self.messages {
+            x.images.clear();
+        }
+        self.messages
+            .push(ChatCompletionMessage::assistant(message));
     }
 }
 
-/// Re-write the user's message into a medical statement.
+/// Override the text of the prompts with the `templates` object of texts by
+/// template name, so the wording can be tuned without a new library.
+///
+/// The `templates` are used for the `locale`, such as `es`, or for every
+/// locale if omitted, replacing those set before for it. The templates
+/// without a text use those of the language of the locale, then those for
+/// every locale, then the defaults. Fails without changing any prompt if a
+/// name isn't a template or a text refers to a variable the template doesn't
+/// have.
 #[wasm_bindgen]
-pub async fn rewrite_message_js(message: &str, key: &str) -> Result<ChatMessageUpdates> {
-    ChatMessageUpdates {
-        parts: rewrite_message(message.to_string(), key.to_string(), 3)
-            .await
-            .map_err(Error::PromptError)?,
-    }
-    .pipe(Ok)
+pub fn set_prompt_templates_js(templates: JsValue, locale: Option<String>) -> Result<()> {
+    let templates: HashMap<String, String> = if templates.is_undefined() || templates.is_null() {
+        HashMap::new()
+    } else {
+        serde_wasm_bindgen::from_value(templates).map_err(Error::JsSerdeError)?
+    };
+    set_templates(templates, locale.as_deref())?;
+    Ok(())
 }
 
-/// Create or update clinical notes from the statement in the notes.
+/// Get the prompt templates as a list of `{name, variables, default}`, where
+/// `default` is the text used unless overridden by `set_prompt_templates_js`.
 #[wasm_bindgen]
-pub async fn create_notes_js(state: StateJs, key: &str) -> Result<StateJs> {
-    let statement = match state.statement {
+pub fn prompt_templates_js() -> Result<JsValue> {
+    default_templates()
+        .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .map_err(Error::JsSerdeError)
+}
+
+/// Limit the requests made to OpenAI by all the `*_js` functions.
+///
+/// At most `max_concurrent` requests wait for a response at once, and at most
+/// `requests_per_minute` requests start in a minute, each unlimited if
+/// omitted. Requests aren't limited until this is called.
+#[wasm_bindgen]
+pub fn set_rate_limits_js(max_concurrent: Option<usize>, requests_per_minute: Option<f64>) {
+    limiter().set_limits(RateLimits {
+        max_concurrent,
+        requests_per_minute,
+    });
+}
+
+/// A document fetcher backed by a JS callback.
+struct JsDocumentFetcher(js_sys::Function);
+
+impl DocumentFetcher for JsDocumentFetcher {
+    fn fetch(&self, url: &str) -> LocalBoxFuture<'_, core::result::Result<String, String>> {
+        let result = self.0.call1(&JsValue::NULL, &JsValue::from_str(url));
+        let url = url.to_string();
+        async move {
+            let promise = result.map_err(|e| format!("{:?}", e))?;
+            JsFuture::from(js_sys::Promise::resolve(&promise))
+                .await
+                .map_err(|e| format!("{:?}", e))?
+                .as_string()
+                .ok_or_else(|| format!("contents of {} aren't a string", url))
+        }
+        .boxed_local()
+    }
+}
+
+/// A persistent embedding cache backed by JS callbacks.
+struct JsEmbeddingCache {
+    get: js_sys::Function,
+    put: js_sys::Function,
+}
+
+impl PersistentCache for JsEmbeddingCache {
+    fn get(&self, key: &str) -> LocalBoxFuture<'_, Option<Vec<f32>>> {
+        let result = self.get.call1(&JsValue::NULL, &JsValue::from_str(key));
+        async move {
+            let value = JsFuture::from(js_sys::Promise::resolve(&result.ok()?))
+                .await
+                .ok()?;
+            if value.is_undefined() || value.is_null() {
+                return None;
+            }
+            Some(js_sys::Float32Array::new(&value).to_vec()).filter(|x| !x.is_empty())
+        }
+        .boxed_local()
+    }
+
+    fn put(&self, key: &str, embedding: &[f32]) -> LocalBoxFuture<'_, ()> {
+        let result = self.put.call2(
+            &JsValue::NULL,
+            &JsValue::from_str(key),
+            &js_sys::Float32Array::from(embedding),
+        );
+        async move {
+            // the cache is best effort, so failing to store is ignored
+            if let Ok(promise) = result {
+                let _ = JsFuture::from(js_sys::Promise::resolve(&promise)).await;
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Cache embeddings so the same text isn't embedded more than once.
+///
+/// Up to `capacity` embeddings are kept in memory. If both `get` and `put` are
+/// provided, they are used to persist embeddings across sessions (e.g. in
+/// IndexedDB): `get(key)` returns the stored `Float32Array` or `undefined`,
+/// and `put(key, embedding)` stores it. Either may return a promise.
+#[wasm_bindgen]
+pub fn set_embedding_cache_js(
+    capacity: usize,
+    get: Option<js_sys::Function>,
+    put: Option<js_sys::Function>,
+) {
+    memory_cache().lock().unwrap().set_capacity(capacity);
+    let persistent = match (get, put) {
+        (Some(get), Some(put)) => {
+            Some(Rc::new(JsEmbeddingCache { get, put }) as Rc<dyn PersistentCache>)
+        }
+        _ => None,
+    };
+    set_persistent_cache(persistent);
+}
+
+/// Call `callback` with an object describing every request made to OpenAI.
+///
+/// Events with `kind: "request"` describe an attempt at a request: its `url`,
+/// the retry `attempt`, the `status`, the `latency_ms`, and the `request` and
+/// error `response` bodies, truncated. Events with `kind: "usage"` give the
+/// tokens used by a completed request. API keys are redacted. Pass
+/// `undefined` to stop tracing.
+#[wasm_bindgen]
+pub fn set_trace_js(callback: Option<js_sys::Function>) {
+    let tracer = callback.map(|callback| {
+        Rc::new(move |event: &TraceEvent| {
+            // tracing is best effort, so failing to report is ignored
+            if let Ok(event) = event.serialize(&serde_wasm_bindgen::Serializer::json_compatible()) {
+                let _ = callback.call1(&JsValue::NULL, &event);
+            }
+        }) as Tracer
+    });
+    set_tracer(tracer);
+}
+
+/// The properties of a model registered with [`register_model_js`].
+#[derive(Debug, Deserialize)]
+struct ModelOptions {
+    context_window: usize,
+    prompt_price: f64,
+    completion_price: f64,
+    #[serde(default)]
+    cl100k: bool,
+    #[serde(default)]
+    reasoning: bool,
+}
+
+/// Register a chat completion model so its prompts can be budgeted and priced.
+///
+/// The `options` object has a `context_window`, the number of tokens the
+/// model accepts for the prompt and completion, and a `prompt_price` and
+/// `completion_price` in USD per million tokens. Models that use the older
+/// `cl100k_base` tokenizer should set `cl100k`. Reasoning models, which reject
+/// `temperature`, `stop` and `max_tokens`, should set `reasoning`.
+#[wasm_bindgen]
+pub fn register_model_js(name: String, options: JsValue) -> Result<()> {
+    let options: ModelOptions =
+        serde_wasm_bindgen::from_value(options).map_err(Error::JsSerdeError)?;
+    register_model(
+        name,
+        ModelInfo {
+            context_window: options.context_window,
+            pricing: Pricing {
+                prompt: options.prompt_price,
+                completion: options.completion_price,
+            },
+            encoding: if options.cl100k {
+                Encoding::Cl100kBase
+            } else {
+                Encoding::O200kBase
+            },
+            reasoning: options.reasoning,
+        },
+    );
+    Ok(())
+}
+
+/// Give up on a streamed response that goes `seconds` without new data, or
+/// wait forever if `undefined`. Defaults to 30 seconds.
+///
+/// A stalled stream is resumed like an interrupted one before failing.
+#[wasm_bindgen]
+pub fn set_stall_timeout_js(seconds: Option<f64>) {
+    config::update_config(|x| {
+        x.stall_timeout = seconds
+            .filter(|x| x.is_finite() && *x >= 0.0)
+            .map(Duration::from_secs_f64)
+    });
+}
+
+/// Set how many times to retry a failed request made outside of a task, such
+/// as moderation, transcription and speech, which defaults to 3. Each task
+/// has its own `max_retries` setting.
+#[wasm_bindgen]
+pub fn set_max_retries_js(max_retries: usize) {
+    config::update_config(|x| x.max_retries = max_retries);
+}
+
+/// Leave out the validation keywords, such as `minimum` and `format`, from
+/// the function schemas sent to the API, for compatible backends that reject
+/// them.
+#[wasm_bindgen]
+pub fn set_portable_schemas_js(portable: bool) {
+    config::update_config(|x| x.portable_schemas = portable);
+}
+
+/// Make completions reproducible for regression testing.
+///
+/// When `deterministic`, every request uses the `seed` (0 if omitted) and a
+/// temperature of 0.
+#[wasm_bindgen]
+pub fn set_deterministic_js(deterministic: bool, seed: Option<i32>) {
+    config::update_config(|x| {
+        x.deterministic = deterministic;
+        x.seed = seed.unwrap_or(0).into();
+    });
+}
+
+/// Set the `locale` of the user, such as `es` or `es-MX`, which selects the
+/// prompt templates for it and makes the models write in its language.
+/// English if omitted. The `locale` of a config passed to a call takes
+/// precedence.
+#[wasm_bindgen]
+pub fn set_locale_js(locale: Option<String>) {
+    config::update_config(|x| x.locale = locale.filter(|x| !x.is_empty()));
+}
+
+/// Send extra headers with every request to OpenAI, e.g.
+/// `{"OpenAI-Organization": "org-...", "OpenAI-Project": "proj_..."}` or the
+/// headers an API gateway requires.
+///
+/// Replaces the headers set before; pass `{}` to remove them.
+#[wasm_bindgen]
+pub fn set_headers_js(headers: JsValue) -> Result<()> {
+    let headers: BTreeMap<String, String> = if headers.is_undefined() || headers.is_null() {
+        BTreeMap::new()
+    } else {
+        serde_wasm_bindgen::from_value(headers).map_err(Error::JsSerdeError)?
+    };
+    config::update_config(|x| x.headers = headers.into_iter().collect());
+    Ok(())
+}
+
+/// Make requests with a list of API `keys` instead of the key passed to each
+/// function, failing over to the next key when one is rejected, rate limited
+/// or out of quota.
+///
+/// Starts again from the first key. Pass `[]` to use the key passed to each
+/// function again.
+#[wasm_bindgen]
+pub fn set_keys_js(keys: Vec<String>) {
+    config::set_keys(keys);
+}
+
+/// Get the index of the key from `set_keys_js` that requests are made with,
+/// or `undefined` if no keys are set.
+#[wasm_bindgen]
+pub fn active_key_index_js() -> Option<usize> {
+    config::active_key().map(|(x, _)| x)
+}
+
+/// Record responses from OpenAI as fixtures, or replay them offline.
+///
+/// The `mode` is `"record"` (send requests and save the responses),
+/// `"replay"` (serve every request from the `fixtures`), or `undefined` to
+/// send requests as usual. The `fixtures` are those from
+/// `replay_fixtures_js`, or `undefined` to start with none.
+#[cfg(feature = "replay")]
+#[wasm_bindgen]
+pub fn set_replay_js(mode: JsValue, fixtures: JsValue) -> Result<()> {
+    let mode: Option<replay::Mode> =
+        serde_wasm_bindgen::from_value(mode).map_err(Error::JsSerdeError)?;
+    let fixtures: replay::Fixtures = if fixtures.is_undefined() || fixtures.is_null() {
+        replay::Fixtures::new()
+    } else {
+        serde_wasm_bindgen::from_value(fixtures).map_err(Error::JsSerdeError)?
+    };
+    replay::set_replay(mode, fixtures);
+    Ok(())
+}
+
+/// Get the fixtures, including the responses recorded so far, as an object
+/// that can be saved as JSON.
+#[cfg(feature = "replay")]
+#[wasm_bindgen]
+pub fn replay_fixtures_js() -> Result<JsValue> {
+    replay::fixtures()
+        .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .map_err(Error::JsSerdeError)
+}
+
+/// Get the distinct `system_fingerprint` values returned by the API so far.
+///
+/// More than one fingerprint means the model changed during the run, so seeded
+/// completions may differ from earlier runs.
+#[wasm_bindgen]
+pub fn system_fingerprints_js() -> Vec<String> {
+    config::fingerprints()
+}
+
+/// Check the user's API `key` when it's entered, rather than failing deep
+/// inside the Clint process.
+///
+/// Returns `"valid"`, `"invalid"` (wrong or revoked key) or `"no_quota"` (the
+/// account has run out of credits). Makes a 1-token completion.
+#[wasm_bindgen]
+pub async fn validate_key_js(key: &str, signal: Option<web_sys::AbortSignal>) -> Result<JsValue> {
+    let status = cancel_token(signal.as_ref())
+        .run(validate_key(key, Some(openai::DEFAULT_TIMEOUT)))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    serde_wasm_bindgen::to_value(&status).map_err(Error::JsSerdeError)
+}
+
+/// Screen the user's message with OpenAI's moderation endpoint.
+///
+/// Returns an object with `flagged`, the flagged `categories`, and whether the
+/// message is about `self_harm` or is `abuse`. The same object is thrown by
+/// `rewrite_message_js` and `respond_js` when their `moderate` setting is on
+/// and the message is flagged.
+#[wasm_bindgen]
+pub async fn moderate_js(
+    message: &str,
+    key: &str,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<JsValue> {
+    let moderation = cancel_token(signal.as_ref())
+        .run(moderate(
+            key,
+            message,
+            Some(openai::DEFAULT_TIMEOUT),
+            config::config().max_retries,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    serde_wasm_bindgen::to_value(&moderation).map_err(Error::JsSerdeError)
+}
+
+/// Transcribe a recording of the user speaking into text.
+///
+/// The `bytes` are the recorded audio, encoded as `mime_type` (`audio/webm`
+/// if omitted, as recorded by `MediaRecorder`). The `language` of the speech
+/// (ISO-639-1, e.g. `en`) is detected if omitted. The text can be passed to
+/// `rewrite_message_js` like a typed message.
+#[wasm_bindgen]
+pub async fn transcribe_js(
+    bytes: &[u8],
+    key: &str,
+    mime_type: Option<String>,
+    language: Option<String>,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<String> {
+    cancel_token(signal.as_ref())
+        .run(transcribe(
+            key,
+            bytes,
+            mime_type.as_deref().unwrap_or("audio/webm"),
+            language.as_deref(),
+            Some(openai::DEFAULT_TIMEOUT),
+            config::config().max_retries,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)
+}
+
+/// Read a reply aloud.
+///
+/// Returns MP3 audio of the `text` read in the `voice` (`alloy` if omitted;
+/// also `ash`, `coral`, `echo`, `fable`, `onyx`, `nova`, `sage` or
+/// `shimmer`). The `text` must be at most 4096 characters.
+#[wasm_bindgen]
+pub async fn speak_js(
+    text: &str,
+    voice: Option<String>,
+    key: &str,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<Vec<u8>> {
+    let voice: Voice = match voice {
+        Some(voice) => {
+            serde_json::from_value(serde_json::Value::String(voice)).map_err(Error::SerdeError)?
+        }
+        None => Voice::default(),
+    };
+    cancel_token(signal.as_ref())
+        .run(speak(
+            key,
+            text,
+            voice,
+            Some(openai::DEFAULT_TIMEOUT),
+            config::config().max_retries,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)
+}
+
+/// Options for a custom chat completion.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ChatOptions {
+    model: ChatCompletionModel,
+    temperature: Option<f32>,
+    max_tokens: Option<u16>,
+    top_p: Option<f32>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    stop: Option<Vec<String>>,
+    seed: Option<i64>,
+    max_retries: usize,
+    max_continuations: usize,
+}
+
+impl Default for ChatOptions {
+    fn default() -> Self {
+        Self {
+            model: ChatCompletionModel::GPT_4O,
+            temperature: None,
+            max_tokens: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            stop: None,
+            seed: None,
+            max_retries: config::config().max_retries,
+            max_continuations: 0,
+        }
+    }
+}
+
+/// Stream a chat completion for custom `messages`, e.g. to explain a medical
+/// term, with the same retries, rate limits and cancellation as the Clint
+/// process.
+///
+/// The `messages` are an array of objects with a `role` (`system`, `user` or
+/// `assistant`), a `content` and optional `images` (objects with a `url`).
+/// The `options` object has optional `model`, `temperature`, `max_tokens`,
+/// `top_p`, `frequency_penalty`, `presence_penalty`, `stop`, `seed`,
+/// `max_retries` and `max_continuations` fields.
+#[wasm_bindgen]
+pub async fn chat_js(
+    messages: JsValue,
+    options: JsValue,
+    key: &str,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<ChatMessageUpdates> {
+    let messages: Vec<ChatCompletionMessage> =
+        serde_wasm_bindgen::from_value(messages).map_err(Error::JsSerdeError)?;
+    let options: ChatOptions = if options.is_undefined() || options.is_null() {
+        ChatOptions::default()
+    } else {
+        serde_wasm_bindgen::from_value(options).map_err(Error::JsSerdeError)?
+    };
+    let args = ChatCompletionArgs {
+        model: options.model,
+        temperature: options.temperature,
+        max_tokens: options.max_tokens,
+        top_p: options.top_p,
+        frequency_penalty: options.frequency_penalty,
+        presence_penalty: options.presence_penalty,
+        stop: options.stop,
+        seed: options.seed,
+        max_continuations: options.max_continuations,
+        ..ChatCompletionArgs::new(key.to_string())
+    }
+    .with_messages(messages);
+    let cancel = cancel_token(signal.as_ref());
+    ChatMessageUpdates {
+        parts: cancel
+            .run(ChatCompletionParts::new(args, options.max_retries))
+            .await
+            .map_err(|_| Error::Cancelled)?
+            .map_err(Error::from)?
+            .with_cancel(cancel),
+        grounded: None,
+    }
+    .pipe(Ok)
+}
+
+/// Classify what the user's `message` asks for, so the app can reply to
+/// out-of-scope requests with a canned response rather than run the rest of
+/// the process.
+///
+/// Returns an object with the `category` (`medical`, `non_medical`, `legal`,
+/// `prescription` or `dosing`) and the `reason` for it. Only `medical`
+/// messages are in scope.
+#[wasm_bindgen]
+pub async fn classify_scope_js(
+    state: &StateJs,
+    message: &str,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<JsValue> {
+    let scope = cancel_token(signal.as_ref())
+        .run(classify_scope(
+            message,
+            key.to_string(),
+            &state.usage.scope,
+            &config.config.scope,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    serde_wasm_bindgen::to_value(&scope).map_err(Error::JsSerdeError)
+}
+
+/// Re-write the user's message into a medical statement.
+#[wasm_bindgen]
+pub async fn rewrite_message_js(
+    message: &str,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<ChatMessageUpdates> {
+    rewrite(&UsageTracker::default(), message, key, config, signal).await
+}
+
+/// Re-write the user's message into a medical statement, like
+/// [`rewrite_message_js`], recording the tokens used in the `state`.
+#[wasm_bindgen]
+pub async fn rewrite_message_with_state_js(
+    state: &StateJs,
+    message: &str,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<ChatMessageUpdates> {
+    rewrite(&state.usage.rewrite, message, key, config, signal).await
+}
+
+async fn rewrite(
+    usage: &UsageTracker,
+    message: &str,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<ChatMessageUpdates> {
+    let cancel = cancel_token(signal.as_ref());
+    ChatMessageUpdates {
+        parts: cancel
+            .run(rewrite_message(
+                message.to_string(),
+                key.to_string(),
+                usage,
+                &config.config.rewrite,
+            ))
+            .await
+            .map_err(|_| Error::Cancelled)?
+            .map_err(Error::from)?
+            .with_cancel(cancel),
+        grounded: None,
+    }
+    .pipe(Ok)
+}
+
+/// Create or update clinical notes from the statement in the notes.
+#[wasm_bindgen]
+pub async fn create_notes_js(
+    state: StateJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<StateJs> {
+    let statement = match state.statement {
         Some(x) => x,
         None => return state.pipe(Ok),
     };
-    let notes = create_update_notes(statement.clone(), state.notes.as_ref(), key.to_string(), 3)
+    let (notes, notes_changes) = cancel_token(signal.as_ref())
+        .run(create_update_notes(
+            statement.clone(),
+            state.notes.as_ref(),
+            &state.profile,
+            key.to_string(),
+            &state.usage.notes,
+            &config.config.notes,
+        ))
         .await
-        .map_err(Error::PromptError)?;
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
     StateJs {
         statement: Some(statement),
         notes: Some(notes),
+        notes_changes,
+        triage: None,
+        ..state
+    }
+    .pipe(Ok)
+}
+
+/// Stream the notes created or updated from the statement in the state.
+///
+/// Once all the updates are received, store the notes in the state with
+/// `set_notes_js`. Returns `undefined` if the state has no statement.
+#[wasm_bindgen]
+pub async fn create_notes_stream_js(
+    state: &StateJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<Option<FunctionCallUpdates>> {
+    let statement = match &state.statement {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let cancel = cancel_token(signal.as_ref());
+    FunctionCallUpdates {
+        parts: cancel
+            .run(stream_notes(
+                statement,
+                state.notes.as_ref(),
+                &state.profile,
+                key.to_string(),
+                &state.usage.notes,
+                &config.config.notes,
+            ))
+            .await
+            .map_err(|_| Error::Cancelled)?
+            .map_err(Error::from)?
+            .with_cancel(cancel),
+    }
+    .pipe(Some)
+    .pipe(Ok)
+}
+
+/// Store the notes streamed by `create_notes_stream_js` in the state.
+#[wasm_bindgen]
+pub fn set_notes_js(state: StateJs, updates: &FunctionCallUpdates) -> Result<StateJs> {
+    let notes = updates.parts.function_output().map_err(Error::from)?;
+    StateJs {
+        notes_changes: NotesDiff::new(state.notes.as_ref(), &notes),
+        notes: Some(notes),
+        triage: None,
         ..state
     }
     .pipe(Ok)
 }
 
+/// Replace the notes in the state with the JSON `notes`, such as the notes
+/// corrected by the user, recording the changes.
+#[wasm_bindgen]
+pub fn set_notes_json_js(state: StateJs, notes: &str) -> Result<StateJs> {
+    let notes: Notes = serde_json::from_str(notes).map_err(Error::SerdeError)?;
+    StateJs {
+        notes_changes: NotesDiff::new(state.notes.as_ref(), &notes),
+        notes: Some(notes),
+        triage: None,
+        ..state
+    }
+    .pipe(Ok)
+}
+
+/// Set the text of the notes section `field`, such as `medications`, in the
+/// state, recording the change. Fails if the field isn't a text section.
+#[wasm_bindgen]
+pub fn update_notes_field_js(state: StateJs, field: &str, value: String) -> Result<StateJs> {
+    let mut notes = state.notes.clone().unwrap_or_default();
+    notes.set_field(field, value).map_err(Error::from)?;
+    StateJs {
+        notes_changes: NotesDiff::new(state.notes.as_ref(), &notes),
+        notes: Some(notes),
+        triage: None,
+        ..state
+    }
+    .pipe(Ok)
+}
+
+/// Flag the contradictions the last changes to the notes in the state, such
+/// as the user's corrections, introduce with the rest of the notes.
+///
+/// Returns an array of contradictions, each with the titles of the
+/// `sections` involved and an `explanation`.
+#[wasm_bindgen]
+pub async fn check_notes_js(
+    state: &StateJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<JsValue> {
+    let notes = match &state.notes {
+        Some(x) => x,
+        None => return Ok(js_sys::Array::new().into()),
+    };
+    let contradictions = cancel_token(signal.as_ref())
+        .run(check_notes(
+            notes,
+            &state.notes_changes,
+            &state.profile,
+            key.to_string(),
+            &state.usage.notes,
+            &config.config.notes,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    serde_wasm_bindgen::to_value(&contradictions).map_err(Error::JsSerdeError)
+}
+
+/// List the information still missing from the notes in the state, such as
+/// the onset of a symptom or the history relevant to the diagnoses, so the
+/// app can show what's left to ask.
+///
+/// Returns an array of gaps, each with the `section` of the notes, what's
+/// `missing` and a `question` to ask the patient.
+#[wasm_bindgen]
+pub async fn information_gaps_js(
+    state: &StateJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<JsValue> {
+    let notes = match &state.notes {
+        Some(x) => x,
+        None => return Ok(js_sys::Array::new().into()),
+    };
+    let gaps = cancel_token(signal.as_ref())
+        .run(information_gaps(
+            notes,
+            &state.profile,
+            state.diagnoses.as_ref(),
+            key.to_string(),
+            &state.usage.gaps,
+            &config.config.gaps,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    serde_wasm_bindgen::to_value(&gaps).map_err(Error::JsSerdeError)
+}
+
+/// Screen the statement and notes in the state for red-flag findings, such
+/// as crushing chest pain, that need the normal flow interrupted.
+///
+/// Returns an object with the urgency `level` (`routine`, `urgent` or
+/// `emergency`) and the triggering `findings`.
+#[wasm_bindgen]
+pub async fn assess_urgency_js(
+    state: &StateJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<JsValue> {
+    let urgency = cancel_token(signal.as_ref())
+        .run(assess_urgency(
+            state.statement.as_deref(),
+            state.notes.as_ref(),
+            &state.profile,
+            key.to_string(),
+            &state.usage.urgency,
+            &config.config.urgency,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    serde_wasm_bindgen::to_value(&urgency).map_err(Error::JsSerdeError)
+}
+
 /// List initial candidate diagnoses from the notes in the state.
 #[wasm_bindgen]
-pub async fn initial_diagnosis_js(state: StateJs, db: &DocDbJs, key: &str) -> Result<StateJs> {
+pub async fn initial_diagnosis_js(
+    state: StateJs,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<StateJs> {
     let notes = match &state.notes {
         Some(x) => x,
         None => return state.pipe(Ok),
     };
-    let diagnoses = initial_diagnosis(
-        notes,
-        state.statement.as_deref(),
-        &db.db,
-        key.to_string(),
-        3,
-    )
-    .await
-    .map_err(Error::PromptError)?;
+    let diagnoses = cancel_token(signal.as_ref())
+        .run(initial_diagnosis(
+            notes,
+            &state.profile,
+            state.statement.as_deref(),
+            &db.db,
+            key.to_string(),
+            &state.usage.diagnosis,
+            &config.config.diagnosis,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    StateJs {
+        diagnoses: Some(diagnoses),
+        triage: None,
+        ..state
+    }
+    .pipe(Ok)
+}
+
+/// Stream the initial candidate diagnoses listed from the notes in the state.
+///
+/// Once all the updates are received, store the diagnoses in the state with
+/// `set_initial_diagnosis_js`. Returns `undefined` if the state has no notes.
+#[wasm_bindgen]
+pub async fn initial_diagnosis_stream_js(
+    state: &StateJs,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<Option<FunctionCallUpdates>> {
+    let notes = match &state.notes {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let cancel = cancel_token(signal.as_ref());
+    FunctionCallUpdates {
+        parts: cancel
+            .run(stream_initial_diagnosis(
+                notes,
+                &state.profile,
+                state.statement.as_deref(),
+                &db.db,
+                key.to_string(),
+                &state.usage.diagnosis,
+                &config.config.diagnosis,
+            ))
+            .await
+            .map_err(|_| Error::Cancelled)?
+            .map_err(Error::from)?
+            .with_cancel(cancel),
+    }
+    .pipe(Some)
+    .pipe(Ok)
+}
+
+/// Find the documents for the diagnoses streamed by
+/// `initial_diagnosis_stream_js` and store them in the state.
+#[wasm_bindgen]
+pub async fn set_initial_diagnosis_js(
+    state: StateJs,
+    updates: &FunctionCallUpdates,
+    db: &DocDbJs,
+    key: &str,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<StateJs> {
+    let diagnoses = cancel_token(signal.as_ref())
+        .run(resolve_initial_diagnosis(
+            &updates.parts,
+            &db.db,
+            key,
+            &state.usage.diagnosis,
+            config::config().max_retries,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
     StateJs {
         diagnoses: Some(diagnoses),
+        triage: None,
         ..state
     }
     .pipe(Ok)
 }
 
-/// Refine the reasoning for each diagnosis in the state.
+/// Refine the reasoning for the first diagnoses in the state, up to the
+/// `max_diagnoses` of the `refine` task, and those after them that are
+/// dangerous to miss, and order the diagnoses from most to least likely.
+///
+/// The documents with the hex IDs in `exclude`, such as those already used
+/// in an earlier prompt, aren't used as context. Fails if any of the IDs is
+/// invalid.
 #[wasm_bindgen]
-pub async fn refine_diagnosis_js(state: StateJs, db: &DocDbJs, key: &str) -> Result<StateJs> {
+pub async fn refine_diagnosis_js(
+    state: StateJs,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+    exclude: Option<Vec<String>>,
+) -> Result<StateJs> {
     let mut state = state;
+    let exclude = decode_doc_ids(&exclude.unwrap_or_default())?;
     let notes = match &state.notes {
         Some(x) => x,
         None => return state.pipe(Ok),
@@ -263,26 +2070,159 @@ pub async fn refine_diagnosis_js(state: StateJs, db: &DocDbJs, key: &str) -> Res
         Some(x) => x,
         None => return state.pipe(Ok),
     };
-    let diagnoses = diagnoses
+    let cancel = cancel_token(signal.as_ref());
+    let mut diagnoses = select_diagnoses(diagnoses, config.config.refine.max_diagnoses)
         .into_iter()
-        .take(8)
         .map(|x| {
             refine_diagnosis(
                 notes,
+                &state.profile,
                 x,
                 state.statement.as_deref(),
+                &exclude,
                 &db.db,
                 key.to_string(),
-                3,
+                &state.usage.diagnosis,
+                &config.config.refine,
             )
         })
         .pipe(join_all)
+        .pipe(|x| cancel.run(x))
         .await
+        .map_err(|_| Error::Cancelled)?
         .into_iter()
         .flatten()
         .collect::<Vec<_>>();
+    // the refined likelihoods can change the order of the differential
+    rank_diagnoses(&mut diagnoses);
+    StateJs {
+        diagnoses: Some(diagnoses),
+        triage: None,
+        ..state
+    }
+    .pipe(Ok)
+}
+
+/// Check the refined reasoning for each diagnosis in the state against the
+/// documents retrieved to refine it and the notes, recording the unsupported
+/// claims and contradictions found so they can be flagged or hidden. Fails
+/// if any of the checks fails.
+#[wasm_bindgen]
+pub async fn verify_diagnosis_js(
+    state: StateJs,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<StateJs> {
+    let mut state = state;
+    let notes = match &state.notes {
+        Some(x) => x,
+        None => return state.pipe(Ok),
+    };
+    let diagnoses = match state.diagnoses.take() {
+        Some(x) => x,
+        None => return state.pipe(Ok),
+    };
+    let cancel = cancel_token(signal.as_ref());
+    let verifications = diagnoses
+        .iter()
+        .map(|x| {
+            verify_diagnosis(
+                notes,
+                &state.profile,
+                x,
+                &db.db,
+                key.to_string(),
+                &state.usage.verify,
+                &config.config.verify,
+            )
+        })
+        .pipe(join_all)
+        .pipe(|x| cancel.run(x))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .into_iter()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(Error::from)?;
+    let diagnoses = diagnoses
+        .into_iter()
+        .zip(verifications)
+        .map(|(x, verification)| ResolvedDiagnosis { verification, ..x })
+        .collect::<Vec<_>>();
     StateJs {
         diagnoses: Some(diagnoses),
+        triage: None,
+        ..state
+    }
+    .pipe(Ok)
+}
+
+/// Check the medications in the notes and profile of the state for
+/// interactions with each other and contraindications with the candidate
+/// diagnoses, the allergies or a pregnancy.
+///
+/// Returns an array of warnings with the `kind` (`interaction` or
+/// `contraindication`), the `medications` involved, the `condition` of a
+/// contraindication, an `explanation` and the `{id, title, url}` `sources`
+/// supporting it. Returns an empty array without notes or medications.
+#[wasm_bindgen]
+pub async fn check_medications_js(
+    state: &StateJs,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<JsValue> {
+    let Some(notes) = &state.notes else {
+        return serde_wasm_bindgen::to_value(&Vec::<Warning>::new()).map_err(Error::JsSerdeError);
+    };
+    let warnings = cancel_token(signal.as_ref())
+        .run(check_medications(
+            notes,
+            &state.profile,
+            state.diagnoses.as_ref(),
+            &db.db,
+            key.to_string(),
+            &state.usage.medications,
+            &config.config.medications,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?
+        .into_iter()
+        .map(|x| Warning::new(x, &db.db))
+        .collect::<Vec<_>>();
+    serde_wasm_bindgen::to_value(&warnings).map_err(Error::JsSerdeError)
+}
+
+/// Recommend the level of care for the diagnoses in the state, stored in the
+/// state with its reasoning and caveats.
+#[wasm_bindgen]
+pub async fn triage_js(
+    state: StateJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<StateJs> {
+    let (notes, diagnoses) = match (&state.notes, &state.diagnoses) {
+        (Some(notes), Some(diagnoses)) => (notes, diagnoses),
+        _ => return state.pipe(Ok),
+    };
+    let triage = cancel_token(signal.as_ref())
+        .run(triage(
+            notes,
+            &state.profile,
+            diagnoses,
+            key.to_string(),
+            &state.usage.triage,
+            &config.config.triage,
+        ))
+        .await
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    StateJs {
+        triage: Some(triage),
         ..state
     }
     .pipe(Ok)
@@ -297,15 +2237,73 @@ pub async fn respond_js(
     diagnosis: bool,
     db: &DocDbJs,
     key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<Option<ChatMessageUpdates>> {
+    respond_with_images(
+        state,
+        message,
+        Vec::new(),
+        diagnosis,
+        db,
+        key,
+        config,
+        signal,
+    )
+    .await
+}
+
+/// Respond to the user's message along with `images` of e.g. a rash or a
+/// medication label, given as URLs or base64 `data:` URLs.
+///
+/// The respond task must be configured with a vision model.
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub async fn respond_with_image_js(
+    state: &StateJs,
+    message: &str,
+    images: Vec<String>,
+    diagnosis: bool,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<Option<ChatMessageUpdates>> {
+    respond_with_images(
+        state,
+        message,
+        images.into_iter().map(ImageUrl::new).collect(),
+        diagnosis,
+        db,
+        key,
+        config,
+        signal,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn respond_with_images(
+    state: &StateJs,
+    message: &str,
+    images: Vec<ImageUrl>,
+    diagnosis: bool,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
 ) -> Result<Option<ChatMessageUpdates>> {
     let notes = match &state.notes {
         Some(x) => x,
         None => return Ok(None),
     };
-    ChatMessageUpdates {
-        parts: respond(
+    let cancel = cancel_token(signal.as_ref());
+    let response = cancel
+        .run(respond(
             notes,
+            &state.profile,
             message.to_string(),
+            images,
             if diagnosis {
                 state.diagnoses.as_ref()
             } else {
@@ -315,36 +2313,124 @@ pub async fn respond_js(
             state.messages.clone(),
             &db.db,
             key.to_string(),
-            3,
-        )
+            &state.usage.respond,
+            &config.config.respond,
+            &config.config.summarize,
+        ))
         .await
-        .map_err(Error::PromptError)?,
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?;
+    ChatMessageUpdates {
+        parts: response.parts.with_cancel(cancel),
+        grounded: Some(response.grounded),
     }
     .pipe(Some)
     .pipe(Ok)
 }
 
-/// Cite documents that are relevant for a message (assistant response).
-#[wasm_bindgen]
-pub async fn cite_js(message: &str, db: &DocDbJs, key: &str) -> Result<String> {
-    cite(message, &db.db, key.to_string(), 3)
+/// Cite the documents relevant for the `message`, skipping those without a
+/// URL, and record them as cited in the `state`, if any.
+async fn cite_sources(
+    state: Option<&mut StateJs>,
+    message: &str,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<Vec<CitedSource>> {
+    let mut scratch = StateJs::default();
+    let state = state.unwrap_or(&mut scratch);
+    let cited = cancel_token(signal.as_ref())
+        .run(cite(
+            message,
+            &state.cited,
+            &db.db,
+            key.to_string(),
+            &state.usage.cite,
+            &config.config.cite,
+        ))
         .await
-        .map_err(Error::PromptError)?
-        .excerpts
+        .map_err(|_| Error::Cancelled)?
+        .map_err(Error::from)?
         .into_iter()
-        .map(|x| {
-            let mut hash: DocId = [0u8; 16];
-            match hex::decode_to_slice(x.id, &mut hash) {
-                Ok(_) => (),
-                Err(_) => return None,
-            };
-            match db.db.get_url(&hash) {
-                Some(url) => Some(format!("- [{}]({})", x.title, url)),
-                None => None,
-            }
+        .filter_map(|x| {
+            let url = db.db.get_url(&x.id)?.to_string();
+            state.cited.insert(x.id);
+            Some(CitedSource {
+                id: hex::encode(x.id),
+                title: x.title,
+                url,
+                reviewed: x.reviewed,
+                quote: x.quote,
+                score: x.score,
+            })
         })
-        .flatten()
+        .collect();
+    Ok(cited)
+}
+
+/// Cite documents that are relevant for a message (assistant response), as
+/// a Markdown list of links.
+///
+/// Documents whose supporting quote isn't found in the document aren't cited.
+#[wasm_bindgen]
+pub async fn cite_js(
+    message: &str,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<String> {
+    cite_sources(None, message, db, key, config, signal)
+        .await?
+        .pipe(|x| cite_links(&x))
+        .pipe(Ok)
+}
+
+/// Cite documents that are relevant for a message (assistant response), like
+/// [`cite_js`]. Documents already cited in the conversation aren't cited
+/// again, and the cited documents and tokens used are recorded in the
+/// `state`.
+#[wasm_bindgen]
+pub async fn cite_with_state_js(
+    state: &mut StateJs,
+    message: &str,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<String> {
+    cite_sources(Some(state), message, db, key, config, signal)
+        .await?
+        .pipe(|x| cite_links(&x))
+        .pipe(Ok)
+}
+
+/// Format the `cited` sources as a Markdown list of links.
+fn cite_links(cited: &[CitedSource]) -> String {
+    cited
+        .iter()
+        .map(|x| format!("- [{}]({})", x.title, x.url))
         .collect::<Vec<_>>()
         .join("\n")
-        .pipe(Ok)
+}
+
+/// Cite documents that are relevant for a message (assistant response), as
+/// an array of `{id, title, url, reviewed, quote, score}` objects, where
+/// `reviewed` is the `YYYY-MM-DD` date the document was last reviewed, if
+/// known, `quote` is the passage of the document supporting the message and
+/// `score` is the share of it found in the document, 1 if it's found whole.
+///
+/// Documents are cited as by [`cite_with_state_js`].
+#[wasm_bindgen]
+pub async fn cite_structured_js(
+    state: &mut StateJs,
+    message: &str,
+    db: &DocDbJs,
+    key: &str,
+    config: &ClintConfigJs,
+    signal: Option<web_sys::AbortSignal>,
+) -> Result<JsValue> {
+    let cited = cite_sources(Some(state), message, db, key, config, signal).await?;
+    serde_wasm_bindgen::to_value(&cited).map_err(Error::JsSerdeError)
 }
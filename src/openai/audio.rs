@@ -0,0 +1,205 @@
+//! Transcribe speech with OpenAI's audio transcription endpoint.
+
+use std::time::Duration;
+
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tap::Pipe;
+
+use super::retry::{send_with_retries, Backoff};
+use super::{Error, Result};
+use crate::http::{authorize, client};
+use crate::timer::timeout;
+
+const TRANSCRIPTION_MODEL: &str = "whisper-1";
+
+#[derive(Debug, Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+/// A file name whose extension tells the endpoint the format of the audio.
+fn file_name(mime_type: &str) -> String {
+    let subtype = mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    let extension = match subtype {
+        "mpeg" | "mp3" => "mp3",
+        "mp4" | "x-m4a" | "m4a" => "m4a",
+        "wav" | "x-wav" | "wave" => "wav",
+        "ogg" => "ogg",
+        "flac" => "flac",
+        _ => "webm",
+    };
+    format!("audio.{}", extension)
+}
+
+/// Is `mime_type` a `type/subtype` MIME type, with optional parameters?
+fn is_mime_type(mime_type: &str) -> bool {
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    let valid = |x: &str| !x.is_empty() && x.chars().all(|x| x.is_ascii_graphic() && x != '"');
+    matches!(essence.split_once('/'), Some((kind, subtype)) if valid(kind) && valid(subtype))
+        && !mime_type.chars().any(|x| x.is_control() || x == '"')
+}
+
+/// A field of a `multipart/form-data` body.
+struct Field<'a> {
+    name: &'a str,
+    /// The file name and MIME type, if the field is a file.
+    file: Option<(String, &'a str)>,
+    value: &'a [u8],
+}
+
+/// Encode the `fields` as a `multipart/form-data` body, returning its content
+/// type and bytes.
+///
+/// The boundary is derived from the values rather than random, so the same
+/// fields always give the same body, which the replay fixtures are keyed by.
+fn multipart(fields: &[Field]) -> (String, Vec<u8>) {
+    let mut hasher = Sha256::new();
+    for field in fields {
+        hasher.update(field.value);
+    }
+    let boundary = format!("clint-{}", hex::encode(&hasher.finalize()[..16]));
+    let mut body = Vec::new();
+    for field in fields {
+        body.extend(format!("--{}\r\n", boundary).as_bytes());
+        match &field.file {
+            Some((file_name, mime_type)) => body.extend(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+                     Content-Type: {}\r\n\r\n",
+                    field.name, file_name, mime_type
+                )
+                .as_bytes(),
+            ),
+            None => body.extend(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                    field.name
+                )
+                .as_bytes(),
+            ),
+        }
+        body.extend(field.value);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+/// Transcribe the speech in `audio`, encoded as `mime_type`.
+///
+/// If the `language` of the speech is known (ISO-639-1, e.g. `en`), it
+/// improves accuracy and latency.
+pub async fn transcribe(
+    token: &str,
+    audio: &[u8],
+    mime_type: &str,
+    language: Option<&str>,
+    time_limit: Option<Duration>,
+    max_retries: usize,
+) -> Result<String> {
+    // without a valid MIME type, the format is inferred from the file name
+    let mime_type = if is_mime_type(mime_type) {
+        mime_type
+    } else {
+        "application/octet-stream"
+    };
+    let mut fields = vec![
+        Field {
+            name: "model",
+            file: None,
+            value: TRANSCRIPTION_MODEL.as_bytes(),
+        },
+        Field {
+            name: "file",
+            file: Some((file_name(mime_type), mime_type)),
+            value: audio,
+        },
+    ];
+    if let Some(language) = language {
+        fields.push(Field {
+            name: "language",
+            file: None,
+            value: language.as_bytes(),
+        });
+    }
+    let (content_type, body) = multipart(&fields);
+    send_with_retries(
+        || {
+            client()
+                .post("https://api.openai.com/v1/audio/transcriptions")
+                .pipe(|x| authorize(x, token))
+                .header(CONTENT_TYPE, &content_type)
+                .body(body.clone())
+        },
+        time_limit,
+        &Backoff::default(),
+        max_retries,
+    )
+    .await?
+    .json::<TranscriptionResponse>()
+    .pipe(|x| timeout(time_limit, x))
+    .await
+    .map_err(|_| Error::Timeout)?
+    .map_err(|_| Error::InvalidTranscription)?
+    .text
+    .pipe(Ok)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_files_by_format() {
+        assert_eq!(file_name("audio/webm;codecs=opus"), "audio.webm");
+        assert_eq!(file_name("audio/mpeg"), "audio.mp3");
+        assert_eq!(file_name("audio/x-wav"), "audio.wav");
+        assert_eq!(file_name(""), "audio.webm");
+    }
+
+    #[test]
+    fn checks_mime_types() {
+        assert!(is_mime_type("audio/webm;codecs=opus"));
+        assert!(!is_mime_type("audio"));
+        assert!(!is_mime_type("audio/webm\r\nX: y"));
+    }
+
+    #[test]
+    fn encodes_multipart_bodies() {
+        let fields = [
+            Field {
+                name: "model",
+                file: None,
+                value: b"abc",
+            },
+            Field {
+                name: "file",
+                file: Some(("audio.webm".to_string(), "audio/webm")),
+                value: &[0xff],
+            },
+        ];
+        let (content_type, body) = multipart(&fields);
+        let boundary = content_type.rsplit('=').next().unwrap();
+        let mut expected = format!(
+            "--{0}\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nabc\r\n\
+             --{0}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"audio.webm\"\r\n\
+             Content-Type: audio/webm\r\n\r\n",
+            boundary
+        )
+        .into_bytes();
+        expected.push(0xff);
+        expected.extend(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        assert_eq!(body, expected);
+        // the same fields give the same body
+        assert_eq!(multipart(&fields), (content_type, body));
+    }
+}
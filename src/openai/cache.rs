@@ -0,0 +1,147 @@
+//! Cache embeddings so the same text isn't embedded more than once.
+//!
+//! Embeddings are kept in a small in-memory LRU cache. An optional persistent
+//! store (e.g. IndexedDB in the browser) is consulted on a miss.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Mutex, OnceLock};
+
+use futures::future::LocalBoxFuture;
+use sha2::{Digest, Sha256};
+
+use super::embed::EmbeddingModel;
+
+/// How many embeddings are kept in memory by default.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// A store that keeps embeddings across sessions.
+pub trait PersistentCache {
+    /// Get the embedding stored for `key`, if any.
+    fn get(&self, key: &str) -> LocalBoxFuture<'_, Option<Vec<f32>>>;
+    /// Store the `embedding` for `key`.
+    fn put(&self, key: &str, embedding: &[f32]) -> LocalBoxFuture<'_, ()>;
+}
+
+/// An in-memory cache that evicts the least recently used embedding.
+#[derive(Debug)]
+pub struct LruCache {
+    capacity: usize,
+    /// The embedding and when it was last used, for each key.
+    entries: HashMap<String, (Vec<f32>, u64)>,
+    clock: u64,
+}
+
+impl LruCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Vec<f32>> {
+        self.clock += 1;
+        let (embedding, used) = self.entries.get_mut(key)?;
+        *used = self.clock;
+        Some(embedding.clone())
+    }
+
+    pub fn put(&mut self, key: String, embedding: Vec<f32>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(x, _)| x.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (embedding, self.clock));
+    }
+
+    /// Change the capacity, evicting the least recently used embeddings that
+    /// no longer fit.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        if self.entries.len() > capacity {
+            let mut entries = self.entries.drain().collect::<Vec<_>>();
+            // `y.cmp(x)` for the most recently used first
+            entries.sort_by(|(_, (_, x)), (_, (_, y))| y.cmp(x));
+            self.entries = entries.into_iter().take(capacity).collect();
+        }
+    }
+}
+
+/// The in-memory cache shared by all embedding requests.
+pub fn memory_cache() -> &'static Mutex<LruCache> {
+    static CACHE: OnceLock<Mutex<LruCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(LruCache::new(DEFAULT_CAPACITY)))
+}
+
+thread_local! {
+    // JS callbacks can't be shared across threads, and WASM has only one.
+    static PERSISTENT: RefCell<Option<Rc<dyn PersistentCache>>> = const { RefCell::new(None) };
+}
+
+/// Set the persistent store consulted when an embedding isn't in memory.
+pub fn set_persistent_cache(cache: Option<Rc<dyn PersistentCache>>) {
+    PERSISTENT.with(|x| *x.borrow_mut() = cache);
+}
+
+/// The persistent store, if any.
+pub fn persistent_cache() -> Option<Rc<dyn PersistentCache>> {
+    PERSISTENT.with(|x| x.borrow().clone())
+}
+
+/// A key for the embedding of `text` that is stable across sessions.
+pub fn cache_key(model: EmbeddingModel, dimensions: Option<usize>, text: &str) -> String {
+    let model = serde_json::to_string(&model).unwrap_or_default();
+    let dimensions = dimensions.map_or(String::new(), |x| x.to_string());
+    let mut hasher = Sha256::new();
+    hasher.update(model.as_bytes());
+    hasher.update([0]);
+    hasher.update(dimensions.as_bytes());
+    hasher.update([0]);
+    hasher.update(text.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.put("a".to_string(), vec![1.0]);
+        cache.put("b".to_string(), vec![2.0]);
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+        cache.put("c".to_string(), vec![3.0]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+        cache.set_capacity(1);
+        assert_eq!(cache.get("c"), None);
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+    }
+
+    #[test]
+    fn keys_depend_on_model() {
+        let text = "abc";
+        assert_eq!(
+            cache_key(EmbeddingModel::TextEmbeddingAda002, None, text),
+            cache_key(EmbeddingModel::TextEmbeddingAda002, None, text)
+        );
+        assert_ne!(
+            cache_key(EmbeddingModel::TextEmbeddingAda002, None, text),
+            cache_key(EmbeddingModel::TextEmbedding3Small, None, text)
+        );
+    }
+}
@@ -2,20 +2,23 @@ use async_sse::{decode as sse_decode, Decoder, Event};
 use bytes::Bytes;
 use futures::stream::{IntoAsyncRead, StreamExt};
 use futures::{Stream, TryStreamExt};
-use schemars::{schema_for, JsonSchema};
+use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::pin::Pin;
 use std::time::Duration;
 use tap::Pipe;
 
-use super::{Error, FinishReason, Result};
-
-#[derive(Debug, Serialize, Deserialize)]
-enum ChatCompletionObjectValue {
-    #[serde(rename = "chat.completion")]
-    ChatCompletion,
-}
+use super::retry::{send_with_retries, Backoff, ReplyError};
+use super::schema::function_parameters;
+use super::trace::trace_usage;
+use super::usage::{Usage, UsageTracker};
+use super::{Error, FinishReason, Result, DEFAULT_TIMEOUT};
+use crate::cancel::CancelToken;
+use crate::config::{config, record_fingerprint, Config};
+use crate::http::{authorize, client};
+use crate::timer::timeout;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ChatCompletionMessageRole {
@@ -57,6 +60,129 @@ pub struct ChatCompletionMessage {
     pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<FunctionCall>,
+    /// Images shown to the model along with the `content`. Only user messages
+    /// to vision models can carry images.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageUrl>,
+}
+
+impl ChatCompletionMessage {
+    /// A message with `content` from the `role`.
+    pub fn new(role: ChatCompletionMessageRole, content: String) -> Self {
+        Self {
+            role,
+            content: Some(content),
+            name: None,
+            function_call: None,
+            images: Vec::new(),
+        }
+    }
+
+    pub fn system(content: String) -> Self {
+        Self::new(ChatCompletionMessageRole::System, content)
+    }
+
+    pub fn user(content: String) -> Self {
+        Self::new(ChatCompletionMessageRole::User, content)
+    }
+
+    pub fn assistant(content: String) -> Self {
+        Self::new(ChatCompletionMessageRole::Assistant, content)
+    }
+}
+
+/// An image attached to a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageUrl {
+    /// A link to the image, or the image itself as a base64 `data:` URL.
+    pub url: String,
+    /// `low`, `high` or `auto` resolution.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ImageUrl {
+    pub fn new(url: String) -> Self {
+        Self { url, detail: None }
+    }
+}
+
+/// An example exchange shown to the model before the actual instructions
+/// (few-shot prompting).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Example {
+    /// The example instructions.
+    pub user: String,
+    /// The reply the model should give to the `user` message.
+    pub assistant: String,
+}
+
+impl Example {
+    /// The user message and assistant reply of the exchange.
+    pub fn messages(examples: &[Example]) -> Vec<ChatCompletionMessage> {
+        examples
+            .iter()
+            .flat_map(|x| {
+                [
+                    (ChatCompletionMessageRole::User, &x.user),
+                    (ChatCompletionMessageRole::Assistant, &x.assistant),
+                ]
+            })
+            .map(|(role, content)| ChatCompletionMessage::new(role, content.clone()))
+            .collect()
+    }
+}
+
+/// A message as sent to the API, where the content of a message with images
+/// is a list of parts.
+#[derive(Debug, Serialize)]
+struct RequestMessage {
+    role: ChatCompletionMessageRole,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<RequestContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum RequestContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+impl From<&ChatCompletionMessage> for RequestMessage {
+    fn from(message: &ChatCompletionMessage) -> Self {
+        let content = if message.images.is_empty() {
+            message.content.clone().map(RequestContent::Text)
+        } else {
+            message
+                .content
+                .iter()
+                .map(|x| ContentPart::Text { text: x.clone() })
+                .chain(message.images.iter().map(|x| ContentPart::ImageUrl {
+                    image_url: x.clone(),
+                }))
+                .collect::<Vec<_>>()
+                .pipe(RequestContent::Parts)
+                .pipe(Some)
+        };
+        Self {
+            role: message.role.clone(),
+            content,
+            name: message.name.clone(),
+            function_call: message.function_call.clone(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -68,6 +194,11 @@ pub struct ChatCompletionChoice {
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct ChatCompletionResponse {
     pub choices: Vec<ChatCompletionChoice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Identifies the backend configuration that served the completion.
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -93,38 +224,74 @@ struct ChatCompletionChoiceUpdate {
 #[derive(Debug, Deserialize)]
 struct ChatCompletionResponseUpdate {
     choices: Vec<ChatCompletionChoiceUpdate>,
+    system_fingerprint: Option<String>,
+    /// Only set in the last chunk, which has no choices.
+    usage: Option<Usage>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ChatCompletionModel {
-    #[serde(rename = "gpt-4")]
-    Gpt4,
-    #[serde(rename = "gpt-4o")]
-    Gpt4o,
-    #[serde(rename = "gpt-4o-mini")]
-    Gpt4oMini,
-    #[serde(rename = "gpt-3.5-turbo")]
-    Gpt35Turbo,
-    #[serde(rename = "gpt-3.5-turbo-16k")]
-    Gpt35Turbo16k,
+/// A chat completion model, identified by its name in the API.
+///
+/// Any model the API accepts can be used. Its context window and pricing are
+/// looked up in the [model registry](super::models).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ChatCompletionModel(Cow<'static, str>);
+
+impl ChatCompletionModel {
+    pub const GPT_4O: Self = Self(Cow::Borrowed("gpt-4o"));
+    pub const GPT_4O_MINI: Self = Self(Cow::Borrowed("gpt-4o-mini"));
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for ChatCompletionModel {
+    fn from(name: String) -> Self {
+        Self(Cow::Owned(name))
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct ChatCompletionRequest {
     model: ChatCompletionModel,
-    messages: Vec<ChatCompletionMessage>,
+    messages: Vec<RequestMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<u16>,
+    /// Replaces `max_tokens` for reasoning models, and includes the reasoning
+    /// tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     functions: Option<Vec<FunctionArg>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     function_call: Option<FunctionCallArg>,
 }
 
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    /// Send a final chunk with the usage of the whole request.
+    include_usage: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct ChatCompletionArgs {
     pub key: String,
@@ -132,8 +299,23 @@ pub struct ChatCompletionArgs {
     pub model: ChatCompletionModel,
     pub max_tokens: Option<u16>,
     pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<i64>,
+    /// The number of choices to sample. Only the first is streamed.
+    pub n: Option<usize>,
     pub functions: Option<Vec<FunctionArg>>,
     pub function_call: Option<FunctionCallArg>,
+    /// The most times a completion cut off by the token limit is continued.
+    pub max_continuations: usize,
+    pub backoff: Backoff,
+    /// Give up on a request if the server doesn't respond in this time.
+    pub timeout: Option<Duration>,
+    pub client: reqwest::Client,
+    /// Records the tokens used by the requests.
+    pub usage: UsageTracker,
 }
 
 impl ChatCompletionArgs {
@@ -141,11 +323,22 @@ impl ChatCompletionArgs {
         Self {
             key,
             messages: Vec::new(),
-            model: ChatCompletionModel::Gpt4o,
+            model: ChatCompletionModel::GPT_4O,
             max_tokens: None,
             temperature: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            stop: None,
+            seed: None,
+            n: None,
             functions: None,
             function_call: None,
+            max_continuations: 0,
+            backoff: Backoff::default(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            client: client(),
+            usage: UsageTracker::default(),
         }
     }
 
@@ -164,6 +357,11 @@ impl ChatCompletionArgs {
         self
     }
 
+    /// Add the `examples` exchanges, before the message they illustrate.
+    pub fn with_examples(self, examples: &[Example]) -> Self {
+        self.with_messages(Example::messages(examples))
+    }
+
     pub fn with_temperature(mut self, temperature: f32) -> Self {
         self.temperature = Some(temperature);
         self
@@ -187,48 +385,232 @@ impl ChatCompletionArgs {
         self.function_call = Some(function_call);
         self
     }
+
+    /// Sample `n` choices, e.g. to pick the best of them.
+    pub fn with_n(mut self, n: usize) -> Self {
+        self.n = (n > 1).then_some(n);
+        self
+    }
+
+    pub fn with_max_continuations(mut self, max_continuations: usize) -> Self {
+        self.max_continuations = max_continuations;
+        self
+    }
+
+    pub fn with_usage(mut self, usage: &UsageTracker) -> Self {
+        self.usage = usage.clone();
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// The request body, overridden by the library-wide `config`.
+    ///
+    /// Reasoning models are sent `max_completion_tokens` instead of
+    /// `max_tokens`, and none of the sampling parameters or stop sequences
+    /// they reject.
+    fn request(&self, stream: bool, config: &Config) -> ChatCompletionRequest {
+        let (temperature, seed) = if config.deterministic {
+            (Some(0.0), Some(config.seed))
+        } else {
+            (self.temperature, self.seed)
+        };
+        let reasoning = self.model.info().reasoning;
+        let sampling = |x: Option<f32>| if reasoning { None } else { x };
+        ChatCompletionRequest {
+            model: self.model.clone(),
+            messages: self.messages.iter().map(RequestMessage::from).collect(),
+            max_tokens: if reasoning { None } else { self.max_tokens },
+            max_completion_tokens: if reasoning { self.max_tokens } else { None },
+            temperature: sampling(temperature),
+            top_p: sampling(self.top_p),
+            frequency_penalty: sampling(self.frequency_penalty),
+            presence_penalty: sampling(self.presence_penalty),
+            stop: if reasoning { None } else { self.stop.clone() },
+            seed,
+            // the stream updates don't tell the choices apart
+            n: if stream { None } else { self.n },
+            stream: Some(stream),
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+            functions: self.functions.clone(),
+            function_call: self.function_call.clone(),
+        }
+    }
+}
+
+const CONTINUE_INSTRUCTIONS: &str = "\
+Your previous reply was cut off. \
+Continue it exactly where it stopped, without repeating any of it.\
+";
+
+/// The arguments to continue the partial `response`, either because its stream
+/// was interrupted or because it reached the token limit.
+///
+/// The partial content is sent back as an assistant message so the model
+/// continues it. Without content there is nothing to salvage, so the
+/// `response` is cleared and the original request re-issued.
+fn continue_args(
+    args: &ChatCompletionArgs,
+    response: &mut ChatCompletionResponse,
+) -> ChatCompletionArgs {
+    let partial = response
+        .choices
+        .first()
+        .and_then(|x| x.message.content.clone())
+        .filter(|x| !x.is_empty());
+    match partial {
+        Some(partial) => args
+            .clone()
+            .with_message(ChatCompletionMessage::assistant(partial))
+            .with_message(ChatCompletionMessage::user(
+                CONTINUE_INSTRUCTIONS.to_string(),
+            )),
+        None => {
+            response.choices.clear();
+            args.clone()
+        }
+    }
+}
+
+/// Whether the model stopped because it reached the token limit while
+/// writing content that can be continued.
+///
+/// Truncated function calls aren't continued: their arguments can't be
+/// stitched together reliably.
+fn is_truncated(response: &ChatCompletionResponse) -> bool {
+    response.choices.first().is_some_and(|x| {
+        x.finish_reason == Some(FinishReason::Length) && x.message.function_call.is_none()
+    })
+}
+
+/// Whether the content filter stopped the model.
+fn is_filtered(response: &ChatCompletionResponse) -> bool {
+    response
+        .choices
+        .first()
+        .is_some_and(|x| x.finish_reason == Some(FinishReason::ContentFilter))
+}
+
+/// Append the content of a `continuation` to the `response` it continues.
+fn merge_continuation(response: &mut ChatCompletionResponse, continuation: ChatCompletionResponse) {
+    let (Some(choice), Some(next)) = (
+        response.choices.first_mut(),
+        continuation.choices.into_iter().next(),
+    ) else {
+        return;
+    };
+    if let Some(content) = next.message.content {
+        choice
+            .message
+            .content
+            .get_or_insert_with(String::new)
+            .push_str(&content);
+    }
+    choice.finish_reason = next.finish_reason;
+    response.usage = match (response.usage, continuation.usage) {
+        (Some(x), Some(y)) => Some(x + y),
+        (x, y) => x.or(y),
+    };
 }
 
 /// Request a chat completion.
+///
+/// If the completion is cut off by the token limit, it is continued up to
+/// [`ChatCompletionArgs::max_continuations`] times.
 pub async fn chat_completion(
     args: ChatCompletionArgs,
     max_retries: usize,
 ) -> Result<ChatCompletionResponse> {
-    let mut n_retried: usize = 0;
-    loop {
-        match reqwest::Client::new()
-            .post("https://api.openai.com/v1/chat/completions")
-            .bearer_auth(args.key.clone())
-            .json(&ChatCompletionRequest {
-                model: args.model.clone(),
-                messages: args.messages.clone(),
-                max_tokens: args.max_tokens,
-                temperature: args.temperature,
-                stream: Some(false),
-                functions: args.functions.clone(),
-                function_call: args.function_call.clone(),
-            })
-            .send()
-            .await
-        {
-            Ok(response) => {
-                return response
-                    .json::<ChatCompletionResponse>()
-                    .await
-                    .map_err(Error::InvalidChatCompletion)?
-                    .pipe(Ok);
-            }
-            Err(err) => {
-                if err.status().is_some_and(|x| x.is_server_error()) && n_retried < max_retries {
-                    std::thread::sleep(Duration::from_secs(2.0f64.powi(n_retried as i32) as u64));
-                    n_retried += 1;
-                    continue;
-                } else {
-                    return Err(Error::NetworkError);
-                }
-            }
-        }
+    let mut response = request_completion(&args, max_retries).await?;
+    let mut continuations = 0;
+    while continuations < args.max_continuations && is_truncated(&response) {
+        continuations += 1;
+        let continuation =
+            request_completion(&continue_args(&args, &mut response), max_retries).await?;
+        merge_continuation(&mut response, continuation);
+    }
+    Ok(response)
+}
+
+async fn request_completion(
+    args: &ChatCompletionArgs,
+    max_retries: usize,
+) -> Result<ChatCompletionResponse> {
+    let response = send_with_retries(
+        || {
+            args.client
+                .post("https://api.openai.com/v1/chat/completions")
+                .pipe(|x| authorize(x, &args.key))
+                .json(&args.request(false, &config()))
+        },
+        args.timeout,
+        &args.backoff,
+        max_retries,
+    )
+    .await?
+    .json::<ChatCompletionResponse>()
+    .pipe(|x| timeout(args.timeout, x))
+    .await
+    .map_err(|_| Error::Timeout)?
+    .map_err(|e| match e {
+        ReplyError::Http(e) => Error::InvalidChatCompletion(e),
+        ReplyError::Format(e) => Error::FormatError(e),
+    })?;
+    if let Some(usage) = &response.usage {
+        args.usage.record(usage, &args.model.pricing());
+        trace_usage(args.model.name(), usage);
+    }
+    if let Some(fingerprint) = &response.system_fingerprint {
+        record_fingerprint(fingerprint);
     }
+    if is_filtered(&response) {
+        return Err(Error::ContentFilter);
+    }
+    Ok(response)
+}
+
+/// Set up `args` to call the function `name` whose parameters are the schema
+/// of `T`.
+fn function_args<T>(
+    args: ChatCompletionArgs,
+    name: String,
+    description: Option<String>,
+) -> Result<ChatCompletionArgs>
+where
+    T: JsonSchema,
+{
+    let parameters = function_parameters::<T>(config().portable_schemas)
+        .map_err(Error::FunctionParameterError)?;
+    args.with_no_functions()
+        .with_function(FunctionArg {
+            name: name.clone(),
+            description,
+            parameters,
+        })
+        .with_function_call(FunctionCallArg { name })
+        .pipe(Ok)
+}
+
+/// Stream a chat completion whose output is a JSON object of type `T`.
+///
+/// The JSON is incomplete until the stream is done. Unlike
+/// [`chat_completion_function`], a malformed output isn't retried.
+pub async fn chat_completion_function_stream<T>(
+    args: ChatCompletionArgs,
+    name: String,
+    description: Option<String>,
+    max_retries: usize,
+) -> Result<ChatCompletionParts>
+where
+    T: JsonSchema,
+{
+    ChatCompletionParts::new(function_args::<T>(args, name, description)?, max_retries).await
 }
 
 /// Request a chat completion whose output is a JSON object of type `T`.
@@ -244,45 +626,57 @@ pub async fn chat_completion_function<T>(
 where
     T: DeserializeOwned + JsonSchema,
 {
-    let parameters = serde_json::to_value(schema_for!(T)).map_err(Error::FunctionParameterError)?;
+    chat_completion_function_select(args, name, description, max_retries, |mut x| {
+        x.swap_remove(0)
+    })
+    .await
+}
+
+/// Request a chat completion whose output is a JSON object of type `T`, and
+/// `select` the best of the choices that parse.
+///
+/// The args can sample several choices with [`ChatCompletionArgs::with_n`].
+/// Choices without a function call or with malformed arguments are skipped.
+/// `select` is called with at least one output, in the order of the choices.
+/// The completion is retried when no choice parses.
+pub async fn chat_completion_function_select<T>(
+    args: ChatCompletionArgs,
+    name: String,
+    description: Option<String>,
+    max_retries: usize,
+    select: impl Fn(Vec<T>) -> T,
+) -> Result<T>
+where
+    T: DeserializeOwned + JsonSchema,
+{
+    let args = function_args::<T>(args, name, description)?;
     let mut n_retried = 0;
     loop {
-        let args = args
-            .clone()
-            .with_no_functions()
-            .with_function(FunctionArg {
-                name: name.clone(),
-                description: description.clone(),
-                parameters: parameters.clone(),
-            })
-            .with_function_call(FunctionCallArg { name: name.clone() });
+        let args = args.clone();
         let args = if n_retried > 0 {
             args.with_temperature(0.5)
         } else {
             args
         };
         let response = chat_completion(args, max_retries).await?;
-        let message = response
-            .choices
-            .into_iter()
-            .next()
-            .ok_or(Error::EmptyChatCompletion)?
-            .message;
-        let function_call = message
-            .function_call
-            .clone()
-            .ok_or(Error::EmptyChatCompletion)?;
-        match serde_json::from_str::<T>(&function_call.arguments) {
-            Ok(result) => return Ok(result),
-            Err(err) => {
-                if n_retried < max_retries {
-                    n_retried += 1;
-                    continue;
-                } else {
-                    return Err(Error::FunctionFormatError(err));
-                }
+        let mut error = Error::EmptyChatCompletion;
+        let mut outputs = Vec::new();
+        for choice in response.choices {
+            let Some(function_call) = choice.message.function_call else {
+                continue;
+            };
+            match serde_json::from_str::<T>(&function_call.arguments) {
+                Ok(output) => outputs.push(output),
+                Err(err) => error = Error::FunctionFormatError(err),
             }
         }
+        if !outputs.is_empty() {
+            return Ok(select(outputs));
+        }
+        if n_retried >= max_retries {
+            return Err(error);
+        }
+        n_retried += 1;
     }
 }
 
@@ -297,6 +691,12 @@ fn update_response(response: &mut ChatCompletionResponse, data: &[u8]) -> Result
     }
     let update: ChatCompletionResponseUpdate =
         serde_json::from_str(&data).map_err(Error::FormatError)?;
+    if update.system_fingerprint.is_some() {
+        response.system_fingerprint = update.system_fingerprint;
+    }
+    if update.usage.is_some() {
+        response.usage = update.usage;
+    }
     if let Some(ChatCompletionChoiceUpdate {
         delta,
         finish_reason,
@@ -313,6 +713,7 @@ fn update_response(response: &mut ChatCompletionResponse, data: &[u8]) -> Result
                             name: x.name.unwrap_or(String::new()),
                             arguments: x.arguments.unwrap_or(String::new()),
                         }),
+                        images: Vec::new(),
                     },
                     finish_reason: None,
                 });
@@ -369,94 +770,232 @@ type ReqwestStreamItem = std::result::Result<Bytes, reqwest::Error>;
 type BoxedIoStream = Pin<Box<dyn Stream<Item = std::result::Result<Bytes, std::io::Error>>>>;
 type Events = Decoder<IntoAsyncRead<BoxedIoStream>>;
 
+/// The most times an interrupted stream is re-issued.
+const MAX_RESUMES: usize = 2;
+
 /// Streaming chat completion response.
 pub struct ChatCompletionParts {
     events: Events,
     response: ChatCompletionResponse,
+    cancel: CancelToken,
+    args: ChatCompletionArgs,
+    max_retries: usize,
+    resumes: usize,
+    continuations: usize,
+    /// The usage of the requests whose streams are done.
+    usage: Option<Usage>,
+    finished: bool,
 }
 
 impl ChatCompletionParts {
     async fn new_stream(
-        args: ChatCompletionArgs,
+        args: &ChatCompletionArgs,
         max_retries: usize,
     ) -> Result<impl Stream<Item = ReqwestStreamItem>> {
-        let mut n_retried = 0;
-        loop {
-            match reqwest::Client::new()
-                .post("https://api.openai.com/v1/chat/completions")
-                .bearer_auth(args.key.clone())
-                .json(&ChatCompletionRequest {
-                    model: args.model.clone(),
-                    messages: args.messages.clone(),
-                    max_tokens: args.max_tokens,
-                    temperature: args.temperature,
-                    stream: Some(true),
-                    functions: args.functions.clone(),
-                    function_call: args.function_call.clone(),
-                })
-                .send()
-                .await
-            {
-                Ok(response) => {
-                    return response.bytes_stream().pipe(Ok);
-                }
-                Err(err) => {
-                    if err.status().is_some_and(|x| x.is_server_error()) && n_retried < max_retries
-                    {
-                        std::thread::sleep(Duration::from_secs(
-                            2.0f64.powi(n_retried as i32) as u64
-                        ));
-                        n_retried += 1;
-                        continue;
-                    } else {
-                        return Err(Error::NetworkError);
-                    }
-                }
-            }
-        }
+        send_with_retries(
+            || {
+                args.client
+                    .post("https://api.openai.com/v1/chat/completions")
+                    .pipe(|x| authorize(x, &args.key))
+                    .json(&args.request(true, &config()))
+            },
+            args.timeout,
+            &args.backoff,
+            max_retries,
+        )
+        .await?
+        .bytes_stream()
+        .pipe(Ok)
     }
 
-    pub async fn new(args: ChatCompletionArgs, max_retries: usize) -> Result<ChatCompletionParts> {
+    async fn new_events(args: &ChatCompletionArgs, max_retries: usize) -> Result<Events> {
         // TODO: map into error types that can be handled
         let stream: BoxedIoStream = Self::new_stream(args, max_retries)
             .await?
             .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))
             .boxed_local();
         let reader = stream.into_async_read();
-        let events = sse_decode(reader);
+        sse_decode(reader).pipe(Ok)
+    }
+
+    pub async fn new(args: ChatCompletionArgs, max_retries: usize) -> Result<ChatCompletionParts> {
         ChatCompletionParts {
-            events,
+            events: Self::new_events(&args, max_retries).await?,
             response: ChatCompletionResponse {
                 choices: Vec::new(),
+                usage: None,
+                system_fingerprint: None,
             },
+            cancel: CancelToken::new(),
+            args,
+            max_retries,
+            resumes: 0,
+            continuations: 0,
+            usage: None,
+            finished: false,
         }
         .pipe(Ok)
     }
 
+    /// Stop streaming the response once `cancel` is cancelled.
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// The response received so far.
+    ///
+    /// If streaming fails, this is the partial response that can be salvaged.
+    pub fn response(&self) -> &ChatCompletionResponse {
+        &self.response
+    }
+
+    /// The function call arguments received so far.
+    pub fn function_arguments(&self) -> Option<&str> {
+        self.response
+            .choices
+            .first()
+            .and_then(|x| x.message.function_call.as_ref())
+            .map(|x| x.arguments.as_str())
+    }
+
+    /// Parse the function call arguments once the stream is done.
+    pub fn function_output<T>(&self) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let arguments = self
+            .function_arguments()
+            .ok_or(Error::EmptyChatCompletion)?;
+        serde_json::from_str(arguments).map_err(Error::FunctionFormatError)
+    }
+
+    /// The tokens used by the requests, known once the stream is done.
+    pub fn usage(&self) -> Option<&Usage> {
+        self.finished.then_some(self.usage.as_ref()).flatten()
+    }
+
+    /// Record the usage reported by the stream that just ended.
+    fn end_request(&mut self) {
+        if let Some(usage) = self.response.usage.take() {
+            self.args.usage.record(&usage, &self.args.model.pricing());
+            trace_usage(self.args.model.name(), &usage);
+            self.usage = Some(match self.usage {
+                Some(total) => total + usage,
+                None => usage,
+            });
+        }
+    }
+
+    /// Record the usage and fingerprint of the completed response, once.
+    fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.end_request();
+        self.response.usage = self.usage;
+        if let Some(fingerprint) = &self.response.system_fingerprint {
+            record_fingerprint(fingerprint);
+        }
+    }
+
+    /// Re-issue the request to continue a response cut off by the token
+    /// limit.
+    ///
+    /// Returns `false` if the response was continued too many times already.
+    async fn continue_truncated(&mut self) -> Result<bool> {
+        if self.continuations >= self.args.max_continuations {
+            return Ok(false);
+        }
+        self.continuations += 1;
+        self.end_request();
+        let args = continue_args(&self.args, &mut self.response);
+        if let Some(choice) = self.response.choices.first_mut() {
+            choice.finish_reason = None;
+        }
+        self.events = self
+            .cancel
+            .run(Self::new_events(&args, self.max_retries))
+            .await
+            .map_err(|_| Error::Cancelled)??;
+        Ok(true)
+    }
+
+    /// Re-issue the request after the stream was interrupted, continuing from
+    /// the partial response.
+    ///
+    /// Returns `false` if the stream was resumed too many times already.
+    async fn resume(&mut self) -> Result<bool> {
+        if self.resumes >= MAX_RESUMES {
+            return Ok(false);
+        }
+        self.resumes += 1;
+        self.end_request();
+        let args = continue_args(&self.args, &mut self.response);
+        self.events = self
+            .cancel
+            .run(Self::new_events(&args, self.max_retries))
+            .await
+            .map_err(|_| Error::Cancelled)??;
+        Ok(true)
+    }
+
     /// Update the response from the stream.
     ///
-    /// Returns `None` when the stream is done.
+    /// Returns `None` when the stream is done, [`Error::ContentFilter`] if the
+    /// content filter stopped the model, and an error if an event can't be
+    /// parsed. If the stream is interrupted or goes without data for the
+    /// `stall_timeout` of the [`config`], or the response is cut off by the
+    /// token limit and [`ChatCompletionArgs::max_continuations`] allows it,
+    /// the request is re-issued to continue the partial response. The usage
+    /// of every request is added up.
     pub async fn next(&mut self) -> Result<Option<&ChatCompletionResponse>> {
         loop {
-            let event = match self.events.next().await {
-                Some(event) => event,
-                // return None to stop iteration
-                None => break Ok(None),
+            let stall_timeout = config().stall_timeout;
+            let event = match self
+                .cancel
+                .run(timeout(stall_timeout, self.events.next()))
+                .await
+                .map_err(|_| Error::Cancelled)?
+            {
+                Err(_) if self.resume().await? => continue,
+                Err(_) => break Err(Error::StreamStalled),
+                Ok(Some(event)) => event,
+                Ok(None) => {
+                    let complete = self
+                        .response
+                        .choices
+                        .first()
+                        .is_some_and(|x| x.finish_reason.is_some());
+                    if !self.finished {
+                        if !complete && self.resume().await? {
+                            continue;
+                        }
+                        if is_truncated(&self.response) && self.continue_truncated().await? {
+                            continue;
+                        }
+                    }
+                    self.finish();
+                    // return None to stop iteration
+                    break Ok(None);
+                }
             };
-            match event {
+            let interrupted = match event {
                 Ok(Event::Message(message)) => {
                     match update_response(&mut self.response, message.data()) {
                         Ok(false) => continue,
+                        Ok(true) if is_filtered(&self.response) => break Err(Error::ContentFilter),
                         Ok(true) => break Ok(Some(&self.response)),
-                        Err(_) => break Err(Error::NetworkError),
+                        Err(e) => break Err(e),
                     }
                 }
                 Ok(Event::Retry(_)) => continue,
-                Err(_) => {
-                    // TODO: retry
-                    break Err(Error::NetworkError);
-                }
+                Err(_) => true,
             };
+            if interrupted && !self.resume().await? {
+                break Err(Error::NetworkError);
+            }
         }
     }
 }
@@ -465,10 +1004,190 @@ impl ChatCompletionParts {
 mod test {
     use super::*;
 
+    #[test]
+    fn request_serializes_sampling() {
+        let mut args = ChatCompletionArgs::new(String::new());
+        args.top_p = Some(0.5);
+        args.stop = Some(vec!["abc".to_string()]);
+        args.seed = Some(1);
+        let request = serde_json::to_value(args.request(false, &Config::default())).unwrap();
+        assert_eq!(request["top_p"], 0.5);
+        assert_eq!(request["stop"], serde_json::json!(["abc"]));
+        assert_eq!(request["seed"], 1);
+        assert!(request.get("presence_penalty").is_none());
+    }
+
+    #[test]
+    fn request_serializes_images() {
+        let request = ChatCompletionArgs::new(String::new())
+            .with_message(ChatCompletionMessage {
+                images: vec![ImageUrl::new("data:image/png;base64,AAAA".to_string())],
+                ..ChatCompletionMessage::user("abc".to_string())
+            })
+            .with_message(ChatCompletionMessage::user("bcd".to_string()))
+            .request(false, &Config::default());
+        let request = serde_json::to_value(request).unwrap();
+        assert_eq!(
+            request["messages"],
+            serde_json::json!([
+                {
+                    "role": "user",
+                    "content": [
+                        {"type": "text", "text": "abc"},
+                        {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}
+                    ]
+                },
+                {"role": "user", "content": "bcd"}
+            ])
+        );
+    }
+
+    #[test]
+    fn examples_alternate_roles() {
+        let args = ChatCompletionArgs::new(String::new()).with_examples(&[Example {
+            user: "abc".to_string(),
+            assistant: "bcd".to_string(),
+        }]);
+        let roles = args
+            .messages
+            .iter()
+            .map(|x| x.role.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            vec![
+                ChatCompletionMessageRole::User,
+                ChatCompletionMessageRole::Assistant
+            ]
+        );
+        assert_eq!(args.messages[1].content.as_deref(), Some("bcd"));
+    }
+
+    #[test]
+    fn request_shaped_for_reasoning_models() {
+        let mut args = ChatCompletionArgs::new(String::new())
+            .with_model(ChatCompletionModel::from("o3-mini".to_string()))
+            .with_temperature(0.5);
+        args.top_p = Some(0.5);
+        args.max_tokens = Some(100);
+        args.stop = Some(vec!["abc".to_string()]);
+        let request = serde_json::to_value(args.request(false, &Config::default())).unwrap();
+        assert_eq!(request["max_completion_tokens"], 100);
+        assert!(request.get("max_tokens").is_none());
+        assert!(request.get("temperature").is_none());
+        assert!(request.get("top_p").is_none());
+        assert!(request.get("stop").is_none());
+        let args = args.with_model(ChatCompletionModel::GPT_4O);
+        let request = serde_json::to_value(args.request(false, &Config::default())).unwrap();
+        assert_eq!(request["max_tokens"], 100);
+        assert_eq!(request["temperature"], 0.5);
+    }
+
+    #[test]
+    fn request_deterministic() {
+        let config = Config {
+            deterministic: true,
+            seed: 2,
+            ..Default::default()
+        };
+        let request = ChatCompletionArgs::new(String::new())
+            .with_temperature(0.5)
+            .request(false, &config);
+        let request = serde_json::to_value(request).unwrap();
+        assert_eq!(request["temperature"], 0.0);
+        assert_eq!(request["seed"], 2);
+    }
+
+    #[test]
+    fn request_includes_stream_usage() {
+        let args = ChatCompletionArgs::new(String::new());
+        let request = serde_json::to_value(args.request(true, &Config::default())).unwrap();
+        assert_eq!(request["stream_options"]["include_usage"], true);
+        let request = serde_json::to_value(args.request(false, &Config::default())).unwrap();
+        assert!(request.get("stream_options").is_none());
+    }
+
+    #[test]
+    fn updates_response_usage() {
+        let mut response = ChatCompletionResponse {
+            choices: Vec::new(),
+            usage: None,
+            system_fingerprint: None,
+        };
+        let data = r#"{"choices":[],"usage":{"prompt_tokens":1,"completion_tokens":2}}"#;
+        assert!(!update_response(&mut response, data.as_bytes()).unwrap());
+        assert_eq!(
+            response.usage,
+            Some(Usage {
+                prompt_tokens: 1,
+                completion_tokens: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn continues_partial_content() {
+        let args = ChatCompletionArgs::new(String::new());
+        let mut response = ChatCompletionResponse {
+            choices: vec![ChatCompletionChoice {
+                message: ChatCompletionMessage::assistant("abc".to_string()),
+                finish_reason: None,
+            }],
+            usage: None,
+            system_fingerprint: None,
+        };
+        let resumed = continue_args(&args, &mut response);
+        assert_eq!(resumed.messages.len(), 2);
+        assert_eq!(resumed.messages[0].content.as_deref(), Some("abc"));
+        assert_eq!(response.choices.len(), 1);
+
+        response.choices[0].message.content = None;
+        let resumed = continue_args(&args, &mut response);
+        assert!(resumed.messages.is_empty());
+        assert!(response.choices.is_empty());
+    }
+
+    #[test]
+    fn merges_continuations() {
+        let response = |content: &str, finish_reason, prompt_tokens| ChatCompletionResponse {
+            choices: vec![ChatCompletionChoice {
+                message: ChatCompletionMessage::assistant(content.to_string()),
+                finish_reason: Some(finish_reason),
+            }],
+            usage: Some(Usage {
+                prompt_tokens,
+                completion_tokens: 1,
+            }),
+            system_fingerprint: None,
+        };
+        let mut merged = response("abc", FinishReason::Length, 1);
+        assert!(is_truncated(&merged));
+        merge_continuation(&mut merged, response("def", FinishReason::Stop, 2));
+        assert!(!is_truncated(&merged));
+        assert_eq!(
+            merged,
+            ChatCompletionResponse {
+                usage: Some(Usage {
+                    prompt_tokens: 3,
+                    completion_tokens: 2,
+                }),
+                ..response("abcdef", FinishReason::Stop, 0)
+            }
+        );
+    }
+
+    #[test]
+    fn args_with_backoff() {
+        let args = ChatCompletionArgs::new(String::new()).with_backoff(Backoff::none());
+        assert_eq!(args.backoff, Backoff::none());
+    }
+
     #[test]
     fn updates_empty_response() {
         let mut response = ChatCompletionResponse {
             choices: Vec::new(),
+            usage: None,
+            system_fingerprint: None,
         };
         let data = r#"{"choices":[{"delta":{"role":"assistant"}}]}"#.as_bytes();
         assert!(update_response(&mut response, data).unwrap());
@@ -476,14 +1195,11 @@ mod test {
             response,
             ChatCompletionResponse {
                 choices: vec![ChatCompletionChoice {
-                    message: ChatCompletionMessage {
-                        role: ChatCompletionMessageRole::Assistant,
-                        content: Some(String::new()),
-                        name: None,
-                        function_call: None,
-                    },
+                    message: ChatCompletionMessage::assistant(String::new()),
                     finish_reason: None,
                 }],
+                usage: None,
+                system_fingerprint: None,
             }
         );
     }
@@ -492,14 +1208,11 @@ mod test {
     fn updates_response_content() {
         let mut response = ChatCompletionResponse {
             choices: vec![ChatCompletionChoice {
-                message: ChatCompletionMessage {
-                    role: ChatCompletionMessageRole::Assistant,
-                    content: Some("abc".to_string()),
-                    name: None,
-                    function_call: None,
-                },
+                message: ChatCompletionMessage::assistant("abc".to_string()),
                 finish_reason: None,
             }],
+            usage: None,
+            system_fingerprint: None,
         };
         let data = r#"{"choices":[{"delta":{"content":"def"}}]}"#.as_bytes();
         assert!(update_response(&mut response, data).unwrap());
@@ -507,14 +1220,11 @@ mod test {
             response,
             ChatCompletionResponse {
                 choices: vec![ChatCompletionChoice {
-                    message: ChatCompletionMessage {
-                        role: ChatCompletionMessageRole::Assistant,
-                        content: Some("abcdef".to_string()),
-                        name: None,
-                        function_call: None,
-                    },
+                    message: ChatCompletionMessage::assistant("abcdef".to_string()),
                     finish_reason: None,
                 }],
+                usage: None,
+                system_fingerprint: None,
             }
         )
     }
@@ -523,14 +1233,11 @@ mod test {
     fn updates_response_function_call() {
         let mut response = ChatCompletionResponse {
             choices: vec![ChatCompletionChoice {
-                message: ChatCompletionMessage {
-                    role: ChatCompletionMessageRole::Assistant,
-                    content: Some(String::new()),
-                    name: None,
-                    function_call: None,
-                },
+                message: ChatCompletionMessage::assistant(String::new()),
                 finish_reason: None,
             }],
+            usage: None,
+            system_fingerprint: None,
         };
         let data = r#"{"choices":[{"delta":{"function_call":{"name":"abc"}}}]}"#.as_bytes();
         assert!(update_response(&mut response, data).unwrap());
@@ -546,9 +1253,12 @@ mod test {
                             name: "abc".to_string(),
                             arguments: String::new(),
                         }),
+                        images: Vec::new(),
                     },
                     finish_reason: None,
                 }],
+                usage: None,
+                system_fingerprint: None,
             }
         )
     }
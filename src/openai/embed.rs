@@ -1,18 +1,35 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 use tap::Pipe;
 
+use super::cache::{cache_key, memory_cache, persistent_cache};
+use super::retry::{send_with_retries, Backoff};
+use super::trace::trace_usage;
+use super::usage::{Usage, UsageTracker};
 use super::{Error, Result};
+use crate::http::{authorize, client};
+use crate::timer::timeout;
 
-#[derive(Debug, Deserialize)]
-enum EmbeddingObjectValue {
-    #[serde(rename = "embedding")]
-    Embedding,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
 pub enum EmbeddingModel {
+    #[default]
     #[serde(rename = "text-embedding-ada-002")]
     TextEmbeddingAda002,
+    #[serde(rename = "text-embedding-3-small")]
+    TextEmbedding3Small,
+    #[serde(rename = "text-embedding-3-large")]
+    TextEmbedding3Large,
+}
+
+impl EmbeddingModel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingModel::TextEmbeddingAda002 => "text-embedding-ada-002",
+            EmbeddingModel::TextEmbedding3Small => "text-embedding-3-small",
+            EmbeddingModel::TextEmbedding3Large => "text-embedding-3-large",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -23,30 +40,102 @@ struct EmbeddingData {
 #[derive(Debug, Deserialize)]
 struct EmbeddingResponse {
     data: Vec<EmbeddingData>,
+    #[serde(default)]
+    usage: Option<Usage>,
 }
 
 #[derive(Debug, Serialize)]
 struct EmbeddingRequest<'a> {
     model: EmbeddingModel,
     input: &'a str,
+    /// Shorten the embedding, only supported by the `text-embedding-3` models.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<usize>,
 }
 
-/// Generate an embedding for the given `text`.
-pub async fn embed(token: &str, text: &str) -> Result<Vec<f32>> {
-    reqwest::Client::new()
-        .post("https://api.openai.com/v1/embeddings")
-        .bearer_auth(token)
-        .json(&EmbeddingRequest {
-            model: EmbeddingModel::TextEmbeddingAda002,
-            input: text,
-        })
-        .send()
-        .await
-        .map_err(|_| Error::InvalidEmbedding)?
-        .json::<EmbeddingResponse>()
-        .await
-        .ok()
-        .and_then(|x| x.data.into_iter().next())
+/// Generate an embedding for the given `text` with the `model`.
+///
+/// If `dimensions` is provided, the embedding is shortened to that many
+/// dimensions. Embeddings are looked up in the [cache](super::cache) before
+/// making a request. Fails with [`Error::Timeout`] if the server doesn't
+/// respond within `time_limit`. Requests that fail because of rate limits or
+/// server errors are retried up to `max_retries` times. The tokens used are
+/// recorded in `usage`.
+pub async fn embed(
+    token: &str,
+    text: &str,
+    model: EmbeddingModel,
+    dimensions: Option<usize>,
+    time_limit: Option<Duration>,
+    usage: &UsageTracker,
+    max_retries: usize,
+) -> Result<Vec<f32>> {
+    let key = cache_key(model, dimensions, text);
+    if let Some(embedding) = memory_cache().lock().unwrap().get(&key) {
+        return Ok(embedding);
+    }
+    let persistent = persistent_cache();
+    if let Some(persistent) = &persistent {
+        if let Some(embedding) = persistent.get(&key).await {
+            memory_cache().lock().unwrap().put(key, embedding.clone());
+            return Ok(embedding);
+        }
+    }
+    let embedding = request_embedding(
+        token,
+        text,
+        model,
+        dimensions,
+        time_limit,
+        usage,
+        max_retries,
+    )
+    .await?;
+    if let Some(persistent) = &persistent {
+        persistent.put(&key, &embedding).await;
+    }
+    memory_cache().lock().unwrap().put(key, embedding.clone());
+    Ok(embedding)
+}
+
+async fn request_embedding(
+    token: &str,
+    text: &str,
+    model: EmbeddingModel,
+    dimensions: Option<usize>,
+    time_limit: Option<Duration>,
+    usage: &UsageTracker,
+    max_retries: usize,
+) -> Result<Vec<f32>> {
+    let response = send_with_retries(
+        || {
+            client()
+                .post("https://api.openai.com/v1/embeddings")
+                .pipe(|x| authorize(x, token))
+                .json(&EmbeddingRequest {
+                    model,
+                    input: text,
+                    dimensions,
+                })
+        },
+        time_limit,
+        &Backoff::default(),
+        max_retries,
+    )
+    .await?
+    .json::<EmbeddingResponse>()
+    .pipe(|x| timeout(time_limit, x))
+    .await
+    .map_err(|_| Error::Timeout)?
+    .map_err(|_| Error::InvalidEmbedding)?;
+    if let Some(x) = &response.usage {
+        usage.record(x, &model.pricing());
+        trace_usage(model.name(), x);
+    }
+    response
+        .data
+        .into_iter()
+        .next()
         .map(|x| x.embedding)
         .ok_or(Error::InvalidEmbedding)?
         .pipe(Ok)
@@ -0,0 +1,119 @@
+//! Check that an API key can be used before starting the Clint process.
+
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::limit::limiter;
+use super::{Error, Result};
+use crate::http::{client, with_headers};
+use crate::timer::timeout;
+
+/// The cheapest model, used for the 1-token completion.
+const PROBE_MODEL: &str = "gpt-4o-mini";
+
+/// Whether a key can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStatus {
+    Valid,
+    /// The key is wrong, revoked, or not allowed to use the API.
+    Invalid,
+    /// The key is valid but its account has run out of credits.
+    NoQuota,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetails {
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetails,
+}
+
+/// Get the status of a key from the response to the probe, or `None` if the
+/// response doesn't tell.
+fn key_status(status: StatusCode, body: &str) -> Option<KeyStatus> {
+    let code = || {
+        serde_json::from_str::<ErrorResponse>(body)
+            .ok()
+            .and_then(|x| x.error.code)
+    };
+    match status {
+        x if x.is_success() => Some(KeyStatus::Valid),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(KeyStatus::Invalid),
+        StatusCode::TOO_MANY_REQUESTS => match code().as_deref() {
+            Some("insufficient_quota") => Some(KeyStatus::NoQuota),
+            // rate limited, but the key was accepted
+            _ => Some(KeyStatus::Valid),
+        },
+        _ => None,
+    }
+}
+
+/// Check the `token` with a 1-token completion.
+///
+/// Unlike listing the models, a completion also tells whether the account has
+/// credits left. Fails with [`Error::NetworkError`] if the server can't tell.
+pub async fn validate_key(token: &str, time_limit: Option<Duration>) -> Result<KeyStatus> {
+    let permit = limiter().acquire().await;
+    let response = client()
+        .post("https://api.openai.com/v1/chat/completions")
+        .bearer_auth(token)
+        .pipe(with_headers)
+        .json(&serde_json::json!({
+            "model": PROBE_MODEL,
+            "messages": [{"role": "user", "content": "Hi"}],
+            "max_tokens": 1,
+        }))
+        .send()
+        .pipe(|x| timeout(time_limit, x))
+        .await
+        .map_err(|_| Error::Timeout)?
+        .map_err(|_| Error::NetworkError)?;
+    drop(permit);
+    let status = response.status();
+    let body = response
+        .text()
+        .pipe(|x| timeout(time_limit, x))
+        .await
+        .map_err(|_| Error::Timeout)?
+        .unwrap_or_default();
+    key_status(status, &body).ok_or(Error::NetworkError)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_key_status() {
+        assert_eq!(key_status(StatusCode::OK, ""), Some(KeyStatus::Valid));
+        assert_eq!(
+            key_status(
+                StatusCode::UNAUTHORIZED,
+                r#"{"error": {"code": "invalid_api_key"}}"#
+            ),
+            Some(KeyStatus::Invalid)
+        );
+        assert_eq!(
+            key_status(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"error": {"code": "insufficient_quota"}}"#
+            ),
+            Some(KeyStatus::NoQuota)
+        );
+        assert_eq!(
+            key_status(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"error": {"code": null}}"#
+            ),
+            Some(KeyStatus::Valid)
+        );
+        assert_eq!(key_status(StatusCode::BAD_GATEWAY, ""), None);
+    }
+}
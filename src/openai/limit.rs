@@ -0,0 +1,183 @@
+//! Limit the requests made to the OpenAI API by all prompts.
+
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Poll, Waker};
+use std::time::Duration;
+
+use tap::Pipe;
+
+use crate::timer::{now_ms, sleep};
+
+/// Limits on the requests made to the API, unlimited by default since the
+/// right limits depend on the account's tier.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimits {
+    /// The most requests that can be waiting for a response at once, or
+    /// unlimited if `None`.
+    pub max_concurrent: Option<usize>,
+    /// The most requests that can start in a minute, or unlimited if `None`.
+    pub requests_per_minute: Option<f64>,
+}
+
+#[derive(Debug)]
+struct State {
+    limits: RateLimits,
+    in_flight: usize,
+    waiting: Vec<Waker>,
+    /// Requests that can start right away, refilled at the per-minute rate.
+    tokens: f64,
+    refilled_ms: f64,
+}
+
+impl State {
+    fn refill(&mut self, now: f64) {
+        if let Some(per_minute) = self.limits.requests_per_minute {
+            let elapsed = (now - self.refilled_ms).max(0.0);
+            self.tokens = (self.tokens + elapsed * per_minute / 60_000.0).min(per_minute.max(1.0));
+        }
+        self.refilled_ms = now;
+    }
+
+    /// Take a token, or get how long to wait before one is available.
+    fn take_token(&mut self, now: f64) -> Option<Duration> {
+        let per_minute = self.limits.requests_per_minute?;
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / per_minute.max(f64::EPSILON))
+                .pipe(Some)
+        }
+    }
+}
+
+/// A semaphore and token bucket shared by the requests it limits.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<State>>,
+}
+
+/// Allows a request to run. The request's slot is freed when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight = state.in_flight.saturating_sub(1);
+        for waker in state.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                limits,
+                in_flight: 0,
+                waiting: Vec::new(),
+                tokens: limits.requests_per_minute.unwrap_or(0.0).max(1.0),
+                refilled_ms: now_ms(),
+            })),
+        }
+    }
+
+    /// Change the limits. Requests already running aren't affected.
+    pub fn set_limits(&self, limits: RateLimits) {
+        let mut state = self.state.lock().unwrap();
+        state.limits = limits;
+        state.tokens = state
+            .tokens
+            .min(limits.requests_per_minute.unwrap_or(0.0).max(1.0));
+        for waker in state.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Wait until a request is allowed to start.
+    pub async fn acquire(&self) -> Permit {
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state
+                .limits
+                .max_concurrent
+                .is_none_or(|x| state.in_flight < x.max(1))
+            {
+                state.in_flight += 1;
+                Poll::Ready(())
+            } else {
+                state.waiting.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+        let permit = Permit {
+            state: self.state.clone(),
+        };
+        loop {
+            let wait = self.state.lock().unwrap().take_token(now_ms());
+            match wait {
+                Some(wait) => sleep(wait).await,
+                None => break permit,
+            }
+        }
+    }
+}
+
+/// The limiter shared by all requests to the API.
+pub fn limiter() -> &'static RateLimiter {
+    static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+    LIMITER.get_or_init(|| RateLimiter::new(RateLimits::default()))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn limits_concurrent_requests() {
+        let limiter = RateLimiter::new(RateLimits {
+            max_concurrent: Some(1),
+            requests_per_minute: None,
+        });
+        let permit = block_on(limiter.acquire());
+        assert!(limiter.acquire().now_or_never().is_none());
+        drop(permit);
+        assert!(limiter.acquire().now_or_never().is_some());
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let permits = (0..100)
+            .map(|_| limiter.acquire().now_or_never())
+            .collect::<Option<Vec<_>>>();
+        assert!(permits.is_some());
+    }
+
+    #[test]
+    fn waits_for_tokens() {
+        let mut state = State {
+            limits: RateLimits {
+                max_concurrent: Some(1),
+                requests_per_minute: Some(60.0),
+            },
+            in_flight: 0,
+            waiting: Vec::new(),
+            tokens: 1.0,
+            refilled_ms: 0.0,
+        };
+        assert_eq!(state.take_token(0.0), None);
+        assert_eq!(state.take_token(0.0), Some(Duration::from_secs(1)));
+        assert_eq!(state.take_token(1000.0), None);
+    }
+}
@@ -1,11 +1,27 @@
 //! Interact with OpenAI's GPT models.
 
+pub mod audio;
+pub mod cache;
 pub mod chat;
 pub mod embed;
+pub mod key;
+pub mod limit;
+pub mod models;
+pub mod moderate;
+#[cfg(feature = "replay")]
+pub mod replay;
+pub mod retry;
+pub mod schema;
+pub mod tokens;
+pub mod trace;
+pub mod tts;
+pub mod usage;
+
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
-use thiserror;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("chat encoding error: {0}")]
@@ -18,6 +34,16 @@ pub enum Error {
     FunctionFormatError(serde_json::Error),
     #[error("network didn't return expected response")]
     NetworkError,
+    #[error("rate limit exceeded, try again later")]
+    RateLimited,
+    #[error("request was cancelled")]
+    Cancelled,
+    #[error("request timed out")]
+    Timeout,
+    #[error("response stream stalled")]
+    StreamStalled,
+    #[error("response was blocked by the content filter")]
+    ContentFilter,
     #[error("failed to request chat completion: {0}")]
     InvalidChatCompletion(#[from] reqwest::Error),
     #[error("failed to get chat completion function output")]
@@ -26,6 +52,15 @@ pub enum Error {
     EmptyChatCompletion,
     #[error("failed to request embedding")]
     InvalidEmbedding,
+    #[error("failed to request moderation")]
+    InvalidModeration,
+    #[error("failed to request transcription")]
+    InvalidTranscription,
+    #[error("failed to request speech")]
+    InvalidSpeech,
+    #[cfg(feature = "replay")]
+    #[error("no fixture to replay for {0}")]
+    MissingFixture(String),
     #[error("failed to serailize embedding")]
     CantSerialize,
     #[error("failed to de-serailize embedding")]
@@ -34,9 +69,38 @@ pub enum Error {
 
 type Result<T> = core::result::Result<T, Error>;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
+/// How long to wait for a response before giving up on a request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FinishReason {
     Stop,
     Length,
+    ContentFilter,
+    ToolCalls,
+    FunctionCall,
+    /// A reason added to the API after this library.
+    #[serde(untagged)]
+    Other(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deserializes_finish_reasons() {
+        let reasons: Vec<FinishReason> =
+            serde_json::from_str(r#"["stop", "content_filter", "tool_calls", "abc"]"#).unwrap();
+        assert_eq!(
+            reasons,
+            vec![
+                FinishReason::Stop,
+                FinishReason::ContentFilter,
+                FinishReason::ToolCalls,
+                FinishReason::Other("abc".to_string()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,295 @@
+//! Properties of chat completion models, looked up by the model's name.
+//!
+//! New models can be registered at runtime so they can be used without a new
+//! release of the library.
+
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+use tap::Pipe;
+
+use super::chat::ChatCompletionModel;
+use super::usage::Pricing;
+
+/// The BPE tokenizer used by a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Cl100kBase,
+    O200kBase,
+}
+
+/// What the library needs to know to budget and price a model's requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelInfo {
+    /// The number of tokens the model accepts for the prompt and completion.
+    pub context_window: usize,
+    pub pricing: Pricing,
+    pub encoding: Encoding,
+    /// A reasoning model takes `max_completion_tokens` instead of
+    /// `max_tokens`, and rejects the sampling parameters like `temperature`
+    /// and `stop`.
+    pub reasoning: bool,
+}
+
+/// Assumed for models missing from the registry: a small context window so
+/// prompts aren't truncated by the API, and an unknown (zero) price.
+const UNKNOWN_MODEL: ModelInfo = ModelInfo {
+    context_window: 8_192,
+    pricing: Pricing {
+        prompt: 0.0,
+        completion: 0.0,
+    },
+    encoding: Encoding::O200kBase,
+    reasoning: false,
+};
+
+/// The models known at release, with prices in USD per million tokens.
+const KNOWN_MODELS: &[(&str, ModelInfo)] = &[
+    (
+        "gpt-4",
+        ModelInfo {
+            context_window: 8_192,
+            pricing: Pricing {
+                prompt: 30.0,
+                completion: 60.0,
+            },
+            encoding: Encoding::Cl100kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-4-turbo",
+        ModelInfo {
+            context_window: 128_000,
+            pricing: Pricing {
+                prompt: 10.0,
+                completion: 30.0,
+            },
+            encoding: Encoding::Cl100kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-4o",
+        ModelInfo {
+            context_window: 128_000,
+            pricing: Pricing {
+                prompt: 2.5,
+                completion: 10.0,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-4o-mini",
+        ModelInfo {
+            context_window: 128_000,
+            pricing: Pricing {
+                prompt: 0.15,
+                completion: 0.6,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-4.1",
+        ModelInfo {
+            context_window: 1_047_576,
+            pricing: Pricing {
+                prompt: 2.0,
+                completion: 8.0,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-4.1-mini",
+        ModelInfo {
+            context_window: 1_047_576,
+            pricing: Pricing {
+                prompt: 0.4,
+                completion: 1.6,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-4.1-nano",
+        ModelInfo {
+            context_window: 1_047_576,
+            pricing: Pricing {
+                prompt: 0.1,
+                completion: 0.4,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-3.5-turbo",
+        ModelInfo {
+            context_window: 16_385,
+            pricing: Pricing {
+                prompt: 0.5,
+                completion: 1.5,
+            },
+            encoding: Encoding::Cl100kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "gpt-3.5-turbo-16k",
+        ModelInfo {
+            context_window: 16_385,
+            pricing: Pricing {
+                prompt: 3.0,
+                completion: 4.0,
+            },
+            encoding: Encoding::Cl100kBase,
+            reasoning: false,
+        },
+    ),
+    (
+        "o1",
+        ModelInfo {
+            context_window: 200_000,
+            pricing: Pricing {
+                prompt: 15.0,
+                completion: 60.0,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: true,
+        },
+    ),
+    (
+        "o1-mini",
+        ModelInfo {
+            context_window: 128_000,
+            pricing: Pricing {
+                prompt: 1.1,
+                completion: 4.4,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: true,
+        },
+    ),
+    (
+        "o3",
+        ModelInfo {
+            context_window: 200_000,
+            pricing: Pricing {
+                prompt: 2.0,
+                completion: 8.0,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: true,
+        },
+    ),
+    (
+        "o3-mini",
+        ModelInfo {
+            context_window: 200_000,
+            pricing: Pricing {
+                prompt: 1.1,
+                completion: 4.4,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: true,
+        },
+    ),
+    (
+        "o4-mini",
+        ModelInfo {
+            context_window: 200_000,
+            pricing: Pricing {
+                prompt: 1.1,
+                completion: 4.4,
+            },
+            encoding: Encoding::O200kBase,
+            reasoning: true,
+        },
+    ),
+];
+
+fn registry() -> &'static RwLock<HashMap<String, ModelInfo>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, ModelInfo>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        KNOWN_MODELS
+            .iter()
+            .map(|&(name, info)| (name.to_string(), info))
+            .collect::<HashMap<_, _>>()
+            .pipe(RwLock::new)
+    })
+}
+
+/// Add or replace the properties of the model called `name`.
+pub fn register_model(name: String, info: ModelInfo) {
+    registry().write().unwrap().insert(name, info);
+}
+
+/// Find the registered model that `name` refers to.
+///
+/// Fine-tuned models (`ft:gpt-4o-mini:org::id`) resolve to their base model,
+/// and dated snapshots (`gpt-4o-2024-08-06`) to the longest registered name
+/// they extend.
+fn lookup(models: &HashMap<String, ModelInfo>, name: &str) -> Option<ModelInfo> {
+    if let Some(info) = models.get(name) {
+        return Some(*info);
+    }
+    let base = name
+        .strip_prefix("ft:")
+        .and_then(|x| x.split(':').next())
+        .unwrap_or(name);
+    if let Some(info) = models.get(base) {
+        return Some(*info);
+    }
+    models
+        .iter()
+        .filter(|(x, _)| {
+            base.strip_prefix(x.as_str())
+                .is_some_and(|rest| rest.starts_with('-'))
+        })
+        .max_by_key(|(x, _)| x.len())
+        .map(|(_, info)| *info)
+}
+
+impl ChatCompletionModel {
+    /// The registered properties of the model.
+    pub fn info(&self) -> ModelInfo {
+        lookup(&registry().read().unwrap(), self.name()).unwrap_or(UNKNOWN_MODEL)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn looks_up_variants() {
+        let models = registry().read().unwrap();
+        let mini = lookup(&models, "gpt-4o-mini").unwrap();
+        assert_eq!(lookup(&models, "gpt-4o-mini-2024-07-18"), Some(mini));
+        assert_eq!(lookup(&models, "ft:gpt-4o-mini:org::abc"), Some(mini));
+        assert_eq!(
+            lookup(&models, "gpt-4o-2024-08-06"),
+            lookup(&models, "gpt-4o")
+        );
+        assert_eq!(lookup(&models, "gpt-4oo"), None);
+    }
+
+    #[test]
+    fn registers_models() {
+        let model = ChatCompletionModel::from("clint-test-model".to_string());
+        assert_eq!(model.info(), UNKNOWN_MODEL);
+        let info = ModelInfo {
+            context_window: 1_000,
+            ..UNKNOWN_MODEL
+        };
+        register_model("clint-test-model".to_string(), info);
+        assert_eq!(model.info(), info);
+    }
+}
@@ -0,0 +1,125 @@
+//! Screen text with OpenAI's moderation endpoint.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::retry::{send_with_retries, Backoff};
+use super::{Error, Result};
+use crate::http::{authorize, client};
+use crate::timer::timeout;
+
+#[derive(Debug, Serialize)]
+struct ModerationRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    categories: HashMap<String, bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+/// The moderation verdict for a text.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Moderation {
+    /// Whether any category was flagged.
+    pub flagged: bool,
+    /// The flagged categories, e.g. `self-harm/intent`.
+    pub categories: Vec<String>,
+    /// Whether the text is about self-harm, in which case the user should be
+    /// pointed to crisis resources.
+    pub self_harm: bool,
+    /// Whether the text is harassment, hate or violence.
+    pub abuse: bool,
+}
+
+impl From<ModerationResult> for Moderation {
+    fn from(result: ModerationResult) -> Self {
+        let mut categories = result
+            .categories
+            .into_iter()
+            .filter_map(|(x, flagged)| flagged.then_some(x))
+            .collect::<Vec<_>>();
+        categories.sort();
+        let any = |prefixes: &[&str]| {
+            categories
+                .iter()
+                .any(|x| prefixes.iter().any(|y| x.starts_with(y)))
+        };
+        Moderation {
+            flagged: result.flagged,
+            self_harm: any(&["self-harm"]),
+            abuse: any(&["harassment", "hate", "violence"]),
+            categories,
+        }
+    }
+}
+
+/// Classify `text` with the moderation endpoint.
+pub async fn moderate(
+    token: &str,
+    text: &str,
+    time_limit: Option<Duration>,
+    max_retries: usize,
+) -> Result<Moderation> {
+    send_with_retries(
+        || {
+            client()
+                .post("https://api.openai.com/v1/moderations")
+                .pipe(|x| authorize(x, token))
+                .json(&ModerationRequest {
+                    model: "omni-moderation-latest",
+                    input: text,
+                })
+        },
+        time_limit,
+        &Backoff::default(),
+        max_retries,
+    )
+    .await?
+    .json::<ModerationResponse>()
+    .pipe(|x| timeout(time_limit, x))
+    .await
+    .map_err(|_| Error::Timeout)?
+    .map_err(|_| Error::InvalidModeration)?
+    .results
+    .into_iter()
+    .next()
+    .ok_or(Error::InvalidModeration)?
+    .pipe(Moderation::from)
+    .pipe(Ok)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summarizes_categories() {
+        let result: ModerationResult = serde_json::from_str(
+            r#"{
+                "flagged": true,
+                "categories": {"self-harm/intent": true, "harassment": false, "sexual": true}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Moderation::from(result),
+            Moderation {
+                flagged: true,
+                categories: vec!["self-harm/intent".to_string(), "sexual".to_string()],
+                self_harm: true,
+                abuse: false,
+            }
+        );
+    }
+}
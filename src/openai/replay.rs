@@ -0,0 +1,198 @@
+//! Record responses from OpenAI as fixtures, and replay them offline.
+//!
+//! Requests are matched to fixtures by their URL and body, so a replay serves
+//! the recorded responses as long as the prompts and settings are unchanged.
+//! This lets the whole Clint process run in tests without a key or network.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tap::Pipe;
+
+use super::retry::Reply;
+use super::{Error, Result};
+use crate::timer::timeout;
+
+/// What to do with the fixtures.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Send requests to the server and save the responses as fixtures.
+    Record,
+    /// Serve every request from the fixtures, failing if there is none.
+    Replay,
+}
+
+/// A recorded response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fixture {
+    /// The endpoint, to tell fixtures apart when reading them.
+    pub url: String,
+    /// The body, if it's text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// The body in hex, if it's binary (e.g. speech).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+}
+
+impl Fixture {
+    fn new(url: String, body: &[u8]) -> Self {
+        match std::str::from_utf8(body) {
+            Ok(text) => Self {
+                url,
+                text: Some(text.to_string()),
+                hex: None,
+            },
+            Err(_) => Self {
+                url,
+                text: None,
+                hex: Some(hex::encode(body)),
+            },
+        }
+    }
+
+    fn body(&self) -> Option<Vec<u8>> {
+        match (&self.text, &self.hex) {
+            (Some(text), _) => Some(text.as_bytes().to_vec()),
+            (None, Some(hex)) => hex::decode(hex).ok(),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The fixtures by request key.
+pub type Fixtures = BTreeMap<String, Fixture>;
+
+#[derive(Debug, Default)]
+struct State {
+    mode: Option<Mode>,
+    fixtures: Fixtures,
+}
+
+fn state() -> &'static Mutex<State> {
+    static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+    STATE.get_or_init(Default::default)
+}
+
+/// Record or replay the `fixtures`, or send requests as usual if `mode` is
+/// `None`.
+pub fn set_replay(mode: Option<Mode>, fixtures: Fixtures) {
+    *state().lock().unwrap() = State { mode, fixtures };
+}
+
+/// The fixtures, including those recorded so far.
+pub fn fixtures() -> Fixtures {
+    state().lock().unwrap().fixtures.clone()
+}
+
+/// The URL and the key of the request built by `build`.
+///
+/// The key is a hash of the URL and body. Headers, and so the API key, aren't
+/// part of it.
+fn request_key(build: &impl Fn() -> RequestBuilder) -> Result<(String, String)> {
+    let request = build().build().map_err(|_| Error::NetworkError)?;
+    let url = request.url().to_string();
+    let body = request
+        .body()
+        .and_then(|x| x.as_bytes())
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    hasher.update([0]);
+    hasher.update(body);
+    Ok((url, hex::encode(hasher.finalize())))
+}
+
+/// Serve the request built by `build` from the fixtures when replaying.
+///
+/// Returns `None` when not replaying, and fails with
+/// [`Error::MissingFixture`] if no fixture matches the request.
+pub(super) fn replay(build: &impl Fn() -> RequestBuilder) -> Result<Option<Reply>> {
+    let state = state().lock().unwrap();
+    if state.mode != Some(Mode::Replay) {
+        return Ok(None);
+    }
+    let (url, key) = request_key(build)?;
+    state
+        .fixtures
+        .get(&key)
+        .and_then(Fixture::body)
+        .ok_or(Error::MissingFixture(url))?
+        .pipe(|x| Reply::Body(x.into()))
+        .pipe(Some)
+        .pipe(Ok)
+}
+
+/// Save the `response` to the request built by `build` when recording.
+pub(super) async fn record(
+    build: &impl Fn() -> RequestBuilder,
+    response: Response,
+    time_limit: Option<Duration>,
+) -> Result<Reply> {
+    if state().lock().unwrap().mode != Some(Mode::Record) {
+        return Ok(Reply::Http(response));
+    }
+    let (url, key) = request_key(build)?;
+    let body = timeout(time_limit, response.bytes())
+        .await
+        .map_err(|_| Error::Timeout)?
+        .map_err(|_| Error::NetworkError)?;
+    state()
+        .lock()
+        .unwrap()
+        .fixtures
+        .insert(key, Fixture::new(url, &body));
+    Ok(Reply::Body(body))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::http::client;
+    use crate::openai::embed::{embed, EmbeddingModel};
+    use crate::openai::retry::{send_with_retries, Backoff};
+    use crate::openai::usage::UsageTracker;
+
+    #[test]
+    fn keeps_binary_fixtures() {
+        let fixture = Fixture::new(String::new(), &[0xff, 0x00]);
+        assert_eq!(fixture.hex.as_deref(), Some("ff00"));
+        assert_eq!(fixture.body(), Some(vec![0xff, 0x00]));
+    }
+
+    #[test]
+    fn replays_fixtures() {
+        let build = || {
+            client()
+                .post("https://api.openai.com/v1/embeddings")
+                .json(&serde_json::json!({"input": "abc"}))
+        };
+        let (url, key) = request_key(&build).unwrap();
+        let body = r#"{"data": [{"embedding": [1.0, 2.0]}]}"#;
+        set_replay(
+            Some(Mode::Replay),
+            [(key, Fixture::new(url, body.as_bytes()))].into(),
+        );
+        let reply = block_on(send_with_retries(build, None, &Backoff::none(), 0)).unwrap();
+        assert_eq!(block_on(reply.bytes()).unwrap(), body.as_bytes());
+        // the embedding request has a different body
+        let embedded = block_on(embed(
+            "",
+            "abc",
+            EmbeddingModel::default(),
+            None,
+            None,
+            &UsageTracker::default(),
+            0,
+        ));
+        assert!(matches!(embedded, Err(Error::MissingFixture(_))));
+        set_replay(None, Fixtures::new());
+    }
+}
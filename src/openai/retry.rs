@@ -0,0 +1,327 @@
+//! Retry requests that fail because of rate limits or server errors.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use tap::Pipe;
+
+use super::limit::limiter;
+#[cfg(feature = "replay")]
+use super::replay;
+use super::trace::{request_event, trace, tracing};
+use super::{Error, Result};
+use crate::config::{active_key, fail_over};
+use crate::http::with_key;
+use crate::timer::{now_ms, sleep, timeout};
+
+/// The policy for how long to wait between attempts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    /// The delay before the first retry. It doubles with every retry.
+    pub initial: Duration,
+    /// Never wait longer than this between two attempts.
+    pub max: Duration,
+    /// Up to this fraction of the delay is added at random so that parallel
+    /// requests don't all retry at the same moment.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// Retry immediately, ignoring `Retry-After`.
+    #[cfg(test)]
+    pub fn none() -> Self {
+        Self {
+            initial: Duration::ZERO,
+            max: Duration::ZERO,
+            jitter: 0.0,
+        }
+    }
+
+    /// The delay before the retry following `n_retried` retries.
+    ///
+    /// The delay grows exponentially and is never shorter than what the
+    /// server asked for with `retry_after`. `random` is a number in `[0, 1)`
+    /// that scales the jitter.
+    fn delay(&self, n_retried: usize, retry_after: Option<Duration>, random: f64) -> Duration {
+        let exponential = self.initial.mul_f64(2.0f64.powi(n_retried.min(16) as i32));
+        let delay = retry_after.map_or(exponential, |x| x.max(exponential));
+        delay.mul_f64(1.0 + self.jitter * random).min(self.max)
+    }
+}
+
+/// Can a request that returned `status` be retried?
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Was the key rejected, given the `status` and `body` of the response?
+///
+/// A key is rejected if it's invalid or out of quota, but not if it's only
+/// rate limited for now.
+fn is_rejected(status: StatusCode, body: Option<&str>) -> bool {
+    #[derive(serde::Deserialize)]
+    struct ErrorBody {
+        error: ErrorDetails,
+    }
+    #[derive(serde::Deserialize)]
+    struct ErrorDetails {
+        code: Option<String>,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+    }
+    let out_of_quota = || {
+        body.and_then(|x| serde_json::from_str::<ErrorBody>(x).ok())
+            .is_some_and(|x| {
+                [x.error.code, x.error.kind]
+                    .iter()
+                    .any(|x| x.as_deref() == Some("insufficient_quota"))
+            })
+    };
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::TOO_MANY_REQUESTS && out_of_quota()
+}
+
+/// Get the delay requested by the server in the `retry-after-ms` or
+/// `Retry-After` headers.
+///
+/// Only the delay-seconds form of `Retry-After` is supported, HTTP dates are
+/// ignored.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let retry_after_ms = headers
+        .get("retry-after-ms")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<f64>().ok())
+        .map(|x| x / 1000.0);
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<f64>().ok());
+    retry_after_ms
+        .or(retry_after)
+        .filter(|x| x.is_finite() && *x >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// A random number in `[0, 1)`.
+#[cfg(target_arch = "wasm32")]
+fn random_unit() -> f64 {
+    js_sys::Math::random()
+}
+
+/// A random number in `[0, 1)`.
+#[cfg(not(target_arch = "wasm32"))]
+fn random_unit() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+    (RandomState::new().hash_one(0u8) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A successful response, either from the server or already read.
+#[derive(Debug)]
+pub enum Reply {
+    Http(Response),
+    /// A body recorded or replayed from fixtures.
+    #[cfg_attr(not(feature = "replay"), allow(dead_code))]
+    Body(Bytes),
+}
+
+/// Failed to read a [`Reply`].
+#[derive(Debug, thiserror::Error)]
+pub enum ReplyError {
+    #[error("failed to read response: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("failed to parse response: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+impl Reply {
+    pub async fn bytes(self) -> std::result::Result<Bytes, ReplyError> {
+        match self {
+            Reply::Http(response) => response.bytes().await?.pipe(Ok),
+            Reply::Body(body) => Ok(body),
+        }
+    }
+
+    pub async fn json<T: DeserializeOwned>(self) -> std::result::Result<T, ReplyError> {
+        let body = self.bytes().await?;
+        serde_json::from_slice::<T>(&body)?.pipe(Ok)
+    }
+
+    pub fn bytes_stream(self) -> LocalBoxStream<'static, reqwest::Result<Bytes>> {
+        match self {
+            Reply::Http(response) => response.bytes_stream().boxed_local(),
+            Reply::Body(body) => stream::once(async { Ok(body) }).boxed_local(),
+        }
+    }
+}
+
+/// Send the request built by `build`, retrying up to `max_retries` times when
+/// the server is rate limiting or failing.
+///
+/// Each attempt fails with [`Error::Timeout`] if the server doesn't respond
+/// within `time_limit`. If keys are set, the request is sent with the active
+/// key, and if the key is rejected or out of quota, the request is sent again
+/// right away with the next key, without counting as a retry.
+///
+/// With the `replay` feature, the response may be served from or recorded to
+/// the fixtures (see [`replay`]).
+pub async fn send_with_retries(
+    build: impl Fn() -> RequestBuilder,
+    time_limit: Option<Duration>,
+    backoff: &Backoff,
+    max_retries: usize,
+) -> Result<Reply> {
+    #[cfg(feature = "replay")]
+    if let Some(reply) = replay::replay(&build)? {
+        return Ok(reply);
+    }
+    let mut n_retried: usize = 0;
+    loop {
+        let permit = limiter().acquire().await;
+        // the key index and the key sent must agree for the fail over
+        let active = active_key();
+        let key = active.as_ref().map(|(x, _)| *x);
+        let request = match &active {
+            Some((_, active)) => with_key(build(), active).map_err(|_| Error::NetworkError)?,
+            None => build(),
+        };
+        let started = now_ms();
+        let sent = timeout(time_limit, request.send()).await;
+        drop(permit);
+        let event = |status: Option<StatusCode>, response: Option<&str>, error: Option<&str>| {
+            let status = status.map(|x| x.as_u16());
+            let latency_ms = now_ms() - started;
+            request_event(&build, n_retried, key, status, latency_ms, response, error)
+        };
+        let sent = match sent {
+            Ok(sent) => sent,
+            Err(_) => {
+                trace(|| event(None, None, Some("timed out")));
+                return Err(Error::Timeout);
+            }
+        };
+        let (status, delay, body) = match sent {
+            Ok(response) if response.status().is_success() => {
+                trace(|| event(Some(response.status()), None, None));
+                #[cfg(feature = "replay")]
+                return replay::record(&build, response, time_limit).await;
+                #[cfg(not(feature = "replay"))]
+                return Ok(Reply::Http(response));
+            }
+            Ok(response) => {
+                let status = response.status();
+                let delay = retry_after(response.headers());
+                // the body tells a key out of quota from one rate limited
+                let body = if tracing() || status == StatusCode::TOO_MANY_REQUESTS {
+                    let body = timeout(time_limit, response.text()).await;
+                    body.ok().and_then(|x| x.ok())
+                } else {
+                    None
+                };
+                trace(|| event(Some(status), body.as_deref(), None));
+                (status, delay, body)
+            }
+            Err(err) => {
+                trace(|| event(err.status(), None, Some(&err.to_string())));
+                match err.status() {
+                    Some(status) => (status, None, None),
+                    None => return Err(Error::NetworkError),
+                }
+            }
+        };
+        if is_rejected(status, body.as_deref()) && key.is_some_and(fail_over) {
+            continue;
+        }
+        if !is_retryable(status) {
+            return Err(Error::NetworkError);
+        }
+        if n_retried >= max_retries {
+            return if status == StatusCode::TOO_MANY_REQUESTS {
+                Err(Error::RateLimited)
+            } else {
+                Err(Error::NetworkError)
+            };
+        }
+        sleep(backoff.delay(n_retried, delay, random_unit())).await;
+        n_retried += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn retries_rate_limit_and_server_errors() {
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn rejects_invalid_keys_and_keys_out_of_quota() {
+        let quota = r#"{"error": {"type": "insufficient_quota", "code": "insufficient_quota"}}"#;
+        let rate = r#"{"error": {"type": "requests", "code": "rate_limit_exceeded"}}"#;
+        assert!(is_rejected(StatusCode::UNAUTHORIZED, None));
+        assert!(is_rejected(StatusCode::TOO_MANY_REQUESTS, Some(quota)));
+        assert!(!is_rejected(StatusCode::TOO_MANY_REQUESTS, Some(rate)));
+        assert!(!is_rejected(StatusCode::TOO_MANY_REQUESTS, None));
+        assert!(!is_rejected(StatusCode::BAD_GATEWAY, Some(quota)));
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+        headers.insert("retry-after-ms", HeaderValue::from_static("250"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn ignores_retry_after_date() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.delay(0, None, 0.0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2, None, 0.0), Duration::from_secs(4));
+        assert_eq!(backoff.delay(2, None, 1.0), Duration::from_secs(6));
+        assert_eq!(backoff.delay(30, None, 1.0), backoff.max);
+    }
+
+    #[test]
+    fn backs_off_at_least_retry_after() {
+        let delay = Backoff::default().delay(0, Some(Duration::from_secs(10)), 0.0);
+        assert_eq!(delay, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn backs_off_none() {
+        let delay = Backoff::none().delay(3, Some(Duration::from_secs(10)), 1.0);
+        assert_eq!(delay, Duration::ZERO);
+    }
+}
@@ -0,0 +1,242 @@
+//! Make the JSON schemas of function parameters portable.
+//!
+//! `schemars` emits keywords (`$schema`, `format`, `definitions` with `$ref`)
+//! that OpenAI accepts but some compatible backends reject. The schemas are
+//! rewritten without references, and for those backends, without the
+//! validation keywords too, leaving only plain types, properties, items and
+//! descriptions.
+//!
+//! Every property is required, even those with a `serde` default, which is
+//! only there to read values saved before the property was added.
+
+use schemars::{schema_for, JsonSchema};
+use serde_json::{Map, Value};
+
+/// Keywords removed from every schema, as they're only needed for `$ref`.
+const STRUCTURAL: &[&str] = &["$schema", "$id", "title", "definitions", "$defs"];
+
+/// Keywords removed from portable schemas. OpenAI uses them to guide the
+/// model, but some compatible backends reject them.
+const VALIDATION: &[&str] = &[
+    "format",
+    "examples",
+    "default",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minItems",
+    "maxItems",
+    "uniqueItems",
+    "readOnly",
+    "writeOnly",
+];
+
+/// The schema of `T` as function parameters, with `$ref` inlined, every
+/// property required, and the validation keywords removed if `portable`.
+pub fn function_parameters<T: JsonSchema>(portable: bool) -> serde_json::Result<Value> {
+    let schema = serde_json::to_value(schema_for!(T))?;
+    Ok(simplify_schema(schema, portable))
+}
+
+/// Inline the `$ref` of `schema`, require every property, and remove the
+/// validation keywords if `portable`.
+pub fn simplify_schema(schema: Value, portable: bool) -> Value {
+    let definitions = ["definitions", "$defs"]
+        .iter()
+        .filter_map(|x| schema.get(x))
+        .filter_map(Value::as_object)
+        .flat_map(|x| x.clone())
+        .collect::<Map<_, _>>();
+    let removed = if portable {
+        [STRUCTURAL, VALIDATION].concat()
+    } else {
+        STRUCTURAL.to_vec()
+    };
+    Simplify {
+        definitions: &definitions,
+        removed: &removed,
+        seen: Vec::new(),
+    }
+    .simplify(schema)
+}
+
+struct Simplify<'a> {
+    definitions: &'a Map<String, Value>,
+    /// The keywords to remove.
+    removed: &'a [&'a str],
+    /// The definitions being inlined, to stop at recursive types.
+    seen: Vec<String>,
+}
+
+impl Simplify<'_> {
+    fn simplify(&mut self, schema: Value) -> Value {
+        match schema {
+            Value::Object(object) => self.simplify_object(object),
+            Value::Array(array) => array.into_iter().map(|x| self.simplify(x)).collect(),
+            x => x,
+        }
+    }
+
+    fn simplify_object(&mut self, mut object: Map<String, Value>) -> Value {
+        // schemars wraps a `$ref` with a description in a single `allOf`
+        if let Some(Value::Array(all_of)) = object.get("allOf") {
+            if let [Value::Object(inner)] = all_of.as_slice() {
+                let inner = inner.clone();
+                object.remove("allOf");
+                for (key, value) in inner {
+                    object.entry(key).or_insert(value);
+                }
+            }
+        }
+        let name = object
+            .get("$ref")
+            .and_then(Value::as_str)
+            .and_then(|x| x.rsplit('/').next())
+            .map(str::to_string);
+        if let Some(name) = name {
+            if let Some(Value::Object(definition)) = self.definitions.get(&name) {
+                if self.seen.contains(&name) {
+                    // a recursive type can't be inlined, so accept any value
+                    object.remove("$ref");
+                } else {
+                    object.remove("$ref");
+                    for (key, value) in definition {
+                        object.entry(key.clone()).or_insert(value.clone());
+                    }
+                    self.seen.push(name);
+                    let simplified = self.simplify_object(object);
+                    self.seen.pop();
+                    return simplified;
+                }
+            }
+        }
+        if let Some(Value::Object(properties)) = object.get("properties") {
+            let required = properties.keys().cloned().map(Value::String).collect();
+            object.insert("required".to_string(), Value::Array(required));
+        }
+        object
+            .into_iter()
+            .filter(|(key, _)| !self.removed.contains(&key.as_str()))
+            .map(|(key, value)| {
+                let value = match key.as_str() {
+                    // the property names are not schemas, but their values are
+                    "properties" => match value {
+                        Value::Object(properties) => properties
+                            .into_iter()
+                            .map(|(name, x)| (name, self.simplify(x)))
+                            .collect::<Map<_, _>>()
+                            .into(),
+                        x => x,
+                    },
+                    "required" | "enum" | "const" | "description" | "type" => value,
+                    _ => self.simplify(value),
+                };
+                (key, value)
+            })
+            .collect::<Map<_, _>>()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, JsonSchema, Deserialize)]
+    struct Inner {
+        /// A count.
+        count: u32,
+    }
+
+    #[derive(Debug, PartialEq, JsonSchema, Deserialize)]
+    struct Outer {
+        /// The inner value.
+        inner: Inner,
+        items: Vec<Inner>,
+    }
+
+    #[derive(Debug, PartialEq, JsonSchema, Deserialize)]
+    struct Defaulted {
+        name: String,
+        #[serde(default)]
+        count: u32,
+    }
+
+    #[derive(Debug, PartialEq, JsonSchema, Deserialize)]
+    struct Tree {
+        children: Vec<Tree>,
+    }
+
+    #[test]
+    fn inlines_refs_and_strips_keywords() {
+        let schema = function_parameters::<Outer>(true).unwrap();
+        let inner = serde_json::json!({
+            "type": "object",
+            "required": ["count"],
+            "properties": {"count": {"description": "A count.", "type": "integer"}},
+        });
+        let mut described = inner.clone();
+        described["description"] = "The inner value.".into();
+        assert_eq!(
+            schema,
+            serde_json::json!({
+                "type": "object",
+                "required": ["inner", "items"],
+                "properties": {
+                    "inner": described,
+                    "items": {"type": "array", "items": inner},
+                },
+            })
+        );
+        // the schema describes the arguments the type is parsed from
+        let arguments = serde_json::json!({"inner": {"count": 1}, "items": []});
+        assert_eq!(
+            serde_json::from_value::<Outer>(arguments).unwrap(),
+            Outer {
+                inner: Inner { count: 1 },
+                items: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn requires_defaulted_properties() {
+        let schema = function_parameters::<Defaulted>(true).unwrap();
+        let mut required = schema["required"].as_array().unwrap().clone();
+        required.sort_by_key(|x| x.to_string());
+        assert_eq!(required, ["count", "name"]);
+    }
+
+    #[test]
+    fn keeps_validation_keywords_unless_portable() {
+        let schema = function_parameters::<Inner>(false).unwrap();
+        let count = &schema["properties"]["count"];
+        assert_eq!(count["minimum"], 0.0);
+        assert_eq!(count["format"], "uint32");
+        assert!(schema.get("$schema").is_none());
+        assert!(schema.get("title").is_none());
+    }
+
+    #[test]
+    fn stops_at_recursive_types() {
+        let schema = function_parameters::<Tree>(true).unwrap();
+        assert_eq!(
+            schema["properties"]["children"]["items"]["properties"]["children"]["items"],
+            serde_json::json!({})
+        );
+        let tree = serde_json::from_value::<Tree>(serde_json::json!({"children": []})).unwrap();
+        assert_eq!(
+            tree,
+            Tree {
+                children: Vec::new()
+            }
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Count tokens to fit prompts in a model's context window.
+
+use super::chat::{ChatCompletionMessage, ChatCompletionModel};
+#[cfg(feature = "tiktoken")]
+use super::models::Encoding;
+
+/// Tokens added to every message for the role and delimiters.
+const TOKENS_PER_MESSAGE: usize = 3;
+
+/// Tokens added to prime the reply.
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Tokens used by an image, assuming a high-detail image of about 1024x1024
+/// pixels. The exact count depends on the size of the image.
+const TOKENS_PER_IMAGE: usize = 765;
+
+impl ChatCompletionModel {
+    /// The number of tokens the model accepts for the prompt and completion.
+    pub fn context_window(&self) -> usize {
+        self.info().context_window
+    }
+}
+
+/// Count the tokens in `text` using the model's BPE tokenizer.
+#[cfg(feature = "tiktoken")]
+pub fn count_tokens(model: &ChatCompletionModel, text: &str) -> usize {
+    let bpe = match model.info().encoding {
+        Encoding::O200kBase => tiktoken_rs::o200k_base_singleton(),
+        Encoding::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+    };
+    let bpe = bpe.lock();
+    bpe.encode_with_special_tokens(text).len()
+}
+
+/// Estimate the tokens in `text` at roughly four characters per token.
+#[cfg(not(feature = "tiktoken"))]
+pub fn count_tokens(_model: &ChatCompletionModel, text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Count the tokens used by `messages` in a prompt, including the reply
+/// priming.
+pub fn count_message_tokens(
+    model: &ChatCompletionModel,
+    messages: &[ChatCompletionMessage],
+) -> usize {
+    messages
+        .iter()
+        .map(|x| message_tokens(model, x))
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+fn message_tokens(model: &ChatCompletionModel, message: &ChatCompletionMessage) -> usize {
+    let content = message
+        .content
+        .as_deref()
+        .map_or(0, |x| count_tokens(model, x));
+    let name = message
+        .name
+        .as_deref()
+        .map_or(0, |x| count_tokens(model, x));
+    let function_call = message.function_call.as_ref().map_or(0, |x| {
+        count_tokens(model, &x.name) + count_tokens(model, &x.arguments)
+    });
+    let images = message.images.len() * TOKENS_PER_IMAGE;
+    TOKENS_PER_MESSAGE + content + name + function_call + images
+}
+
+/// Keep the leading `texts` whose tokens fit in `budget`.
+///
+/// The texts are ordered by priority, so the first text that doesn't fit
+/// drops it and all the following texts.
+pub fn fit_texts(model: &ChatCompletionModel, texts: Vec<String>, budget: usize) -> Vec<String> {
+    let mut used = 0;
+    texts
+        .into_iter()
+        .take_while(|x| {
+            used += count_tokens(model, x);
+            used <= budget
+        })
+        .collect()
+}
+
+/// Keep the most recent `messages` whose tokens fit in `budget`.
+pub fn fit_messages(
+    model: &ChatCompletionModel,
+    messages: Vec<ChatCompletionMessage>,
+    budget: usize,
+) -> Vec<ChatCompletionMessage> {
+    let mut used = 0;
+    let n_fit = messages
+        .iter()
+        .rev()
+        .take_while(|x| {
+            used += message_tokens(model, x);
+            used <= budget
+        })
+        .count();
+    let n_drop = messages.len() - n_fit;
+    messages.into_iter().skip(n_drop).collect()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn message(content: &str) -> ChatCompletionMessage {
+        ChatCompletionMessage::user(content.to_string())
+    }
+
+    #[test]
+    fn counts_tokens() {
+        let model = ChatCompletionModel::GPT_4O;
+        assert_eq!(count_tokens(&model, ""), 0);
+        assert!(count_tokens(&model, "The patient has a headache.") > 0);
+    }
+
+    #[test]
+    fn fits_leading_texts() {
+        let model = ChatCompletionModel::GPT_4O;
+        let texts = vec!["abc ".repeat(10), "abc ".repeat(100), "abc".to_string()];
+        let budget = count_tokens(&model, &texts[0]) + 1;
+        assert_eq!(fit_texts(&model, texts.clone(), budget), texts[..1]);
+    }
+
+    #[test]
+    fn fits_recent_messages() {
+        let model = ChatCompletionModel::GPT_4O;
+        let messages = vec![message(&"abc ".repeat(100)), message("abc"), message("bcd")];
+        let budget = message_tokens(&model, &messages[1]) + message_tokens(&model, &messages[2]);
+        assert_eq!(
+            fit_messages(&model, messages.clone(), budget),
+            messages[1..]
+        );
+    }
+}
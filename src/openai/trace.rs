@@ -0,0 +1,162 @@
+//! Trace the requests made to OpenAI to debug slow or failing prompts.
+//!
+//! Every attempt at a request emits an event with its latency and outcome,
+//! and every completed request its token usage. API keys are never included:
+//! headers aren't traced and anything that looks like a key is redacted from
+//! bodies and errors.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use reqwest::RequestBuilder;
+use serde::Serialize;
+
+use super::usage::Usage;
+
+/// Bodies are cut to this many characters.
+const MAX_BODY_CHARS: usize = 2000;
+
+/// An event emitted to the tracer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// An attempt at a request finished.
+    Request {
+        url: String,
+        /// The number of retries before this attempt.
+        attempt: usize,
+        /// The index of the key used, if keys are set.
+        key_index: Option<usize>,
+        /// The status code, if the server responded.
+        status: Option<u16>,
+        latency_ms: f64,
+        /// The request body, truncated.
+        request: String,
+        /// The body of an error response, truncated.
+        response: Option<String>,
+        /// Why the attempt failed, if the server didn't respond.
+        error: Option<String>,
+    },
+    /// The tokens used by a completed request.
+    Usage {
+        model: String,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    },
+}
+
+/// A callback that receives the trace events.
+pub type Tracer = Rc<dyn Fn(&TraceEvent)>;
+
+thread_local! {
+    // JS callbacks can't be shared across threads, and WASM has only one.
+    static TRACER: RefCell<Option<Tracer>> = const { RefCell::new(None) };
+}
+
+/// Send the trace events to `tracer`, or stop tracing if `None`.
+pub fn set_tracer(tracer: Option<Tracer>) {
+    TRACER.with(|x| *x.borrow_mut() = tracer);
+}
+
+/// Is a tracer listening?
+pub fn tracing() -> bool {
+    TRACER.with(|x| x.borrow().is_some())
+}
+
+/// Emit the event built by `event`, which is only built if a tracer is
+/// listening.
+pub fn trace(event: impl FnOnce() -> TraceEvent) {
+    // clone the tracer so it can set another tracer from the callback
+    if let Some(tracer) = TRACER.with(|x| x.borrow().clone()) {
+        tracer(&event());
+    }
+}
+
+/// Emit the tokens used by a request to `model`.
+pub fn trace_usage(model: &str, usage: &Usage) {
+    trace(|| TraceEvent::Usage {
+        model: model.to_string(),
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+    });
+}
+
+/// Replace anything that looks like an OpenAI key (`sk-...`) in `text`.
+fn redact(text: &str) -> String {
+    let is_key_char = |x: char| x.is_ascii_alphanumeric() || x == '-' || x == '_';
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("sk-") {
+        // skip words that end in `sk`, like `risk-`
+        let in_word = rest[..start].chars().next_back().is_some_and(is_key_char);
+        redacted.push_str(&rest[..start + 3]);
+        rest = &rest[start + 3..];
+        if !in_word {
+            redacted.push_str("***");
+            rest = rest.trim_start_matches(is_key_char);
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Redact and truncate a body.
+pub fn clean_body(body: &str) -> String {
+    let mut body = redact(body);
+    if let Some((end, _)) = body.char_indices().nth(MAX_BODY_CHARS) {
+        body.truncate(end);
+        body.push_str("...");
+    }
+    body
+}
+
+/// Describe an attempt at the request built by `build`.
+pub fn request_event(
+    build: &impl Fn() -> RequestBuilder,
+    attempt: usize,
+    key_index: Option<usize>,
+    status: Option<u16>,
+    latency_ms: f64,
+    response: Option<&str>,
+    error: Option<&str>,
+) -> TraceEvent {
+    let request = build().build().ok();
+    TraceEvent::Request {
+        url: request
+            .as_ref()
+            .map_or(String::new(), |x| x.url().to_string()),
+        attempt,
+        key_index,
+        status,
+        latency_ms,
+        request: request
+            .as_ref()
+            .and_then(|x| x.body())
+            .and_then(|x| x.as_bytes())
+            .map_or(String::new(), |x| clean_body(&String::from_utf8_lossy(x))),
+        response: response.map(clean_body),
+        error: error.map(redact),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redacts_keys() {
+        assert_eq!(
+            redact("Incorrect API key provided: sk-proj-abc_123. See"),
+            "Incorrect API key provided: sk-***. See"
+        );
+        assert_eq!(redact("no key"), "no key");
+        assert_eq!(redact("low-risk-factor"), "low-risk-factor");
+    }
+
+    #[test]
+    fn truncates_bodies() {
+        let body = "a".repeat(MAX_BODY_CHARS + 1);
+        assert_eq!(clean_body(&body).len(), MAX_BODY_CHARS + 3);
+        assert_eq!(clean_body("abc"), "abc");
+    }
+}
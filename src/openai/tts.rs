@@ -0,0 +1,74 @@
+//! Read text aloud with OpenAI's text-to-speech endpoint.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::retry::{send_with_retries, Backoff};
+use super::{Error, Result};
+use crate::http::{authorize, client};
+use crate::timer::timeout;
+
+const SPEECH_MODEL: &str = "tts-1";
+
+/// The voices the speech is read in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Voice {
+    #[default]
+    Alloy,
+    Ash,
+    Coral,
+    Echo,
+    Fable,
+    Onyx,
+    Nova,
+    Sage,
+    Shimmer,
+}
+
+#[derive(Debug, Serialize)]
+struct SpeechRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+    voice: Voice,
+    response_format: &'a str,
+}
+
+/// Read `text` aloud in the `voice`.
+///
+/// Returns the speech as MP3 audio. The endpoint accepts at most 4096
+/// characters of `text`.
+pub async fn speak(
+    token: &str,
+    text: &str,
+    voice: Voice,
+    time_limit: Option<Duration>,
+    max_retries: usize,
+) -> Result<Vec<u8>> {
+    send_with_retries(
+        || {
+            client()
+                .post("https://api.openai.com/v1/audio/speech")
+                .pipe(|x| authorize(x, token))
+                .json(&SpeechRequest {
+                    model: SPEECH_MODEL,
+                    input: text,
+                    voice,
+                    response_format: "mp3",
+                })
+        },
+        time_limit,
+        &Backoff::default(),
+        max_retries,
+    )
+    .await?
+    .bytes()
+    .pipe(|x| timeout(time_limit, x))
+    .await
+    .map_err(|_| Error::Timeout)?
+    .map_err(|_| Error::InvalidSpeech)?
+    .to_vec()
+    .pipe(Ok)
+}
@@ -0,0 +1,124 @@
+//! Track the tokens used by requests and estimate their cost.
+
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use super::chat::ChatCompletionModel;
+use super::embed::EmbeddingModel;
+
+/// Tokens used by a request, as reported in the response's `usage` field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+}
+
+impl std::ops::Add for Usage {
+    type Output = Usage;
+
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+        }
+    }
+}
+
+/// Price in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Pricing {
+    /// The cost in USD of the tokens in `usage`.
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+impl ChatCompletionModel {
+    /// The list price of the model's tokens.
+    pub fn pricing(&self) -> Pricing {
+        self.info().pricing
+    }
+}
+
+impl EmbeddingModel {
+    /// The list price of the model's tokens.
+    pub fn pricing(&self) -> Pricing {
+        let prompt = match self {
+            EmbeddingModel::TextEmbeddingAda002 => 0.1,
+            EmbeddingModel::TextEmbedding3Small => 0.02,
+            EmbeddingModel::TextEmbedding3Large => 0.13,
+        };
+        Pricing {
+            prompt,
+            completion: 0.0,
+        }
+    }
+}
+
+/// Tokens and estimated cost accumulated over many requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotal {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Estimated cost in USD.
+    pub cost: f64,
+}
+
+/// Accumulates the usage of requests that share it.
+///
+/// Cloning the tracker shares the accumulated total.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageTracker {
+    total: Arc<Mutex<UsageTotal>>,
+}
+
+impl UsageTracker {
+    /// Add the `usage` of a request priced at `pricing`.
+    pub fn record(&self, usage: &Usage, pricing: &Pricing) {
+        let mut total = self.total.lock().unwrap();
+        total.prompt_tokens += usage.prompt_tokens;
+        total.completion_tokens += usage.completion_tokens;
+        total.cost += pricing.cost(usage);
+    }
+
+    /// The usage accumulated so far.
+    pub fn total(&self) -> UsageTotal {
+        *self.total.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tracker_accumulates_usage() {
+        let tracker = UsageTracker::default();
+        let usage = Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+        };
+        tracker
+            .clone()
+            .record(&usage, &ChatCompletionModel::GPT_4O.pricing());
+        tracker.record(&usage, &ChatCompletionModel::GPT_4O.pricing());
+        assert_eq!(
+            tracker.total(),
+            UsageTotal {
+                prompt_tokens: 2_000_000,
+                completion_tokens: 1_000_000,
+                cost: 15.0,
+            }
+        );
+    }
+}
@@ -1,15 +1,17 @@
-use futures::future::join_all;
+use std::collections::HashSet;
+
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tap::Pipe;
 
-use super::utils::{embed_for_db, get_excerpt, quote_lines, Error, Result, SYSTEM_IDENTITY};
-use crate::docdb::DocDb;
-use crate::openai::chat::{
-    chat_completion_function, ChatCompletionArgs, ChatCompletionMessage, ChatCompletionMessageRole,
-    ChatCompletionModel,
+use super::config::TaskConfig;
+use super::templates::Template;
+use super::utils::{
+    call_function_select, embed_for_db, fit_excerpts, get_excerpts, quote_lines, similar_documents,
+    system_identity, Function, Result,
 };
-use crate::utils::render_template;
+use crate::docdb::{decode_doc_id, DocDb, DocId, Filter};
+use crate::openai::usage::UsageTracker;
 
 #[derive(Debug, Default, JsonSchema, Deserialize)]
 pub struct CiteExcerpt {
@@ -17,6 +19,10 @@ pub struct CiteExcerpt {
     pub id: String,
     #[schemars(description = "The excerpt title.")]
     pub title: String,
+    #[schemars(
+        description = "A short passage of at least five words copied word for word from the excerpt that supports the message."
+    )]
+    pub quote: String,
 }
 
 #[derive(Debug, Default, JsonSchema, Deserialize)]
@@ -25,7 +31,24 @@ pub struct CiteDocuments {
     pub excerpts: Vec<CiteExcerpt>,
 }
 
-const MESSAGE_INSTRUCTIONS: &'static str = "\
+/// A document cited for a message, with the quote supporting the message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Citation {
+    pub id: DocId,
+    /// The title of the document, or the one given by the model if the
+    /// document has none.
+    pub title: String,
+    /// The date the document was last reviewed, if known.
+    pub reviewed: Option<String>,
+    pub quote: String,
+    /// The share of the quote found in the document, 1 if it's found whole.
+    pub score: f32,
+}
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "cite",
+    variables: &["excerpts", "message"],
+    default: "\
 Consider the following document excerpts and their IDs:
 
 {excerpts}
@@ -38,8 +61,10 @@ Provide select the most relevant documents excerpts to cite. \
 Cite only excerpts that are related to the contents of the above message. \
 Don't cite any excerpts if none are related to the message, \
 Include the excerpt's Markdown title and ID. \
-The ID can be found in the link `<id:...>`).\
-";
+The ID can be found in the link `<id:...>`). \
+Also include a short passage of the excerpt supporting the message, copied word for word.\
+",
+};
 
 #[derive(Serialize)]
 struct MessageInstructions {
@@ -48,13 +73,13 @@ struct MessageInstructions {
 }
 
 impl MessageInstructions {
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_INSTRUCTIONS, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
     }
 }
 
 impl MessageInstructions {
-    fn new(message: &str, excerpts: Vec<String>) -> Self {
+    fn new(message: &str, excerpts: &[String]) -> Self {
         Self {
             excerpts: excerpts
                 .iter()
@@ -66,43 +91,177 @@ impl MessageInstructions {
     }
 }
 
+/// The least share of the word triples of a quote found in the document for
+/// the quote to be verified, allowing for small differences such as elided
+/// words.
+const QUOTE_MATCH: f32 = 0.8;
+
+/// The fewest words of a quote, as a few common words such as "headache"
+/// appear in most documents and verify nothing.
+const MIN_QUOTE_WORDS: usize = 5;
+
+/// The lowercase words of `text`, ignoring punctuation.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// How much of the `quote` appears in the `document`, ignoring case and
+/// punctuation: 1 if it appears whole, else the share of its word triples
+/// that do, or 0 for quotes of fewer than [`MIN_QUOTE_WORDS`] words.
+fn quote_score(quote: &str, document: &str) -> f32 {
+    let (quote, document) = (words(quote), words(document));
+    if quote.len() < MIN_QUOTE_WORDS {
+        return 0.0;
+    }
+    if document.windows(quote.len()).any(|x| x == quote.as_slice()) {
+        return 1.0;
+    }
+    let triples = document.windows(3).collect::<HashSet<_>>();
+    let found = quote.windows(3).filter(|x| triples.contains(x)).count();
+    found as f32 / (quote.len() - 2) as f32
+}
+
+/// Keep the `excerpts` of the `retrieved` documents whose quote appears in
+/// the document, dropping those of other documents.
+async fn verify_quotes(
+    excerpts: Vec<CiteExcerpt>,
+    retrieved: &[DocId],
+    db: &DocDb,
+) -> Vec<Citation> {
+    let mut verified = Vec::new();
+    for excerpt in excerpts {
+        let Ok(hash) = decode_doc_id(excerpt.id.trim().as_bytes()) else {
+            continue;
+        };
+        if !retrieved.contains(&hash) {
+            continue;
+        }
+        let Ok(document) = db.get_chunk(&hash).await else {
+            continue;
+        };
+        let score = quote_score(&excerpt.quote, &document);
+        if score >= QUOTE_MATCH && !verified.iter().any(|x: &Citation| x.id == hash) {
+            verified.push(Citation {
+                id: hash,
+                title: db.get_title(&hash).map_or(excerpt.title, |x| x.to_string()),
+                reviewed: db.get_reviewed(&hash).map(|x| x.to_string()),
+                quote: excerpt.quote,
+                score,
+            });
+        }
+    }
+    verified
+}
+
+const FUNCTION: Function = Function {
+    name: "list_document_ids",
+    description: "List document IDs with supporting quotes.",
+};
+
+/// Merge the excerpts of all the samples, in order. Duplicates are dropped
+/// once the quotes are verified.
+fn all_excerpts(samples: Vec<CiteDocuments>) -> CiteDocuments {
+    CiteDocuments {
+        excerpts: samples.into_iter().flat_map(|x| x.excerpts).collect(),
+    }
+}
+
+/// Pick the documents to cite for the `message`, leaving out those in
+/// `exclude`, such as those already cited earlier in the conversation.
+///
+/// Each citation quotes its document, and those whose quote isn't found in
+/// the document are dropped, as the model can cite a plausible but unrelated
+/// document. If the `task` samples several completions, their citations are
+/// merged.
 pub async fn cite(
     message: &str,
+    exclude: &HashSet<DocId>,
     db: &DocDb,
     key: String,
-    max_retries: usize,
-) -> Result<CiteDocuments> {
-    let embedding = embed_for_db(message, db, &key).await?;
-    let hashes = db.get_similar(embedding.view(), 8, None);
-    let excerpts = hashes
-        .iter()
-        .map(|x| get_excerpt(x, db))
-        .pipe(join_all)
-        .await
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
-
-    chat_completion_function(
-        ChatCompletionArgs::new(key)
-            .with_model(ChatCompletionModel::Gpt4o)
-            .with_temperature(0.0)
-            .with_message(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::System,
-                content: Some(SYSTEM_IDENTITY.to_string()),
-                name: None,
-                function_call: None,
-            })
-            .with_message(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::User,
-                content: Some(MessageInstructions::new(message, excerpts).render()?),
-                name: None,
-                function_call: None,
-            }),
-        "list_document_ids".to_string(),
-        Some("List document IDs.".to_string()),
-        max_retries,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Vec<Citation>> {
+    let embedding = embed_for_db(message, db, &key, usage, task.max_retries).await?;
+    let filter = Filter::excluding(exclude.iter().copied());
+    let hashes = similar_documents(db, &embedding, Some(&filter), task)?;
+    let excerpts = get_excerpts(&hashes, db, message, task).await;
+
+    let (system, instructions) = fit_excerpts(excerpts, task, |x| {
+        let instructions = MessageInstructions::new(message, x).render(task)?;
+        Ok((system_identity(task), instructions))
+    })?;
+    let cited = call_function_select(
+        &FUNCTION,
+        system,
+        instructions,
+        key,
+        usage,
+        task,
+        all_excerpts,
     )
-    .await
-    .map_err(Error::OpenAIError)
+    .await?;
+    Ok(verify_quotes(cited.excerpts, &hashes, db).await)
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::docdb::DocumentFetcher;
+
+    #[test]
+    fn citations_have_reviewed_date() {
+        struct Document;
+        impl DocumentFetcher for Document {
+            fn fetch(
+                &self,
+                _url: &str,
+            ) -> futures::future::LocalBoxFuture<'_, core::result::Result<String, String>>
+            {
+                let document = "Migraine attacks last 4-72 hours, with nausea.".to_string();
+                Box::pin(futures::future::ready(Ok(document)))
+            }
+        }
+        let id = [0x01; 16];
+        let mut db = DocDb::default();
+        db.set_fetcher(Some(Rc::new(Document)));
+        db.set_document_url(Some("bundle:{id}".to_string()))
+            .unwrap();
+        db.set_reviewed(format!("{}\t2024-03-01\n", hex::encode(id)).as_bytes())
+            .unwrap();
+        let excerpt = CiteExcerpt {
+            id: hex::encode(id),
+            title: "Migraine".to_string(),
+            quote: "attacks last 4-72 hours".to_string(),
+        };
+        let cited = futures::executor::block_on(verify_quotes(vec![excerpt], &[id], &db));
+        assert_eq!(cited.len(), 1);
+        assert_eq!(cited[0].reviewed.as_deref(), Some("2024-03-01"));
+    }
+
+    #[test]
+    fn scores_quotes() {
+        let document = "Migraine is a primary headache disorder. \
+            Attacks last 4-72 hours, with nausea and sensitivity to light.";
+        assert_eq!(
+            quote_score("attacks last 4-72 hours, with nausea", document),
+            1.0
+        );
+        assert_eq!(
+            quote_score(
+                "Migraine is a headache disorder. Attacks last 4–72 hours with nausea",
+                document
+            ),
+            0.8
+        );
+        assert!(quote_score("Attacks last a week with fever", document) < QUOTE_MATCH);
+        assert_eq!(quote_score("", document), 0.0);
+        assert_eq!(quote_score("headache", document), 0.0);
+        assert_eq!(quote_score("a primary headache disorder", document), 0.0);
+        assert_eq!(quote_score("fever", document), 0.0);
+    }
 }
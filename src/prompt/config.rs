@@ -0,0 +1,286 @@
+//! Settings for each prompt of the Clint process.
+//!
+//! Deployments can trade cost for quality by using smaller models or fewer
+//! context documents for some of the tasks.
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::config::config;
+use crate::docdb::Aggregation;
+use crate::openai::chat::{ChatCompletionModel, Example};
+
+/// Who the replies are written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Audience {
+    /// Patients, in plain language.
+    Patient,
+    /// Clinicians, in precise medical terminology.
+    Clinician,
+    /// Readers at an 8th-grade reading level, in short sentences and common
+    /// words.
+    EighthGrade,
+}
+
+/// Settings for a single prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskConfig {
+    pub model: ChatCompletionModel,
+    pub temperature: f32,
+    /// The number of documents retrieved as context. Unused by the tasks that
+    /// don't retrieve documents.
+    pub retrieval_depth: usize,
+    /// Also retrieve documents with this many focused search queries written
+    /// from the context, such as one about the patient's history, so
+    /// documents about what a single embedding misses are found, still
+    /// keeping `retrieval_depth` documents in all. Only used by the
+    /// `diagnosis`, `refine` and `respond` tasks.
+    pub search_queries: usize,
+    /// Search with a short reference passage written about the case rather
+    /// than the case itself, which finds more documents for vague statements.
+    /// Only used by the `diagnosis`, `refine` and `respond` tasks.
+    pub hypothetical_document: bool,
+    /// How many of the recent messages, along with the message being
+    /// answered, help find documents. Only used by the `respond` task.
+    pub conversation_turns: usize,
+    /// How much the recent messages weigh, from 0 to 1, against the notes
+    /// when finding documents, so follow-up questions find the documents
+    /// they're about. Only used by the `respond` task.
+    pub conversation_weight: f32,
+    /// The model writing the search queries and hypothetical document, a
+    /// cheaper one than `model` to save cost, or `model` if omitted.
+    pub query_model: Option<ChatCompletionModel>,
+    /// The most diagnoses refined, not counting those after them that must
+    /// not be missed. Only used by the `refine` task.
+    pub max_diagnoses: usize,
+    /// The least similarity of a retrieved document with the query, so weak
+    /// matches don't dilute the context, which then holds fewer than
+    /// `retrieval_depth` documents. It applies to each chunk before the
+    /// origin weights and the `parent_aggregation`.
+    pub min_similarity: Option<f32>,
+    /// Re-rank the retrieved documents so they aren't near duplicates, from 0
+    /// for the most diverse to 1 for the most similar to the query.
+    pub mmr_lambda: Option<f32>,
+    /// Retrieve the most similar chunk of each document, ranking documents by
+    /// the `max` or `sum` of the similarities of their chunks, so the context
+    /// covers more documents. Takes precedence over `mmr_lambda`.
+    pub parent_aggregation: Option<Aggregation>,
+    /// Only retrieve documents in these languages, such as `es`, unless
+    /// empty. Documents in the later languages only fill in for missing
+    /// documents in the earlier ones.
+    pub languages: Vec<String>,
+    /// Flag the retrieved documents last reviewed more than this many days
+    /// ago as possibly out of date, so the reply can say so.
+    pub stale_after_days: Option<u32>,
+    /// Cut the text of each retrieved document to this many tokens, keeping
+    /// the paragraphs most related to the context, so a single long document
+    /// can't crowd out the others. Documents are kept whole if `null`.
+    pub excerpt_tokens: Option<usize>,
+    /// Tell the model the knowledge base doesn't cover the message, rather
+    /// than let it improvise, if no document is at least this similar to it.
+    /// It's always told if no documents are found. Only used by the `respond`
+    /// task.
+    pub min_grounding: Option<f32>,
+    /// Replaces the built-in system persona, to adjust the tone and scope of
+    /// the replies or add mandatory disclaimers, such as reminding the user
+    /// that the assistant isn't a doctor.
+    pub system_identity: Option<String>,
+    /// Write the replies for this audience rather than as the prompt says.
+    /// Only used by the `respond` and `refine` tasks.
+    pub audience: Option<Audience>,
+    /// The locale of the user, such as `es` or `es-MX`, which selects the
+    /// prompt templates for it and the language of the replies. The locale
+    /// of the library is used if omitted.
+    pub locale: Option<String>,
+    /// How many times to retry a failed request or a malformed completion.
+    pub max_retries: usize,
+    /// How many times to continue a reply cut off by the token limit. Unused
+    /// by the tasks that reply with structured data.
+    pub max_continuations: usize,
+    /// Screen the user's message with the moderation endpoint first. Unused by
+    /// the tasks that don't take a user message.
+    pub moderate: bool,
+    /// How many completions to sample, keeping the best. Only used by the
+    /// tasks that reply with structured data, and not when streaming. The
+    /// samples only differ with a `temperature` above 0.
+    pub samples: usize,
+    /// Example exchanges shown to the model before the instructions, to tune
+    /// the style of its replies. For the tasks that reply with structured
+    /// data, the `assistant` reply is the JSON output.
+    pub examples: Vec<Example>,
+}
+
+impl Default for TaskConfig {
+    fn default() -> Self {
+        Self {
+            model: ChatCompletionModel::GPT_4O,
+            temperature: 0.0,
+            retrieval_depth: 8,
+            search_queries: 0,
+            hypothetical_document: false,
+            conversation_turns: 0,
+            conversation_weight: 0.0,
+            query_model: None,
+            max_diagnoses: 8,
+            min_similarity: None,
+            mmr_lambda: None,
+            parent_aggregation: None,
+            languages: Vec::new(),
+            stale_after_days: None,
+            excerpt_tokens: None,
+            min_grounding: None,
+            system_identity: None,
+            audience: None,
+            locale: None,
+            max_retries: 3,
+            max_continuations: 0,
+            moderate: false,
+            samples: 1,
+            examples: Vec::new(),
+        }
+    }
+}
+
+impl TaskConfig {
+    /// The locale of the task, or else of the library, if any.
+    pub fn locale(&self) -> Option<String> {
+        self.locale.clone().or_else(|| config().locale)
+    }
+}
+
+/// Settings for every prompt of the Clint process.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClintConfig {
+    /// Uses `gpt-4o-mini` unless a model is set, as classifying a message
+    /// should cost much less than the process it can skip.
+    #[serde(deserialize_with = "deserialize_scope")]
+    pub scope: TaskConfig,
+    pub rewrite: TaskConfig,
+    pub notes: TaskConfig,
+    pub gaps: TaskConfig,
+    pub urgency: TaskConfig,
+    pub diagnosis: TaskConfig,
+    pub refine: TaskConfig,
+    pub verify: TaskConfig,
+    pub medications: TaskConfig,
+    pub triage: TaskConfig,
+    pub summarize: TaskConfig,
+    pub respond: TaskConfig,
+    pub cite: TaskConfig,
+    /// The locale of the user for every task without its own `locale`.
+    pub locale: Option<String>,
+}
+
+impl Default for ClintConfig {
+    fn default() -> Self {
+        Self {
+            scope: scope_task(TaskConfig::default(), None),
+            rewrite: TaskConfig::default(),
+            notes: TaskConfig::default(),
+            gaps: TaskConfig::default(),
+            urgency: TaskConfig::default(),
+            diagnosis: TaskConfig::default(),
+            refine: TaskConfig::default(),
+            verify: TaskConfig::default(),
+            medications: TaskConfig::default(),
+            triage: TaskConfig::default(),
+            summarize: TaskConfig::default(),
+            respond: TaskConfig::default(),
+            cite: TaskConfig::default(),
+            locale: None,
+        }
+    }
+}
+
+/// The settings of the `scope` task, with the cheaper model unless `model`
+/// is set.
+fn scope_task(task: TaskConfig, model: Option<ChatCompletionModel>) -> TaskConfig {
+    TaskConfig {
+        model: model.unwrap_or(ChatCompletionModel::GPT_4O_MINI),
+        ..task
+    }
+}
+
+fn deserialize_scope<'de, D>(deserializer: D) -> Result<TaskConfig, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Scope {
+        model: Option<ChatCompletionModel>,
+        #[serde(flatten)]
+        task: TaskConfig,
+    }
+    let Scope { model, task } = Scope::deserialize(deserializer)?;
+    Ok(scope_task(task, model))
+}
+
+impl ClintConfig {
+    /// The config with its `locale` set on every task without its own.
+    pub fn localized(mut self) -> Self {
+        for task in [
+            &mut self.scope,
+            &mut self.rewrite,
+            &mut self.notes,
+            &mut self.gaps,
+            &mut self.urgency,
+            &mut self.diagnosis,
+            &mut self.refine,
+            &mut self.verify,
+            &mut self.medications,
+            &mut self.triage,
+            &mut self.summarize,
+            &mut self.respond,
+            &mut self.cite,
+        ] {
+            if task.locale.is_none() {
+                task.locale.clone_from(&self.locale);
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deserializes_partial_config() {
+        let config: ClintConfig =
+            serde_json::from_str(r#"{"respond": {"model": "gpt-4.1", "retrieval_depth": 4}}"#)
+                .unwrap();
+        assert_eq!(
+            config.respond,
+            TaskConfig {
+                model: ChatCompletionModel::from("gpt-4.1".to_string()),
+                retrieval_depth: 4,
+                ..Default::default()
+            }
+        );
+        assert_eq!(config.cite, TaskConfig::default());
+    }
+
+    #[test]
+    fn scope_uses_cheaper_model() {
+        let mini = ChatCompletionModel::GPT_4O_MINI;
+        assert_eq!(ClintConfig::default().scope.model, mini);
+        let config: ClintConfig = serde_json::from_str(r#"{"scope": {"samples": 3}}"#).unwrap();
+        assert_eq!(config.scope.model, mini);
+        assert_eq!(config.scope.samples, 3);
+        let config: ClintConfig =
+            serde_json::from_str(r#"{"scope": {"model": "gpt-4.1"}}"#).unwrap();
+        assert_eq!(config.scope.model.name(), "gpt-4.1");
+    }
+
+    #[test]
+    fn localizes_tasks() {
+        let config: ClintConfig =
+            serde_json::from_str(r#"{"locale": "es", "cite": {"locale": "fr"}}"#).unwrap();
+        let config = config.localized();
+        assert_eq!(config.respond.locale.as_deref(), Some("es"));
+        assert_eq!(config.cite.locale.as_deref(), Some("fr"));
+    }
+}
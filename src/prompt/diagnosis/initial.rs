@@ -1,27 +1,42 @@
-use futures::future::join_all;
+use futures::future::try_join_all;
 use serde::Serialize;
 use tap::Pipe;
 
+use super::super::config::TaskConfig;
 use super::super::notes::Notes;
-use super::super::utils::{embed_for_db, quote_lines, Error, Result};
-use super::super::utils::{get_excerpt, SystemInstructionsExcerpts};
+use super::super::profile::Profile;
+use super::super::retrieve::{retrieve_documents, RetrievalQuery};
+use super::super::templates::Template;
+use super::super::utils::{
+    first_max_by_key, fit_excerpts, prompt_args, quote_lines, Error, Result,
+};
+use super::super::utils::{get_excerpts, SystemInstructionsExcerpts};
 use super::utils::{dedup_diagnoses, find_diagnosis_doc, CandidateDiagnoses, ResolvedDiagnosis};
 use crate::docdb::DocDb;
+use crate::openai::chat::ChatCompletionArgs;
 use crate::openai::chat::{
-    chat_completion_function, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionModel,
+    chat_completion_function_select, chat_completion_function_stream, ChatCompletionParts,
 };
+use crate::openai::usage::UsageTracker;
 use crate::prompt::utils::EmbedStructure;
-use crate::{openai::chat::ChatCompletionArgs, utils::render_template};
 
-const MESSAGE_LIST_INSTRUCTIONS: &'static str = "\
+pub const MESSAGE_LIST_INSTRUCTIONS: Template = Template {
+    name: "diagnosis_initial",
+    variables: &["notes"],
+    default: "\
 Consider the following clinical notes:
 
 {notes}
 
 List some plausible candidate diagnoses that are supported by the notes,
 in order from most likely to least likely. \
-Explain why the notes support and contradict each candidate diagnosis.\
-";
+Explain why the notes support and contradict each candidate diagnosis, \
+rate how likely it is, \
+and flag it if it's dangerous to miss. \
+Include dangerous diagnoses that are plausible even if unlikely, such as meningitis or pulmonary embolism. \
+Don't list diagnoses that the pertinent negatives effectively rule out.\
+",
+};
 
 #[derive(Serialize)]
 struct MessageInstructions {
@@ -35,76 +50,134 @@ impl MessageInstructions {
         }
     }
 
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_LIST_INSTRUCTIONS, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_LIST_INSTRUCTIONS.render(&self, task)
     }
 }
 
-/// Come up with an initial diagnosis given the `notes`.
-///
-/// If a `statement` is provided, it is used to help find context documents.
-pub async fn initial_diagnosis(
+const FUNCTION_NAME: &str = "list_diagnoses";
+const FUNCTION_DESCRIPTION: &str = "List plausible diagnoses.";
+
+async fn candidates_args(
     notes: &Notes,
+    profile: &Profile,
     statement: Option<&str>,
     db: &DocDb,
     key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<ChatCompletionArgs> {
+    let context = EmbedStructure::new(notes, profile, None, statement).render()?;
+    let hashes = retrieve_documents(RetrievalQuery::new(&context), db, &key, usage, task)
+        .await?
+        .documents;
+    let excerpts = get_excerpts(&hashes, db, &context, task).await;
+
+    let instructions = MessageInstructions::new(notes).render(task)?;
+    let (system, instructions) = fit_excerpts(excerpts, task, |x| {
+        let system = SystemInstructionsExcerpts::new(x, profile, task).render(task)?;
+        Ok((system, instructions.clone()))
+    })?;
+    Ok(prompt_args(system, instructions, key, usage, task))
+}
+
+/// Find the documents for the `candidates` and drop the duplicates, as well
+/// as the candidates without a document.
+async fn resolve_candidates(
+    candidates: &CandidateDiagnoses,
+    db: &DocDb,
+    key: &str,
+    usage: &UsageTracker,
     max_retries: usize,
 ) -> Result<Vec<ResolvedDiagnosis>> {
-    let embedding = embed_for_db(
-        &EmbedStructure::new(notes, None, statement).render()?,
-        db,
-        &key,
-    )
-    .await?;
-    let hashes = db.get_similar(embedding.view(), 8, None);
-    let excerpts = hashes
+    let resolved = candidates
+        .diagnoses
         .iter()
-        .map(|x| get_excerpt(x, db))
-        .pipe(join_all)
-        .await
+        .map(|x| find_diagnosis_doc(x, db, key, usage, max_retries))
+        .pipe(try_join_all)
+        .await?
         .into_iter()
         .flatten()
         .collect::<Vec<_>>();
+    Ok(dedup_diagnoses(resolved))
+}
 
-    let args = ChatCompletionArgs::new(key.clone())
-        .with_model(ChatCompletionModel::Gpt4o)
-        .with_temperature(0.0)
-        .with_message(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::System,
-            content: Some(SystemInstructionsExcerpts::new(&excerpts).render()?),
-            name: None,
-            function_call: None,
-        })
-        .with_message(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
-            content: Some(MessageInstructions::new(notes).render()?),
-            name: None,
-            function_call: None,
-        });
-    let candidates: CandidateDiagnoses = chat_completion_function(
-        args,
-        "list_diagnoses".to_string(),
-        Some("List plausible diagnoses.".to_string()),
-        max_retries,
+/// Pick the candidates with the most diagnoses.
+fn most_diagnoses(samples: Vec<CandidateDiagnoses>) -> CandidateDiagnoses {
+    first_max_by_key(samples, |x| x.diagnoses.len())
+}
+
+/// Come up with an initial diagnosis given the `notes` and the patient's
+/// `profile`.
+///
+/// If a `statement` is provided, it is used to help find context documents.
+/// If the `task` samples several completions, the one with the most diagnoses
+/// is kept.
+pub async fn initial_diagnosis(
+    notes: &Notes,
+    profile: &Profile,
+    statement: Option<&str>,
+    db: &DocDb,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Vec<ResolvedDiagnosis>> {
+    let args = candidates_args(notes, profile, statement, db, key.clone(), usage, task).await?;
+    // the sample that considers the most diagnoses is the least likely to
+    // miss the right one
+    let candidates: CandidateDiagnoses = chat_completion_function_select(
+        args.with_n(task.samples),
+        FUNCTION_NAME.to_string(),
+        Some(FUNCTION_DESCRIPTION.to_string()),
+        task.max_retries,
+        most_diagnoses,
     )
     .await
     .map_err(Error::OpenAIError)?;
+    resolve_candidates(&candidates, db, &key, usage, task.max_retries).await
+}
 
-    let resolved = candidates
-        .diagnoses
-        .iter()
-        .map(|x| find_diagnosis_doc(x, db, &key))
-        .pipe(join_all)
-        .await
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
-    let resolved = dedup_diagnoses(resolved);
-    resolved.pipe(Ok)
+/// Like [`initial_diagnosis`], but stream the candidate diagnoses as they're
+/// listed.
+///
+/// Resolve the candidates with [`resolve_initial_diagnosis`] once the stream
+/// is done.
+pub async fn stream_initial_diagnosis(
+    notes: &Notes,
+    profile: &Profile,
+    statement: Option<&str>,
+    db: &DocDb,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<ChatCompletionParts> {
+    let args = candidates_args(notes, profile, statement, db, key, usage, task).await?;
+    chat_completion_function_stream::<CandidateDiagnoses>(
+        args,
+        FUNCTION_NAME.to_string(),
+        Some(FUNCTION_DESCRIPTION.to_string()),
+        task.max_retries,
+    )
+    .await
+    .map_err(Error::OpenAIError)
+}
+
+/// Find the documents for the candidate diagnoses streamed by `parts`,
+/// retrying a failed request up to `max_retries` times.
+pub async fn resolve_initial_diagnosis(
+    parts: &ChatCompletionParts,
+    db: &DocDb,
+    key: &str,
+    usage: &UsageTracker,
+    max_retries: usize,
+) -> Result<Vec<ResolvedDiagnosis>> {
+    let candidates: CandidateDiagnoses = parts.function_output().map_err(Error::OpenAIError)?;
+    resolve_candidates(&candidates, db, key, usage, max_retries).await
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::utils::CandidateDiagnosis;
     use super::*;
 
     #[test]
@@ -113,8 +186,39 @@ mod test {
             chief_complaint: "abc".to_string(),
             ..Default::default()
         })
-        .render()
+        .render(&Default::default())
         .unwrap();
         assert!(instructions.contains("notes:\n\n> # Chief Complaint\n> \n> abc"));
     }
+
+    #[test]
+    fn instructions_rule_out_pertinent_negatives() {
+        let instructions = MessageInstructions::new(&Notes {
+            pertinent_negatives: "No chest pain.".to_string(),
+            ..Default::default()
+        })
+        .render(&Default::default())
+        .unwrap();
+        assert!(instructions.contains("> # Pertinent Negatives\n> \n> No chest pain."));
+        assert!(instructions.contains("the pertinent negatives effectively rule out"));
+    }
+
+    #[test]
+    fn picks_most_diagnoses() {
+        let candidates = |names: &[&str]| CandidateDiagnoses {
+            diagnoses: names
+                .iter()
+                .map(|x| CandidateDiagnosis {
+                    name: x.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let best = most_diagnoses(vec![
+            candidates(&["a"]),
+            candidates(&["b", "c"]),
+            candidates(&["d", "e"]),
+        ]);
+        assert_eq!(best.diagnoses[0].name, "b");
+    }
 }
@@ -3,7 +3,12 @@
 mod initial;
 mod refine;
 mod utils;
+mod verify;
 
-pub use initial::initial_diagnosis;
+pub use initial::MESSAGE_LIST_INSTRUCTIONS as INITIAL_INSTRUCTIONS;
+pub use initial::{initial_diagnosis, resolve_initial_diagnosis, stream_initial_diagnosis};
 pub use refine::refine_diagnosis;
-pub use utils::ResolvedDiagnosis;
+pub use refine::MESSAGE_INSTRUCTIONS as REFINE_INSTRUCTIONS;
+pub use utils::{rank_diagnoses, select_diagnoses, ResolvedDiagnosis};
+pub use verify::verify_diagnosis;
+pub use verify::MESSAGE_INSTRUCTIONS as VERIFY_INSTRUCTIONS;
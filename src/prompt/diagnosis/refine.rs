@@ -1,19 +1,26 @@
-use futures::future::join_all;
-use serde::Serialize;
+use std::collections::HashSet;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tap::Pipe;
 
+use super::super::config::TaskConfig;
 use super::super::notes::Notes;
-use super::super::utils::{embed_for_db, quote_lines, Error, Result};
-use super::super::utils::{get_excerpt, SystemInstructionsExcerpts};
-use super::utils::{CandidateDiagnosis, ResolvedDiagnosis};
-use crate::docdb::DocDb;
-use crate::openai::chat::{
-    chat_completion, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionModel,
-};
+use super::super::profile::Profile;
+use super::super::retrieve::{retrieve_documents, RetrievalQuery};
+use super::super::templates::Template;
+use super::super::utils::SystemInstructionsExcerpts;
+use super::super::utils::{audience_instructions, get_excerpts, retrieved_sources};
+use super::super::utils::{call_function, fit_excerpts, quote_lines, Function, Result};
+use super::utils::{CandidateDiagnosis, Likelihood, NextStep, ResolvedDiagnosis};
+use crate::docdb::{DocDb, DocId, Filter};
+use crate::openai::usage::UsageTracker;
 use crate::prompt::utils::EmbedStructure;
-use crate::{openai::chat::ChatCompletionArgs, utils::render_template};
 
-const MESSAGE_INSTRUCTIONS: &'static str = "\
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "diagnosis_refine",
+    variables: &["notes", "candidate_diagnosis", "audience"],
+    default: "\
 Consider the following clinical notes:
 
 {notes}
@@ -28,87 +35,122 @@ Explain why the notes support the diagnosis. \
 Explain if there discrepancies between the notes and the diagnosis. \
 Keep in mind that the notes might be incomplete, \
 so some manifestations of the diagnosis might be missing from the notes. \
-Answer in 50 words or less.\
-";
+Answer in 50 words or less. \
+Rate how likely the diagnosis is, \
+and flag it if it's dangerous to miss, such as a life-threatening condition, even if unlikely. \
+Suggest the tests or examinations that would confirm or rule out the diagnosis, \
+only those recommended by the document excerpts. \
+List the IDs of the excerpts that support your reasoning.\
+{{ if audience }}
+
+{audience}{{ endif }}\
+",
+};
 
 #[derive(Serialize)]
 struct MessageInstructions {
     notes: String,
     candidate_diagnosis: String,
+    audience: String,
 }
 
 impl MessageInstructions {
-    fn new(notes: &Notes, candidate_diagnosis: &CandidateDiagnosis) -> Self {
+    fn new(notes: &Notes, candidate_diagnosis: &CandidateDiagnosis, task: &TaskConfig) -> Self {
         Self {
             notes: notes.to_markdown(0).as_str().pipe(quote_lines),
             candidate_diagnosis: candidate_diagnosis
                 .to_markdown(0)
                 .as_str()
                 .pipe(quote_lines),
+            audience: audience_instructions(task).unwrap_or_default(),
         }
     }
 
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_INSTRUCTIONS, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
     }
 }
 
+#[derive(Debug, JsonSchema, Deserialize)]
+struct Refinement {
+    #[schemars(description = "The improved reasoning for the diagnosis. 50 words or less.")]
+    reasoning: String,
+    #[schemars(description = "How likely the diagnosis is given the notes.")]
+    likelihood: Likelihood,
+    #[schemars(
+        description = "Is the diagnosis dangerous to miss, such as a life-threatening condition, even if unlikely?"
+    )]
+    must_not_miss: bool,
+    #[schemars(
+        description = "The tests or examinations recommended by the excerpts to confirm or rule out the diagnosis."
+    )]
+    next_steps: Vec<NextStep>,
+    #[schemars(description = "The IDs of the excerpts that support the reasoning. \
+        The ID must contain only hex characters and can be found in the link `<id:...>`.")]
+    sources: Vec<String>,
+}
+
+const FUNCTION: Function = Function {
+    name: "refine_diagnosis",
+    description: "Record the refined diagnosis.",
+};
+
 /// Refine an existing `diagnosis` by looking up relevant documents and
-/// prompting the LLM to reason about the diagnosis given the `notes`.
+/// prompting the LLM to reason about the diagnosis given the `notes` and the
+/// patient's `profile`, with its likelihood and the tests or examinations
+/// the documents recommend to confirm it.
 ///
 /// If a `statement` is provided, it is used to help find context documents.
+/// The documents in `exclude`, such as those already used in an earlier
+/// prompt, aren't used as context. The reasoning is written for the audience
+/// of the `task`, if any.
+///
+/// The refinement is recorded with a function call, which can't be continued
+/// once cut off, so the `max_continuations` of the `task` is unused.
+#[allow(clippy::too_many_arguments)]
 pub async fn refine_diagnosis(
     notes: &Notes,
+    profile: &Profile,
     diagnosis: ResolvedDiagnosis,
     statement: Option<&str>,
+    exclude: &HashSet<DocId>,
     db: &DocDb,
     key: String,
-    max_retries: usize,
+    usage: &UsageTracker,
+    task: &TaskConfig,
 ) -> Result<ResolvedDiagnosis> {
-    let embedding = embed_for_db(
-        &EmbedStructure::new(notes, Some(&vec![diagnosis.clone()]), statement).render()?,
+    let context =
+        EmbedStructure::new(notes, profile, Some(&vec![diagnosis.clone()]), statement).render()?;
+    let filter = Filter::excluding(exclude.iter().copied());
+    let hashes = retrieve_documents(
+        RetrievalQuery::new(&context).with_filter(&filter),
         db,
         &key,
+        usage,
+        task,
     )
-    .await?;
-    let hashes = db.get_similar(embedding.view(), 8, None);
-    let excerpts = hashes
-        .iter()
-        .map(|x| get_excerpt(x, db))
-        .pipe(join_all)
-        .await
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
-
-    let args = ChatCompletionArgs::new(key.clone())
-        .with_model(ChatCompletionModel::Gpt4o)
-        .with_temperature(0.0)
-        .with_message(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::System,
-            content: Some(SystemInstructionsExcerpts::new(&excerpts).render()?),
-            name: None,
-            function_call: None,
-        })
-        .with_message(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
-            content: Some(MessageInstructions::new(notes, &diagnosis.diagnosis).render()?),
-            name: None,
-            function_call: None,
-        });
-    let refined = chat_completion(args, max_retries)
-        .await
-        .map_err(Error::OpenAIError)?
-        .choices
-        .into_iter()
-        .next()
-        .ok_or(Error::NetworkResponseError)?
-        .message
-        .content
-        .ok_or(Error::NetworkResponseError)?;
+    .await?
+    .documents;
+    let excerpts = get_excerpts(&hashes, db, &context, task).await;
+    let instructions = MessageInstructions::new(notes, &diagnosis.diagnosis, task).render(task)?;
+    let (system, instructions) = fit_excerpts(excerpts, task, |x| {
+        let system = SystemInstructionsExcerpts::new(x, profile, task).render(task)?;
+        Ok((system, instructions.clone()))
+    })?;
+    let refinement: Refinement =
+        call_function(&FUNCTION, system, instructions, key, usage, task).await?;
 
     Ok(ResolvedDiagnosis {
-        refined: Some(refined),
+        refined: Some(refinement.reasoning),
+        diagnosis: CandidateDiagnosis {
+            likelihood: Some(refinement.likelihood),
+            must_not_miss: refinement.must_not_miss,
+            ..diagnosis.diagnosis.clone()
+        },
+        next_steps: refinement.next_steps,
+        sources: retrieved_sources(&refinement.sources, &hashes),
+        retrieved: hashes,
+        verification: None,
         ..diagnosis.clone()
     })
 }
@@ -126,13 +168,14 @@ mod test {
             },
             &CandidateDiagnosis {
                 name: "bcd".to_string(),
-                reasoning_for: String::new(),
-                reasoning_against: String::new(),
+                ..Default::default()
             },
+            &Default::default(),
         )
-        .render()
+        .render(&Default::default())
         .unwrap();
         assert!(instructions.contains("notes:\n\n> # Chief Complaint\n> \n> abc"));
         assert!(instructions.contains("diagnosis:\n\n> # bcd"));
+        assert!(instructions.ends_with("support your reasoning."));
     }
 }
@@ -1,12 +1,32 @@
-use itertools::Itertools;
 use std::collections::HashSet;
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tap::Pipe;
 
-use super::super::utils::embed_for_db;
-use crate::docdb::{DocDb, DocId};
+use super::super::utils::{embed_for_db, Result};
+use crate::docdb::{ConditionCodes, DocDb, DocId};
+use crate::openai::usage::UsageTracker;
+
+/// How likely a diagnosis is given the notes, from least to most likely.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema, Deserialize, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Likelihood {
+    Low,
+    Medium,
+    High,
+}
+
+impl Likelihood {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Likelihood::Low => "low",
+            Likelihood::Medium => "medium",
+            Likelihood::High => "high",
+        }
+    }
+}
 
 #[derive(Debug, Clone, Default, JsonSchema, Deserialize, Serialize)]
 pub struct CandidateDiagnosis {
@@ -20,12 +40,39 @@ pub struct CandidateDiagnosis {
         description = "What in the patient notes that contradict this diagnosis? 30 words or less."
     )]
     pub reasoning_against: String,
+    #[schemars(
+        with = "Likelihood",
+        description = "How likely the diagnosis is given the notes."
+    )]
+    #[serde(default)]
+    pub likelihood: Option<Likelihood>,
+    #[schemars(
+        description = "Is the diagnosis dangerous to miss, such as a life-threatening condition, even if unlikely?"
+    )]
+    #[serde(default)]
+    pub must_not_miss: bool,
 }
 
 impl CandidateDiagnosis {
+    /// The name of the diagnosis, with its likelihood if known.
+    fn title(&self) -> String {
+        let mut notes = Vec::new();
+        if let Some(x) = self.likelihood {
+            notes.push(format!("likelihood: {}", x.as_str()));
+        }
+        if self.must_not_miss {
+            notes.push("must not miss".to_string());
+        }
+        if notes.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, notes.join(", "))
+        }
+    }
+
     pub fn to_markdown(&self, depth: usize) -> String {
         let depth = "#".repeat(depth);
-        let title = format!("{}# {}", depth, &self.name);
+        let title = format!("{}# {}", depth, self.title());
         let mut parts: Vec<&str> = vec![&title];
         if !self.reasoning_for.is_empty() {
             parts.push(&self.reasoning_for)
@@ -33,7 +80,7 @@ impl CandidateDiagnosis {
         if !self.reasoning_against.is_empty() {
             parts.push(&self.reasoning_against)
         }
-        return parts.join("\n\n");
+        parts.join("\n\n")
     }
 }
 
@@ -43,82 +90,264 @@ pub struct CandidateDiagnoses {
     pub diagnoses: Vec<CandidateDiagnosis>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A test or examination to confirm or rule out a diagnosis.
+#[derive(Debug, Clone, PartialEq, JsonSchema, Deserialize, Serialize)]
+pub struct NextStep {
+    #[schemars(description = "The test or examination, such as a complete blood count.")]
+    pub name: String,
+    #[schemars(description = "What it would show about the diagnosis. 20 words or less.")]
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ResolvedDiagnosis {
     pub doc_hash: DocId,
     pub diagnosis: CandidateDiagnosis,
     pub refined: Option<String>,
+    /// The ICD-10 and SNOMED CT codes of the condition, if known.
+    #[serde(default)]
+    pub codes: ConditionCodes,
+    /// The tests or examinations suggested when refining the diagnosis.
+    #[serde(default)]
+    pub next_steps: Vec<NextStep>,
+    /// The retrieved documents that support the refined reasoning.
+    #[serde(default)]
+    pub sources: Vec<DocId>,
+    /// The documents retrieved as context to refine the reasoning, which it's
+    /// verified against.
+    #[serde(default)]
+    pub retrieved: Vec<DocId>,
+    /// The problems found checking the refined reasoning, if checked.
+    #[serde(default)]
+    pub verification: Option<Verification>,
+}
+
+/// The problems found checking the reasoning for a diagnosis against the
+/// documents it cites and the notes.
+#[derive(Debug, Clone, Default, PartialEq, JsonSchema, Deserialize, Serialize)]
+pub struct Verification {
+    #[schemars(
+        description = "The claims in the reasoning that neither the excerpts nor the notes support."
+    )]
+    pub unsupported_claims: Vec<String>,
+    #[schemars(
+        description = "The claims in the reasoning that contradict the excerpts or the notes."
+    )]
+    pub contradictions: Vec<String>,
 }
 
 impl ResolvedDiagnosis {
     pub fn to_markdown(&self, depth: usize) -> String {
-        match &self.refined {
+        let markdown = match &self.refined {
             Some(refined) => {
                 let depth = "#".repeat(depth);
-                format!("{}# {}\n\n{}", depth, &self.diagnosis.name, refined)
+                format!("{}# {}\n\n{}", depth, self.diagnosis.title(), refined)
             }
             None => self.diagnosis.to_markdown(depth),
+        };
+        let mut parts = vec![markdown];
+        let mut list = |title: &str, items: Vec<String>| {
+            if !items.is_empty() {
+                parts.push(format!("{}:\n\n- {}", title, items.join("\n- ")));
+            }
+        };
+        list(
+            "Next steps",
+            self.next_steps
+                .iter()
+                .map(|x| format!("{}: {}", x.name, x.reason))
+                .collect(),
+        );
+        if let Some(verification) = &self.verification {
+            list(
+                "Unsupported claims",
+                verification.unsupported_claims.clone(),
+            );
+            list("Contradictions", verification.contradictions.clone());
         }
+        parts.join("\n\n")
     }
 }
 
+/// Find the document of the condition of the `candidate_diagnosis`, by its
+/// alias or else the nearest embedding.
+///
+/// Returns `None` if no condition matches, and fails if the diagnosis can't
+/// be embedded, retrying up to `max_retries` times, or looked up.
 pub async fn find_diagnosis_doc(
     candidate_diagnosis: &CandidateDiagnosis,
     db: &DocDb,
     key: &str,
-) -> Option<ResolvedDiagnosis> {
-    let embedding = embed_for_db(candidate_diagnosis.to_markdown(0).as_str(), db, key)
-        .await
-        .ok()?;
-    let filter = db
-        .get_is_introduction()
-        .union(db.get_is_symptoms())
-        .map(|x| x.clone())
-        .collect::<HashSet<_>>()
-        .pipe(Some);
-    let hashes = db.get_similar(embedding.view(), 8, filter.as_ref());
-    let hashes_count = hashes
-        .into_iter()
-        .map(|x| {
-            let mut hash = &x;
-            loop {
-                if db.get_is_diagnosis().contains(hash) {
-                    return Some(hash.to_owned());
-                }
-                hash = if let Some(hash) = db.get_parent(hash) {
-                    hash
-                } else {
-                    return None;
-                };
-            }
-        })
-        .flatten()
-        .counts();
-    let mut hashes_count = hashes_count.into_iter().collect::<Vec<_>>();
-    // `y.cmp(x)` for descending order
-    hashes_count.sort_by(|(_, x), (_, y)| y.cmp(x));
-    let (hash, _) = hashes_count.first()?;
-    let name = db.get_title(hash)?.to_string();
-    Some(ResolvedDiagnosis {
+    usage: &UsageTracker,
+    max_retries: usize,
+) -> Result<Option<ResolvedDiagnosis>> {
+    // a known alias is more reliable than the nearest embeddings
+    if let Some(hash) = db.resolve_alias(&candidate_diagnosis.name) {
+        if let Some(name) = db.get_title(hash) {
+            return Ok(Some(ResolvedDiagnosis {
+                doc_hash: hash.to_owned(),
+                diagnosis: CandidateDiagnosis {
+                    name: name.to_string(),
+                    ..candidate_diagnosis.clone()
+                },
+                codes: db.get_codes(hash).cloned().unwrap_or_default(),
+                ..Default::default()
+            }));
+        }
+    }
+    // the likelihood and danger say nothing about which condition it is
+    let query = CandidateDiagnosis {
+        likelihood: None,
+        must_not_miss: false,
+        ..candidate_diagnosis.clone()
+    }
+    .to_markdown(0);
+    let embedding = embed_for_db(&query, db, key, usage, max_retries).await?;
+    let Some(hash) = &db.resolve_condition(embedding.view())? else {
+        return Ok(None);
+    };
+    let Some(name) = db.get_title(hash).map(|x| x.to_string()) else {
+        return Ok(None);
+    };
+    Ok(Some(ResolvedDiagnosis {
         doc_hash: hash.to_owned(),
         diagnosis: CandidateDiagnosis {
             name,
-            reasoning_for: candidate_diagnosis.reasoning_for.clone(),
-            reasoning_against: candidate_diagnosis.reasoning_against.clone(),
+            ..candidate_diagnosis.clone()
         },
-        refined: None,
-    })
+        codes: db.get_codes(hash).cloned().unwrap_or_default(),
+        ..Default::default()
+    }))
+}
+
+/// Order the `diagnoses` from most to least likely, keeping the order of
+/// those as likely and putting those without a likelihood last.
+pub fn rank_diagnoses(diagnoses: &mut [ResolvedDiagnosis]) {
+    diagnoses.sort_by_key(|x| std::cmp::Reverse(x.diagnosis.likelihood));
 }
 
+/// Keep the first of the `diagnoses` of each condition, flagged as must not
+/// miss if any of its duplicates is.
 pub fn dedup_diagnoses(diagnoses: Vec<ResolvedDiagnosis>) -> Vec<ResolvedDiagnosis> {
     let mut seen: HashSet<DocId> = HashSet::new();
     let mut deduped: Vec<ResolvedDiagnosis> = Vec::new();
     for diagnosis in diagnoses {
         if seen.contains(&diagnosis.doc_hash) {
+            if diagnosis.diagnosis.must_not_miss {
+                if let Some(x) = deduped
+                    .iter_mut()
+                    .find(|x| x.doc_hash == diagnosis.doc_hash)
+                {
+                    x.diagnosis.must_not_miss = true;
+                }
+            }
             continue;
         }
-        seen.insert(diagnosis.doc_hash.clone());
+        seen.insert(diagnosis.doc_hash);
         deduped.push(diagnosis);
     }
     deduped
 }
+
+/// Keep the first `n` of the `diagnoses`, and any after them flagged as must
+/// not miss, in order.
+pub fn select_diagnoses(diagnoses: Vec<ResolvedDiagnosis>, n: usize) -> Vec<ResolvedDiagnosis> {
+    diagnoses
+        .into_iter()
+        .enumerate()
+        .filter(|(i, x)| *i < n || x.diagnosis.must_not_miss)
+        .map(|(_, x)| x)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::openai::schema::function_parameters;
+
+    #[test]
+    fn ranks_diagnoses_by_likelihood() {
+        let diagnosis = |name: &str, likelihood| ResolvedDiagnosis {
+            diagnosis: CandidateDiagnosis {
+                name: name.to_string(),
+                likelihood,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut diagnoses = vec![
+            diagnosis("a", None),
+            diagnosis("b", Some(Likelihood::Low)),
+            diagnosis("c", Some(Likelihood::High)),
+            diagnosis("d", Some(Likelihood::Low)),
+        ];
+        rank_diagnoses(&mut diagnoses);
+        let names = diagnoses
+            .iter()
+            .map(|x| x.diagnosis.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["c", "b", "d", "a"]);
+        assert_eq!(diagnoses[0].to_markdown(0), "# c (likelihood: high)");
+    }
+
+    #[test]
+    fn keeps_must_not_miss_diagnoses() {
+        let diagnosis = |hash: u8, must_not_miss| ResolvedDiagnosis {
+            doc_hash: [hash; 16],
+            diagnosis: CandidateDiagnosis {
+                name: hash.to_string(),
+                must_not_miss,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let diagnoses = dedup_diagnoses(vec![
+            diagnosis(1, false),
+            diagnosis(2, false),
+            diagnosis(1, true),
+            diagnosis(3, false),
+            diagnosis(4, true),
+        ]);
+        assert!(diagnoses[0].diagnosis.must_not_miss);
+        let names = select_diagnoses(diagnoses, 2)
+            .into_iter()
+            .map(|x| x.diagnosis.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["1", "2", "4"]);
+    }
+
+    #[test]
+    fn renders_next_steps() {
+        let diagnosis = ResolvedDiagnosis {
+            diagnosis: CandidateDiagnosis {
+                name: "Anemia".to_string(),
+                ..Default::default()
+            },
+            refined: Some("Fits the fatigue.".to_string()),
+            next_steps: vec![NextStep {
+                name: "Complete blood count".to_string(),
+                reason: "Shows low hemoglobin.".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            diagnosis.to_markdown(0),
+            "# Anemia\n\nFits the fatigue.\n\nNext steps:\n\n\
+             - Complete blood count: Shows low hemoglobin."
+        );
+    }
+
+    #[test]
+    fn candidates_schema_is_inlined() {
+        let schema = function_parameters::<CandidateDiagnoses>(false).unwrap();
+        let candidate = &schema["properties"]["diagnoses"]["items"];
+        assert_eq!(candidate["type"], "object");
+        assert_eq!(
+            candidate["properties"]["name"]["description"],
+            "Name of the diagnosis disease or condition."
+        );
+        let schema = schema.to_string();
+        assert!(!schema.contains("$ref") && !schema.contains("definitions"));
+        assert!(!schema.contains("$schema"));
+    }
+}
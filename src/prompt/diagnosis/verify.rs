@@ -0,0 +1,130 @@
+use serde::Serialize;
+use tap::Pipe;
+
+use super::super::config::TaskConfig;
+use super::super::notes::Notes;
+use super::super::profile::Profile;
+use super::super::templates::Template;
+use super::super::utils::SystemInstructionsExcerpts;
+use super::super::utils::{
+    call_function, fit_excerpts, get_excerpts, quote_lines, Function, Result,
+};
+use super::utils::{ResolvedDiagnosis, Verification};
+use crate::docdb::DocDb;
+use crate::openai::usage::UsageTracker;
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "diagnosis_verify",
+    variables: &["notes", "diagnosis"],
+    default: "\
+Consider the following clinical notes:
+
+{notes}
+
+Consider the following reasoning for a diagnosis:
+
+{diagnosis}
+
+Check each claim of the reasoning against the document excerpts and the notes. \
+List the claims that neither the excerpts nor the notes support, \
+and the claims that contradict them. \
+Don't list claims that are supported.\
+",
+};
+
+#[derive(Serialize)]
+struct MessageInstructions {
+    notes: String,
+    diagnosis: String,
+}
+
+impl MessageInstructions {
+    fn new(notes: &Notes, diagnosis: &ResolvedDiagnosis) -> Self {
+        Self {
+            notes: notes.to_markdown(0).as_str().pipe(quote_lines),
+            diagnosis: ResolvedDiagnosis {
+                next_steps: Vec::new(),
+                verification: None,
+                ..diagnosis.clone()
+            }
+            .to_markdown(0)
+            .as_str()
+            .pipe(quote_lines),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+const FUNCTION: Function = Function {
+    name: "verify_reasoning",
+    description: "Record the unsupported and contradicting claims.",
+};
+
+/// Check the refined reasoning for the `diagnosis` against the documents
+/// retrieved to refine it and the `notes`, with the patient's `profile` as
+/// context, so made-up claims can be flagged.
+///
+/// A diagnosis that isn't refined has no reasoning to check, so there's no
+/// verification.
+pub async fn verify_diagnosis(
+    notes: &Notes,
+    profile: &Profile,
+    diagnosis: &ResolvedDiagnosis,
+    db: &DocDb,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Option<Verification>> {
+    if diagnosis.refined.is_none() {
+        return Ok(None);
+    }
+    let refined = diagnosis.refined.as_deref().unwrap_or_default();
+    let excerpts = get_excerpts(&diagnosis.retrieved, db, refined, task).await;
+
+    let instructions = MessageInstructions::new(notes, diagnosis).render(task)?;
+    let (system, instructions) = fit_excerpts(excerpts, task, |x| {
+        let system = SystemInstructionsExcerpts::new(x, profile, task).render(task)?;
+        Ok((system, instructions.clone()))
+    })?;
+    let verification: Verification =
+        call_function(&FUNCTION, system, instructions, key, usage, task).await?;
+    Ok(Some(verification))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::utils::{CandidateDiagnosis, NextStep};
+    use super::*;
+
+    #[test]
+    fn instructions_leave_out_next_steps() {
+        let diagnosis = ResolvedDiagnosis {
+            diagnosis: CandidateDiagnosis {
+                name: "Anemia".to_string(),
+                ..Default::default()
+            },
+            refined: Some("Fits the fatigue.".to_string()),
+            next_steps: vec![NextStep {
+                name: "Complete blood count".to_string(),
+                reason: String::new(),
+            }],
+            verification: Some(Verification {
+                unsupported_claims: vec!["Always fatal.".to_string()],
+                contradictions: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        let instructions = MessageInstructions::new(&Notes::default(), &diagnosis)
+            .render(&Default::default())
+            .unwrap();
+        assert!(instructions.contains("diagnosis:\n\n> # Anemia\n> \n> Fits the fatigue."));
+        assert!(!instructions.contains("Complete blood count"));
+        assert!(!instructions.contains("Always fatal."));
+        assert!(diagnosis
+            .to_markdown(0)
+            .ends_with("Unsupported claims:\n\n- Always fatal."));
+    }
+}
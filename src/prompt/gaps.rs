@@ -0,0 +1,133 @@
+//! Find what the notes are still missing, so the app can show what's left to
+//! ask the patient.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::config::TaskConfig;
+use super::diagnosis::ResolvedDiagnosis;
+use super::notes::{Notes, INFORMATION_NOTES};
+use super::profile::Profile;
+use super::templates::Template;
+use super::utils::{
+    call_function_select, first_max_by_key, quote_lines, Function, Result,
+    SystemInstructionsExcerpts,
+};
+use crate::openai::usage::UsageTracker;
+
+/// An element of the notes that isn't known yet.
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct InformationGap {
+    #[schemars(description = "The title of the section of the notes missing the information.")]
+    pub section: String,
+    #[schemars(description = "What isn't known yet, such as the onset of the headache.")]
+    pub missing: String,
+    #[schemars(description = "A question to ask the patient to find out. 20 words or less.")]
+    pub question: String,
+}
+
+#[derive(Debug, Default, JsonSchema, Deserialize)]
+struct InformationGaps {
+    #[schemars(description = "The missing information, most important first.")]
+    gaps: Vec<InformationGap>,
+}
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "gaps",
+    variables: &["notes", "diagnosis"],
+    default: "\
+Consider the following clinical notes:
+
+{notes}
+{{if diagnosis}}
+You have arrived at the following differential diagnosis:
+
+{diagnosis}
+{{endif}}
+List the standard elements of clinical notes that are still missing, \
+such as the onset, severity, and alleviating and aggravating factors of each symptom, \
+and the history relevant to the leading diagnoses. \
+Don't list information that is already in the notes or that the patient said they don't know.\
+",
+};
+
+#[derive(Serialize)]
+struct MessageInstructions {
+    notes: String,
+    diagnosis: String,
+}
+
+impl MessageInstructions {
+    fn new(notes: &Notes, diagnoses: Option<&Vec<ResolvedDiagnosis>>) -> Self {
+        Self {
+            notes: notes.to_markdown(0).as_str().pipe(quote_lines),
+            diagnosis: diagnoses
+                .map(|x| {
+                    x.iter()
+                        .map(|x| x.diagnosis.to_markdown(0))
+                        .collect::<Vec<_>>()
+                        .join("\n\n")
+                })
+                .unwrap_or_default()
+                .as_str()
+                .pipe(quote_lines),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+const FUNCTION: Function = Function {
+    name: "list_missing_information",
+    description: "List the information missing from the notes.",
+};
+
+/// Pick the sample with the most gaps.
+fn most_gaps(samples: Vec<InformationGaps>) -> InformationGaps {
+    first_max_by_key(samples, |x| x.gaps.len())
+}
+
+/// List the information missing from the `notes`, including the history
+/// relevant to the `diagnoses` if any, with the patient's `profile` as
+/// context.
+///
+/// If the `task` samples several completions, the one with the most gaps is
+/// kept.
+pub async fn information_gaps(
+    notes: &Notes,
+    profile: &Profile,
+    diagnoses: Option<&Vec<ResolvedDiagnosis>>,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Vec<InformationGap>> {
+    let InformationGaps { gaps } = call_function_select(
+        &FUNCTION,
+        SystemInstructionsExcerpts::new(&[INFORMATION_NOTES.text(task)], profile, task)
+            .render(task)?,
+        MessageInstructions::new(notes, diagnoses).render(task)?,
+        key,
+        usage,
+        task,
+        most_gaps,
+    )
+    .await?;
+    Ok(gaps)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instructions_render_without_diagnosis() {
+        let instructions = MessageInstructions::new(&Notes::default(), None)
+            .render(&Default::default())
+            .unwrap();
+        assert!(instructions.contains("# Chief Complaint"));
+        assert!(!instructions.contains("differential diagnosis"));
+    }
+}
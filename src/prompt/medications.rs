@@ -0,0 +1,274 @@
+//! Check the patient's medications for interactions with each other and for
+//! contraindications with the candidate diagnoses.
+
+use futures::future::try_join_all;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::config::TaskConfig;
+use super::diagnosis::ResolvedDiagnosis;
+use super::notes::Notes;
+use super::profile::Profile;
+use super::retrieve::{interleave, retrieve_documents, RetrievalQuery};
+use super::templates::Template;
+use super::utils::{
+    call_function, fit_excerpts, get_excerpts, quote_lines, retrieved_sources, Function, Result,
+    SystemInstructionsExcerpts,
+};
+use crate::docdb::{DocDb, DocId};
+use crate::openai::usage::UsageTracker;
+
+/// What a warning is about: `Interaction` between medications taken
+/// together, or `Contraindication` of a medication with a condition, such as
+/// a candidate diagnosis, an allergy or pregnancy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    #[default]
+    Interaction,
+    Contraindication,
+}
+
+#[derive(Debug, Default, JsonSchema, Deserialize)]
+struct WarningOutput {
+    #[schemars(description = "Whether the warning is an interaction or a contraindication.")]
+    kind: WarningKind,
+    #[schemars(description = "The medications involved, as the patient named them.")]
+    medications: Vec<String>,
+    #[schemars(
+        description = "The condition the medication is contraindicated with, empty for an interaction."
+    )]
+    condition: String,
+    #[schemars(description = "Why it's a risk and what to do about it, in one or two sentences.")]
+    explanation: String,
+    #[schemars(
+        description = "The IDs of the excerpts supporting the warning, found in the links `<id:...>`."
+    )]
+    sources: Vec<String>,
+}
+
+#[derive(Debug, Default, JsonSchema, Deserialize)]
+struct Warnings {
+    #[schemars(description = "The warnings, none if there are no risks.")]
+    warnings: Vec<WarningOutput>,
+}
+
+/// A risk of the patient's medications, with the documents supporting it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MedicationWarning {
+    pub kind: WarningKind,
+    pub medications: Vec<String>,
+    /// The condition the medication is contraindicated with, if any.
+    pub condition: Option<String>,
+    pub explanation: String,
+    /// The retrieved documents supporting the warning.
+    pub sources: Vec<DocId>,
+}
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "medications",
+    variables: &["medications", "allergies", "diagnoses"],
+    default: "\
+Consider the medications I take:
+
+{medications}
+
+Consider my allergies:
+
+{allergies}
+
+Consider the diagnoses being considered for me:
+
+{diagnoses}
+
+Using the document excerpts, check for interactions between my medications \
+and for contraindications of my medications with the diagnoses, my allergies or a pregnancy. \
+Only report risks supported by the excerpts, citing them. \
+Don't report any if there are no risks.\
+",
+};
+
+#[derive(Serialize)]
+struct MessageInstructions {
+    medications: String,
+    allergies: String,
+    diagnoses: String,
+}
+
+impl MessageInstructions {
+    fn new(notes: &Notes, profile: &Profile, diagnoses: Option<&Vec<ResolvedDiagnosis>>) -> Self {
+        let mut medications = profile
+            .medications
+            .iter()
+            .map(|x| format!("- {}", x))
+            .collect::<Vec<_>>();
+        if !notes.medications.trim().is_empty() {
+            medications.push(notes.medications.trim().to_string());
+        }
+        let diagnoses = diagnoses
+            .into_iter()
+            .flatten()
+            .map(|x| format!("- {}", x.diagnosis.name))
+            .collect::<Vec<_>>();
+        let or_none = |x: String| {
+            if x.trim().is_empty() {
+                "None".to_string()
+            } else {
+                x
+            }
+        };
+        Self {
+            medications: medications.join("\n").pipe(|x| quote_lines(&x)),
+            allergies: or_none(notes.allergies.trim().to_string()).pipe(|x| quote_lines(&x)),
+            diagnoses: or_none(diagnoses.join("\n")).pipe(|x| quote_lines(&x)),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+/// The texts to find the documents about each medication with: the
+/// medication and the allergies and diagnoses it could be contraindicated
+/// with, leaving out the wording of the instructions, which would dilute the
+/// embeddings.
+fn retrieval_queries(
+    notes: &Notes,
+    profile: &Profile,
+    diagnoses: Option<&Vec<ResolvedDiagnosis>>,
+) -> Vec<String> {
+    let noted = notes
+        .medications
+        .lines()
+        .map(|x| x.trim().trim_start_matches(['-', '*']).trim())
+        .filter(|x| !x.is_empty())
+        .map(str::to_string);
+    let conditions = std::iter::once(notes.allergies.trim().to_string())
+        .chain(
+            diagnoses
+                .into_iter()
+                .flatten()
+                .map(|x| x.diagnosis.name.clone()),
+        )
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    profile
+        .medications
+        .iter()
+        .cloned()
+        .chain(noted)
+        .map(|x| [vec![x], conditions.clone()].concat().join("\n"))
+        .collect()
+}
+
+const FUNCTION: Function = Function {
+    name: "record_medication_warnings",
+    description: "Record the interactions and contraindications of the medications.",
+};
+
+/// Check the medications in the `notes` and the patient's `profile` for
+/// interactions with each other and contraindications with the candidate
+/// `diagnoses`, the allergies in the `notes` or a pregnancy, using the
+/// documents retrieved for each medication, taken in turn up to the
+/// `retrieval_depth` of the `task`.
+///
+/// Without medications there's nothing to check, so there are no warnings and
+/// no completion is made. Sources that weren't retrieved are dropped.
+pub async fn check_medications(
+    notes: &Notes,
+    profile: &Profile,
+    diagnoses: Option<&Vec<ResolvedDiagnosis>>,
+    db: &DocDb,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Vec<MedicationWarning>> {
+    if profile.medications.is_empty() && notes.medications.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let queries = retrieval_queries(notes, profile, diagnoses);
+    let mut hashes = queries
+        .iter()
+        .map(|x| retrieve_documents(RetrievalQuery::new(x), db, &key, usage, task))
+        .pipe(try_join_all)
+        .await?
+        .into_iter()
+        .map(|x| x.documents)
+        .collect::<Vec<_>>()
+        .pipe(interleave);
+    hashes.truncate(task.retrieval_depth);
+    let excerpts = get_excerpts(&hashes, db, &queries.join("\n\n"), task).await;
+
+    let instructions = MessageInstructions::new(notes, profile, diagnoses).render(task)?;
+
+    let (system, instructions) = fit_excerpts(excerpts, task, |x| {
+        let system = SystemInstructionsExcerpts::new(x, profile, task).render(task)?;
+        Ok((system, instructions.clone()))
+    })?;
+    let warnings: Warnings =
+        call_function(&FUNCTION, system, instructions, key, usage, task).await?;
+    warnings
+        .warnings
+        .into_iter()
+        .map(|x| MedicationWarning {
+            kind: x.kind,
+            medications: x.medications,
+            condition: Some(x.condition).filter(|x| !x.trim().is_empty()),
+            explanation: x.explanation,
+            sources: retrieved_sources(&x.sources, &hashes),
+        })
+        .collect::<Vec<_>>()
+        .pipe(Ok)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instructions_list_medications() {
+        let instructions = MessageInstructions::new(
+            &Notes {
+                medications: "ibuprofen as needed".to_string(),
+                ..Default::default()
+            },
+            &Profile {
+                medications: vec!["warfarin".to_string()],
+                ..Default::default()
+            },
+            None,
+        )
+        .render(&Default::default())
+        .unwrap();
+        assert!(instructions.contains("I take:\n\n> - warfarin\n> ibuprofen as needed"));
+        assert!(instructions.contains("allergies:\n\n> None"));
+    }
+
+    #[test]
+    fn queries_each_medication() {
+        let mut diagnosis = ResolvedDiagnosis::default();
+        diagnosis.diagnosis.name = "Peptic ulcer".to_string();
+        let queries = retrieval_queries(
+            &Notes {
+                medications: "- ibuprofen as needed\n\n- lisinopril".to_string(),
+                allergies: "Penicillin".to_string(),
+                ..Default::default()
+            },
+            &Profile {
+                medications: vec!["warfarin".to_string()],
+                ..Default::default()
+            },
+            Some(&vec![diagnosis]),
+        );
+        assert_eq!(
+            queries,
+            [
+                "warfarin\nPenicillin\nPeptic ulcer",
+                "ibuprofen as needed\nPenicillin\nPeptic ulcer",
+                "lisinopril\nPenicillin\nPeptic ulcer",
+            ]
+        );
+    }
+}
@@ -1,8 +1,19 @@
 //! Functions for calling GPT with prompts specific to Clint.
 
 pub mod cite;
+pub mod config;
 pub mod diagnosis;
+pub mod gaps;
+pub mod medications;
 pub mod notes;
+pub mod profile;
 pub mod respond;
+pub mod retrieve;
 pub mod rewrite;
+pub mod scope;
+pub mod search;
+pub mod summarize;
+pub mod templates;
+pub mod triage;
+pub mod urgency;
 pub mod utils;
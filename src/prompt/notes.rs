@@ -2,25 +2,87 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tap::Pipe;
 
-use super::utils::{quote_lines, Error, Result, SystemInstructionsExcerpts};
+use super::config::TaskConfig;
+use super::profile::Profile;
+use super::templates::Template;
+use super::utils::{first_max_by_key, quote_lines, Error, Result, SystemInstructionsExcerpts};
 use crate::openai::chat::{
-    chat_completion_function, ChatCompletionMessage, ChatCompletionMessageRole,
+    chat_completion_function, chat_completion_function_select, chat_completion_function_stream,
+    ChatCompletionMessage, ChatCompletionParts,
 };
+use crate::openai::usage::UsageTracker;
 use crate::{openai::chat::ChatCompletionArgs, utils::render_template};
 
-#[derive(Debug, Default, JsonSchema, Serialize, Deserialize)]
+/// A symptom the patient reports, with the attributes known about it.
+#[derive(Debug, Default, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct Symptom {
+    #[schemars(description = "The name of the symptom, in medical terminology")]
+    pub name: String,
+    #[schemars(description = "When the symptom started, or empty if unknown")]
+    pub onset: String,
+    #[schemars(description = "How long the symptom lasts or has lasted, or empty if unknown")]
+    pub duration: String,
+    #[schemars(description = "How severe the symptom is, or empty if unknown")]
+    pub severity: String,
+    #[schemars(description = "The side of the body affected, or empty if not applicable")]
+    pub laterality: String,
+    #[schemars(description = "What alleviates, aggravates or otherwise modifies the symptom")]
+    pub modifiers: Vec<String>,
+}
+
+impl Symptom {
+    /// The symptom as a Markdown list item, skipping unknown attributes.
+    fn to_markdown(&self) -> String {
+        let attributes = [
+            ("onset", self.onset.as_str()),
+            ("duration", &self.duration),
+            ("severity", &self.severity),
+            ("laterality", &self.laterality),
+            ("modifiers", &self.modifiers.join(", ")),
+        ]
+        .into_iter()
+        .filter(|(_, x)| !x.is_empty())
+        .map(|(name, x)| format!("{}: {}", name, x))
+        .collect::<Vec<_>>();
+        if attributes.is_empty() {
+            format!("- {}", self.name)
+        } else {
+            format!("- {} ({})", self.name, attributes.join("; "))
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, JsonSchema, Serialize, Deserialize)]
 pub struct Notes {
     #[schemars(description = "The patient's Chief Complaint")]
     pub chief_complaint: String,
     #[schemars(description = "History of Present Illness")]
     pub history_of_present_illness: String,
+    #[schemars(description = "The symptoms the patient reports")]
+    #[serde(default)]
+    pub symptoms: Vec<Symptom>,
+    #[schemars(description = "Symptoms the patient explicitly denies")]
+    #[serde(default)]
+    pub pertinent_negatives: String,
     #[schemars(description = "The patient's medical history")]
     pub patient_history: String,
+    #[schemars(description = "The patient's current medications")]
+    #[serde(default)]
+    pub medications: String,
+    #[schemars(description = "The patient's allergies")]
+    #[serde(default)]
+    pub allergies: String,
+    #[schemars(description = "The medical history of the patient's family")]
+    #[serde(default)]
+    pub family_history: String,
+    #[schemars(description = "The patient's social history")]
+    #[serde(default)]
+    pub social_history: String,
     #[schemars(description = "Review of Systems")]
     pub review_of_systems: String,
 }
 
-const NOTES_MARKDOWN: &'static str = "\
+const NOTES_MARKDOWN: &str = "\
 {depth}# Chief Complaint
 
 {chief_complaint}
@@ -29,10 +91,34 @@ const NOTES_MARKDOWN: &'static str = "\
 
 {history_of_present_illness}
 
+{depth}# Symptoms
+
+{symptoms}
+
+{depth}# Pertinent Negatives
+
+{pertinent_negatives}
+
 {depth}# Patient History
 
 {patient_history}
 
+{depth}# Medications
+
+{medications}
+
+{depth}# Allergies
+
+{allergies}
+
+{depth}# Family History
+
+{family_history}
+
+{depth}# Social History
+
+{social_history}
+
 {depth}# Review of Systems
 
 {review_of_systems}\
@@ -43,7 +129,13 @@ struct NotesMarkdown<'a> {
     depth: &'a str,
     chief_complaint: &'a str,
     history_of_present_illness: &'a str,
+    symptoms: &'a str,
+    pertinent_negatives: &'a str,
     patient_history: &'a str,
+    medications: &'a str,
+    allergies: &'a str,
+    family_history: &'a str,
+    social_history: &'a str,
     review_of_systems: &'a str,
 }
 
@@ -56,11 +148,23 @@ impl<'a> NotesMarkdown<'a> {
 impl Notes {
     pub fn to_markdown(&self, depth: usize) -> String {
         let depth = "#".repeat(depth);
+        let symptoms = self
+            .symptoms
+            .iter()
+            .map(Symptom::to_markdown)
+            .collect::<Vec<_>>()
+            .join("\n");
         NotesMarkdown {
             depth: &depth,
             chief_complaint: &self.chief_complaint,
             history_of_present_illness: &self.history_of_present_illness,
+            symptoms: &symptoms,
+            pertinent_negatives: &self.pertinent_negatives,
             patient_history: &self.patient_history,
+            medications: &self.medications,
+            allergies: &self.allergies,
+            family_history: &self.family_history,
+            social_history: &self.social_history,
             review_of_systems: &self.review_of_systems,
         }
         .render()
@@ -68,7 +172,152 @@ impl Notes {
     }
 }
 
-const INFORMATION_NOTES: &'static str = "\
+impl Notes {
+    /// Set the text of the section `field`, such as `medications`, when the
+    /// user corrects it.
+    ///
+    /// The symptoms aren't text, so replace them with the whole notes
+    /// instead.
+    pub fn set_field(&mut self, field: &str, value: String) -> Result<()> {
+        let section = match field {
+            "chief_complaint" => &mut self.chief_complaint,
+            "history_of_present_illness" => &mut self.history_of_present_illness,
+            "pertinent_negatives" => &mut self.pertinent_negatives,
+            "patient_history" => &mut self.patient_history,
+            "medications" => &mut self.medications,
+            "allergies" => &mut self.allergies,
+            "family_history" => &mut self.family_history,
+            "social_history" => &mut self.social_history,
+            "review_of_systems" => &mut self.review_of_systems,
+            _ => return Err(Error::UnknownNotesField(field.to_string())),
+        };
+        *section = value;
+        Ok(())
+    }
+
+    /// The title and text of each section, with a section per symptom.
+    fn sections(&self) -> Vec<(String, String)> {
+        [
+            ("Chief Complaint", &self.chief_complaint),
+            (
+                "History of Present Illness",
+                &self.history_of_present_illness,
+            ),
+            ("Pertinent Negatives", &self.pertinent_negatives),
+            ("Patient History", &self.patient_history),
+            ("Medications", &self.medications),
+            ("Allergies", &self.allergies),
+            ("Family History", &self.family_history),
+            ("Social History", &self.social_history),
+            ("Review of Systems", &self.review_of_systems),
+        ]
+        .into_iter()
+        .map(|(title, text)| (title.to_string(), text.trim().to_string()))
+        .chain(
+            self.symptoms
+                .iter()
+                .map(|x| (format!("Symptom: {}", x.name), x.to_markdown())),
+        )
+        .collect()
+    }
+}
+
+/// How a section of the notes changed in an update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Removed,
+}
+
+/// A section of the notes, or a symptom, changed by an update.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotesChange {
+    /// The title of the section, or `Symptom: ` and the symptom's name.
+    pub section: String,
+    pub kind: ChangeKind,
+    /// The text before the update, empty if added.
+    pub before: String,
+    /// The text after the update, empty if removed.
+    pub after: String,
+}
+
+/// The changes an update made to the notes, so the user can see what was
+/// learned from their statement and correct it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotesDiff {
+    pub changes: Vec<NotesChange>,
+}
+
+impl NotesDiff {
+    /// The changes from the `previous` notes, if any, to the `current`
+    /// notes, in the order of the sections.
+    pub fn new(previous: Option<&Notes>, current: &Notes) -> Self {
+        let mut before = previous.map(Notes::sections).unwrap_or_default();
+        let mut changes = Vec::new();
+        for (section, after) in current.sections() {
+            let before = before
+                .iter()
+                .position(|(x, _)| *x == section)
+                .map(|i| before.remove(i).1)
+                .unwrap_or_default();
+            let kind = match (before.is_empty(), after.is_empty()) {
+                _ if before == after => continue,
+                (true, _) => ChangeKind::Added,
+                (false, true) => ChangeKind::Removed,
+                (false, false) => ChangeKind::Changed,
+            };
+            changes.push(NotesChange {
+                section,
+                kind,
+                before,
+                after,
+            });
+        }
+        // symptoms no longer in the notes
+        changes.extend(before.into_iter().filter(|(_, x)| !x.is_empty()).map(
+            |(section, before)| NotesChange {
+                section,
+                kind: ChangeKind::Removed,
+                before,
+                after: String::new(),
+            },
+        ));
+        Self { changes }
+    }
+
+    /// The changes as Markdown, with the previous text of changed and removed
+    /// sections quoted.
+    pub fn to_markdown(&self, depth: usize) -> String {
+        let depth = "#".repeat(depth);
+        self.changes
+            .iter()
+            .map(|x| match x.kind {
+                ChangeKind::Added => format!("{}# {} (added)\n\n{}", depth, x.section, x.after),
+                ChangeKind::Changed => format!(
+                    "{}# {} (changed)\n\n{}\n\nPreviously:\n\n{}",
+                    depth,
+                    x.section,
+                    x.after,
+                    quote_lines(&x.before)
+                ),
+                ChangeKind::Removed => format!(
+                    "{}# {} (removed)\n\n{}",
+                    depth,
+                    x.section,
+                    quote_lines(&x.before)
+                ),
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+pub const INFORMATION_NOTES: Template = Template {
+    name: "notes_information",
+    variables: &[],
+    default: "\
 # Structure of Clinical Notes
 
 Clinical notes must contain the following sections.
@@ -90,15 +339,55 @@ radiation, \
 temporal factor, \
 severity.
 
+## Symptoms
+
+The _Symptoms_ list each symptom the patient reports, \
+from the chief complaint, the History of Present Illness and the Review of Systems, \
+with its onset, duration, severity, laterality and modifiers when known.
+
+## Pertinent Negatives
+
+The _Pertinent Negatives_ are the symptoms the patient explicitly denies, \
+such as \"no chest pain\", \
+that help rule out diagnoses. \
+Record them here rather than in the other sections.
+
 ## Patient History
 
 The _Patient History_ is the patient's relevant medical history. \
 Include information about the patient but not strictly related to the chief complaint such as: \
 current or past medical conditions, \
 surgical history, \
-family history, \
+hospitalizations, \
+immunizations, \
 etc.
 
+## Medications
+
+The _Medications_ are the medications the patient currently takes, \
+including over-the-counter drugs and supplements, \
+with their dose and frequency when known.
+
+## Allergies
+
+The _Allergies_ are the patient's allergies to medications, foods or other substances, \
+with the reaction each causes when known.
+
+## Family History
+
+The _Family History_ is the medical history of the patient's relatives, \
+such as conditions that run in the family and the causes of death of close relatives.
+
+## Social History
+
+The _Social History_ is the patient's circumstances relevant to their health such as: \
+occupation, \
+living situation, \
+tobacco, alcohol and drug use, \
+diet and exercise, \
+sexual history, \
+travel.
+
 ## Review of Systems
 
 The _Review of Systems_ is a list of signs or symptoms of disease in body systems not uncovered in the History of Present Illness. \
@@ -118,9 +407,13 @@ hematologic & lymphatic, \
 neurologic, \
 psychiatric, \
 allergic & immunologic.\
-";
+",
+};
 
-const MESSAGE_INSTRUCTIONS_NOTES: &'static str = "\
+pub const MESSAGE_INSTRUCTIONS_NOTES: Template = Template {
+    name: "notes_update",
+    variables: &["current_notes", "statement"],
+    default: "\
 You have recorded the following patient notes:
 
 {current_notes}
@@ -129,6 +422,7 @@ Update your notes by adding information from the following patient statement. \
 The patient might not use the correct or most precise terminology, \
 so include multiple possible interpretations of the patient's statement. \
 Include only information that belongs in clinical notes. \
+Record symptoms the patient denies as pertinent negatives. \
 Be sure to follow the complete structure of clinical notes, \
 including empty sections if you lack information. \
 Don't discard any information from your current notes.
@@ -136,7 +430,8 @@ Don't discard any information from your current notes.
 Patient statement:
 
 {statement}\
-";
+",
+};
 
 #[derive(Serialize)]
 struct MessageInstructionsNotes {
@@ -152,16 +447,20 @@ impl MessageInstructionsNotes {
         }
     }
 
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_INSTRUCTIONS_NOTES, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS_NOTES.render(&self, task)
     }
 }
 
-const MESSAGE_INSTRUCTIONS: &'static str = "\
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "notes",
+    variables: &["statement"],
+    default: "\
 Start writing clinical notes with information from the following patient statement. \
 The patient might not use the correct or most precise terminology, \
 so include multiple possible interpretations of the patient's statement. \
 Include only information that belongs in clinical notes. \
+Record symptoms the patient denies as pertinent negatives. \
 Be sure to follow the complete structure of clinical notes, \
 including empty sections if you lack information, \
 and capture the patient's chief complaint.
@@ -169,7 +468,8 @@ and capture the patient's chief complaint.
 Patient statement:
 
 {statement}\
-";
+",
+};
 
 #[derive(Serialize)]
 struct MessageInstructions {
@@ -183,45 +483,184 @@ impl MessageInstructions {
         }
     }
 
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_INSTRUCTIONS, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
     }
 }
 
-/// Create or update the clinical notes `current_notes` with the patient
-/// `statement`.
-pub async fn create_update_notes(
-    statement: String,
+const FUNCTION_NAME: &str = "record_notes";
+const FUNCTION_DESCRIPTION: &str = "Record patient notes.";
+
+fn notes_args(
+    statement: &str,
     current_notes: Option<&Notes>,
+    profile: &Profile,
     key: String,
-    max_retries: usize,
-) -> Result<Notes> {
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<ChatCompletionArgs> {
     let instructions = if let Some(current_notes) = current_notes {
-        MessageInstructionsNotes::new(&statement, current_notes).render()?
+        MessageInstructionsNotes::new(statement, current_notes).render(task)?
     } else {
-        MessageInstructions::new(&statement).render()?
+        MessageInstructions::new(statement).render(task)?
     };
+    ChatCompletionArgs::new(key)
+        .with_usage(usage)
+        .with_model(task.model.clone())
+        .with_temperature(task.temperature)
+        .with_message(ChatCompletionMessage::system(
+            SystemInstructionsExcerpts::new(&[INFORMATION_NOTES.text(task)], profile, task)
+                .render(task)?,
+        ))
+        .with_examples(&task.examples)
+        .with_message(ChatCompletionMessage::user(instructions))
+        .pipe(Ok)
+}
+
+/// Pick the notes with the most sections filled in.
+fn most_complete(samples: Vec<Notes>) -> Notes {
+    first_max_by_key(samples, |x| {
+        x.sections().iter().filter(|(_, x)| !x.is_empty()).count()
+    })
+}
+
+/// Create or update the clinical notes `current_notes` with the patient
+/// `statement`, along with the changes made to them. The patient's `profile`
+/// is given as context.
+///
+/// If the `task` samples several completions, the most complete notes are
+/// kept.
+pub async fn create_update_notes(
+    statement: String,
+    current_notes: Option<&Notes>,
+    profile: &Profile,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<(Notes, NotesDiff)> {
+    let notes = chat_completion_function_select(
+        notes_args(&statement, current_notes, profile, key, usage, task)?.with_n(task.samples),
+        FUNCTION_NAME.to_string(),
+        Some(FUNCTION_DESCRIPTION.to_string()),
+        task.max_retries,
+        most_complete,
+    )
+    .await
+    .map_err(Error::OpenAIError)?;
+    let changes = NotesDiff::new(current_notes, &notes);
+    Ok((notes, changes))
+}
+
+pub const CONSISTENCY_INSTRUCTIONS: Template = Template {
+    name: "notes_consistency",
+    variables: &["notes", "changes"],
+    default: "\
+You have recorded the following patient notes:
+
+{notes}
+
+The following sections of the notes were just changed:
+
+{changes}
+
+List any contradictions the changed sections introduce with the rest of the notes, \
+such as a medication dose that differs between sections \
+or a symptom recorded both as present and as denied. \
+Don't list missing information or changes that are consistent with the notes.\
+",
+};
+
+#[derive(Serialize)]
+struct ConsistencyInstructions {
+    notes: String,
+    changes: String,
+}
+
+impl ConsistencyInstructions {
+    fn new(notes: &Notes, changes: &NotesDiff) -> Self {
+        Self {
+            notes: notes.to_markdown(0).as_str().pipe(quote_lines),
+            changes: changes.to_markdown(0).as_str().pipe(quote_lines),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        CONSISTENCY_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+/// Sections of the notes that contradict each other.
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize)]
+pub struct Contradiction {
+    #[schemars(description = "The titles of the sections that contradict each other.")]
+    pub sections: Vec<String>,
+    #[schemars(description = "How the sections contradict each other. 30 words or less.")]
+    pub explanation: String,
+}
+
+#[derive(Debug, Default, JsonSchema, Deserialize)]
+struct Contradictions {
+    #[schemars(description = "Contradictions introduced by the changed sections.")]
+    contradictions: Vec<Contradiction>,
+}
+
+const CONSISTENCY_FUNCTION_NAME: &str = "flag_contradictions";
+const CONSISTENCY_FUNCTION_DESCRIPTION: &str = "Flag contradictions in patient notes.";
+
+/// Flag the contradictions the `changes`, such as the user's corrections,
+/// introduce in the `notes`.
+///
+/// Without changes there's nothing to check, so no completion is made.
+pub async fn check_notes(
+    notes: &Notes,
+    changes: &NotesDiff,
+    profile: &Profile,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Vec<Contradiction>> {
+    if changes.changes.is_empty() {
+        return Ok(Vec::new());
+    }
     let args = ChatCompletionArgs::new(key)
-        .with_temperature(0.0)
-        .with_message(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::System,
-            content: Some(
-                SystemInstructionsExcerpts::new(&vec![INFORMATION_NOTES.to_string()]).render()?,
-            ),
-            name: None,
-            function_call: None,
-        })
-        .with_message(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
-            content: Some(instructions),
-            name: None,
-            function_call: None,
-        });
-    chat_completion_function(
+        .with_usage(usage)
+        .with_model(task.model.clone())
+        .with_temperature(task.temperature)
+        .with_message(ChatCompletionMessage::system(
+            SystemInstructionsExcerpts::new(&[INFORMATION_NOTES.text(task)], profile, task)
+                .render(task)?,
+        ))
+        .with_message(ChatCompletionMessage::user(
+            ConsistencyInstructions::new(notes, changes).render(task)?,
+        ));
+    let Contradictions { contradictions } = chat_completion_function(
         args,
-        "record_notes".to_string(),
-        Some("Record patient notes.".to_string()),
-        max_retries,
+        CONSISTENCY_FUNCTION_NAME.to_string(),
+        Some(CONSISTENCY_FUNCTION_DESCRIPTION.to_string()),
+        task.max_retries,
+    )
+    .await
+    .map_err(Error::OpenAIError)?;
+    Ok(contradictions)
+}
+
+/// Like [`create_update_notes`], but stream the notes as they're written.
+///
+/// Parse the notes with [`ChatCompletionParts::function_output`] once the
+/// stream is done.
+pub async fn stream_notes(
+    statement: &str,
+    current_notes: Option<&Notes>,
+    profile: &Profile,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<ChatCompletionParts> {
+    chat_completion_function_stream::<Notes>(
+        notes_args(statement, current_notes, profile, key, usage, task)?,
+        FUNCTION_NAME.to_string(),
+        Some(FUNCTION_DESCRIPTION.to_string()),
+        task.max_retries,
     )
     .await
     .map_err(Error::OpenAIError)
@@ -230,6 +669,37 @@ pub async fn create_update_notes(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::openai::schema::function_parameters;
+
+    #[test]
+    fn picks_most_complete_notes() {
+        let notes = |chief_complaint: &str, medications: &str| Notes {
+            chief_complaint: chief_complaint.to_string(),
+            medications: medications.to_string(),
+            ..Default::default()
+        };
+        let best = most_complete(vec![
+            notes("a", ""),
+            notes("b", "ibuprofen"),
+            notes("c", "aspirin"),
+        ]);
+        assert_eq!(best.chief_complaint, "b");
+    }
+
+    #[test]
+    fn notes_schema_is_portable() {
+        let schema = function_parameters::<Notes>(true).unwrap();
+        assert_eq!(schema["type"], "object");
+        assert!(schema["properties"]["chief_complaint"].is_object());
+        // the sections added later are required of the model all the same
+        let required = schema["required"].as_array().unwrap();
+        assert!(required.contains(&"medications".into()));
+        assert!(required.contains(&"symptoms".into()));
+        let schema = schema.to_string();
+        for keyword in ["$schema", "title", "format", "$ref", "definitions"] {
+            assert!(!schema.contains(&format!("\"{}\"", keyword)));
+        }
+    }
 
     #[test]
     fn notes_renders_markdown() {
@@ -238,6 +708,7 @@ mod test {
             history_of_present_illness: String::new(),
             patient_history: String::new(),
             review_of_systems: String::new(),
+            ..Default::default()
         }
         .to_markdown(0);
         assert!(notes_md.starts_with("# "));
@@ -251,11 +722,128 @@ mod test {
             history_of_present_illness: String::new(),
             patient_history: String::new(),
             review_of_systems: String::new(),
+            ..Default::default()
         }
         .to_markdown(2);
         assert!(notes_md.starts_with("### "));
     }
 
+    #[test]
+    fn notes_renders_symptoms() {
+        let notes_md = Notes {
+            symptoms: vec![
+                Symptom {
+                    name: "headache".to_string(),
+                    onset: "2 days ago".to_string(),
+                    laterality: "left".to_string(),
+                    modifiers: vec![
+                        "worse with light".to_string(),
+                        "better lying down".to_string(),
+                    ],
+                    ..Default::default()
+                },
+                Symptom {
+                    name: "nausea".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+        .to_markdown(0);
+        assert!(notes_md.contains(
+            "# Symptoms\n\n- headache (onset: 2 days ago; laterality: left; \
+             modifiers: worse with light, better lying down)\n- nausea\n\n"
+        ));
+    }
+
+    #[test]
+    fn records_pertinent_negatives() {
+        let notes = Notes {
+            chief_complaint: "Headache.".to_string(),
+            pertinent_negatives: "No fever. No neck stiffness.".to_string(),
+            ..Default::default()
+        };
+        let notes_md = notes.to_markdown(0);
+        assert!(notes_md.contains("# Pertinent Negatives\n\nNo fever. No neck stiffness."));
+        let diff = NotesDiff::new(None, &notes);
+        assert!(diff
+            .changes
+            .iter()
+            .any(|x| x.section == "Pertinent Negatives" && x.kind == ChangeKind::Added));
+        // notes saved before the section was added read as having none
+        let saved = r#"{"chief_complaint": "Headache.", "history_of_present_illness": "",
+            "patient_history": "", "review_of_systems": ""}"#;
+        let saved: Notes = serde_json::from_str(saved).unwrap();
+        assert_eq!(saved.pertinent_negatives, "");
+    }
+
+    #[test]
+    fn diffs_notes() {
+        let previous = Notes {
+            chief_complaint: "Headache.".to_string(),
+            medications: "Ibuprofen.".to_string(),
+            symptoms: vec![Symptom {
+                name: "nausea".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let current = Notes {
+            chief_complaint: "Headache.".to_string(),
+            medications: "Ibuprofen 200 mg.".to_string(),
+            allergies: "Penicillin.".to_string(),
+            ..Default::default()
+        };
+        let diff = NotesDiff::new(Some(&previous), &current);
+        let changes = diff
+            .changes
+            .iter()
+            .map(|x| (x.section.as_str(), x.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                ("Medications", ChangeKind::Changed),
+                ("Allergies", ChangeKind::Added),
+                ("Symptom: nausea", ChangeKind::Removed),
+            ]
+        );
+        assert!(diff.to_markdown(1).starts_with(
+            "## Medications (changed)\n\nIbuprofen 200 mg.\n\nPreviously:\n\n> Ibuprofen."
+        ));
+        assert!(NotesDiff::new(Some(&current), &current).changes.is_empty());
+        assert_eq!(NotesDiff::new(None, &current).changes.len(), 3);
+    }
+
+    #[test]
+    fn sets_notes_fields() {
+        let mut notes = Notes::default();
+        notes
+            .set_field("medications", "Ibuprofen 200 mg.".to_string())
+            .unwrap();
+        assert_eq!(notes.medications, "Ibuprofen 200 mg.");
+        assert!(matches!(
+            notes.set_field("symptoms", String::new()),
+            Err(Error::UnknownNotesField(_))
+        ));
+    }
+
+    #[test]
+    fn notes_deserializes_without_new_sections() {
+        let notes: Notes = serde_json::from_str(
+            r#"{
+                "chief_complaint": "abc",
+                "history_of_present_illness": "",
+                "patient_history": "",
+                "review_of_systems": ""
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(notes.chief_complaint, "abc");
+        assert!(notes.medications.is_empty());
+        assert!(notes.to_markdown(0).contains("# Allergies"));
+    }
+
     #[test]
     fn instructions_renders_with_notes() {
         let instructions = MessageInstructionsNotes::new(
@@ -265,7 +853,7 @@ mod test {
                 ..Default::default()
             },
         )
-        .render()
+        .render(&Default::default())
         .unwrap();
         assert!(instructions.contains("patient notes:\n\n> "));
         assert!(instructions.contains("Patient statement:\n\n> abc"));
@@ -273,7 +861,9 @@ mod test {
 
     #[test]
     fn instructions_renders_without_notes() {
-        let instructions = MessageInstructions::new("abc").render().unwrap();
+        let instructions = MessageInstructions::new("abc")
+            .render(&Default::default())
+            .unwrap();
         assert!(instructions.contains("Patient statement:\n\n> abc"));
     }
 }
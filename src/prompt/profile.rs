@@ -0,0 +1,74 @@
+//! Patient demographics entered in a form rather than extracted from the
+//! conversation, so they reliably inform every prompt.
+
+use serde::{Deserialize, Serialize};
+
+/// The sex of the patient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sex {
+    Female,
+    Male,
+    Other,
+}
+
+/// What the patient told the app about themselves, with unknown fields
+/// omitted.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    /// The age in years.
+    pub age: Option<u32>,
+    pub sex: Option<Sex>,
+    pub pregnant: Option<bool>,
+    /// The medications currently taken.
+    pub medications: Vec<String>,
+}
+
+impl Profile {
+    /// The known fields as a Markdown list, empty if none are known.
+    pub fn to_markdown(&self) -> String {
+        let sex = self.sex.map(|x| match x {
+            Sex::Female => "female",
+            Sex::Male => "male",
+            Sex::Other => "other",
+        });
+        [
+            ("Age", self.age.map(|x| format!("{} years", x))),
+            ("Sex", sex.map(str::to_string)),
+            (
+                "Pregnant",
+                self.pregnant
+                    .map(|x| if x { "yes" } else { "no" }.to_string()),
+            ),
+            (
+                "Medications",
+                Some(self.medications.join(", ")).filter(|x| !x.is_empty()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, x)| Some(format!("- {}: {}", name, x?)))
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profile_renders_known_fields() {
+        let profile = Profile {
+            age: Some(34),
+            sex: Some(Sex::Female),
+            medications: vec!["levothyroxine".to_string(), "ibuprofen".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            profile.to_markdown(),
+            "- Age: 34 years\n- Sex: female\n- Medications: levothyroxine, ibuprofen"
+        );
+        assert_eq!(Profile::default().to_markdown(), "");
+    }
+}
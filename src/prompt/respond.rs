@@ -1,21 +1,29 @@
-use futures::future::join_all;
 use serde::Serialize;
 use tap::Pipe;
 
+use super::config::TaskConfig;
 use super::diagnosis::ResolvedDiagnosis;
 use super::notes::Notes;
+use super::profile::Profile;
+use super::retrieve::{retrieve_documents, RetrievalQuery};
+use super::summarize::{summarize_messages, SUMMARY_TOKENS};
+use super::templates::Template;
 use super::utils::{
-    embed_for_db, get_excerpt, quote_lines, EmbedStructure, Error, Result,
-    SystemInstructionsExcerpts,
+    audience_instructions, fit_context, get_excerpts, quote_lines, screen_message, EmbedStructure,
+    Error, Result, SystemInstructionsExcerpts,
 };
 use crate::docdb::DocDb;
 use crate::openai::chat::{
-    ChatCompletionArgs, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionModel,
-    ChatCompletionParts,
+    ChatCompletionArgs, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionParts,
+    Example, ImageUrl,
 };
-use crate::utils::render_template;
+use crate::openai::tokens::{count_message_tokens, fit_messages};
+use crate::openai::usage::UsageTracker;
 
-const MESSAGE_INSTRUCTIONS: &'static str = "\
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "respond",
+    variables: &["message", "notes", "stale", "abstain", "audience"],
+    default: "\
 My message is:
 
 {message}
@@ -24,34 +32,120 @@ You have recorded the following clinical notes about me:
 
 {notes}
 
-Please respond to the my message using plain English. \
+Please respond to my message. \
 You can ask me questions to gather more information for your notes. \
 Don't ask questions that have already been answered or can be answered from the notes. \
 Don't repeat what was already said in a prior message.\
-";
+{{ if stale }} {stale}{{ endif }}\
+{{ if abstain }} {abstain}{{ endif }}\
+{{ if audience }}
+
+{audience}{{ endif }}\
+",
+};
+
+/// The `stale` variable of the instructions when excerpts can be flagged as
+/// out of date.
+pub const STALE_INSTRUCTIONS: Template = Template {
+    name: "respond_stale",
+    variables: &[],
+    default: "\
+When guidance comes from an excerpt marked as possibly out of date, \
+say so and suggest confirming it with a clinician.\
+",
+};
+
+/// The `abstain` variable of the instructions when no relevant excerpts were
+/// found.
+pub const ABSTAIN_INSTRUCTIONS: Template = Template {
+    name: "respond_abstain",
+    variables: &[],
+    default: "\
+No document excerpts relevant to my message were found. \
+Don't improvise medical information: \
+say that your knowledge base doesn't cover my message and suggest asking a clinician.\
+",
+};
+
+/// A response to the user's message, streamed as it's written.
+pub struct Response {
+    pub parts: ChatCompletionParts,
+    /// Whether documents relevant to the message were found to ground the
+    /// response. If not, the model is told to say the knowledge base doesn't
+    /// cover the message.
+    pub grounded: bool,
+}
+
+/// Whether any documents were `found`, the most similar having the
+/// `similarity` at least the `min_grounding` of the `task`.
+fn is_grounded(found: bool, similarity: Option<f32>, task: &TaskConfig) -> bool {
+    found
+        && task
+            .min_grounding
+            .is_none_or(|x| similarity.is_some_and(|y| y >= x))
+}
+
+/// The optional guidance closing the instructions, empty if not given.
+#[derive(Serialize)]
+struct Guidance {
+    stale: String,
+    abstain: String,
+    audience: String,
+}
+
+impl Guidance {
+    /// The guidance for the `task`, telling the model to abstain unless the
+    /// response is `grounded`.
+    fn new(task: &TaskConfig, grounded: bool) -> Self {
+        Self {
+            stale: match task.stale_after_days {
+                Some(_) => STALE_INSTRUCTIONS.text(task),
+                None => String::new(),
+            },
+            abstain: match grounded {
+                true => String::new(),
+                false => ABSTAIN_INSTRUCTIONS.text(task),
+            },
+            audience: audience_instructions(task).unwrap_or_default(),
+        }
+    }
+}
 
 #[derive(Serialize)]
 struct MessageInstructions {
     pub notes: String,
     pub message: String,
+    #[serde(flatten)]
+    guidance: Guidance,
 }
 
 impl MessageInstructions {
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_INSTRUCTIONS, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
     }
 }
 
 impl MessageInstructions {
-    fn new(notes: &Notes, message: &str) -> Self {
+    fn new(notes: &Notes, message: &str, guidance: Guidance) -> Self {
         Self {
             notes: notes.to_markdown(0).pipe(|x| quote_lines(x.as_str())),
             message: message.pipe(quote_lines),
+            guidance,
         }
     }
 }
 
-const MESSAGE_INSTRUCTIONS_DIAGNOSIS: &'static str = "\
+pub const MESSAGE_INSTRUCTIONS_DIAGNOSIS: Template = Template {
+    name: "respond_diagnosis",
+    variables: &[
+        "message",
+        "notes",
+        "diagnosis",
+        "stale",
+        "abstain",
+        "audience",
+    ],
+    default: "\
 My message is:
 
 {message}
@@ -64,101 +158,189 @@ You have arrived at the following differential diagnosis:
 
 {diagnosis}
 
-Please respond to the my message using plain English. \
+Please respond to my message. \
 You can ask me questions to gather more information for your notes and to narrow the diagnosis. \
 Don't ask questions that have already been answered or can be answered from the notes. \
 Please also explain any plausible diagnoses. \
 Don't repeat what was already said in a prior message.\
-";
+{{ if stale }} {stale}{{ endif }}\
+{{ if abstain }} {abstain}{{ endif }}\
+{{ if audience }}
+
+{audience}{{ endif }}\
+",
+};
 
 #[derive(Serialize)]
 struct MessageInstructionsDiagnosis {
     pub notes: String,
     pub diagnosis: String,
     pub message: String,
+    #[serde(flatten)]
+    guidance: Guidance,
 }
 
 impl MessageInstructionsDiagnosis {
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_INSTRUCTIONS_DIAGNOSIS, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS_DIAGNOSIS.render(&self, task)
     }
 }
 
 impl MessageInstructionsDiagnosis {
-    fn new(notes: &Notes, diagnoses: &Vec<ResolvedDiagnosis>, message: &str) -> Self {
+    fn new(
+        notes: &Notes,
+        diagnoses: &[ResolvedDiagnosis],
+        message: &str,
+        guidance: Guidance,
+    ) -> Self {
         Self {
             notes: notes.to_markdown(0).pipe(|x| quote_lines(x.as_str())),
             diagnosis: diagnoses
-                .into_iter()
+                .iter()
                 .map(|x| x.diagnosis.to_markdown(0))
                 .collect::<Vec<_>>()
                 .join("\n\n")
                 .pipe(|x| quote_lines(x.as_str())),
             message: message.pipe(quote_lines),
+            guidance,
         }
     }
 }
 
-/// Respond to the user's `message`.
+/// The last `turns` of the `messages` followed by the user's `message`, as a
+/// transcript to find the documents the conversation is about.
+fn recent_turns(messages: &[ChatCompletionMessage], message: &str, turns: usize) -> String {
+    messages
+        .iter()
+        .filter_map(|x| {
+            let speaker = match x.role {
+                ChatCompletionMessageRole::User => "User",
+                ChatCompletionMessageRole::Assistant => "Assistant",
+                _ => return None,
+            };
+            Some(format!("{}: {}", speaker, x.content.as_deref()?))
+        })
+        .collect::<Vec<_>>()
+        .pipe(|x| x[x.len().saturating_sub(turns)..].to_vec())
+        .into_iter()
+        .chain(std::iter::once(format!("User: {}", message)))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Respond to the user's `message`, with the patient's `profile` as context.
 ///
 /// If a `diagnoses` is provided, the response include a description of the
 /// more plausible diagnoses. If a `statement` is provided, it is used to help
-/// find context documents.
+/// find context documents. Any `images` are shown to the model along with the
+/// `message`, so the `task` must use a vision model.
+///
+/// If the `messages` history doesn't fit in the context window, the older
+/// messages are replaced by a summary written with the `summarize` task. The
+/// recent messages can also help find context documents, as set by the
+/// `conversation_turns` and `conversation_weight` of the `task`.
+///
+/// The `task` also sets how the response is written:
+/// - with `moderate`, fails with [`Error::Flagged`] when moderation flags the
+///   `message`
+/// - with `stale_after_days`, points out guidance from stale excerpts
+/// - with an `audience`, is written for it
+///
+/// If no relevant documents are found, the model is told to say so rather
+/// than improvise.
+#[allow(clippy::too_many_arguments)]
 pub async fn respond(
     notes: &Notes,
+    profile: &Profile,
     message: String,
+    images: Vec<ImageUrl>,
     diagnoses: Option<&Vec<ResolvedDiagnosis>>,
     statement: Option<&str>,
     messages: Vec<ChatCompletionMessage>,
     db: &DocDb,
     key: String,
-    max_retries: usize,
-) -> Result<ChatCompletionParts> {
-    let embedding = embed_for_db(
-        &EmbedStructure::new(notes, diagnoses, statement).render()?,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+    summarize: &TaskConfig,
+) -> Result<Response> {
+    screen_message(&message, &key, task).await?;
+    let context = EmbedStructure::new(notes, profile, diagnoses, statement).render()?;
+    let conversation = recent_turns(&messages, &message, task.conversation_turns);
+    let retrieved = retrieve_documents(
+        RetrievalQuery::new(&context).with_conversation(&conversation),
         db,
         &key,
+        usage,
+        task,
     )
     .await?;
-    let hashes = db.get_similar(embedding.view(), 8, None);
-    let excerpts = hashes
-        .iter()
-        .map(|x| get_excerpt(x, db))
-        .pipe(join_all)
-        .await
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
+    // the excerpts are cut around what the user asks rather than the notes
+    let mut excerpts = get_excerpts(&retrieved.documents, db, &conversation, task).await;
+    let grounded = is_grounded(!excerpts.is_empty(), retrieved.similarity, task);
+    if !grounded {
+        excerpts.clear();
+    }
+
+    let model = &task.model;
+    let guidance = Guidance::new(task, grounded);
+    let content = if let Some(diagnoses) = diagnoses {
+        MessageInstructionsDiagnosis::new(notes, diagnoses, &message, guidance).render(task)?
+    } else {
+        MessageInstructions::new(notes, &message, guidance).render(task)?
+    };
+    let instructions = ChatCompletionMessage {
+        images,
+        ..ChatCompletionMessage::user(content)
+    };
+    let system = ChatCompletionMessage::system(
+        SystemInstructionsExcerpts::new(&[], profile, task).render(task)?,
+    );
+    let examples = Example::messages(&task.examples);
+    let fixed = [
+        vec![system.clone()],
+        examples.clone(),
+        vec![instructions.clone()],
+    ]
+    .concat();
+    let (excerpts, recent) = fit_context(model, &fixed, excerpts, messages.clone());
+    let messages = if recent.len() < messages.len() {
+        // make room for the summary by dropping more of the older messages
+        let budget = count_message_tokens(model, &recent).saturating_sub(SUMMARY_TOKENS);
+        let recent = fit_messages(model, recent, budget);
+        let older = &messages[..messages.len() - recent.len()];
+        let summary = summarize_messages(older, key.clone(), usage, summarize).await?;
+        std::iter::once(summary).chain(recent).collect()
+    } else {
+        recent
+    };
 
     ChatCompletionParts::new(
         ChatCompletionArgs::new(key)
-            .with_model(ChatCompletionModel::Gpt4o)
-            .with_temperature(0.0)
+            .with_usage(usage)
+            .with_model(model.clone())
+            .with_temperature(task.temperature)
+            .with_max_continuations(task.max_continuations)
             .with_message(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::System,
-                content: Some(SystemInstructionsExcerpts::new(&excerpts).render()?),
-                name: None,
-                function_call: None,
+                content: Some(
+                    SystemInstructionsExcerpts::new(&excerpts, profile, task).render(task)?,
+                ),
+                ..system
             })
+            .with_messages(examples)
             .with_messages(messages)
-            .with_message(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::User,
-                content: Some(if let Some(diagnoses) = diagnoses {
-                    MessageInstructionsDiagnosis::new(notes, diagnoses, &message).render()?
-                } else {
-                    MessageInstructions::new(notes, &message).render()?
-                }),
-                name: None,
-                function_call: None,
-            }),
-        max_retries,
+            .with_message(instructions),
+        task.max_retries,
     )
     .await
-    .map_err(Error::OpenAIError)
+    .map_err(Error::OpenAIError)?
+    .pipe(|parts| Response { parts, grounded })
+    .pipe(Ok)
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::config::Audience;
+    use super::super::utils::AUDIENCE_CLINICIAN;
     use super::*;
 
     #[test]
@@ -169,10 +351,57 @@ mod test {
                 ..Default::default()
             },
             "bcd",
+            Guidance::new(&Default::default(), true),
         )
-        .render()
+        .render(&Default::default())
         .unwrap();
         assert!(instructions.contains("message is:\n\n> bcd"));
         assert!(instructions.contains("notes about me:\n\n> # Chief Complaint\n> \n> abc"));
+        assert!(instructions.ends_with("in a prior message."));
+    }
+
+    #[test]
+    fn instructions_render_guidance() {
+        let task = TaskConfig {
+            stale_after_days: Some(365),
+            audience: Some(Audience::Clinician),
+            ..Default::default()
+        };
+        let instructions =
+            MessageInstructions::new(&Default::default(), "bcd", Guidance::new(&task, false))
+                .render(&Default::default())
+                .unwrap();
+        let expected = format!(
+            "in a prior message. {} {}\n\n{}",
+            STALE_INSTRUCTIONS.default, ABSTAIN_INSTRUCTIONS.default, AUDIENCE_CLINICIAN.default
+        );
+        assert!(instructions.ends_with(&expected));
+    }
+
+    #[test]
+    fn grounds_on_similar_documents() {
+        let task = TaskConfig {
+            min_grounding: Some(0.5),
+            ..Default::default()
+        };
+        assert!(is_grounded(true, Some(0.6), &task));
+        assert!(!is_grounded(true, Some(0.4), &task));
+        assert!(!is_grounded(false, None, &task));
+        assert!(is_grounded(true, None, &Default::default()));
+    }
+
+    #[test]
+    fn recent_turns_end_with_the_message() {
+        let message = |role, content: &str| ChatCompletionMessage::new(role, content.to_string());
+        let messages = [
+            message(ChatCompletionMessageRole::User, "I have a headache."),
+            message(ChatCompletionMessageRole::System, "Summary"),
+            message(ChatCompletionMessageRole::Assistant, "Try ibuprofen."),
+        ];
+        assert_eq!(
+            recent_turns(&messages, "What about that medication?", 1),
+            "Assistant: Try ibuprofen.\n\nUser: What about that medication?"
+        );
+        assert_eq!(recent_turns(&messages, "Hi", 0), "User: Hi");
     }
 }
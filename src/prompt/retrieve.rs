@@ -0,0 +1,358 @@
+//! Find the documents used as context by the prompts, optionally searching
+//! with several focused queries or a hypothetical document rather than the
+//! embedding of the case itself.
+
+use futures::future::try_join_all;
+use ndarray::{Array1, Zip};
+use noisy_float::prelude::N32;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::config::TaskConfig;
+use super::templates::Template;
+use super::utils::{embed_for_db, quote_lines, similar_documents, system_identity, Error, Result};
+use crate::docdb::{DocDb, DocId, Filter};
+use crate::openai::chat::{
+    chat_completion, chat_completion_function, ChatCompletionArgs, ChatCompletionMessage,
+};
+use crate::openai::usage::UsageTracker;
+
+pub const QUERIES_INSTRUCTIONS: Template = Template {
+    name: "search_queries",
+    variables: &["context", "count"],
+    default: "\
+Consider the following patient case:
+
+{context}
+
+Write {count} short search queries to find medical documents relevant to the case. \
+Focus each query on a different aspect: \
+the cluster of symptoms, \
+the leading diagnostic hypothesis, \
+and the conditions in the patient's history that could be related.\
+",
+};
+
+pub const HYPOTHETICAL_INSTRUCTIONS: Template = Template {
+    name: "hypothetical_document",
+    variables: &["context"],
+    default: "\
+Consider the following patient case:
+
+{context}
+
+Write a short passage, of one paragraph, from a medical reference about the condition \
+most likely to explain the case. \
+Write it as the reference would, describing the condition rather than the patient.\
+",
+};
+
+/// The most tokens of a hypothetical document.
+const HYPOTHETICAL_TOKENS: u16 = 256;
+
+#[derive(Serialize)]
+struct HypotheticalInstructions {
+    context: String,
+}
+
+impl HypotheticalInstructions {
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        HYPOTHETICAL_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+#[derive(Serialize)]
+struct QueriesInstructions {
+    context: String,
+    count: usize,
+}
+
+impl QueriesInstructions {
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        QUERIES_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+#[derive(Debug, Default, JsonSchema, Deserialize)]
+struct SearchQueries {
+    #[schemars(description = "The search queries, 15 words or less each.")]
+    queries: Vec<String>,
+}
+
+/// Write up to `count` focused search queries for the patient case in
+/// `context`, with the query model of the `task`.
+async fn search_queries(
+    context: &str,
+    count: usize,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Vec<String>> {
+    let instructions = QueriesInstructions {
+        context: quote_lines(context),
+        count,
+    };
+    let SearchQueries { mut queries } = chat_completion_function(
+        ChatCompletionArgs::new(key)
+            .with_usage(usage)
+            .with_model(task.query_model.clone().unwrap_or(task.model.clone()))
+            .with_temperature(task.temperature)
+            .with_message(ChatCompletionMessage::system(system_identity(task)))
+            .with_message(ChatCompletionMessage::user(instructions.render(task)?)),
+        "list_search_queries".to_string(),
+        Some("List search queries.".to_string()),
+        task.max_retries,
+    )
+    .await
+    .map_err(Error::OpenAIError)?;
+    queries.retain(|x| !x.trim().is_empty());
+    queries.truncate(count);
+    Ok(queries)
+}
+
+/// Write a reference passage about the condition likely to explain the
+/// patient case in `context`, with the query model of the `task`.
+///
+/// A vague statement embeds poorly, while the passage embeds close to the
+/// documents that answer it, even if the condition it describes is wrong.
+async fn hypothetical_document(
+    context: &str,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<String> {
+    chat_completion(
+        hypothetical_args(context, key, usage, task)?,
+        task.max_retries,
+    )
+    .await
+    .map_err(Error::OpenAIError)?
+    .choices
+    .into_iter()
+    .next()
+    .and_then(|x| x.message.content)
+    .filter(|x| !x.trim().is_empty())
+    .ok_or(Error::NetworkResponseError)
+}
+
+/// The request writing the hypothetical document for the `context`.
+fn hypothetical_args(
+    context: &str,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<ChatCompletionArgs> {
+    let mut args = ChatCompletionArgs::new(key)
+        .with_usage(usage)
+        .with_model(task.query_model.clone().unwrap_or(task.model.clone()))
+        .with_temperature(task.temperature)
+        .with_message(ChatCompletionMessage::system(system_identity(task)))
+        .with_message(ChatCompletionMessage::user(
+            HypotheticalInstructions {
+                context: quote_lines(context),
+            }
+            .render(task)?,
+        ));
+    args.max_tokens = Some(HYPOTHETICAL_TOKENS);
+    Ok(args)
+}
+
+/// Mix the embedding of the `conversation` into that of the case, scaling
+/// both to unit length first so the `weight` of the conversation is its share
+/// of the direction of the result.
+fn blend(case: &Array1<N32>, conversation: &Array1<N32>, weight: f32) -> Array1<N32> {
+    let unit = |x: &Array1<N32>| {
+        let norm = x.dot(x).raw().sqrt();
+        if norm > 0.0 {
+            x.mapv(|y| y / norm)
+        } else {
+            x.clone()
+        }
+    };
+    let weight = weight.clamp(0.0, 1.0);
+    Zip::from(&unit(case))
+        .and(&unit(conversation))
+        .map_collect(|&x, &y| x * (1.0 - weight) + y * weight)
+}
+
+/// Merge the `results` of several queries, taking the next document of each
+/// in turn so every query is represented when the context is cut short.
+pub fn interleave(results: Vec<Vec<DocId>>) -> Vec<DocId> {
+    let mut merged = Vec::new();
+    let longest = results.iter().map(Vec::len).max().unwrap_or_default();
+    for i in 0..longest {
+        for id in results.iter().filter_map(|x| x.get(i)) {
+            if !merged.contains(id) {
+                merged.push(*id);
+            }
+        }
+    }
+    merged
+}
+
+/// The documents found for a patient case.
+#[derive(Debug, Default)]
+pub struct Retrieved {
+    /// The documents, the most relevant first.
+    pub documents: Vec<DocId>,
+    /// The similarity of the most similar document with the case, rather than
+    /// the hypothetical document searched with, if any.
+    pub similarity: Option<f32>,
+}
+
+/// What to search the documents with.
+#[derive(Debug, Clone, Copy)]
+pub struct RetrievalQuery<'a> {
+    /// The patient case.
+    pub context: &'a str,
+    /// The recent turns of the chat, if any.
+    pub conversation: Option<&'a str>,
+    /// The documents to search among, all by default.
+    pub filter: Option<&'a Filter>,
+}
+
+impl<'a> RetrievalQuery<'a> {
+    pub fn new(context: &'a str) -> Self {
+        Self {
+            context,
+            conversation: None,
+            filter: None,
+        }
+    }
+
+    pub fn with_conversation(mut self, conversation: &'a str) -> Self {
+        self.conversation = Some(conversation);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: &'a Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+}
+
+/// Find the documents for the patient case in the `context` of the `query`
+/// that pass its `filter`, the most relevant first.
+///
+/// If the query has a `conversation`, its embedding is mixed into that of the
+/// case with the `conversation_weight` of the `task`, so follow-up questions
+/// find the documents they're about.
+///
+/// If the `task` asks for a hypothetical document, it is searched with
+/// instead of the `context`, which is used if the document can't be written.
+///
+/// If the `task` asks for search queries, the documents found with each of
+/// them are merged with those found with the whole `context`, keeping the
+/// `retrieval_depth` of the `task`. The documents found with the `context`
+/// alone are used if the queries can't be written.
+pub async fn retrieve_documents(
+    query: RetrievalQuery<'_>,
+    db: &DocDb,
+    key: &str,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Retrieved> {
+    let RetrievalQuery {
+        context,
+        conversation,
+        filter,
+    } = query;
+    let hypothetical = if task.hypothetical_document {
+        hypothetical_document(context, key.to_string(), usage, task)
+            .await
+            .ok()
+    } else {
+        None
+    };
+    // the hypothetical document is close to the documents by construction, so
+    // the grounding is measured with the case itself
+    let mut case = embed_for_db(context, db, key, usage, task.max_retries).await?;
+    let mut embedding = match &hypothetical {
+        Some(x) => embed_for_db(x, db, key, usage, task.max_retries).await?,
+        None => case.clone(),
+    };
+    if let Some(conversation) = conversation.filter(|_| task.conversation_weight > 0.0) {
+        let conversation = embed_for_db(conversation, db, key, usage, task.max_retries).await?;
+        embedding = blend(&embedding, &conversation, task.conversation_weight);
+        case = blend(&case, &conversation, task.conversation_weight);
+    }
+    let similarity = db
+        .get_similar_scored(case.view(), 1, filter)?
+        .first()
+        .map(|(_, x)| *x);
+    let found = similar_documents(db, &embedding, filter, task)?;
+    if task.search_queries == 0 {
+        return Ok(Retrieved {
+            documents: found,
+            similarity,
+        });
+    }
+    let queries = search_queries(context, task.search_queries, key.to_string(), usage, task)
+        .await
+        .unwrap_or_default();
+    let embeddings = queries
+        .iter()
+        .map(|x| embed_for_db(x, db, key, usage, task.max_retries))
+        .pipe(try_join_all)
+        .await?;
+    let mut results = vec![found];
+    for embedding in embeddings {
+        results.push(similar_documents(db, &embedding, filter, task)?);
+    }
+    let mut documents = interleave(results);
+    documents.truncate(task.retrieval_depth);
+    Ok(Retrieved {
+        documents,
+        similarity,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::openai::chat::ChatCompletionModel;
+
+    #[test]
+    fn blends_embeddings() {
+        let embedding = |x: &[f32]| x.iter().copied().map(N32::from_f32).collect::<Array1<_>>();
+        let case = embedding(&[2.0, 0.0]);
+        let conversation = embedding(&[0.0, 0.5]);
+        assert_eq!(blend(&case, &conversation, 0.25), embedding(&[0.75, 0.25]));
+        assert_eq!(blend(&case, &conversation, 0.0), embedding(&[1.0, 0.0]));
+    }
+
+    #[test]
+    fn hypothetical_document_uses_query_model() {
+        let model = ChatCompletionModel::from("gpt-4o-mini".to_string());
+        let task = TaskConfig {
+            query_model: Some(model.clone()),
+            ..Default::default()
+        };
+        let args = hypothetical_args(
+            "Headache for a week.",
+            String::new(),
+            &Default::default(),
+            &task,
+        )
+        .unwrap();
+        assert_eq!(args.model, model);
+        assert_eq!(args.max_tokens, Some(HYPOTHETICAL_TOKENS));
+        let instructions = args.messages[1].content.as_deref().unwrap();
+        assert!(instructions.contains("> Headache for a week."));
+        assert!(instructions.contains("from a medical reference"));
+    }
+
+    #[test]
+    fn interleaves_results() {
+        let id = |x: u8| [x; 16];
+        assert_eq!(
+            interleave(vec![
+                vec![id(1), id(2), id(3)],
+                vec![id(4), id(1)],
+                vec![id(5)]
+            ]),
+            [id(1), id(4), id(5), id(2), id(3)]
+        );
+    }
+}
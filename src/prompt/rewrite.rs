@@ -1,13 +1,16 @@
 use serde::Serialize;
 
-use super::utils::SYSTEM_IDENTITY;
-use super::utils::{quote_lines, Error, Result};
-use crate::openai::chat::{
-    ChatCompletionArgs, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionParts,
-};
-use crate::utils::render_template;
+use super::config::TaskConfig;
+use super::templates::Template;
+use super::utils::system_identity;
+use super::utils::{quote_lines, screen_message, Error, Result};
+use crate::openai::chat::{ChatCompletionArgs, ChatCompletionMessage, ChatCompletionParts};
+use crate::openai::usage::UsageTracker;
 
-const MESSAGE_INSTRUCTIONS: &'static str = "\
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "rewrite",
+    variables: &["query"],
+    default: "\
 Rewrite the following statement using precise medical terminology, \
 referring to the patient in the 3rd person. \
 If there is ambiguity in how a symptom is describe, \
@@ -16,7 +19,8 @@ provide multiple descriptions of the symptom.
 Statement:
 
 {query}\
-";
+",
+};
 
 #[derive(Serialize)]
 struct MessageInstructions {
@@ -24,8 +28,8 @@ struct MessageInstructions {
 }
 
 impl MessageInstructions {
-    fn render(&self) -> Result<String> {
-        render_template(MESSAGE_INSTRUCTIONS, &self).map_err(Error::TemplateError)
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
     }
 }
 
@@ -38,27 +42,28 @@ impl MessageInstructions {
 }
 
 /// Rewrite a user's `message` in the 3rd person using precise medical terminology.
+///
+/// If the `task` screens messages, fails with [`Error::Flagged`] when
+/// moderation flags the `message`.
 pub async fn rewrite_message(
     message: String,
     key: String,
-    max_retries: usize,
+    usage: &UsageTracker,
+    task: &TaskConfig,
 ) -> Result<ChatCompletionParts> {
+    screen_message(&message, &key, task).await?;
     ChatCompletionParts::new(
         ChatCompletionArgs::new(key)
-            .with_temperature(0.0)
-            .with_message(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::System,
-                content: Some(SYSTEM_IDENTITY.to_string()),
-                name: None,
-                function_call: None,
-            })
-            .with_message(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::User,
-                content: Some(MessageInstructions::new(&message).render()?),
-                name: None,
-                function_call: None,
-            }),
-        max_retries,
+            .with_usage(usage)
+            .with_model(task.model.clone())
+            .with_temperature(task.temperature)
+            .with_max_continuations(task.max_continuations)
+            .with_message(ChatCompletionMessage::system(system_identity(task)))
+            .with_examples(&task.examples)
+            .with_message(ChatCompletionMessage::user(
+                MessageInstructions::new(&message).render(task)?,
+            )),
+        task.max_retries,
     )
     .await
     .map_err(Error::OpenAIError)
@@ -70,7 +75,9 @@ mod test {
 
     #[test]
     fn instructions_renders() {
-        let instructions = MessageInstructions::new("abc").render().unwrap();
+        let instructions = MessageInstructions::new("abc")
+            .render(&Default::default())
+            .unwrap();
         assert!(instructions.contains("Statement:\n\n> abc"));
     }
 }
@@ -0,0 +1,140 @@
+//! Classify whether a message is in scope before running the Clint process,
+//! so the app can reply to out-of-scope requests with a canned response.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::config::TaskConfig;
+use super::templates::Template;
+use super::utils::{
+    call_function_select, first_max_by_key, quote_lines, system_identity, Function, Result,
+};
+use crate::openai::usage::UsageTracker;
+
+/// What a message asks for: `Medical` for the health questions Clint
+/// answers, which are in scope, `NonMedical` for anything unrelated to
+/// health, `Legal` for legal advice, `Prescription` for a prescription or
+/// refill, and `Dosing` for starting, stopping or changing the dose of a
+/// medication.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScopeCategory {
+    #[default]
+    Medical,
+    NonMedical,
+    Legal,
+    Prescription,
+    Dosing,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct Scope {
+    #[schemars(description = "What the message asks for.")]
+    pub category: ScopeCategory,
+    #[schemars(description = "Why the message is in this category, in one sentence.")]
+    pub reason: String,
+}
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "scope",
+    variables: &["message"],
+    default: "\
+Consider the following message from a patient:
+
+{message}
+
+Classify what the message asks for. \
+It's medical if it describes symptoms or asks about health, conditions, tests or care, \
+even if it's vague. \
+It's non-medical if it's unrelated to health. \
+It's legal if it asks for legal advice, such as about malpractice or insurance disputes. \
+It's a prescription if it asks for a prescription or a refill. \
+It's dosing if it asks to start, stop or change the dose of a medication.\
+",
+};
+
+#[derive(Serialize)]
+struct MessageInstructions {
+    message: String,
+}
+
+impl MessageInstructions {
+    fn new(message: &str) -> Self {
+        Self {
+            message: message.pipe(quote_lines),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+const FUNCTION: Function = Function {
+    name: "classify_scope",
+    description: "Record what the message asks for.",
+};
+
+/// Pick a sample of the most common category.
+fn most_common(samples: Vec<Scope>) -> Scope {
+    let categories = samples.iter().map(|x| x.category).collect::<Vec<_>>();
+    first_max_by_key(samples, |x| {
+        categories.iter().filter(|y| **y == x.category).count()
+    })
+}
+
+/// Classify what the user's `message` asks for, so out-of-scope requests
+/// don't go through the rest of the process.
+///
+/// The prompt is short, so a cheaper model than for the other tasks is used
+/// by default. If the `task` samples several completions, the most common
+/// category is kept.
+pub async fn classify_scope(
+    message: &str,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Scope> {
+    call_function_select(
+        &FUNCTION,
+        system_identity(task),
+        MessageInstructions::new(message).render(task)?,
+        key,
+        usage,
+        task,
+        most_common,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::openai::schema::function_parameters;
+
+    #[test]
+    fn scope_schema_lists_categories() {
+        let schema = function_parameters::<Scope>(false).unwrap();
+        assert_eq!(
+            schema["properties"]["category"]["enum"],
+            serde_json::json!(["medical", "non_medical", "legal", "prescription", "dosing"])
+        );
+    }
+
+    #[test]
+    fn keeps_most_common_category() {
+        let scope = |category, reason: &str| Scope {
+            category,
+            reason: reason.to_string(),
+        };
+        let samples = vec![
+            scope(ScopeCategory::Legal, "first"),
+            scope(ScopeCategory::Medical, "second"),
+            scope(ScopeCategory::Medical, "third"),
+            scope(ScopeCategory::Legal, "fourth"),
+            scope(ScopeCategory::Dosing, "fifth"),
+        ];
+        assert_eq!(most_common(samples).reason, "first");
+    }
+}
@@ -0,0 +1,43 @@
+//! Search the documents for a query, outside of the Clint process.
+
+use serde::Serialize;
+use tap::Pipe;
+
+use super::utils::{embed_for_db, Error, Result};
+use crate::config::config;
+use crate::docdb::DocDb;
+use crate::openai::usage::UsageTracker;
+
+/// A document found by [`search`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    /// The hex ID of the document.
+    pub id: String,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    /// The similarity of the document to the query.
+    pub score: f32,
+}
+
+/// Find up to `n` documents most similar to the `query`, the most similar
+/// first.
+pub async fn search(
+    query: &str,
+    n: usize,
+    db: &DocDb,
+    key: &str,
+    usage: &UsageTracker,
+) -> Result<Vec<SearchResult>> {
+    let embedding = embed_for_db(query, db, key, usage, config().max_retries).await?;
+    db.get_similar_scored(embedding.view(), n, None)
+        .map_err(Error::DocDbError)?
+        .into_iter()
+        .map(|(id, score)| SearchResult {
+            id: hex::encode(id),
+            title: db.get_title(&id).map(String::from),
+            url: db.get_url(&id).map(String::from),
+            score,
+        })
+        .collect::<Vec<_>>()
+        .pipe(Ok)
+}
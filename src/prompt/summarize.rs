@@ -0,0 +1,173 @@
+use futures::future::try_join_all;
+use serde::Serialize;
+use tap::Pipe;
+
+use super::config::TaskConfig;
+use super::templates::Template;
+use super::utils::system_identity;
+use super::utils::{quote_lines, Error, Result};
+use crate::openai::chat::{
+    chat_completion, ChatCompletionArgs, ChatCompletionMessage, ChatCompletionMessageRole,
+};
+use crate::openai::tokens::count_message_tokens;
+use crate::openai::usage::UsageTracker;
+
+/// Tokens reserved in the prompt for the conversation summary.
+pub const SUMMARY_TOKENS: usize = 512;
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "summarize",
+    variables: &["conversation"],
+    default: "\
+Summarize the following conversation between you and the patient. \
+Keep all information that is relevant to the patient's care, \
+including symptoms, history, questions you asked and the patient's answers. \
+Answer in 200 words or less.
+
+Conversation:
+
+{conversation}\
+",
+};
+
+#[derive(Serialize)]
+struct MessageInstructions {
+    conversation: String,
+}
+
+impl MessageInstructions {
+    fn new(messages: &[ChatCompletionMessage]) -> Self {
+        Self {
+            conversation: messages
+                .iter()
+                .filter_map(|x| {
+                    let speaker = match x.role {
+                        ChatCompletionMessageRole::User => "Patient",
+                        ChatCompletionMessageRole::Assistant => "You",
+                        _ => return None,
+                    };
+                    let content = x.content.as_deref()?;
+                    format!("{}:\n\n{}", speaker, quote_lines(content)).pipe(Some)
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+/// Split `messages` into runs of consecutive messages that each fit in
+/// `budget` tokens. A message longer than the budget is a run of its own.
+fn chunk_messages(
+    task: &TaskConfig,
+    messages: &[ChatCompletionMessage],
+    budget: usize,
+) -> Vec<Vec<ChatCompletionMessage>> {
+    let reply = count_message_tokens(&task.model, &[]);
+    let mut chunks: Vec<Vec<ChatCompletionMessage>> = Vec::new();
+    let mut used = reply;
+    for message in messages {
+        let tokens = count_message_tokens(&task.model, std::slice::from_ref(message)) - reply;
+        match chunks.last_mut() {
+            Some(chunk) if used + tokens <= budget => chunk.push(message.clone()),
+            _ => {
+                chunks.push(vec![message.clone()]);
+                used = reply;
+            }
+        }
+        used += tokens;
+    }
+    chunks
+}
+
+/// Condense the conversation `messages` into a system message that can
+/// replace them in the history.
+///
+/// If the `messages` don't fit in the context window of the `task` model,
+/// they're summarized in chunks that do, and the summaries are joined.
+pub async fn summarize_messages(
+    messages: &[ChatCompletionMessage],
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<ChatCompletionMessage> {
+    let overhead = count_message_tokens(
+        &task.model,
+        &[ChatCompletionMessage::user(format!(
+            "{}{}",
+            system_identity(task),
+            MessageInstructions::new(&[]).render(task)?
+        ))],
+    );
+    let budget = task
+        .model
+        .context_window()
+        .saturating_sub(overhead + SUMMARY_TOKENS);
+    let summary = chunk_messages(task, messages, budget)
+        .iter()
+        .map(|x| summarize_chunk(x, key.clone(), usage, task))
+        .pipe(try_join_all)
+        .await?
+        .join("\n\n");
+    Ok(ChatCompletionMessage::system(format!(
+        "Summary of the earlier conversation:\n\n{}",
+        quote_lines(&summary)
+    )))
+}
+
+async fn summarize_chunk(
+    messages: &[ChatCompletionMessage],
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<String> {
+    let mut args = ChatCompletionArgs::new(key)
+        .with_usage(usage)
+        .with_model(task.model.clone())
+        .with_temperature(task.temperature)
+        .with_message(ChatCompletionMessage::system(system_identity(task)))
+        .with_message(ChatCompletionMessage::user(
+            MessageInstructions::new(messages).render(task)?,
+        ));
+    args.max_tokens = Some(u16::try_from(SUMMARY_TOKENS).unwrap_or(u16::MAX));
+    chat_completion(args, task.max_retries)
+        .await
+        .map_err(Error::OpenAIError)?
+        .choices
+        .into_iter()
+        .next()
+        .ok_or(Error::NetworkResponseError)?
+        .message
+        .content
+        .ok_or(Error::NetworkResponseError)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instructions_renders() {
+        let instructions = MessageInstructions::new(&[
+            ChatCompletionMessage::user("abc".to_string()),
+            ChatCompletionMessage::assistant("bcd".to_string()),
+        ])
+        .render(&Default::default())
+        .unwrap();
+        assert!(instructions.contains("Conversation:\n\nPatient:\n\n> abc\n\nYou:\n\n> bcd"));
+    }
+
+    #[test]
+    fn chunks_messages_to_budget() {
+        let message = |content: &str| ChatCompletionMessage::user(content.to_string());
+        let task = TaskConfig::default();
+        let messages = [message("abc"), message("bcd"), message("cde")];
+        let budget = count_message_tokens(&task.model, &messages[..2]);
+        let chunks = chunk_messages(&task, &messages, budget);
+        assert_eq!(chunks, [messages[..2].to_vec(), messages[2..].to_vec()]);
+        assert_eq!(chunk_messages(&task, &messages, 0).len(), 3);
+    }
+}
@@ -0,0 +1,332 @@
+//! The text of the prompts, with defaults that can be overridden at runtime
+//! so deployments can tune the wording without changing the library.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use serde::Serialize;
+
+use super::config::TaskConfig;
+use super::utils::{Error, Result};
+use super::{
+    cite, diagnosis, gaps, medications, notes, respond, retrieve, rewrite, scope, summarize,
+    triage, urgency, utils,
+};
+use crate::utils::render_template;
+
+/// A prompt template and the variables it's rendered with.
+#[derive(Debug, Serialize)]
+pub struct Template {
+    /// The name the template is overridden by.
+    pub name: &'static str,
+    /// The variables the template can refer to.
+    pub variables: &'static [&'static str],
+    /// The text used unless overridden.
+    pub default: &'static str,
+}
+
+/// Every template that can be overridden.
+const TEMPLATES: &[&Template] = &[
+    &rewrite::MESSAGE_INSTRUCTIONS,
+    &notes::MESSAGE_INSTRUCTIONS,
+    &notes::MESSAGE_INSTRUCTIONS_NOTES,
+    &notes::INFORMATION_NOTES,
+    &notes::CONSISTENCY_INSTRUCTIONS,
+    &gaps::MESSAGE_INSTRUCTIONS,
+    &diagnosis::INITIAL_INSTRUCTIONS,
+    &diagnosis::REFINE_INSTRUCTIONS,
+    &diagnosis::VERIFY_INSTRUCTIONS,
+    &respond::MESSAGE_INSTRUCTIONS,
+    &respond::MESSAGE_INSTRUCTIONS_DIAGNOSIS,
+    &respond::STALE_INSTRUCTIONS,
+    &respond::ABSTAIN_INSTRUCTIONS,
+    &urgency::MESSAGE_INSTRUCTIONS,
+    &scope::MESSAGE_INSTRUCTIONS,
+    &medications::MESSAGE_INSTRUCTIONS,
+    &triage::MESSAGE_INSTRUCTIONS,
+    &triage::TITLE_SELF_CARE,
+    &triage::TITLE_GENERAL_PRACTITIONER,
+    &triage::TITLE_URGENT_CARE,
+    &triage::TITLE_EMERGENCY_ROOM,
+    &summarize::MESSAGE_INSTRUCTIONS,
+    &cite::MESSAGE_INSTRUCTIONS,
+    &utils::SYSTEM_INSTRUCTIONS_EXCERPTS,
+    &utils::SYSTEM_INSTRUCTIONS_PROFILE,
+    &retrieve::QUERIES_INSTRUCTIONS,
+    &retrieve::HYPOTHETICAL_INSTRUCTIONS,
+    &utils::AUDIENCE_PATIENT,
+    &utils::AUDIENCE_CLINICIAN,
+    &utils::AUDIENCE_EIGHTH_GRADE,
+];
+
+/// The built-in texts of the templates for other languages than English, as
+/// the language, the name of the template and its text.
+const LOCALIZED: &[(&str, &str, &str)] = &[
+    (
+        "es",
+        "respond",
+        "\
+Mi mensaje es:
+
+{message}
+
+Has registrado las siguientes notas clínicas sobre mí:
+
+{notes}
+
+Por favor, responde a mi mensaje. \
+Puedes hacerme preguntas para reunir más información para tus notas. \
+No hagas preguntas que ya se hayan respondido o que se puedan responder con las notas. \
+No repitas lo que ya se dijo en un mensaje anterior.\
+{{ if stale }} {stale}{{ endif }}\
+{{ if abstain }} {abstain}{{ endif }}\
+{{ if audience }}
+
+{audience}{{ endif }}\
+",
+    ),
+    (
+        "es",
+        "respond_diagnosis",
+        "\
+Mi mensaje es:
+
+{message}
+
+Has registrado las siguientes notas clínicas sobre mí:
+
+{notes}
+
+Has llegado al siguiente diagnóstico diferencial:
+
+{diagnosis}
+
+Por favor, responde a mi mensaje. \
+Puedes hacerme preguntas para reunir más información para tus notas y para acotar el diagnóstico. \
+No hagas preguntas que ya se hayan respondido o que se puedan responder con las notas. \
+Explica también los diagnósticos plausibles. \
+No repitas lo que ya se dijo en un mensaje anterior.\
+{{ if stale }} {stale}{{ endif }}\
+{{ if abstain }} {abstain}{{ endif }}\
+{{ if audience }}
+
+{audience}{{ endif }}\
+",
+    ),
+    (
+        "es",
+        "respond_stale",
+        "\
+Cuando una indicación provenga de un extracto marcado como posiblemente desactualizado, \
+dilo y sugiere confirmarla con un profesional de la salud.\
+",
+    ),
+    (
+        "es",
+        "respond_abstain",
+        "\
+No se encontraron extractos de documentos relevantes para mi mensaje. \
+No improvises información médica: \
+di que tu base de conocimientos no cubre mi mensaje y sugiere consultar a un profesional de la salud.\
+",
+    ),
+    (
+        "es",
+        "audience_patient",
+        "\
+Escribe para un paciente sin formación médica: \
+usa un lenguaje sencillo y explica cualquier término médico que uses.\
+",
+    ),
+    (
+        "es",
+        "audience_clinician",
+        "\
+Escribe para un profesional de la salud: \
+usa terminología médica precisa y no expliques conceptos clínicos comunes.\
+",
+    ),
+    (
+        "es",
+        "audience_eighth_grade",
+        "\
+Escribe con un nivel de lectura de octavo grado: \
+usa frases cortas y palabras comunes, y evita la jerga médica.\
+",
+    ),
+    ("es", "triage_self_care", "Autocuidado"),
+    ("es", "triage_general_practitioner", "Consulta a un médico de cabecera"),
+    ("es", "triage_urgent_care", "Acude a un centro de atención urgente"),
+    ("es", "triage_emergency_room", "Acude a urgencias"),
+];
+
+/// The built-in text of the template `name` for the language of the
+/// `locale`, if any.
+fn localized(locale: Option<&str>, name: &str) -> Option<&'static str> {
+    let language = locale?.split(['-', '_']).next()?;
+    LOCALIZED
+        .iter()
+        .find(|(x, y, _)| x.eq_ignore_ascii_case(language) && *y == name)
+        .map(|(_, _, x)| *x)
+}
+
+/// The texts overriding the templates by name, for each locale, where the
+/// empty locale is used by every locale without its own text.
+type Overrides = HashMap<String, HashMap<&'static str, String>>;
+
+fn overrides() -> &'static Mutex<Overrides> {
+    static OVERRIDES: OnceLock<Mutex<Overrides>> = OnceLock::new();
+    OVERRIDES.get_or_init(Default::default)
+}
+
+/// The text overriding the template `name` for the `locale`: that of the
+/// locale, such as `es-MX`, of its language, such as `es`, or of every
+/// locale.
+fn lookup<'a>(overrides: &'a Overrides, locale: Option<&str>, name: &str) -> Option<&'a str> {
+    let locale = locale.unwrap_or_default();
+    let language = locale.split(['-', '_']).next().unwrap_or_default();
+    [locale, language, ""]
+        .into_iter()
+        .find_map(|x| overrides.get(x)?.get(name))
+        .map(|x| x.as_str())
+}
+
+impl Template {
+    /// The text of the template for the locale of the `task`: overridden,
+    /// built in for the language of the locale, or the default.
+    pub fn text(&self, task: &TaskConfig) -> String {
+        let locale = task.locale();
+        lookup(&overrides().lock().unwrap(), locale.as_deref(), self.name)
+            .or_else(|| localized(locale.as_deref(), self.name))
+            .unwrap_or(self.default)
+            .to_string()
+    }
+
+    /// Render the text of the template for the `task` with the variables in
+    /// `context`.
+    pub fn render(&self, context: &impl Serialize, task: &TaskConfig) -> Result<String> {
+        render_template(&self.text(task), context).map_err(Error::TemplateError)
+    }
+
+    /// Check that the `text` only refers to the variables of the template,
+    /// by rendering it with the variables both empty and not.
+    fn check(&self, text: &str) -> Result<()> {
+        for value in ["", "x"] {
+            let context = self
+                .variables
+                .iter()
+                .map(|&x| (x, value))
+                .collect::<HashMap<_, _>>();
+            render_template(text, &context).map_err(|e| Error::InvalidTemplate {
+                name: self.name.to_string(),
+                reason: e.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Override the text of the templates named in `texts` for the `locale`, or
+/// for every locale if `None`, replacing earlier overrides for it.
+///
+/// Templates without a text for the locale use the text for its language,
+/// then the text for every locale, then the built-in text for its language,
+/// then the default. Fails without changing any template if a name isn't a
+/// template, or a text refers to a variable the template doesn't have.
+pub fn set_templates(texts: HashMap<String, String>, locale: Option<&str>) -> Result<()> {
+    let mut checked = HashMap::new();
+    for (name, text) in texts {
+        let template = TEMPLATES
+            .iter()
+            .find(|x| x.name == name)
+            .ok_or(Error::UnknownTemplate(name))?;
+        template.check(&text)?;
+        checked.insert(template.name, text);
+    }
+    overrides()
+        .lock()
+        .unwrap()
+        .insert(locale.unwrap_or_default().to_string(), checked);
+    Ok(())
+}
+
+/// Every template that can be overridden, with its default text.
+pub fn default_templates() -> &'static [&'static Template] {
+    TEMPLATES
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        for template in TEMPLATES {
+            template.check(template.default).unwrap();
+        }
+        let mut names = default_templates()
+            .iter()
+            .map(|x| x.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), TEMPLATES.len());
+        for (language, name, text) in LOCALIZED {
+            let template = TEMPLATES.iter().find(|x| x.name == *name).unwrap();
+            template.check(text).unwrap();
+            assert_eq!(localized(Some(language), name), Some(*text));
+        }
+        let task = TaskConfig {
+            locale: Some("es-MX".to_string()),
+            ..Default::default()
+        };
+        assert!(respond::ABSTAIN_INSTRUCTIONS
+            .text(&task)
+            .starts_with("No se encontraron"));
+        assert_eq!(localized(Some("fr"), "respond"), None);
+    }
+
+    #[test]
+    fn overrides_are_checked() {
+        let texts = |name: &str, text: &str| [(name.to_string(), text.to_string())].into();
+        assert!(matches!(
+            set_templates(texts("unknown", ""), None),
+            Err(Error::UnknownTemplate(_))
+        ));
+        assert!(matches!(
+            set_templates(texts("rewrite", "Rewrite {message}"), Some("es")),
+            Err(Error::InvalidTemplate { .. })
+        ));
+        assert_eq!(
+            rewrite::MESSAGE_INSTRUCTIONS.text(&Default::default()),
+            rewrite::MESSAGE_INSTRUCTIONS.default
+        );
+        // a locale no other test uses, so their templates stay the defaults
+        set_templates(texts("respond_abstain", "Abstain."), Some("xx")).unwrap();
+        let text =
+            lookup(&overrides().lock().unwrap(), Some("xx"), "respond_abstain").map(String::from);
+        overrides().lock().unwrap().clear();
+        assert_eq!(text.as_deref(), Some("Abstain."));
+    }
+
+    #[test]
+    fn overrides_fall_back_by_locale() {
+        let overrides: Overrides = [
+            ("", vec![("cite", "any")]),
+            ("es", vec![("cite", "es"), ("rewrite", "es")]),
+            ("es-MX", vec![("cite", "es-MX")]),
+        ]
+        .into_iter()
+        .map(|(locale, texts)| {
+            let texts = texts.into_iter().map(|(x, y)| (x, y.to_string()));
+            (locale.to_string(), texts.collect())
+        })
+        .collect();
+        assert_eq!(lookup(&overrides, Some("es-MX"), "cite"), Some("es-MX"));
+        assert_eq!(lookup(&overrides, Some("es-MX"), "rewrite"), Some("es"));
+        assert_eq!(lookup(&overrides, Some("es_AR"), "cite"), Some("es"));
+        assert_eq!(lookup(&overrides, Some("fr"), "cite"), Some("any"));
+        assert_eq!(lookup(&overrides, None, "cite"), Some("any"));
+        assert_eq!(lookup(&overrides, None, "rewrite"), None);
+    }
+}
@@ -0,0 +1,222 @@
+//! Recommend the level of care the patient should seek given the
+//! differential diagnosis.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::config::TaskConfig;
+use super::diagnosis::ResolvedDiagnosis;
+use super::notes::Notes;
+use super::profile::Profile;
+use super::templates::Template;
+use super::utils::{
+    call_function_select, first_max_by_key, quote_lines, system_identity_with_profile, Function,
+    Result,
+};
+use crate::openai::usage::UsageTracker;
+
+/// Where the patient should seek care: `SelfCare` at home, a
+/// `GeneralPractitioner` appointment, `UrgentCare` the same day or the
+/// `EmergencyRoom` now.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CareLevel {
+    SelfCare,
+    GeneralPractitioner,
+    UrgentCare,
+    EmergencyRoom,
+}
+
+pub const TITLE_SELF_CARE: Template = Template {
+    name: "triage_self_care",
+    variables: &[],
+    default: "Self-care",
+};
+
+pub const TITLE_GENERAL_PRACTITIONER: Template = Template {
+    name: "triage_general_practitioner",
+    variables: &[],
+    default: "See a general practitioner",
+};
+
+pub const TITLE_URGENT_CARE: Template = Template {
+    name: "triage_urgent_care",
+    variables: &[],
+    default: "Go to urgent care",
+};
+
+pub const TITLE_EMERGENCY_ROOM: Template = Template {
+    name: "triage_emergency_room",
+    variables: &[],
+    default: "Go to the emergency room",
+};
+
+impl CareLevel {
+    /// The heading of the recommendation in the locale of the `task`.
+    fn title(&self, task: &TaskConfig) -> String {
+        match self {
+            CareLevel::SelfCare => TITLE_SELF_CARE,
+            CareLevel::GeneralPractitioner => TITLE_GENERAL_PRACTITIONER,
+            CareLevel::UrgentCare => TITLE_URGENT_CARE,
+            CareLevel::EmergencyRoom => TITLE_EMERGENCY_ROOM,
+        }
+        .text(task)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct Triage {
+    #[schemars(description = "The recommended level of care.")]
+    pub care_level: CareLevel,
+    #[schemars(description = "Why this level of care is recommended. 50 words or less.")]
+    pub reasoning: String,
+    #[schemars(
+        description = "When the patient should seek a higher level of care instead, \
+        and the limits of this recommendation."
+    )]
+    pub caveats: Vec<String>,
+}
+
+impl Triage {
+    /// The recommendation as Markdown, headed in the locale of the `task`.
+    pub fn to_markdown(&self, depth: usize, task: &TaskConfig) -> String {
+        let depth = "#".repeat(depth);
+        let mut parts = vec![
+            format!("{}# {}", depth, self.care_level.title(task)),
+            self.reasoning.clone(),
+        ];
+        if !self.caveats.is_empty() {
+            parts.push(
+                self.caveats
+                    .iter()
+                    .map(|x| format!("- {}", x))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+        parts.join("\n\n")
+    }
+}
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "triage",
+    variables: &["notes", "diagnosis"],
+    default: "\
+Consider the following clinical notes:
+
+{notes}
+
+You have arrived at the following differential diagnosis:
+
+{diagnosis}
+
+Recommend the level of care the patient should seek: \
+self-care, a general practitioner, urgent care or the emergency room. \
+Weigh the most serious plausible diagnoses, not only the most likely. \
+Explain your reasoning, \
+and list the caveats such as the symptoms that should make the patient seek a higher level of care.\
+",
+};
+
+#[derive(Serialize)]
+struct MessageInstructions {
+    notes: String,
+    diagnosis: String,
+}
+
+impl MessageInstructions {
+    fn new(notes: &Notes, diagnoses: &[ResolvedDiagnosis]) -> Self {
+        Self {
+            notes: notes.to_markdown(0).as_str().pipe(quote_lines),
+            diagnosis: diagnoses
+                .iter()
+                .map(|x| x.to_markdown(0))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+                .as_str()
+                .pipe(quote_lines),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+const FUNCTION: Function = Function {
+    name: "recommend_care",
+    description: "Recommend the level of care.",
+};
+
+/// Pick the sample recommending the highest level of care.
+fn highest_care(samples: Vec<Triage>) -> Triage {
+    first_max_by_key(samples, |x| x.care_level)
+}
+
+/// Recommend the level of care for the `diagnoses` given the `notes`, with
+/// the patient's `profile` as context.
+///
+/// If the `task` samples several completions, the highest level of care is
+/// kept.
+pub async fn triage(
+    notes: &Notes,
+    profile: &Profile,
+    diagnoses: &[ResolvedDiagnosis],
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Triage> {
+    call_function_select(
+        &FUNCTION,
+        system_identity_with_profile(profile, task)?,
+        MessageInstructions::new(notes, diagnoses).render(task)?,
+        key,
+        usage,
+        task,
+        highest_care,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn triage_renders_markdown() {
+        let triage = Triage {
+            care_level: CareLevel::UrgentCare,
+            reasoning: "Possible fracture.".to_string(),
+            caveats: vec!["Go to the ER if the pain worsens.".to_string()],
+        };
+        assert_eq!(
+            triage.to_markdown(1, &TaskConfig::default()),
+            "## Go to urgent care\n\nPossible fracture.\n\n- Go to the ER if the pain worsens."
+        );
+        let task = TaskConfig {
+            locale: Some("es".to_string()),
+            ..Default::default()
+        };
+        assert!(triage
+            .to_markdown(0, &task)
+            .starts_with("# Acude a un centro de atención urgente"));
+    }
+
+    #[test]
+    fn picks_highest_care() {
+        let triage = |care_level, reasoning: &str| Triage {
+            care_level,
+            reasoning: reasoning.to_string(),
+            caveats: Vec::new(),
+        };
+        let best = highest_care(vec![
+            triage(CareLevel::SelfCare, "a"),
+            triage(CareLevel::UrgentCare, "b"),
+            triage(CareLevel::UrgentCare, "c"),
+        ]);
+        assert_eq!(best.reasoning, "b");
+    }
+}
@@ -0,0 +1,154 @@
+//! Screen for red-flag findings that need urgent care, so the app can
+//! interrupt the normal flow with an emergency banner.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+
+use super::config::TaskConfig;
+use super::notes::Notes;
+use super::profile::Profile;
+use super::templates::Template;
+use super::utils::{
+    call_function_select, first_max_by_key, quote_lines, system_identity_with_profile, Function,
+    Result,
+};
+use crate::openai::usage::UsageTracker;
+
+/// How soon the patient needs care: `Routine` without red flags, `Urgent`
+/// within hours, such as at urgent care, or `Emergency` right away.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum UrgencyLevel {
+    #[default]
+    Routine,
+    Urgent,
+    Emergency,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, JsonSchema, Serialize, Deserialize)]
+pub struct Urgency {
+    #[schemars(description = "How soon the patient needs care.")]
+    pub level: UrgencyLevel,
+    #[schemars(
+        description = "The red-flag findings that make care urgent, such as thunderclap headache."
+    )]
+    pub findings: Vec<String>,
+}
+
+pub const MESSAGE_INSTRUCTIONS: Template = Template {
+    name: "urgency",
+    variables: &["statement", "notes"],
+    default: "\
+Consider the following patient statement:
+
+{statement}
+
+You have recorded the following clinical notes about the patient:
+
+{notes}
+
+Screen the statement and the notes for red-flag findings that need urgent or emergency care, \
+such as a sudden thunderclap headache, \
+crushing chest pain, \
+difficulty breathing, \
+signs of a stroke, \
+heavy bleeding, \
+or thoughts of self-harm. \
+Rate the urgency as routine if there are no red flags. \
+List only the red-flag findings, in the patient's words where possible.\
+",
+};
+
+#[derive(Serialize)]
+struct MessageInstructions {
+    statement: String,
+    notes: String,
+}
+
+impl MessageInstructions {
+    fn new(statement: Option<&str>, notes: Option<&Notes>) -> Self {
+        Self {
+            statement: statement.unwrap_or_default().pipe(quote_lines),
+            notes: notes
+                .map(|x| x.to_markdown(0))
+                .unwrap_or_default()
+                .as_str()
+                .pipe(quote_lines),
+        }
+    }
+
+    fn render(&self, task: &TaskConfig) -> Result<String> {
+        MESSAGE_INSTRUCTIONS.render(&self, task)
+    }
+}
+
+const FUNCTION: Function = Function {
+    name: "assess_urgency",
+    description: "Record the urgency of care and the red-flag findings.",
+};
+
+/// Pick the most urgent of the samples.
+fn most_urgent(samples: Vec<Urgency>) -> Urgency {
+    first_max_by_key(samples, |x| x.level)
+}
+
+/// Screen the patient's `statement` and `notes` for red-flag findings, with
+/// the patient's `profile` as context.
+///
+/// Without a statement or notes there's nothing to screen, so the urgency is
+/// routine and no completion is made. If the `task` samples several
+/// completions, the most urgent is kept.
+pub async fn assess_urgency(
+    statement: Option<&str>,
+    notes: Option<&Notes>,
+    profile: &Profile,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<Urgency> {
+    if statement.is_none() && notes.is_none() {
+        return Ok(Urgency::default());
+    }
+    call_function_select(
+        &FUNCTION,
+        system_identity_with_profile(profile, task)?,
+        MessageInstructions::new(statement, notes).render(task)?,
+        key,
+        usage,
+        task,
+        most_urgent,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::openai::schema::function_parameters;
+
+    #[test]
+    fn urgency_schema_lists_levels() {
+        let schema = function_parameters::<Urgency>(false).unwrap();
+        assert_eq!(
+            schema["properties"]["level"]["enum"],
+            serde_json::json!(["routine", "urgent", "emergency"])
+        );
+    }
+
+    #[test]
+    fn picks_most_urgent() {
+        let urgency = |level, finding: &str| Urgency {
+            level,
+            findings: vec![finding.to_string()],
+        };
+        let best = most_urgent(vec![
+            urgency(UrgencyLevel::Routine, "a"),
+            urgency(UrgencyLevel::Urgent, "b"),
+            urgency(UrgencyLevel::Urgent, "c"),
+        ]);
+        assert_eq!(best.findings, ["b"]);
+    }
+}
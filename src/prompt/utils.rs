@@ -1,18 +1,34 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 
+use futures::stream::{self, StreamExt};
 use ndarray::Array1;
 use noisy_float::prelude::N32;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tap::Pipe;
-use thiserror;
 
-use crate::docdb::{DocDb, DocId};
+use super::config::{Audience, TaskConfig};
+use super::templates::Template;
+use crate::docdb::{decode_doc_id, DocDb, DocId, Filter};
+use crate::openai::chat::{
+    chat_completion_function, chat_completion_function_select, ChatCompletionArgs,
+    ChatCompletionMessage, ChatCompletionModel, Example,
+};
 use crate::openai::embed::embed;
+use crate::openai::moderate::{moderate, Moderation};
+use crate::openai::tokens::{count_message_tokens, count_tokens, fit_messages, fit_texts};
+use crate::openai::usage::UsageTracker;
+use crate::openai::DEFAULT_TIMEOUT;
 use crate::utils::render_template;
 
 use super::diagnosis::ResolvedDiagnosis;
 use super::notes::Notes;
+use super::profile::Profile;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -23,11 +39,21 @@ pub enum Error {
     NetworkResponseError,
     #[error("embedding error")]
     EmbeddingError,
+    #[error(transparent)]
+    DocDbError(#[from] crate::docdb::Error),
+    #[error("the message was flagged by moderation")]
+    Flagged(Moderation),
+    #[error("unknown prompt template: {0}")]
+    UnknownTemplate(String),
+    #[error("prompt template {name} is invalid: {reason}")]
+    InvalidTemplate { name: String, reason: String },
+    #[error("unknown notes field: {0}")]
+    UnknownNotesField(String),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
-pub const SYSTEM_IDENTITY: &'static str = "\
+pub const SYSTEM_IDENTITY: &str = "\
 Act as an expert clinician with extensive knowledge of medical topics: \
 anatomy, \
 embryology, \
@@ -42,24 +68,153 @@ and other related fields.
 You are assessing an outpatient.\
 ";
 
-const SYSTEM_INSTRUCTIONS_EXCERPTS: &'static str = "\
-{system_identity}
+pub const SYSTEM_INSTRUCTIONS_EXCERPTS: Template = Template {
+    name: "system_excerpts",
+    variables: &["system_identity", "profile", "excerpts"],
+    default: "\
+{system_identity}\
+{{ if profile }}
+
+The patient's profile is:
+
+{profile}{{ endif }}
 
 You can refer to the following document excerpts:
 
 {excerpts}\
-";
+",
+};
+
+/// The names of the languages of the common locales, to instruct the models
+/// to write in them.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("de", "German"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("hi", "Hindi"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ru", "Russian"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("zh", "Chinese"),
+];
+
+/// The name of the language of the `locale`, such as `Spanish` for `es-MX`,
+/// or the locale itself if it isn't a common one.
+fn language_name(locale: &str) -> String {
+    let language = locale.split(['-', '_']).next().unwrap_or_default();
+    LANGUAGES
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case(language))
+        .map_or_else(
+            || format!("the language of the locale {}", locale),
+            |(_, x)| x.to_string(),
+        )
+}
+
+/// The system identity of the `task`, or [`SYSTEM_IDENTITY`] by default,
+/// instructing the model to write in the language of the locale of the
+/// `task`, if any.
+pub fn system_identity(task: &TaskConfig) -> String {
+    let identity = task.system_identity.as_deref().unwrap_or(SYSTEM_IDENTITY);
+    match task.locale() {
+        Some(locale) => format!(
+            "{}\n\nAlways write in {}, the language of the user, \
+            even when the excerpts or the instructions are in another language.",
+            identity,
+            language_name(&locale)
+        ),
+        None => identity.to_string(),
+    }
+}
+
+pub const SYSTEM_INSTRUCTIONS_PROFILE: Template = Template {
+    name: "system_profile",
+    variables: &["system_identity", "profile"],
+    default: "\
+{system_identity}\
+{{ if profile }}
+
+The patient's profile is:
+
+{profile}{{ endif }}\
+",
+};
+
+#[derive(Serialize)]
+struct SystemInstructionsProfile {
+    system_identity: String,
+    profile: String,
+}
+
+/// The [`system_identity`] of the `task`, telling the model about the
+/// patient's `profile` if anything is known.
+pub fn system_identity_with_profile(profile: &Profile, task: &TaskConfig) -> Result<String> {
+    let instructions = SystemInstructionsProfile {
+        system_identity: system_identity(task),
+        profile: profile.to_markdown(),
+    };
+    SYSTEM_INSTRUCTIONS_PROFILE.render(&instructions, task)
+}
+
+pub const AUDIENCE_PATIENT: Template = Template {
+    name: "audience_patient",
+    variables: &[],
+    default: "\
+Write for a patient without medical training: \
+use plain language and explain any medical term you use.\
+",
+};
+
+pub const AUDIENCE_CLINICIAN: Template = Template {
+    name: "audience_clinician",
+    variables: &[],
+    default: "\
+Write for a clinician: \
+use precise medical terminology and don't explain common clinical concepts.\
+",
+};
+
+pub const AUDIENCE_EIGHTH_GRADE: Template = Template {
+    name: "audience_eighth_grade",
+    variables: &[],
+    default: "\
+Write at an 8th-grade reading level: \
+use short sentences and common words, and avoid medical jargon.\
+",
+};
+
+/// The instructions to write for the audience of the `task`, if any.
+pub fn audience_instructions(task: &TaskConfig) -> Option<String> {
+    match task.audience? {
+        Audience::Patient => AUDIENCE_PATIENT.text(task),
+        Audience::Clinician => AUDIENCE_CLINICIAN.text(task),
+        Audience::EighthGrade => AUDIENCE_EIGHTH_GRADE.text(task),
+    }
+    .pipe(Some)
+}
 
 #[derive(Serialize)]
 pub struct SystemInstructionsExcerpts {
-    system_identity: &'static str,
+    system_identity: String,
+    profile: String,
     excerpts: String,
 }
 
 impl SystemInstructionsExcerpts {
-    pub fn new(excerpts: &Vec<String>) -> Self {
+    /// The system instructions with the `excerpts`, telling the model about
+    /// the patient's `profile` if anything is known.
+    pub fn new(excerpts: &[String], profile: &Profile, task: &TaskConfig) -> Self {
         Self {
-            system_identity: SYSTEM_IDENTITY,
+            system_identity: system_identity(task),
+            profile: profile.to_markdown(),
             excerpts: excerpts
                 .iter()
                 .map(|x| quote_lines(x.as_str()))
@@ -68,15 +223,21 @@ impl SystemInstructionsExcerpts {
         }
     }
 
-    pub fn render(&self) -> Result<String> {
-        render_template(SYSTEM_INSTRUCTIONS_EXCERPTS, &self).map_err(Error::TemplateError)
+    pub fn render(&self, task: &TaskConfig) -> Result<String> {
+        SYSTEM_INSTRUCTIONS_EXCERPTS.render(&self, task)
     }
 }
 
-const EMBED_STRUCTURE: &'static str = "\
+const EMBED_STRUCTURE: &str = "\
 # Clinical Notes
 
 {notes}\
+{{if profile}}
+
+# Patient Profile
+
+{profile}\
+{{endif}}\
 {{if diagnoses}}
 
 # Differential Diagnosis
@@ -94,6 +255,7 @@ const EMBED_STRUCTURE: &'static str = "\
 #[derive(Serialize)]
 pub struct EmbedStructure {
     notes: String,
+    profile: String,
     diagnoses: String,
     statement: String,
 }
@@ -101,11 +263,13 @@ pub struct EmbedStructure {
 impl EmbedStructure {
     pub fn new(
         notes: &Notes,
+        profile: &Profile,
         diagnoses: Option<&Vec<ResolvedDiagnosis>>,
         statement: Option<&str>,
     ) -> Self {
         Self {
             notes: notes.to_markdown(1),
+            profile: profile.to_markdown(),
             diagnoses: match diagnoses {
                 Some(x) => x
                     .iter()
@@ -123,6 +287,126 @@ impl EmbedStructure {
     }
 }
 
+/// Tokens reserved for the completion when fitting a prompt in the model's
+/// context window.
+pub const COMPLETION_TOKENS: usize = 4_096;
+
+/// Fit the `excerpts` and then the `history` in what remains of the `model`'s
+/// context window after the `fixed` messages of the prompt.
+///
+/// The excerpts are ordered by relevance so the last ones are dropped first.
+/// The oldest messages of the history are dropped first.
+pub fn fit_context(
+    model: &ChatCompletionModel,
+    fixed: &[ChatCompletionMessage],
+    excerpts: Vec<String>,
+    history: Vec<ChatCompletionMessage>,
+) -> (Vec<String>, Vec<ChatCompletionMessage>) {
+    let budget = model
+        .context_window()
+        .saturating_sub(COMPLETION_TOKENS + count_message_tokens(model, fixed));
+    let excerpts = fit_texts(model, excerpts, budget);
+    let budget = budget.saturating_sub(excerpts.iter().map(|x| count_tokens(model, x)).sum());
+    let history = fit_messages(model, history, budget);
+    (excerpts, history)
+}
+
+/// The openings of paragraphs that are boilerplate rather than content, such
+/// as reference lists and legal notices.
+const BOILERPLATE: &[&str] = &[
+    "references",
+    "bibliography",
+    "further reading",
+    "see also",
+    "external links",
+    "related articles",
+    "cite this",
+    "copyright",
+    "©",
+    "disclaimer",
+];
+
+/// Marks the paragraphs left out of a truncated document.
+const OMISSION: &str = "[...]";
+
+fn is_boilerplate(paragraph: &str) -> bool {
+    let start = paragraph.trim_start_matches(['#', ' ']).to_lowercase();
+    BOILERPLATE.iter().any(|x| start.starts_with(x))
+}
+
+/// The lowercase words of `text` long enough to tell what it's about.
+fn keywords(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| x.chars().count() > 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Cut `text` to at most `budget` tokens, a tenth at a time from the end.
+fn cut_text<'a>(model: &ChatCompletionModel, mut text: &'a str, budget: usize) -> &'a str {
+    while !text.is_empty() && count_tokens(model, text) > budget {
+        let n = text.chars().count() * 9 / 10;
+        text = &text[..text.char_indices().nth(n).map_or(0, |(i, _)| i)];
+    }
+    text
+}
+
+/// Cut the `document` to at most `budget` tokens if it's longer, keeping the
+/// paragraphs sharing the most words with the `query`, in their order, and
+/// marking those left out.
+///
+/// Boilerplate paragraphs are left out first. If no paragraph fits on its
+/// own, the most related one is cut short.
+pub fn truncate_document(
+    model: &ChatCompletionModel,
+    document: &str,
+    query: &str,
+    budget: usize,
+) -> String {
+    if count_tokens(model, document) <= budget {
+        return document.to_string();
+    }
+    let paragraphs = document
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !is_boilerplate(x))
+        .collect::<Vec<_>>();
+    let query = keywords(query);
+    let mut ranked = (0..paragraphs.len()).collect::<Vec<_>>();
+    ranked.sort_by_key(|&i| Reverse(keywords(paragraphs[i]).intersection(&query).count()));
+    let omission = count_tokens(model, OMISSION) + 1;
+    let mut kept = Vec::new();
+    let mut used = 0;
+    for &i in &ranked {
+        let tokens = count_tokens(model, paragraphs[i]) + omission;
+        if used + tokens <= budget {
+            kept.push(i);
+            used += tokens;
+        }
+    }
+    let Some(&best) = ranked.first() else {
+        return OMISSION.to_string();
+    };
+    if kept.is_empty() {
+        let text = cut_text(model, paragraphs[best], budget.saturating_sub(omission));
+        return format!("{} {}", text.trim_end(), OMISSION);
+    }
+    kept.sort_unstable();
+    let mut parts = Vec::new();
+    let mut next = 0;
+    for i in kept {
+        if i > next {
+            parts.push(OMISSION);
+        }
+        parts.push(paragraphs[i]);
+        next = i + 1;
+    }
+    if next < paragraphs.len() {
+        parts.push(OMISSION);
+    }
+    parts.join("\n\n")
+}
+
 pub fn quote_lines(content: &str) -> String {
     content
         .lines()
@@ -131,51 +415,388 @@ pub fn quote_lines(content: &str) -> String {
         .join("\n")
 }
 
-pub async fn get_excerpt(hash: &DocId, db: &DocDb) -> Option<String> {
-    let document = match db.get_document(&hash).await {
+/// Get the excerpt for the document `hash`, only its span of the parent if
+/// it's a chunk, headed by its titles.
+///
+/// The excerpt is also headed by the date it was last reviewed, if known,
+/// flagged if it's more than the `stale_after_days` of the `task` ago. The
+/// text is cut to the `excerpt_tokens` of the `task`, keeping
+/// the paragraphs most related to the `query`.
+pub async fn get_excerpt(
+    hash: &DocId,
+    db: &DocDb,
+    query: &str,
+    task: &TaskConfig,
+) -> Option<String> {
+    let document = match db.get_chunk(hash).await {
         Ok(document) => document,
         Err(_) => return None,
     };
-    let mut titles: Vec<&str> = vec![];
-    let mut hash_for_title = Some(hash);
-    loop {
-        match hash_for_title {
-            Some(hash) => {
-                if let Some(title) = db.get_title(hash) {
-                    titles.push(title);
-                }
-                hash_for_title = db.get_parent(hash);
-            }
-            None => break,
+    let titles = db
+        .get_path(hash)
+        .iter()
+        .filter_map(|x| db.get_title(x))
+        .collect::<Vec<_>>();
+    let mut parts = Vec::new();
+    if !titles.is_empty() {
+        parts.push(format!("# {}", titles.join(" > ")));
+    }
+    if let Some(reviewed) = db.get_reviewed(hash) {
+        let stale = task.stale_after_days.is_some_and(|x| db.is_stale(hash, x));
+        if stale {
+            parts.push(format!("Last reviewed: {} (may be out of date)", reviewed));
+        } else {
+            parts.push(format!("Last reviewed: {}", reviewed));
         }
     }
-    if !titles.is_empty() {
-        format!(
-            "# {}\n\n{}\n\n<id:{}>",
-            titles.into_iter().rev().collect::<Vec<_>>().join(" > "),
-            document.trim(),
-            hex::encode(hash)
-        )
-        .pipe(Some)
-    } else {
-        format!("{}\n\n<id:{}>", document.trim(), hex::encode(hash)).pipe(Some)
+    let document = document.trim();
+    match task.excerpt_tokens {
+        Some(budget) => parts.push(truncate_document(&task.model, document, query, budget)),
+        None => parts.push(document.to_string()),
     }
+    parts.push(format!("<id:{}>", hex::encode(hash)));
+    parts.join("\n\n").pipe(Some)
+}
+
+/// Get the excerpts for the documents `hashes`, in the same order, as
+/// [`get_excerpt`] does.
+///
+/// A document whose text is in another of the `hashes`, such as a chunk
+/// retrieved along with its parent, is replaced by the one containing it so
+/// the text isn't repeated.
+///
+/// At most [`DocDb::get_fetch_concurrency`] documents are fetched at once.
+/// Documents that can't be fetched are skipped.
+pub async fn get_excerpts(
+    hashes: &[DocId],
+    db: &DocDb,
+    query: &str,
+    task: &TaskConfig,
+) -> Vec<String> {
+    stream::iter(db.merge_contained(hashes))
+        .map(|x| async move { get_excerpt(&x, db, query, task).await })
+        .buffered(db.get_fetch_concurrency())
+        .filter_map(|x| async { x })
+        .collect()
+        .await
+}
+
+/// A function the model is asked to call to record its output.
+pub struct Function {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// The request of a prompt made of the `system` message, the examples of the
+/// `task` and the user's `instructions`.
+pub fn prompt_args(
+    system: String,
+    instructions: String,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> ChatCompletionArgs {
+    ChatCompletionArgs::new(key)
+        .with_usage(usage)
+        .with_model(task.model.clone())
+        .with_temperature(task.temperature)
+        .with_message(ChatCompletionMessage::system(system))
+        .with_examples(&task.examples)
+        .with_message(ChatCompletionMessage::user(instructions))
+}
+
+/// Ask the model to call the `function` after the `system` message, the
+/// examples of the `task` and the user's `instructions`.
+pub async fn call_function<T>(
+    function: &Function,
+    system: String,
+    instructions: String,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+) -> Result<T>
+where
+    T: DeserializeOwned + JsonSchema,
+{
+    chat_completion_function(
+        prompt_args(system, instructions, key, usage, task),
+        function.name.to_string(),
+        Some(function.description.to_string()),
+        task.max_retries,
+    )
+    .await
+    .map_err(Error::OpenAIError)
 }
 
-pub async fn embed_for_db(text: &str, db: &DocDb, key: &str) -> Result<Array1<N32>> {
-    let embedding = embed(&key, text)
-        .await?
+/// Like [`call_function`], but sample as many completions as the `task`
+/// asks for and `select` the output among them.
+pub async fn call_function_select<T>(
+    function: &Function,
+    system: String,
+    instructions: String,
+    key: String,
+    usage: &UsageTracker,
+    task: &TaskConfig,
+    select: impl Fn(Vec<T>) -> T,
+) -> Result<T>
+where
+    T: DeserializeOwned + JsonSchema,
+{
+    chat_completion_function_select(
+        prompt_args(system, instructions, key, usage, task).with_n(task.samples),
+        function.name.to_string(),
+        Some(function.description.to_string()),
+        task.max_retries,
+        select,
+    )
+    .await
+    .map_err(Error::OpenAIError)
+}
+
+/// Pick the first of the `samples` with the largest `key`, to `select` the
+/// output of [`call_function_select`].
+///
+/// The samples are never empty, as [`chat_completion_function_select`] calls
+/// `select` with at least one output.
+pub fn first_max_by_key<T, K: Ord>(samples: Vec<T>, key: impl Fn(&T) -> K) -> T {
+    samples
         .into_iter()
-        .map(|x| N32::try_from(x))
-        .collect::<std::result::Result<Vec<_>, _>>()
-        .map_err(|_| Error::EmbeddingError)?;
+        .rev()
+        .max_by_key(key)
+        .expect("samples aren't empty")
+}
+
+/// Render the `system` message and `instructions` of a prompt, with as many
+/// of the `excerpts` as fit in the context window of the model of the `task`
+/// along with its examples.
+///
+/// `render` renders them with the excerpts it's given, and is first called
+/// without any to measure the rest of the prompt.
+pub fn fit_excerpts(
+    excerpts: Vec<String>,
+    task: &TaskConfig,
+    render: impl Fn(&[String]) -> Result<(String, String)>,
+) -> Result<(String, String)> {
+    let (system, instructions) = render(&[])?;
+    let fixed = [
+        vec![ChatCompletionMessage::system(system)],
+        Example::messages(&task.examples),
+        vec![ChatCompletionMessage::user(instructions)],
+    ]
+    .concat();
+    let (excerpts, _) = fit_context(&task.model, &fixed, excerpts, Vec::new());
+    render(&excerpts)
+}
+
+/// Fail with [`Error::Flagged`] if the `task` screens messages and moderation
+/// flags the `message`.
+pub async fn screen_message(message: &str, key: &str, task: &TaskConfig) -> Result<()> {
+    if !task.moderate {
+        return Ok(());
+    }
+    let moderation = moderate(key, message, Some(DEFAULT_TIMEOUT), task.max_retries).await?;
+    if moderation.flagged {
+        return Err(Error::Flagged(moderation));
+    }
+    Ok(())
+}
+
+/// Embed `text` with the model of the `db`, retrying a failed request up to
+/// `max_retries` times.
+pub async fn embed_for_db(
+    text: &str,
+    db: &DocDb,
+    key: &str,
+    usage: &UsageTracker,
+    max_retries: usize,
+) -> Result<Array1<N32>> {
+    let embedding = embed(
+        key,
+        text,
+        db.get_embedding_model(),
+        db.get_embedding_dimensions(),
+        Some(DEFAULT_TIMEOUT),
+        usage,
+        max_retries,
+    )
+    .await?
+    .into_iter()
+    .map(N32::try_from)
+    .collect::<std::result::Result<Vec<_>, _>>()
+    .map_err(|_| Error::EmbeddingError)?;
     let embedding =
         Array1::from_shape_vec((embedding.len(),), embedding).map_err(|_| Error::EmbeddingError)?;
-    db.get_pca_mapped(embedding.view()).to_owned().pipe(Ok)
+    db.get_pca_mapped(embedding.view())
+        .map_err(Error::DocDbError)?
+        .to_owned()
+        .pipe(Ok)
+}
+
+/// Get the documents most similar to the `query`, as many as the `task`
+/// retrieves, leaving out those less similar than its threshold and those
+/// that don't pass the `filter`.
+///
+/// If the `task` asks for distinct documents or diversity, the documents are
+/// re-ranked so they aren't near duplicates.
+pub fn similar_documents(
+    db: &DocDb,
+    query: &Array1<N32>,
+    filter: Option<&Filter>,
+    task: &TaskConfig,
+) -> Result<Vec<DocId>> {
+    let depth = task.retrieval_depth;
+    let languages = (!task.languages.is_empty()).then(|| Filter {
+        languages: task.languages.clone(),
+        ..filter.cloned().unwrap_or_default()
+    });
+    let filter = languages.as_ref().or(filter);
+    let min_similarity = task.min_similarity;
+    let scored = match (task.parent_aggregation, task.mmr_lambda) {
+        (Some(aggregation), _) => {
+            db.get_similar_by_parent(query.view(), depth, filter, aggregation, min_similarity)
+        }
+        (None, Some(lambda)) => {
+            db.get_similar_diverse(query.view(), depth, filter, lambda, min_similarity)
+        }
+        (None, None) => {
+            return match min_similarity {
+                Some(min_score) => db.get_similar_above(query.view(), min_score, depth, filter),
+                None => db.get_similar(query.view(), depth, filter),
+            }
+            .map_err(Error::DocDbError)
+        }
+    };
+    scored
+        .map_err(Error::DocDbError)?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>()
+        .pipe(Ok)
+}
+
+/// The documents with the hex IDs in `sources`, in order, skipping those
+/// that aren't `retrieved` so made-up IDs aren't cited.
+pub fn retrieved_sources(sources: &[String], retrieved: &[DocId]) -> Vec<DocId> {
+    let mut found = Vec::new();
+    for source in sources {
+        let Ok(hash) = decode_doc_id(source.trim().as_bytes()) else {
+            continue;
+        };
+        if retrieved.contains(&hash) && !found.contains(&hash) {
+            found.push(hash);
+        }
+    }
+    found
 }
 
 #[cfg(test)]
 mod test {
+    #[test]
+    fn fits_excerpts_in_context_window() {
+        let task = super::TaskConfig {
+            model: "unregistered-model".to_string().into(),
+            ..Default::default()
+        };
+        let excerpts = vec!["Short excerpt.".to_string(), "word ".repeat(20_000)];
+        let (system, instructions) = super::fit_excerpts(excerpts, &task, |x| {
+            Ok((x.join("\n"), "Instructions.".to_string()))
+        })
+        .unwrap();
+        assert_eq!(system, "Short excerpt.");
+        assert_eq!(instructions, "Instructions.");
+    }
+
+    #[test]
+    fn overrides_system_identity() {
+        let task = super::TaskConfig {
+            system_identity: Some("Remind the user you are not a doctor.".to_string()),
+            ..Default::default()
+        };
+        let system = super::SystemInstructionsExcerpts::new(&[], &Default::default(), &task)
+            .render(&Default::default())
+            .unwrap();
+        assert!(system.starts_with("Remind the user you are not a doctor."));
+        assert!(!system.contains(super::SYSTEM_IDENTITY));
+    }
+
+    #[test]
+    fn places_profile_in_system_instructions() {
+        let task = super::TaskConfig::default();
+        let profile = super::Profile {
+            age: Some(42),
+            ..Default::default()
+        };
+        let system = super::system_identity_with_profile(&profile, &task).unwrap();
+        assert!(system.starts_with(super::SYSTEM_IDENTITY));
+        assert!(system.ends_with(&format!("profile is:\n\n{}", profile.to_markdown())));
+        let system = super::system_identity_with_profile(&Default::default(), &task).unwrap();
+        assert_eq!(system, super::SYSTEM_IDENTITY);
+        let system = super::SystemInstructionsExcerpts::new(&["abc".to_string()], &profile, &task)
+            .render(&task)
+            .unwrap();
+        assert!(system.contains(&format!(
+            "profile is:\n\n{}\n\nYou can",
+            profile.to_markdown()
+        )));
+    }
+
+    #[test]
+    fn selects_audience_instructions() {
+        let task = super::TaskConfig {
+            audience: Some(super::Audience::EighthGrade),
+            ..Default::default()
+        };
+        assert_eq!(
+            super::audience_instructions(&task),
+            Some(super::AUDIENCE_EIGHTH_GRADE.default.to_string())
+        );
+        assert_eq!(super::audience_instructions(&Default::default()), None);
+    }
+
+    #[test]
+    fn names_languages() {
+        assert_eq!(super::language_name("es-MX"), "Spanish");
+        assert_eq!(super::language_name("PT_br"), "Portuguese");
+        assert_eq!(super::language_name("xx"), "the language of the locale xx");
+    }
+
+    #[test]
+    fn truncates_documents_around_the_query() {
+        let model = super::ChatCompletionModel::GPT_4O;
+        let filler = "Unrelated background about the history of medicine. ".repeat(8);
+        let related = "Migraine causes a throbbing headache with nausea. ".repeat(8);
+        let document = [
+            filler.trim(),
+            related.trim(),
+            "References: Smith et al., 2020.",
+            filler.trim(),
+        ]
+        .join("\n\n");
+        let budget = super::count_tokens(&model, &related) + 8;
+        assert_eq!(
+            super::truncate_document(&model, &document, "headache and nausea", budget),
+            format!("[...]\n\n{}\n\n[...]", related.trim())
+        );
+        assert_eq!(
+            super::truncate_document(&model, "Short.", "headache", budget),
+            "Short."
+        );
+    }
+
+    #[test]
+    fn keeps_retrieved_sources() {
+        let sources = [
+            hex::encode([2; 16]),
+            "not hex".to_string(),
+            hex::encode([3; 16]),
+            hex::encode([2; 16]),
+        ];
+        assert_eq!(
+            super::retrieved_sources(&sources, &[[1; 16], [2; 16]]),
+            [[2; 16]]
+        );
+    }
+
     #[test]
     fn quotes_lines() {
         assert_eq!(
@@ -0,0 +1,100 @@
+//! Async timers that don't block the browser's main thread.
+
+use std::future::Future;
+use std::pin::pin;
+use std::time::Duration;
+
+use futures::future::{select, Either};
+
+/// The future didn't complete before its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Wait for `duration` without blocking the thread.
+///
+/// Uses `setTimeout` in the browser and the tokio timer elsewhere.
+pub async fn sleep(duration: Duration) {
+    if duration.is_zero() {
+        return;
+    }
+    #[cfg(target_arch = "wasm32")]
+    gloo_timers::future::sleep(duration).await;
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::time::sleep(duration).await;
+}
+
+/// Let the browser handle events and repaint before continuing, such as
+/// between the stages of a long computation.
+///
+/// Does nothing outside the browser.
+pub async fn yield_now() {
+    #[cfg(target_arch = "wasm32")]
+    gloo_timers::future::TimeoutFuture::new(0).await;
+}
+
+/// The time in milliseconds since an arbitrary point in the past.
+///
+/// Only differences between two calls are meaningful.
+pub fn now_ms() -> f64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        use std::sync::OnceLock;
+        use std::time::Instant;
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+/// The time in milliseconds since the Unix epoch, by the system clock.
+pub fn unix_ms() -> f64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |x| x.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Run `future` to completion unless it takes longer than `duration`.
+///
+/// Without a `duration`, the future can run forever.
+pub async fn timeout<F: Future>(
+    duration: Option<Duration>,
+    future: F,
+) -> Result<F::Output, TimedOut> {
+    let duration = match duration {
+        Some(x) => x,
+        None => return Ok(future.await),
+    };
+    match select(pin!(future), pin!(sleep(duration))).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(TimedOut),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn completes_without_timeout() {
+        assert_eq!(
+            futures::executor::block_on(timeout(None, async { 1 })),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn sleeps_zero_without_runtime() {
+        futures::executor::block_on(sleep(Duration::ZERO));
+    }
+}
@@ -1,6 +1,4 @@
 use serde::Serialize;
-use thiserror;
-use tinytemplate;
 use tinytemplate::{format_unescaped, TinyTemplate};
 
 #[derive(Debug, thiserror::Error)]
@@ -3,11 +3,50 @@
 #![cfg(target_arch = "wasm32")]
 
 extern crate wasm_bindgen_test;
+use clint_lib::*;
+use wasm_bindgen::JsValue;
 use wasm_bindgen_test::*;
 
 wasm_bindgen_test_configure!(run_in_browser);
 
+fn json(text: &str) -> JsValue {
+    js_sys::JSON::parse(text).unwrap()
+}
+
+#[wasm_bindgen_test]
+fn parses_config() {
+    assert!(ClintConfigJs::new(JsValue::UNDEFINED).is_ok());
+    assert!(ClintConfigJs::new(json(r#"{"respond": {"retrieval_depth": 4}}"#)).is_ok());
+    assert!(ClintConfigJs::new(json(r#"{"respond": {"retrieval_depth": "4"}}"#)).is_err());
+}
+
+#[wasm_bindgen_test]
+fn round_trips_state() {
+    let mut state = StateJs::new();
+    state.set_statement(Some("abc".to_string()));
+    state.add_user_message("bcd".to_string());
+    state.add_assistant_message("cde".to_string());
+    let text = state.to_string().unwrap();
+    assert_eq!(
+        StateJs::from_string(&text).unwrap().to_string().unwrap(),
+        text
+    );
+}
+
+#[wasm_bindgen_test]
+fn checks_prompt_templates() {
+    let templates = prompt_templates_js().unwrap();
+    assert!(js_sys::Array::is_array(&templates));
+    assert!(set_prompt_templates_js(json(r#"{"not_a_template": "abc"}"#), None).is_err());
+    assert!(set_prompt_templates_js(JsValue::UNDEFINED, None).is_ok());
+}
+
+#[cfg(feature = "replay")]
 #[wasm_bindgen_test]
-fn pass() {
-    assert_eq!(1 + 1, 2);
+async fn fails_without_fixture() {
+    set_replay_js(JsValue::from_str("replay"), JsValue::UNDEFINED).unwrap();
+    let config = ClintConfigJs::new(JsValue::UNDEFINED).unwrap();
+    let rewritten = rewrite_message_js("abc", "", &config, None).await;
+    set_replay_js(JsValue::UNDEFINED, JsValue::UNDEFINED).unwrap();
+    assert!(rewritten.is_err());
 }